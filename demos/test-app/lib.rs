@@ -104,6 +104,7 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
     let path = parts[0];
     let mut headers = vec![];
     headers.push(("Access-Control-Allow-Origin".to_string(), "*".to_string()));
+    // there is no certificate in replicated execution, e.g. when called by another canister
     let certificate_header =
         ASSET_HASHES.with(|a| make_asset_certificate_header(&a.borrow(), path));
 
@@ -113,12 +114,12 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
             let mut status_code = 200;
             match mode.clone() {
                 CertifiedContent => {
-                    headers.push(certificate_header);
+                    headers.extend(certificate_header);
                 }
                 Redirect { location } => {
                     // needs to be certified content for the service worker
                     // (which the browser will then ignore and redirect anyway)
-                    headers.push(certificate_header);
+                    headers.extend(certificate_header);
                     headers.push(("Location".to_string(), location));
                     status_code = 302;
                 }
@@ -137,7 +138,7 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
             })
         }),
         _ => {
-            headers.push(certificate_header);
+            headers.extend(certificate_header);
             ASSETS.with(|a| match a.borrow().get(path) {
                 Some((asset_headers, value)) => {
                     headers.append(&mut asset_headers.clone());
@@ -161,24 +162,22 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
 fn make_asset_certificate_header(
     asset_hashes: &RbTree<&'static str, Hash>,
     asset_name: &str,
-) -> (String, String) {
-    let certificate = api::data_certificate().unwrap_or_else(|| {
-        api::trap("data certificate is only available in query calls");
-    });
+) -> Option<(String, String)> {
+    let certificate = api::data_certificate()?;
     let witness = asset_hashes.witness(asset_name.as_bytes());
     let tree = ic_certified_map::labeled(b"http_assets", witness);
     let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
     serializer.self_describe().unwrap();
     tree.serialize(&mut serializer)
         .unwrap_or_else(|e| api::trap(&format!("failed to serialize a hash tree: {}", e)));
-    (
+    Some((
         "IC-Certificate".to_string(),
         format!(
             "certificate=:{}:, tree=:{}:",
             base64::encode(&certificate),
             base64::encode(&serializer.into_inner())
         ),
-    )
+    ))
 }
 
 #[init]
@@ -30,6 +30,13 @@ pub fn create_challenge(
     framework::call_candid(env, canister_id, "create_challenge", ()).map(|(x,)| x)
 }
 
+pub fn create_challenge_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<types::CreateChallengeResponse, CallError> {
+    framework::call_candid(env, canister_id, "create_challenge_v2", ()).map(|(x,)| x)
+}
+
 pub fn register(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -55,6 +62,8 @@ pub fn prepare_delegation(
     frontend_hostname: types::FrontendHostname,
     session_key: types::SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    actual_origin: Option<types::FrontendHostname>,
 ) -> Result<(types::UserKey, types::Timestamp), CallError> {
     framework::call_candid_as(
         env,
@@ -66,6 +75,8 @@ pub fn prepare_delegation(
             frontend_hostname,
             session_key,
             max_time_to_live,
+            targets,
+            actual_origin,
         ),
     )
 }
@@ -78,13 +89,20 @@ pub fn get_delegation(
     frontend_hostname: types::FrontendHostname,
     session_key: types::SessionKey,
     timestamp: u64,
+    targets: Option<Vec<Principal>>,
 ) -> Result<types::GetDelegationResponse, CallError> {
     framework::query_candid_as(
         env,
         canister_id,
         sender,
         "get_delegation",
-        (user_number, frontend_hostname, session_key, timestamp),
+        (
+            user_number,
+            frontend_hostname,
+            session_key,
+            timestamp,
+            targets,
+        ),
     )
     .map(|(x,)| x)
 }
@@ -106,6 +124,23 @@ pub fn get_principal(
     .map(|(x,)| x)
 }
 
+pub fn get_principals(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostnames: Vec<types::FrontendHostname>,
+) -> Result<Vec<(types::FrontendHostname, Principal)>, CallError> {
+    framework::query_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_principals",
+        (user_number, frontend_hostnames),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn lookup(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -114,6 +149,28 @@ pub fn lookup(
     framework::query_candid(env, canister_id, "lookup", (user_number,)).map(|(x,)| x)
 }
 
+pub fn lookup_certified(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    user_number: types::UserNumber,
+) -> Result<types::CertifiedLookupResponse, CallError> {
+    framework::query_candid(env, canister_id, "lookup_certified", (user_number,)).map(|(x,)| x)
+}
+
+pub fn lookup_by_credential_id(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    credential_id: types::CredentialId,
+) -> Result<types::CredentialLookupResponse, CallError> {
+    framework::query_candid(
+        env,
+        canister_id,
+        "lookup_by_credential_id",
+        (credential_id,),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn add(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -157,6 +214,22 @@ pub fn remove(
     )
 }
 
+pub fn apply_device_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    operations: Vec<types::DeviceOperation>,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "apply_device_operations",
+        (user_number, operations),
+    )
+}
+
 pub fn get_anchor_info(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -167,6 +240,118 @@ pub fn get_anchor_info(
         .map(|(x,)| x)
 }
 
+pub fn add_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    device_data: types::DeviceData,
+) -> Result<types::AddDeviceResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "add_v2",
+        (user_number, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn update_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    device_key: types::PublicKey,
+    device_data: types::DeviceData,
+) -> Result<types::UpdateDeviceResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "update_v2",
+        (user_number, device_key, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn remove_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    device_key: types::PublicKey,
+) -> Result<types::RemoveDeviceResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "remove_v2",
+        (user_number, device_key),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_anchor_info_certified(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<types::CertifiedAnchorInfoResponse, CallError> {
+    framework::query_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_anchor_info_certified",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_anchor_info_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<types::GetAnchorInfoResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_anchor_info_v2",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn prepare_delegation_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostname: types::FrontendHostname,
+    session_key: types::SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    actual_origin: Option<types::FrontendHostname>,
+) -> Result<types::PrepareDelegationResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "prepare_delegation_v2",
+        (
+            user_number,
+            frontend_hostname,
+            session_key,
+            max_time_to_live,
+            targets,
+            actual_origin,
+        ),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn enter_device_registration_mode(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -221,16 +406,198 @@ pub fn verify_tentative_device(
     sender: PrincipalId,
     user_number: types::UserNumber,
     verification_code: types::DeviceVerificationCode,
+    tentative_device_key: Option<types::DeviceKey>,
 ) -> Result<types::VerifyTentativeDeviceResponse, CallError> {
     framework::call_candid_as(
         env,
         canister_id,
         sender,
         "verify_tentative_device",
-        (user_number, verification_code),
+        (user_number, verification_code, tentative_device_key),
     )
     .map(|(x,)| x)
 }
 
+pub fn config(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<types::InternetIdentityConfig, CallError> {
+    framework::query_candid(env, canister_id, "config", ()).map(|(x,)| x)
+}
+
+pub fn export_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    start: types::UserNumber,
+    count: u32,
+) -> Result<types::AnchorExport, CallError> {
+    framework::query_candid_as(env, canister_id, sender, "export_anchors", (start, count))
+        .map(|(x,)| x)
+}
+
+pub fn import_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    header: types::AnchorExportHeader,
+    anchors: Vec<types::ExportedAnchor>,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "import_anchors",
+        (header, anchors),
+    )
+}
+
 /// A "compatibility" module for the previous version of II to handle API changes.
 pub mod compat {}
+
+pub fn get_anchor_events(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    cursor: Option<u64>,
+) -> Result<types::AnchorEvents, CallError> {
+    framework::query_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_anchor_events",
+        (user_number, cursor),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn lock_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<(), CallError> {
+    framework::call_candid_as(env, canister_id, sender, "lock_anchor", (user_number,))
+}
+
+pub fn unlock_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<(), CallError> {
+    framework::call_candid_as(env, canister_id, sender, "unlock_anchor", (user_number,))
+}
+
+pub fn set_recovery_device_policy(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    policy: types::RecoveryDevicePolicy,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "set_recovery_device_policy",
+        (user_number, policy),
+    )
+}
+
+pub fn delete_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<types::DeleteAnchorResponse, CallError> {
+    framework::call_candid_as(env, canister_id, sender, "delete_anchor", (user_number,))
+        .map(|(x,)| x)
+}
+
+pub fn list_sessions(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<Vec<types::SessionInfo>, CallError> {
+    framework::call_candid_as(env, canister_id, sender, "list_sessions", (user_number,))
+        .map(|(x,)| x)
+}
+
+pub fn revoke_session(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    session_key_hash: types::SessionKeyHash,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "revoke_session",
+        (user_number, session_key_hash),
+    )
+}
+
+pub fn revoke_all_sessions_for_frontend(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostname: types::FrontendHostname,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "revoke_all_sessions_for_frontend",
+        (user_number, frontend_hostname),
+    )
+}
+
+pub fn get_session_revocation_status(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    user_key: types::UserKey,
+    session_key: types::SessionKey,
+) -> Result<types::SessionRevocationStatus, CallError> {
+    framework::query_candid(
+        env,
+        canister_id,
+        "get_session_revocation_status",
+        (user_key, session_key),
+    )
+    .map(|(x,)| x)
+}
+
+/// Bindings for the test app (demos/test-app), which serves as a dapp with alternative origins.
+pub mod test_app {
+    use crate::framework;
+    use crate::framework::CallError;
+    use candid::{CandidType, Deserialize};
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+
+    #[derive(Clone, Debug, CandidType, Deserialize)]
+    pub enum AlternativeOriginsMode {
+        CertifiedContent,
+        UncertifiedContent,
+        Redirect { location: String },
+    }
+
+    pub fn update_alternative_origins(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        alternative_origins: &str,
+        mode: AlternativeOriginsMode,
+    ) -> Result<(), CallError> {
+        framework::call_candid(
+            env,
+            canister_id,
+            "update_alternative_origins",
+            (alternative_origins, mode),
+        )
+    }
+}
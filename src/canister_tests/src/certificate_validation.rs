@@ -26,6 +26,8 @@ pub enum ValidationError {
     CertificateExpired,
     AssetPathLookupFailed,
     AssetHashMismatch,
+    RevocationLookupFailed,
+    CredentialsLookupFailed,
 }
 
 /// Validates asset certification according to the HTTP gateway specification:
@@ -91,6 +93,62 @@ pub fn validate_certification(
     Ok(())
 }
 
+/// Validates the certified revocation status of a session key and returns whether the session key
+/// is revoked for the given user key. The tree must either contain the path
+/// ["revoked", sha256(seed), sha256(session_key)] or prove its absence, where the seed is the last
+/// 32 bytes of the user key.
+pub fn validate_session_revocation_status(
+    certificate: &[u8],
+    tree: &[u8],
+    canister_id: CanisterId,
+    user_key: &[u8],
+    session_key: &[u8],
+    root_key: ThresholdSigPublicKey,
+) -> Result<bool, ValidationError> {
+    let tree: HashTree = serde_cbor::from_slice(tree).map_err(|err| MalformedCertificate {
+        message: format!("failed to decode cbor value: {:?}", err),
+    })?;
+    verify_certificate(certificate, &canister_id, &root_key, &tree.digest())
+        .map_err(|err| ValidationError::CertificateValidationFailed { inner: err })?;
+
+    let seed = &user_key[user_key.len().saturating_sub(32)..];
+    let seed_hash: [u8; 32] = Sha256::digest(seed).into();
+    let session_key_hash: [u8; 32] = Sha256::digest(session_key).into();
+    match tree.lookup_path(&[
+        "revoked".into(),
+        (&seed_hash[..]).into(),
+        (&session_key_hash[..]).into(),
+    ]) {
+        LookupResult::Found(_) => Ok(true),
+        LookupResult::Absent => Ok(false),
+        _ => Err(ValidationError::RevocationLookupFailed),
+    }
+}
+
+/// Validates the certified response of `lookup_certified` and returns the certified hash of the
+/// devices of the anchor, found at the path ["credentials", <user_number as big-endian u64>].
+pub fn validate_certified_lookup(
+    certificate: &[u8],
+    tree: &[u8],
+    canister_id: CanisterId,
+    user_number: u64,
+    root_key: ThresholdSigPublicKey,
+) -> Result<Vec<u8>, ValidationError> {
+    let tree: HashTree = serde_cbor::from_slice(tree).map_err(|err| MalformedCertificate {
+        message: format!("failed to decode cbor value: {:?}", err),
+    })?;
+    verify_certificate(certificate, &canister_id, &root_key, &tree.digest())
+        .map_err(|err| ValidationError::CertificateValidationFailed { inner: err })?;
+
+    match tree.lookup_path(&[
+        "credentials".into(),
+        (&user_number.to_be_bytes()[..]).into(),
+    ]) {
+        LookupResult::Found(hash) => Ok(hash.to_vec()),
+        _ => Err(ValidationError::CredentialsLookupFailed),
+    }
+}
+
 fn parse_header(ic_certificate: &str) -> Result<(&str, &str), ValidationError> {
     let captures = Regex::new("^certificate=:([^:]*):,\\s*tree=:([^:]*):$")
         .unwrap()
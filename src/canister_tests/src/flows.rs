@@ -1,5 +1,5 @@
-use crate::api::{create_challenge, http_request, register};
-use crate::framework::{device_data_1, principal_1};
+use crate::api::{create_challenge, get_anchor_info, http_request, register};
+use crate::framework::{device_data_1, principal_1, CallError};
 use ic_state_machine_tests::{CanisterId, StateMachine};
 use ic_types::PrincipalId;
 use internet_identity_interface::{
@@ -34,6 +34,21 @@ pub fn register_anchor_with(
     user_number
 }
 
+/// Returns the devices of the anchor including their aliases, which `lookup` does not expose.
+pub fn get_devices(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: UserNumber,
+) -> Result<Vec<DeviceData>, CallError> {
+    let anchor_info = get_anchor_info(env, canister_id, sender, user_number)?;
+    Ok(anchor_info
+        .devices
+        .into_iter()
+        .map(DeviceData::from)
+        .collect())
+}
+
 pub fn get_metrics(env: &StateMachine, canister_id: CanisterId) -> String {
     let response = http_request(
         &env,
@@ -48,6 +48,20 @@ lazy_static! {
         ", &def_path, &std::env::current_dir().map(|x| x.display().to_string()).unwrap_or("an unknown directory".to_string()));
         get_wasm_path("II_WASM".to_string(), &def_path).expect(&err)
     };
+
+    /** The Wasm module of the test app, which is used as a dapp listing alternative origins */
+    pub static ref TEST_APP_WASM: Vec<u8> = {
+        let def_path = path::PathBuf::from("..").join("..").join("demos").join("test-app").join("test_app.wasm");
+        let err = format!("
+        Could not find the test app Wasm module.
+
+        I will look for it at {:?}, and you can specify another path with the environment variable TEST_APP_WASM (note that I run from {:?}).
+
+        In order to build the Wasm module, please run the following command:
+            ./demos/test-app/build.sh
+        ", &def_path, &std::env::current_dir().map(|x| x.display().to_string()).unwrap_or("an unknown directory".to_string()));
+        get_wasm_path("TEST_APP_WASM".to_string(), &def_path).expect(&err)
+    };
 }
 
 /** Helper that returns the content of `default_path` if found, or None if the file does not exist.
@@ -96,6 +110,12 @@ pub fn install_ii_canister_with_arg(
     env.install_canister(wasm, byts, None).unwrap()
 }
 
+pub fn install_test_app_canister(env: &StateMachine) -> CanisterId {
+    let byts = candid::encode_args(()).expect("error encoding test app installation arg");
+    env.install_canister(TEST_APP_WASM.clone(), byts, None)
+        .unwrap()
+}
+
 pub fn upgrade_ii_canister(env: &StateMachine, canister_id: CanisterId, wasm: Vec<u8>) {
     let nulls = vec![IDLValue::Null; 1];
     let args = IDLArgs::new(&nulls);
@@ -103,10 +123,35 @@ pub fn upgrade_ii_canister(env: &StateMachine, canister_id: CanisterId, wasm: Ve
     env.upgrade_canister(canister_id, wasm, byts).unwrap()
 }
 
-pub const PUBKEY_1: &str = "test";
-pub const PUBKEY_2: &str = "some other key";
-pub const RECOVERY_PUBKEY_1: &str = "recovery 1";
-pub const RECOVERY_PUBKEY_2: &str = "recovery 2";
+pub fn upgrade_ii_canister_with_arg(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    wasm: Vec<u8>,
+    arg: Option<InternetIdentityInit>,
+) -> Result<(), UserError> {
+    let byts = candid::encode_one(arg).expect("error encoding II upgrade arg as candid");
+    env.upgrade_canister(canister_id, wasm, byts)
+}
+
+pub const PUBKEY_1: &[u8] = &ed25519_der_key(0x01);
+pub const PUBKEY_2: &[u8] = &ed25519_der_key(0x02);
+pub const RECOVERY_PUBKEY_1: &[u8] = &ed25519_der_key(0x11);
+pub const RECOVERY_PUBKEY_2: &[u8] = &ed25519_der_key(0x12);
+
+/// A DER encoded Ed25519 public key with all key bytes set to `fill`. The canister only checks
+/// the structure of device keys, so these make valid test keys.
+pub const fn ed25519_der_key(fill: u8) -> [u8; 44] {
+    const PREFIX: [u8; 12] = [
+        0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+    ];
+    let mut key = [fill; 44];
+    let mut i = 0;
+    while i < PREFIX.len() {
+        key[i] = PREFIX[i];
+        i += 1;
+    }
+    key
+}
 
 pub fn principal_1() -> PrincipalId {
     PrincipalId(Principal::self_authenticating(PUBKEY_1))
@@ -167,6 +212,14 @@ pub fn recovery_device_data_2() -> types::DeviceData {
     }
 }
 
+/// The device as returned by `lookup`, which does not expose device aliases.
+pub fn without_alias(device: types::DeviceData) -> types::DeviceData {
+    types::DeviceData {
+        alias: String::new(),
+        ..device
+    }
+}
+
 /* Here are a few functions that are not directly related to II and could be upstreamed
  * (were actually stolen from somewhere else)
  */
@@ -402,10 +455,19 @@ pub fn verify_delegation(
 ) {
     // transform delegation into ic typed delegation so that we have access to the signature domain separator
     // (via as_signed_bytes)
-    let delegation = Delegation::new(
-        signed_delegation.delegation.pubkey.clone().into_vec(),
-        Time::from_nanos_since_unix_epoch(signed_delegation.delegation.expiration),
-    );
+    let pubkey = signed_delegation.delegation.pubkey.clone().into_vec();
+    let expiration = Time::from_nanos_since_unix_epoch(signed_delegation.delegation.expiration);
+    let delegation = match signed_delegation.delegation.targets {
+        Some(ref targets) => Delegation::new_with_targets(
+            pubkey,
+            expiration,
+            targets
+                .iter()
+                .map(|target| CanisterId::new(PrincipalId(*target)).unwrap())
+                .collect(),
+        ),
+        None => Delegation::new(pubkey, expiration),
+    };
 
     // this requires imports of internal crypto infrastructure
     // -> extend state-machine-tests to offer the functionality instead (see L2-739)
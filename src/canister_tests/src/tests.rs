@@ -27,7 +27,10 @@ fn ii_upgrade_retains_anchors() {
 
     let retrieved_device_data = api::lookup(&env, canister_id, user_number).expect("lookup failed");
 
-    assert_eq!(retrieved_device_data, vec![device_data_1()]);
+    assert_eq!(
+        retrieved_device_data,
+        vec![framework::without_alias(device_data_1())]
+    );
 }
 
 /// Tests for making sure that any release can be rolled back. This tests stable memory compatibility and pre / post install hooks.
@@ -37,6 +40,7 @@ mod rollback_tests {
     use crate::{api, flows, framework};
     use candid::Principal;
     use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface::DeviceData;
     use serde_bytes::ByteBuf;
 
     /// Tests simple upgrade and downgrade.
@@ -61,9 +65,13 @@ mod rollback_tests {
         api::health_check(&env, canister_id);
         framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM_PREVIOUS.clone());
         api::health_check(&env, canister_id);
-        let mut devices_after = api::get_anchor_info(&env, canister_id, principal_1(), user_number)
-            .unwrap()
-            .devices;
+        let mut devices_after: Vec<DeviceData> =
+            api::get_anchor_info(&env, canister_id, principal_1(), user_number)
+                .unwrap()
+                .devices
+                .into_iter()
+                .map(DeviceData::from)
+                .collect();
 
         devices_before.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
         devices_after.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
@@ -94,7 +102,7 @@ mod rollback_tests {
 
         // use anchor
         let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices, [device_data_1()]);
+        assert_eq!(devices, [framework::without_alias(device_data_1())]);
 
         let (user_key, _) = api::prepare_delegation(
             &env,
@@ -104,6 +112,8 @@ mod rollback_tests {
             frontend_hostname.to_string(),
             ByteBuf::from("session key"),
             None,
+            None,
+            None,
         )?;
         assert_eq!(Principal::self_authenticating(user_key), principal);
 
@@ -132,9 +142,10 @@ mod registration_tests {
     use ic_error_types::ErrorCode::CanisterCalledTrap;
     use ic_state_machine_tests::StateMachine;
     use internet_identity_interface::{
-        ChallengeAttempt, DeviceProtection, InternetIdentityInit, RegisterResponse,
+        ChallengeAttempt, DeviceData, DeviceProtection, InternetIdentityInit, RegisterResponse,
     };
     use regex::Regex;
+    use serde_bytes::ByteBuf;
     use std::time::Duration;
 
     /// Tests user registration with cross checks for lookup, get_anchor_info and get_principal.
@@ -145,9 +156,16 @@ mod registration_tests {
         let user_number = flows::register_anchor(&env, canister_id);
 
         let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices, vec![device_data_1()]);
+        assert_eq!(devices, vec![framework::without_alias(device_data_1())]);
         let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
-        assert_eq!(anchor_info.devices, vec![device_data_1()]);
+        assert_eq!(
+            anchor_info
+                .devices
+                .into_iter()
+                .map(DeviceData::from)
+                .collect::<Vec<_>>(),
+            vec![device_data_1()]
+        );
         let principal = api::get_principal(
             &env,
             canister_id,
@@ -181,7 +199,10 @@ mod registration_tests {
             &env,
             framework::II_WASM.clone(),
             Some(InternetIdentityInit {
-                assigned_user_number_range: (127, 129),
+                assigned_user_number_range: Some((127, 129)),
+                storage_layout_version: None,
+                admin: None,
+                config: None,
             }),
         );
 
@@ -260,6 +281,35 @@ mod registration_tests {
         Ok(())
     }
 
+    /// Verifies that anchors cannot be registered with a device whose public key is not a valid
+    /// DER encoded key.
+    #[test]
+    fn should_not_register_malformed_public_key() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let mut device1 = device_data_1();
+        device1.pubkey = ByteBuf::from("not a public key");
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        let result = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device1,
+            ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key,
+            },
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("malformed public key").unwrap(),
+        );
+        Ok(())
+    }
+
     /// Tests that the solution to the captcha needs to be correct.
     #[test]
     fn should_not_allow_wrong_captcha() -> Result<(), CallError> {
@@ -339,6 +389,7 @@ mod stable_memory_tests {
     use candid::Principal;
     use ic_error_types::ErrorCode::CanisterCalledTrap;
     use ic_state_machine_tests::{PrincipalId, StateMachine};
+    use internet_identity_interface::CredentialLookupResponse;
     use internet_identity_interface::DeviceData;
     use internet_identity_interface::DeviceProtection::Unprotected;
     use internet_identity_interface::KeyType::Unknown;
@@ -422,18 +473,64 @@ mod stable_memory_tests {
 
         // check known anchors in the backup
         let devices = api::lookup(&env, canister_id, 10_000)?;
-        assert_eq!(devices, vec![device1]);
+        assert_eq!(devices, vec![framework::without_alias(device1)]);
 
         let mut devices = api::lookup(&env, canister_id, 10_002)?;
         devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
-        assert_eq!(devices, vec![device2, device3]);
+        assert_eq!(
+            devices,
+            vec![
+                framework::without_alias(device2),
+                framework::without_alias(device3)
+            ]
+        );
 
         let devices = api::lookup(&env, canister_id, 10_029)?;
-        assert_eq!(devices, vec![device4]);
+        assert_eq!(devices, vec![framework::without_alias(device4)]);
 
         let devices = api::lookup(&env, canister_id, 10_030)?;
-        assert_eq!(devices, vec![device5, device6]);
+        assert_eq!(
+            devices,
+            vec![
+                framework::without_alias(device5),
+                framework::without_alias(device6)
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// Tests that the anchors restored from a backup can be looked up by credential ID.
+    #[test]
+    fn should_lookup_anchors_from_backup_by_credential_id() -> Result<(), CallError> {
+        const CREDENTIAL_ID_1: &str = "63b8afb386dd757dfa5ba9550bca66936717766f395bafad9052a384edc446b11228bcb9cb684980bb5a81270b31d4b9561787296d40204d31e96c1b386b4984";
+        const CREDENTIAL_ID_4: &str = "0192eea062df84cde762eff346aaa3a7fb44f1aa19d888ae407295b77c4c754b755b2b7b90d9174c0cf41d3eb3928f1eb310e3b3a4bc00445179df0f84b7f8b1db";
+
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
 
+        let stable_memory_backup =
+            std::fs::read(PathBuf::from("stable_memory/genesis-memory-layout.bin")).unwrap();
+        env.set_stable_memory(canister_id, &stable_memory_backup);
+        // upgrade again to reset cached header info in II storage module
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_eq!(
+            api::lookup_by_credential_id(
+                &env,
+                canister_id,
+                ByteBuf::from(hex::decode(CREDENTIAL_ID_1).unwrap())
+            )?,
+            CredentialLookupResponse::Anchors(vec![10_000])
+        );
+        assert_eq!(
+            api::lookup_by_credential_id(
+                &env,
+                canister_id,
+                ByteBuf::from(hex::decode(CREDENTIAL_ID_4).unwrap())
+            )?,
+            CredentialLookupResponse::Anchors(vec![10_029])
+        );
         Ok(())
     }
 
@@ -463,6 +560,8 @@ mod stable_memory_tests {
             "example.com".to_string(),
             ByteBuf::from("dummykey"),
             None,
+            None,
+            None,
         )?;
 
         // check that we get the same user key; this proves that the salt was recovered from the backup
@@ -535,6 +634,8 @@ mod stable_memory_tests {
             frontend_hostname.clone(),
             session_key.clone(),
             None,
+            None,
+            None,
         )?;
         api::prepare_delegation(
             &env,
@@ -544,6 +645,8 @@ mod stable_memory_tests {
             frontend_hostname,
             session_key,
             None,
+            None,
+            None,
         )?;
         Ok(())
     }
@@ -598,154 +701,1482 @@ mod stable_memory_tests {
     }
 }
 
-/// Tests related to local device management (add, remove, lookup, get_anchor_info).
-/// Tests for the 'add remote device flow' are in the module [remote_device_registration_tests].
+/// Tests for the storage layout version 2 (variable-sized anchor records) and the migration to it.
 #[cfg(test)]
-mod device_management_tests {
-    use crate::framework::{
-        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
-        recovery_device_data_1, recovery_device_data_2, CallError,
-    };
+mod storage_layout_tests {
+    use crate::framework::{device_data_1, expect_user_error_with_message, principal_1, CallError};
     use crate::{api, flows, framework};
+    use candid::Principal;
     use ic_error_types::ErrorCode::CanisterCalledTrap;
-    use ic_state_machine_tests::StateMachine;
+    use ic_state_machine_tests::{PrincipalId, StateMachine};
     use internet_identity_interface as types;
+    use internet_identity_interface::InternetIdentityInit;
     use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use std::path::PathBuf;
 
-    /// Verifies that a new device can be added.
+    fn layout_v2_arg() -> Option<InternetIdentityInit> {
+        Some(InternetIdentityInit {
+            assigned_user_number_range: None,
+            storage_layout_version: Some(2),
+            admin: None,
+            config: None,
+        })
+    }
+
+    /// A DER encoded RSA public key with a 2104 bit modulus, which is exactly 300 bytes.
+    fn large_pubkey(i: u8) -> Vec<u8> {
+        let mut key =
+            hex::decode("30820128300d06092a864886f70d010101050003820115003082011002820107")
+                .unwrap();
+        key.push(0x40);
+        key.extend([i; 262]);
+        key.extend([0x02, 0x03, 0x01, 0x00, 0x01]);
+        key
+    }
+
+    /// A device that uses up the maximum size of all variable-sized fields.
+    fn large_device(i: u8) -> types::DeviceData {
+        types::DeviceData {
+            pubkey: ByteBuf::from(large_pubkey(i)),
+            alias: format!("{:0>64}", i),
+            credential_id: Some(ByteBuf::from(vec![i; 200])),
+            purpose: types::Purpose::Authentication,
+            key_type: types::KeyType::CrossPlatform,
+            protection: types::DeviceProtection::Unprotected,
+        }
+    }
+
+    /// Verifies that anchors are not limited to 2KiB with layout version 2.
     #[test]
-    fn should_add_additional_device() -> Result<(), CallError> {
+    fn should_store_large_anchor_with_layout_v2() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-
-        api::add(
+        let canister_id = framework::install_ii_canister_with_arg(
             &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            device_data_2(),
-        )?;
-        let mut devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 2);
-        assert!(devices.iter().any(|device| device == &device_data_1()));
-        assert!(devices.iter().any(|device| device == &device_data_2()));
+            framework::II_WASM.clone(),
+            layout_v2_arg(),
+        );
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        let mut anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        let mut expected_devices = vec![device_data_1()];
+        for i in 0..9 {
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                large_device(i),
+            )?;
+            expected_devices.push(large_device(i));
+        }
 
-        // sort devices to not fail on different orderings
-        devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
-        anchor_info.devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
-        assert_eq!(devices, anchor_info.devices);
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            expected_devices
+        );
         Ok(())
     }
 
-    /// Verifies that the same device cannot be added twice.
+    /// Verifies that layout version 1 still limits anchors to 2KiB.
     #[test]
-    fn should_not_add_existing_device() -> Result<(), CallError> {
+    fn should_not_store_large_anchor_with_layout_v1() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
+        for i in 0..3 {
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                large_device(i),
+            )?;
+        }
         let result = api::add(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device_data_1(), // this device was already added during registration
+            large_device(3),
         );
 
         expect_user_error_with_message(
             result,
             CanisterCalledTrap,
-            Regex::new("Device already added\\.").unwrap(),
+            Regex::new("failed to write device data of user [0-9]+: attempted to store an entry of size [0-9]+ which is larger then the max allowed entry size").unwrap(),
         );
         Ok(())
     }
 
-    /// Verifies that a second recovery phrase cannot be added.
+    /// Verifies that anchors survive the migration from layout version 1 to 2 and can be grown afterwards.
     #[test]
-    fn should_not_add_second_recovery_phrase() -> Result<(), CallError> {
+    fn should_migrate_anchors_to_layout_v2() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-
+        let mut user_numbers = vec![];
+        for _ in 0..5 {
+            user_numbers.push(flows::register_anchor(&env, canister_id));
+        }
         api::add(
             &env,
             canister_id,
             principal_1(),
-            user_number,
-            recovery_device_data_1(),
+            user_numbers[2],
+            large_device(0),
         )?;
-        let result = api::add(
+
+        framework::upgrade_ii_canister_with_arg(
             &env,
             canister_id,
-            principal_1(),
-            user_number,
-            recovery_device_data_2(),
-        );
+            framework::II_WASM.clone(),
+            layout_v2_arg(),
+        )
+        .expect("migration to layout version 2 failed");
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("There is already a recovery phrase and only one is allowed\\.").unwrap(),
+        for user_number in &user_numbers {
+            let expected_devices = if *user_number == user_numbers[2] {
+                vec![device_data_1(), large_device(0)]
+            } else {
+                vec![device_data_1()]
+            };
+            assert_eq!(
+                flows::get_devices(&env, canister_id, principal_1(), *user_number)?,
+                expected_devices
+            );
+        }
+
+        // the migrated anchors are no longer limited in size and new anchors can be registered
+        for i in 1..9 {
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_numbers[2],
+                large_device(i),
+            )?;
+        }
+        assert_eq!(api::lookup(&env, canister_id, user_numbers[2])?.len(), 10);
+        let user_number = flows::register_anchor(&env, canister_id);
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![framework::without_alias(device_data_1())]
         );
+
+        // upgrading again keeps the layout
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        assert_eq!(api::lookup(&env, canister_id, user_numbers[2])?.len(), 10);
         Ok(())
     }
 
-    /// Verifies that the devices cannot be added for other users.
+    /// Verifies that the salt and the anchors of a backup survive the migration to layout version 2.
     #[test]
-    fn should_not_add_device_for_different_user() {
+    fn should_issue_same_principal_after_migrating_backup() -> Result<(), CallError> {
+        const PUBLIC_KEY: &str = "305e300c060a2b0601040183b8430101034e00a50102032620012158206c52bead5df52c208a9b1c7be0a60847573e5be4ac4fe08ea48036d0ba1d2acf225820b33daeb83bc9c77d8ad762fd68e3eab08684e463c49351b3ab2a14a400138387";
+        let principal = PrincipalId(Principal::self_authenticating(
+            hex::decode(PUBLIC_KEY).unwrap(),
+        ));
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
-            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
 
-        let result = api::add(
+        let stable_memory_backup =
+            std::fs::read(PathBuf::from("stable_memory/genesis-memory-layout.bin")).unwrap();
+        env.set_stable_memory(canister_id, &stable_memory_backup);
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let principal_before = api::get_principal(
             &env,
             canister_id,
-            principal_1(),
-            user_number_2,
-            device_data_1(),
+            principal,
+            10_030,
+            "example.com".to_string(),
+        )?;
+        let devices_before = api::lookup(&env, canister_id, 10_030)?;
+
+        framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            layout_v2_arg(),
+        )
+        .expect("migration to layout version 2 failed");
+
+        let principal_after = api::get_principal(
+            &env,
+            canister_id,
+            principal,
+            10_030,
+            "example.com".to_string(),
+        )?;
+        assert_eq!(principal_before, principal_after);
+        assert_eq!(api::lookup(&env, canister_id, 10_030)?, devices_before);
+        Ok(())
+    }
+
+    /// Verifies that the storage cannot be migrated back to layout version 1.
+    #[test]
+    fn should_not_migrate_from_layout_v2_to_v1() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister_with_arg(
+            &env,
+            framework::II_WASM.clone(),
+            layout_v2_arg(),
         );
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        let result = framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            Some(InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: Some(1),
+                admin: None,
+                config: None,
+            }),
         );
+
+        match result {
+            Ok(()) => panic!("expected migration to layout version 1 to fail"),
+            Err(user_error) => assert!(Regex::new(
+                "cannot migrate storage from layout version 2 to 1"
+            )
+            .unwrap()
+            .is_match(user_error.description())),
+        }
     }
+}
 
-    /// Verifies that a new device can be added to anchors that were registered using the previous II release.
+/// Tests for the state that is kept on the heap and persisted to stable memory across upgrades
+/// (inflight captcha challenges, tentative device registrations, usage metrics and signatures).
+#[cfg(test)]
+mod persistent_state_tests {
+    use crate::framework::{
+        assert_metric, device_data_1, device_data_2, principal_1, principal_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface as types;
+    use serde_bytes::ByteBuf;
+
+    /// Verifies that a captcha challenge created before an upgrade can be solved after the upgrade.
     #[test]
-    fn should_add_additional_device_after_ii_upgrade() -> Result<(), CallError> {
+    fn should_keep_inflight_challenge_across_upgrade() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let challenge = api::create_challenge(&env, canister_id)?;
 
         framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
-        api::add(
+
+        let response = api::register(
             &env,
             canister_id,
             principal_1(),
-            user_number,
-            device_data_2(),
+            &device_data_1(),
+            types::ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key,
+            },
         )?;
-
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 2);
-        assert!(devices.iter().any(|device| device == &device_data_2()));
+        assert!(matches!(
+            response,
+            types::RegisterResponse::Registered { .. }
+        ));
         Ok(())
     }
 
-    #[cfg(test)]
-    mod update {
+    /// Verifies that a remote device can be verified if II is upgraded during the device registration flow.
+    #[test]
+    fn should_keep_tentative_device_registration_across_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        use crate::framework::expect_user_error_with_message;
-        use crate::framework::{
-            device_data_1, device_data_2, principal_1, principal_2, CallError, PUBKEY_2,
-        };
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let add_response = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        let verification_code = match add_response {
+            types::AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        };
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_users_in_registration_mode",
+            1,
+        );
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_users_in_registration_mode",
+            1,
+        );
+        let verification_response = api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            verification_code,
+            None,
+        )?;
+        assert!(matches!(
+            verification_response,
+            types::VerifyTentativeDeviceResponse::Verified
+        ));
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![
+                framework::without_alias(device_data_1()),
+                framework::without_alias(device_data_2())
+            ]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the usage metrics and signatures are not reset by an upgrade.
+    #[test]
+    fn should_keep_usage_metrics_and_signatures_across_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session public key"),
+            None,
+            None,
+            None,
+        )?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        // a second upgrade must not lose the state either
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            1,
+        );
+        assert_metric(&env, canister_id, "internet_identity_delegation_counter", 1);
+        assert_metric(&env, canister_id, "internet_identity_signature_count", 1);
+        Ok(())
+    }
+
+    /// Verifies that the persisted state does not interfere with the anchors on rollback and
+    /// that the persisted state is not mistaken for anchor data once the previous release
+    /// registers new anchors.
+    #[test]
+    fn should_keep_anchors_when_rolling_back_with_persisted_state() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number_1 = flows::register_anchor(&env, canister_id);
+        api::create_challenge(&env, canister_id)?;
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
+
+        // roll back
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM_PREVIOUS.clone());
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number_1)?,
+            vec![device_data_1()]
+        );
+        let user_number_2 = flows::register_anchor(&env, canister_id);
+
+        // and upgrade again
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number_1)?,
+            vec![framework::without_alias(device_data_1())]
+        );
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number_2)?,
+            vec![framework::without_alias(device_data_1())]
+        );
+        let user_number_3 = flows::register_anchor(&env, canister_id);
+        assert_eq!(user_number_3, user_number_2 + 1);
+        Ok(())
+    }
+
+    /// Verifies that the state is persisted across an upgrade that migrates the storage layout.
+    #[test]
+    fn should_keep_state_across_storage_layout_migration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        flows::register_anchor(&env, canister_id);
+        let challenge = api::create_challenge(&env, canister_id)?;
+
+        framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: Some(2),
+                admin: None,
+                config: None,
+            }),
+        )
+        .expect("migration to layout version 2 failed");
+
+        let response = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            types::ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key,
+            },
+        )?;
+        assert!(matches!(
+            response,
+            types::RegisterResponse::Registered { .. }
+        ));
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            2,
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the admin-only export and import of anchors used for logical backups.
+#[cfg(test)]
+mod anchor_export_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1,
+        principal_recovery_1, recovery_device_data_1, CallError,
+    };
+    use crate::{api, flows, framework};
+    use candid::Principal;
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn admin() -> PrincipalId {
+        PrincipalId(Principal::self_authenticating("admin"))
+    }
+
+    fn install_with_admin(env: &StateMachine, storage_layout_version: u8) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: Some(storage_layout_version),
+                admin: Some(admin().0),
+                config: None,
+            }),
+        )
+    }
+
+    /// Verifies that anchors exported in multiple pages can be imported into a canister using a
+    /// different storage layout and keep their devices and principals.
+    #[test]
+    fn should_export_and_import_anchors() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 1);
+        let user_numbers: Vec<types::UserNumber> = (0..3)
+            .map(|_| flows::register_anchor(&env, source_id))
+            .collect();
+        api::add(
+            &env,
+            source_id,
+            principal_1(),
+            user_numbers[1],
+            device_data_2(),
+        )?;
+        let frontend_hostname = "https://some-dapp.com".to_string();
+        let principal = api::get_principal(
+            &env,
+            source_id,
+            principal_1(),
+            user_numbers[1],
+            frontend_hostname.clone(),
+        )?;
+
+        let target_id = install_with_admin(&env, 2);
+        let mut next = Some(0);
+        let mut pages = 0;
+        while let Some(start) = next {
+            let export = api::export_anchors(&env, source_id, admin(), start, 2)?;
+            assert_eq!(export.header.num_anchors, 3);
+            api::import_anchors(&env, target_id, admin(), export.header, export.anchors)?;
+            next = export.next_user_number;
+            pages += 1;
+        }
+        assert_eq!(pages, 2);
+
+        assert_eq!(
+            api::lookup(&env, target_id, user_numbers[0])?,
+            vec![framework::without_alias(device_data_1())]
+        );
+        assert_eq!(
+            api::lookup(&env, target_id, user_numbers[1])?,
+            vec![
+                framework::without_alias(device_data_1()),
+                framework::without_alias(device_data_2())
+            ]
+        );
+        assert_eq!(
+            api::get_principal(
+                &env,
+                target_id,
+                principal_1(),
+                user_numbers[1],
+                frontend_hostname
+            )?,
+            principal
+        );
+        assert_eq!(flows::register_anchor(&env, target_id), user_numbers[2] + 1);
+        Ok(())
+    }
+
+    /// Verifies that the import adopts the exported salt even if the salt of the target canister
+    /// has already been initialized (e.g. by creating a captcha).
+    #[test]
+    fn should_import_anchors_into_canister_with_salt() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 1);
+        let user_number = flows::register_anchor(&env, source_id);
+        let frontend_hostname = "https://some-dapp.com".to_string();
+        let principal = api::get_principal(
+            &env,
+            source_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.clone(),
+        )?;
+
+        let target_id = install_with_admin(&env, 2);
+        api::create_challenge(&env, target_id)?;
+        let export = api::export_anchors(&env, source_id, admin(), 0, 10)?;
+        api::import_anchors(&env, target_id, admin(), export.header, export.anchors)?;
+
+        assert_eq!(
+            api::get_principal(
+                &env,
+                target_id,
+                principal_1(),
+                user_number,
+                frontend_hostname
+            )?,
+            principal
+        );
+        Ok(())
+    }
+
+    /// Verifies that deleted anchors stay deleted when exported and imported.
+    #[test]
+    fn should_export_and_import_deleted_anchors() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 1);
+        let user_number = flows::register_anchor(&env, source_id);
+        api::add(
+            &env,
+            source_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        api::delete_anchor(&env, source_id, principal_recovery_1(), user_number)?;
+
+        let export = api::export_anchors(&env, source_id, admin(), 0, 10)?;
+        assert!(export.anchors[0].data.is_empty());
+        let target_id = install_with_admin(&env, 2);
+        api::import_anchors(&env, target_id, admin(), export.header, export.anchors)?;
+
+        expect_user_error_with_message(
+            api::get_anchor_info(&env, target_id, principal_1(), user_number),
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        assert_eq!(flows::register_anchor(&env, target_id), user_number + 1);
+        Ok(())
+    }
+
+    /// Verifies that the event logs are exported and imported with the anchors.
+    #[test]
+    fn should_export_and_import_event_logs() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 2);
+        let user_number = flows::register_anchor(&env, source_id);
+        api::add(&env, source_id, principal_1(), user_number, device_data_2())?;
+        let events = api::get_anchor_events(&env, source_id, principal_1(), user_number, None)?;
+        assert_eq!(events.events.len(), 1);
+
+        let export = api::export_anchors(&env, source_id, admin(), 0, 10)?;
+        let target_id = install_with_admin(&env, 2);
+        api::import_anchors(&env, target_id, admin(), export.header, export.anchors)?;
+
+        assert_eq!(
+            api::get_anchor_events(&env, target_id, principal_1(), user_number, None)?,
+            events
+        );
+        Ok(())
+    }
+
+    /// Verifies that the devices of imported anchors are checked like the devices passed to `add`.
+    #[test]
+    fn should_not_import_invalid_anchors() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 1);
+        flows::register_anchor(&env, source_id);
+        let mut export = api::export_anchors(&env, source_id, admin(), 0, 10)?;
+        export.anchors[0].data =
+            ByteBuf::from(candid::encode_one(vec![device_data_1(), device_data_1()]).unwrap());
+
+        let target_id = install_with_admin(&env, 2);
+        let result = api::import_anchors(&env, target_id, admin(), export.header, export.anchors);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("invalid devices of user \\d+: Device already added.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the anchors have to be imported in order.
+    #[test]
+    fn should_not_import_anchors_out_of_order() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let source_id = install_with_admin(&env, 1);
+        flows::register_anchor(&env, source_id);
+        flows::register_anchor(&env, source_id);
+        let export = api::export_anchors(&env, source_id, admin(), 0, 10)?;
+        let expected = export.anchors[0].user_number;
+
+        let target_id = install_with_admin(&env, 2);
+        let result = api::import_anchors(
+            &env,
+            target_id,
+            admin(),
+            export.header,
+            export.anchors[1..].to_vec(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new(&format!("expected Identity Anchor {} but got", expected)).unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that only the admin can export and import anchors.
+    #[test]
+    fn should_only_allow_admin_to_export_and_import() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_admin(&env, 1);
+        flows::register_anchor(&env, canister_id);
+        let export = api::export_anchors(&env, canister_id, admin(), 0, 10)?;
+
+        expect_user_error_with_message(
+            api::export_anchors(&env, canister_id, principal_1(), 0, 10),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ is not authorized to call this method").unwrap(),
+        );
+        expect_user_error_with_message(
+            api::import_anchors(
+                &env,
+                canister_id,
+                principal_1(),
+                export.header,
+                export.anchors,
+            ),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ is not authorized to call this method").unwrap(),
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the limits that can be overridden with the install and upgrade argument.
+#[cfg(test)]
+mod config_tests {
+    use crate::framework::{device_data_2, principal_1, recovery_device_data_1, CallError};
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface as types;
+
+    fn arg_with_config(
+        config: types::InternetIdentityConfigOverrides,
+    ) -> Option<types::InternetIdentityInit> {
+        Some(types::InternetIdentityInit {
+            assigned_user_number_range: None,
+            storage_layout_version: None,
+            admin: None,
+            config: Some(config),
+        })
+    }
+
+    /// Verifies that the canister uses the default limits if none are given.
+    #[test]
+    fn should_use_default_config() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        assert_eq!(
+            api::config(&env, canister_id)?,
+            types::InternetIdentityConfig {
+                max_entries_per_user: 10,
+                max_expiration_period_ns: 30 * 24 * 60 * 60 * 1_000_000_000,
+                default_expiration_period_ns: 30 * 60 * 1_000_000_000,
+                captcha_challenge_lifetime_ns: 300 * 1_000_000_000,
+                max_inflight_challenges: 500,
+                registration_mode_duration_ns: 900 * 1_000_000_000,
+                max_device_registration_attempts: 3,
+                registration_rate_limit: types::RateLimitConfig {
+                    time_per_token_ns: 1_000_000_000,
+                    max_tokens: 1000,
+                },
+                challenge: types::ChallengeConfig::Captcha,
+                device_protection_policy: types::DeviceProtectionPolicy::RecoveryPhrasesOnly,
+                verification_code_format: types::VerificationCodeFormat::Decimal { digits: 6 },
+                alternative_origins_canisters: vec![],
+            }
+        );
+        Ok(())
+    }
+
+    /// Verifies that the overrides given on install are applied and kept across upgrades.
+    #[test]
+    fn should_keep_config_overrides_across_upgrades() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister_with_arg(
+            &env,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                max_entries_per_user: Some(2),
+                ..Default::default()
+            }),
+        );
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let response = api::add_v2(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        assert_eq!(
+            response,
+            types::AddDeviceResponse::TooManyDevices { limit: 2 }
+        );
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        assert_eq!(api::config(&env, canister_id)?.max_entries_per_user, 2);
+
+        framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                max_device_registration_attempts: Some(1),
+                ..Default::default()
+            }),
+        )
+        .expect("upgrade with config overrides failed");
+        let config = api::config(&env, canister_id)?;
+        assert_eq!(config.max_entries_per_user, 2);
+        assert_eq!(config.max_device_registration_attempts, 1);
+        Ok(())
+    }
+
+    /// Verifies that the config is kept in stable memory across a rollback to the previous
+    /// release and a subsequent upgrade.
+    #[test]
+    fn should_keep_config_across_rollback() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister_with_arg(
+            &env,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                max_entries_per_user: Some(2),
+                ..Default::default()
+            }),
+        );
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM_PREVIOUS.clone());
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_eq!(api::config(&env, canister_id)?.max_entries_per_user, 2);
+        Ok(())
+    }
+
+    /// Verifies that an upgrade with an inconsistent config fails.
+    #[test]
+    fn should_reject_inconsistent_config() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                default_expiration_period_ns: Some(2_000),
+                max_expiration_period_ns: Some(1_000),
+                ..Default::default()
+            }),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(api::config(&env, canister_id)?.max_entries_per_user, 10);
+        Ok(())
+    }
+
+    /// Verifies that verification codes that are too short to be secure are rejected.
+    #[test]
+    fn should_reject_short_verification_codes() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                verification_code_format: Some(types::VerificationCodeFormat::Decimal {
+                    digits: 4,
+                }),
+                ..Default::default()
+            }),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            api::config(&env, canister_id)?.verification_code_format,
+            types::VerificationCodeFormat::Decimal { digits: 6 }
+        );
+        Ok(())
+    }
+
+    /// Verifies that the device registration mode cannot be configured to last more than a day.
+    #[test]
+    fn should_reject_too_long_registration_mode_duration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                registration_mode_duration_ns: Some(u64::MAX),
+                ..Default::default()
+            }),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            api::config(&env, canister_id)?.registration_mode_duration_ns,
+            900 * 1_000_000_000
+        );
+        Ok(())
+    }
+
+    /// Verifies that at most 100 canisters can be allow-listed to provide alternative origins.
+    #[test]
+    fn should_reject_too_many_alternative_origins_canisters() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = framework::upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            framework::II_WASM.clone(),
+            arg_with_config(types::InternetIdentityConfigOverrides {
+                alternative_origins_canisters: Some(vec![Principal::anonymous(); 101]),
+                ..Default::default()
+            }),
+        );
+
+        assert!(result.is_err());
+        assert!(api::config(&env, canister_id)?
+            .alternative_origins_canisters
+            .is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for the rate limit of the registrations.
+#[cfg(test)]
+mod registration_rate_limit_tests {
+    use crate::framework::{assert_metric, device_data_1, principal_1, CallError};
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+    use internet_identity_interface as types;
+    use std::time::Duration;
+
+    fn install_with_rate_limit(env: &StateMachine) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    registration_rate_limit: Some(types::RateLimitConfig {
+                        time_per_token_ns: Duration::from_secs(10).as_nanos() as u64,
+                        max_tokens: 2,
+                    }),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+
+    fn register(
+        env: &StateMachine,
+        canister_id: CanisterId,
+    ) -> Result<types::RegisterResponse, CallError> {
+        let challenge = api::create_challenge(env, canister_id)?;
+        api::register(
+            env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            types::ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key,
+            },
+        )
+    }
+
+    /// Verifies that registrations are rate limited once the burst is used up and allowed
+    /// again after the refill time.
+    #[test]
+    fn should_rate_limit_registrations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_rate_limit(&env);
+        flows::register_anchor(&env, canister_id);
+        flows::register_anchor(&env, canister_id);
+
+        assert!(matches!(
+            register(&env, canister_id)?,
+            types::RegisterResponse::RateLimited
+        ));
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_registration_rate_limit_available_tokens",
+            0,
+        );
+
+        env.advance_time(Duration::from_secs(10));
+
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_registration_rate_limit_available_tokens",
+            1,
+        );
+        assert!(matches!(
+            register(&env, canister_id)?,
+            types::RegisterResponse::Registered { .. }
+        ));
+        assert!(matches!(
+            register(&env, canister_id)?,
+            types::RegisterResponse::RateLimited
+        ));
+        Ok(())
+    }
+
+    /// Verifies that a rate limited registration does not consume the solved challenge.
+    #[test]
+    fn should_keep_challenge_when_rate_limited() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_rate_limit(&env);
+        flows::register_anchor(&env, canister_id);
+        flows::register_anchor(&env, canister_id);
+        let challenge = api::create_challenge(&env, canister_id)?;
+        let attempt = types::ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        };
+
+        assert!(matches!(
+            api::register(
+                &env,
+                canister_id,
+                principal_1(),
+                &device_data_1(),
+                attempt.clone()
+            )?,
+            types::RegisterResponse::RateLimited
+        ));
+
+        env.advance_time(Duration::from_secs(10));
+
+        assert!(matches!(
+            api::register(&env, canister_id, principal_1(), &device_data_1(), attempt)?,
+            types::RegisterResponse::Registered { .. }
+        ));
+        Ok(())
+    }
+
+    /// Verifies that an upgrade does not refill the rate limit.
+    #[test]
+    fn should_keep_rate_limit_across_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_rate_limit(&env);
+        flows::register_anchor(&env, canister_id);
+        flows::register_anchor(&env, canister_id);
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert!(matches!(
+            register(&env, canister_id)?,
+            types::RegisterResponse::RateLimited
+        ));
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_registration_rate_limit_max_tokens",
+            2,
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the challenge mechanisms (captcha and proof of work) protecting the registration.
+#[cfg(test)]
+mod challenge_tests {
+    use crate::framework::{device_data_1, expect_user_error_with_message, principal_1, CallError};
+    use crate::{api, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, StateMachine, UserError};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use sha2::{Digest, Sha256};
+
+    const DIFFICULTY: u8 = 8;
+
+    fn install_with_proof_of_work(env: &StateMachine) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    challenge: Some(types::ChallengeConfig::ProofOfWork {
+                        difficulty: DIFFICULTY,
+                    }),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+
+    fn create_proof_of_work_challenge(
+        env: &StateMachine,
+        canister_id: CanisterId,
+    ) -> Result<types::ProofOfWorkChallenge, CallError> {
+        match api::create_challenge_v2(env, canister_id)? {
+            types::CreateChallengeResponse::ProofOfWork(challenge) => Ok(challenge),
+            response => panic!("expected proof of work challenge, got {:?}", response),
+        }
+    }
+
+    fn leading_zero_bits(challenge_key: &str, nonce: &str) -> u32 {
+        let hash = Sha256::digest(format!("{}:{}", challenge_key, nonce).as_bytes());
+        let mut bits = 0;
+        for byte in hash.iter() {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        bits
+    }
+
+    /// Returns the first nonce for which `accept` holds.
+    fn find_nonce(challenge_key: &str, accept: impl Fn(u32) -> bool) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| accept(leading_zero_bits(challenge_key, nonce)))
+            .unwrap()
+    }
+
+    fn register(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        challenge_key: types::ChallengeKey,
+        nonce: String,
+    ) -> Result<types::RegisterResponse, CallError> {
+        api::register(
+            env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            types::ChallengeAttempt {
+                chars: nonce,
+                key: challenge_key,
+            },
+        )
+    }
+
+    /// Verifies that captcha challenges are created if no challenge mechanism is configured.
+    #[test]
+    fn should_create_captcha_by_default() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        assert!(matches!(
+            api::create_challenge_v2(&env, canister_id)?,
+            types::CreateChallengeResponse::Captcha(_)
+        ));
+        Ok(())
+    }
+
+    /// Verifies that an anchor can be registered with a solved proof of work challenge.
+    #[test]
+    fn should_register_with_proof_of_work() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_proof_of_work(&env);
+
+        let challenge = create_proof_of_work_challenge(&env, canister_id)?;
+        assert_eq!(challenge.difficulty, DIFFICULTY);
+        let nonce = find_nonce(&challenge.challenge_key, |bits| bits >= DIFFICULTY as u32);
+
+        assert!(matches!(
+            register(&env, canister_id, challenge.challenge_key, nonce)?,
+            types::RegisterResponse::Registered { .. }
+        ));
+        Ok(())
+    }
+
+    /// Verifies that a nonce that does not meet the difficulty is rejected.
+    #[test]
+    fn should_reject_insufficient_proof_of_work() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_proof_of_work(&env);
+
+        let challenge = create_proof_of_work_challenge(&env, canister_id)?;
+        let nonce = find_nonce(&challenge.challenge_key, |bits| bits < DIFFICULTY as u32);
+
+        assert!(matches!(
+            register(&env, canister_id, challenge.challenge_key, nonce)?,
+            types::RegisterResponse::BadChallenge
+        ));
+        Ok(())
+    }
+
+    /// Verifies that the captcha-only create_challenge cannot be used with proof of work.
+    #[test]
+    fn should_not_create_captcha_if_proof_of_work_is_configured() {
+        let env = StateMachine::new();
+        let canister_id = install_with_proof_of_work(&env);
+
+        expect_user_error_with_message(
+            api::create_challenge(&env, canister_id),
+            CanisterCalledTrap,
+            Regex::new("the canister uses proof of work challenges, use create_challenge_v2")
+                .unwrap(),
+        );
+    }
+
+    /// Verifies that a proof of work difficulty that cannot be solved is rejected.
+    #[test]
+    fn should_reject_too_high_difficulty() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = upgrade_with_difficulty(&env, canister_id, 33);
+
+        assert!(result.is_err());
+    }
+
+    /// Verifies that a proof of work difficulty that takes no work to solve is rejected.
+    #[test]
+    fn should_reject_too_low_difficulty() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        let result = upgrade_with_difficulty(&env, canister_id, 7);
+
+        assert!(result.is_err());
+    }
+
+    fn upgrade_with_difficulty(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        difficulty: u8,
+    ) -> Result<(), UserError> {
+        framework::upgrade_ii_canister_with_arg(
+            env,
+            canister_id,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    challenge: Some(types::ChallengeConfig::ProofOfWork { difficulty }),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+}
+
+/// Tests related to local device management (add, remove, lookup, get_anchor_info).
+/// Tests for the 'add remote device flow' are in the module [remote_device_registration_tests].
+#[cfg(test)]
+mod device_management_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        recovery_device_data_1, recovery_device_data_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn install_with_security_key_protection(env: &StateMachine) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    device_protection_policy: Some(
+                        types::DeviceProtectionPolicy::RecoveryPhrasesAndSecurityKeys,
+                    ),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+
+    /// A protected hardware security key, used for recovery.
+    fn protected_security_key() -> types::DeviceData {
+        let mut device = device_data_2();
+        device.key_type = types::KeyType::CrossPlatform;
+        device.purpose = types::Purpose::Recovery;
+        device.protection = types::DeviceProtection::Protected;
+        device
+    }
+
+    /// Verifies that a new device can be added.
+    #[test]
+    fn should_add_additional_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let mut devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 2);
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_1())));
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_2())));
+
+        let mut anchor_info_devices: Vec<types::DeviceData> =
+            api::get_anchor_info(&env, canister_id, principal_1(), user_number)?
+                .devices
+                .into_iter()
+                .map(types::DeviceData::from)
+                .collect();
+
+        // sort devices to not fail on different orderings
+        devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
+        anchor_info_devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
+        assert_eq!(devices, anchor_info_devices);
+        Ok(())
+    }
+
+    /// Verifies that the same device cannot be added twice.
+    #[test]
+    fn should_not_add_existing_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_1(), // this device was already added during registration
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device already added\\.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that a second recovery phrase cannot be added.
+    #[test]
+    fn should_not_add_second_recovery_phrase() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_2(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("There is already a recovery phrase and only one is allowed\\.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that devices with a malformed public key cannot be added.
+    #[test]
+    fn should_not_add_malformed_public_key() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut device = device_data_2();
+        device.pubkey = ByteBuf::from("not a public key");
+
+        let result = api::add(&env, canister_id, principal_1(), user_number, device);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("malformed public key").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that devices using an algorithm other than ECDSA P-256, Ed25519 or RSA cannot be
+    /// added.
+    #[test]
+    fn should_not_add_public_key_of_unsupported_algorithm() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut device = device_data_2();
+        // Ed448 (OID 1.3.101.113) subject public key info
+        let mut ed448_key = hex::decode("3043300506032b6571033a00").unwrap();
+        ed448_key.extend_from_slice(&[0x42; 57]);
+        device.pubkey = ByteBuf::from(ed448_key);
+
+        let result = api::add(&env, canister_id, principal_1(), user_number, device);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("unsupported public key algorithm: 1\\.3\\.101\\.113").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the devices cannot be added for other users.
+    #[test]
+    fn should_not_add_device_for_different_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            device_data_1(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that a new device can be added to anchors that were registered using the previous II release.
+    #[test]
+    fn should_add_additional_device_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 2);
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_2())));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod update {
+
+        use crate::framework::expect_user_error_with_message;
+        use crate::framework::{
+            device_data_1, device_data_2, principal_1, principal_2, CallError, PUBKEY_2,
+        };
         use crate::{api, flows, framework};
         use ic_error_types::ErrorCode::CanisterCalledTrap;
         use ic_state_machine_tests::StateMachine;
@@ -753,1708 +2184,5576 @@ mod device_management_tests {
         use regex::Regex;
         use serde_bytes::ByteBuf;
 
-        /// Verifies that a device can be updated
-        #[test]
-        fn should_update_device() -> Result<(), CallError> {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            let principal = principal_1();
-            let mut device = device_data_1();
+        /// Verifies that a device can be updated
+        #[test]
+        fn should_update_device() -> Result<(), CallError> {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let principal = principal_1();
+            let mut device = device_data_1();
+
+            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
+
+            let devices = flows::get_devices(&env, canister_id, principal, user_number)?;
+            assert_eq!(devices, vec![device.clone()]);
+
+            device.alias.push_str("some suffix");
+
+            api::update(
+                &env,
+                canister_id,
+                principal,
+                user_number,
+                device.clone().pubkey,
+                device.clone(),
+            )?;
+
+            let devices = flows::get_devices(&env, canister_id, principal, user_number)?;
+            assert_eq!(devices, vec![device]);
+
+            Ok(())
+        }
+
+        /// Verifies that a protected device can be updated
+        #[test]
+        fn should_update_protected_device() -> Result<(), CallError> {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let principal = principal_1();
+            let mut device = device_data_1();
+            device.protection = types::DeviceProtection::Protected;
+            device.key_type = types::KeyType::SeedPhrase;
+
+            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
+
+            let devices = flows::get_devices(&env, canister_id, principal, user_number)?;
+            assert_eq!(devices, vec![device.clone()]);
+
+            device.alias.push_str("some suffix");
+
+            api::update(
+                &env,
+                canister_id,
+                principal,
+                user_number,
+                device.clone().pubkey,
+                device.clone(),
+            )?;
+
+            let devices = flows::get_devices(&env, canister_id, principal, user_number)?;
+            assert_eq!(devices, vec![device]);
+
+            Ok(())
+        }
+
+        /// Verifies that the device key (i.e. the device ID) cannot be updated
+        #[test]
+        fn should_not_modify_pubkey() {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let principal = principal_1();
+            let mut device = device_data_1();
+
+            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
+
+            let original_pubkey = device.pubkey;
+            let pubkey_2 = ByteBuf::from(PUBKEY_2);
+            assert_ne!(original_pubkey, pubkey_2);
+            device.pubkey = pubkey_2;
+
+            let result = api::update(
+                &env,
+                canister_id,
+                principal,
+                user_number,
+                original_pubkey,
+                device.clone(),
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("device key may not be updated").unwrap(),
+            );
+        }
+
+        /// Verifies that users can only update their own devices.
+        #[test]
+        fn should_not_update_device_of_different_user() {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+            let user_number_2 =
+                flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number_2,
+                device_data_2().pubkey,
+                device_data_2(),
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+            );
+        }
+
+        /// Verifies that unprotected devices can only be updated from themselves
+        #[test]
+        fn should_not_update_protected_with_different_device() {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let mut device1 = device_data_1();
+            device1.protection = types::DeviceProtection::Protected;
+            device1.key_type = types::KeyType::SeedPhrase;
+
+            let user_number =
+                flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_2(),
+            )
+            .unwrap();
+
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                device1.pubkey.clone(),
+                device1.clone(), // data here doesnt' actually matter
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                    .unwrap(),
+            );
+        }
+
+        /// Verifies that non-recovery devices cannot be updated to be protected.
+        #[test]
+        fn should_not_update_non_recovery_device_to_be_protected() {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let user_number = flows::register_anchor(&env, canister_id);
+
+            let mut device1 = device_data_1();
+            device1.protection = types::DeviceProtection::Protected;
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device1.pubkey.clone(),
+                device1.clone(), // data here doesnt' actually matter
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("Only recovery phrases can be protected but key type is Unknown")
+                    .unwrap(),
+            );
+        }
+
+        /// Verifies that a protected security key can be updated by itself.
+        #[test]
+        fn should_update_protected_security_key() -> Result<(), CallError> {
+            let env = StateMachine::new();
+            let canister_id = super::install_with_security_key_protection(&env);
+            let user_number = flows::register_anchor(&env, canister_id);
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                super::protected_security_key(),
+            )?;
+
+            let mut device = super::protected_security_key();
+            device.alias = "security key".to_string();
+            api::update(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                device.pubkey.clone(),
+                device.clone(),
+            )?;
+
+            let devices = api::lookup(&env, canister_id, user_number)?;
+            assert!(devices
+                .iter()
+                .any(|d| d == &framework::without_alias(device.clone())));
+            Ok(())
+        }
+
+        /// Verifies that a protected security key can only be updated by itself.
+        #[test]
+        fn should_not_update_protected_security_key_with_different_device() {
+            let env = StateMachine::new();
+            let canister_id = super::install_with_security_key_protection(&env);
+            let user_number = flows::register_anchor(&env, canister_id);
+            let device = super::protected_security_key();
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device.clone(),
+            )
+            .unwrap();
+
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device.pubkey.clone(),
+                device.clone(),
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                    .unwrap(),
+            );
+        }
+
+        /// Verifies that an authentication security key can be updated to be protected.
+        #[test]
+        fn should_update_authentication_security_key_to_be_protected() -> Result<(), CallError> {
+            let env = StateMachine::new();
+            let canister_id = super::install_with_security_key_protection(&env);
+            let mut device1 = device_data_1();
+            device1.key_type = types::KeyType::CrossPlatform;
+            let user_number =
+                flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+
+            device1.protection = types::DeviceProtection::Protected;
+            api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device1.pubkey.clone(),
+                device1.clone(),
+            )?;
+
+            let devices = api::lookup(&env, canister_id, user_number)?;
+            assert_eq!(devices, vec![framework::without_alias(device1)]);
+            Ok(())
+        }
+
+        /// Verifies that security keys cannot be protected with the default policy.
+        #[test]
+        fn should_not_update_security_key_to_be_protected_by_default() {
+            let env = StateMachine::new();
+            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+            let mut device1 = device_data_1();
+            device1.key_type = types::KeyType::CrossPlatform;
+            let user_number =
+                flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+
+            device1.protection = types::DeviceProtection::Protected;
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device1.pubkey.clone(),
+                device1.clone(),
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new("Only recovery phrases can be protected but key type is CrossPlatform")
+                    .unwrap(),
+            );
+        }
+
+        /// Verifies that platform devices cannot be protected, even if security keys can.
+        #[test]
+        fn should_not_update_platform_device_to_be_protected() {
+            let env = StateMachine::new();
+            let canister_id = super::install_with_security_key_protection(&env);
+            let mut device1 = device_data_1();
+            device1.key_type = types::KeyType::Platform;
+            let user_number =
+                flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+
+            device1.protection = types::DeviceProtection::Protected;
+            let result = api::update(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device1.pubkey.clone(),
+                device1.clone(),
+            );
+
+            expect_user_error_with_message(
+                result,
+                CanisterCalledTrap,
+                Regex::new(
+                    "Only recovery phrases and security keys can be protected but key type is Platform",
+                )
+                .unwrap(),
+            );
+        }
+    }
+
+    /// Verifies that a device can be removed.
+    #[test]
+    fn should_remove_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_2())));
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 1);
+        assert!(!devices
+            .iter()
+            .any(|device| device.pubkey == device_data_2().pubkey));
+        Ok(())
+    }
+
+    /// Verifies that a protected device can be removed.
+    #[test]
+    fn should_remove_protected_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let mut device2 = device_data_2();
+        device2.protection = types::DeviceProtection::Protected;
+        device2.key_type = types::KeyType::SeedPhrase;
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_2())));
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 1);
+        assert!(!devices
+            .iter()
+            .any(|device| device.pubkey == device_data_2().pubkey));
+        Ok(())
+    }
+
+    /// Verifies that the even last device can be removed.
+    /// This behaviour should be changed because it makes anchors unusable, see L2-745.
+    #[test]
+    fn should_remove_last_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_1().pubkey,
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.is_empty());
+        Ok(())
+    }
+
+    /// Verifies that users can only remove their own devices.
+    #[test]
+    fn should_not_remove_device_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            device_data_2().pubkey,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that unprotected devices can not be removed with another device
+    #[test]
+    fn should_not_remove_protected_with_different_device() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let mut device1 = device_data_1();
+        device1.protection = types::DeviceProtection::Protected;
+        device1.key_type = types::KeyType::SeedPhrase;
+
+        let user_number = flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )
+        .unwrap();
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device1.pubkey.clone(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                .unwrap(),
+        );
+    }
+
+    /// Verifies that a protected security key can be removed by itself.
+    #[test]
+    fn should_remove_protected_security_key() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_security_key_protection(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+        let device = protected_security_key();
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.clone(),
+        )?;
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device.pubkey.clone(),
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![framework::without_alias(device_data_1())]);
+        Ok(())
+    }
+
+    /// Verifies that a protected security key cannot be removed with another device.
+    #[test]
+    fn should_not_remove_protected_security_key_with_different_device() {
+        let env = StateMachine::new();
+        let canister_id = install_with_security_key_protection(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+        let device = protected_security_key();
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.clone(),
+        )
+        .unwrap();
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                .unwrap(),
+        );
+    }
+
+    /// Verifies that a device can be removed if it has been added using the previous II release.
+    #[test]
+    fn should_remove_device_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices
+            .iter()
+            .any(|device| device == &framework::without_alias(device_data_2())));
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 1);
+        assert!(!devices
+            .iter()
+            .any(|device| device.pubkey == device_data_2().pubkey));
+        Ok(())
+    }
+
+    /// Verifies that get_anchor_info requires authentication.
+    #[test]
+    fn should_not_allow_get_anchor_info_for_different_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::get_anchor_info(&env, canister_id, principal_2(), user_number);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+}
+
+/// Tests for applying several device operations in a single call.
+#[cfg(test)]
+mod device_operations_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface::{DeviceData, DeviceOperation};
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn device_data_3() -> DeviceData {
+        DeviceData {
+            pubkey: ByteBuf::from(framework::ed25519_der_key(0x03).to_vec()),
+            alias: "My third device".to_string(),
+            ..device_data_2()
+        }
+    }
+
+    /// Verifies that a device can be replaced by another one in a single call.
+    #[test]
+    fn should_replace_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        api::apply_device_operations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            vec![
+                DeviceOperation::Add(device_data_3()),
+                DeviceOperation::Remove(device_data_2().pubkey),
+            ],
+        )?;
+
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            vec![device_data_1(), device_data_3()]
+        );
+        Ok(())
+    }
+
+    /// Verifies that later operations see the result of earlier ones.
+    #[test]
+    fn should_apply_operations_in_order() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut device = device_data_2();
+        device.alias = "new alias".to_string();
+
+        api::apply_device_operations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            vec![
+                DeviceOperation::Add(device_data_2()),
+                DeviceOperation::Update(device.clone()),
+            ],
+        )?;
+
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            vec![device_data_1(), device]
+        );
+        Ok(())
+    }
+
+    /// Verifies that no operation is applied if one of them fails.
+    #[test]
+    fn should_not_apply_any_operation_if_one_fails() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::apply_device_operations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            vec![
+                DeviceOperation::Add(device_data_2()),
+                DeviceOperation::Remove(device_data_3().pubkey),
+            ],
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Could not find device to mutate, check device key").unwrap(),
+        );
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            vec![device_data_1()]
+        );
+        Ok(())
+    }
+
+    /// Verifies that a device cannot be added twice in the same call.
+    #[test]
+    fn should_not_add_same_device_twice() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::apply_device_operations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            vec![
+                DeviceOperation::Add(device_data_2()),
+                DeviceOperation::Add(device_data_2()),
+            ],
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device already added\\.").unwrap(),
+        );
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            vec![device_data_1()]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the number of operations per call is limited.
+    #[test]
+    fn should_not_apply_too_many_operations() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::apply_device_operations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            vec![DeviceOperation::Update(device_data_1()); 21],
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("number of device operations 21 exceeds the limit of 20").unwrap(),
+        );
+    }
+}
+
+/// Tests for looking up anchors by the credential IDs of their devices.
+#[cfg(test)]
+mod credential_lookup_tests {
+    use crate::framework::{
+        device_data_2, expect_user_error_with_message, principal_1, principal_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn device_with_credential_id(credential_id: &str) -> types::DeviceData {
+        types::DeviceData {
+            credential_id: Some(ByteBuf::from(credential_id)),
+            ..device_data_2()
+        }
+    }
+
+    /// Verifies that the anchor of a device can be looked up by its credential ID.
+    #[test]
+    fn should_lookup_anchor_by_credential_id() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_with_credential_id("credential 2"),
+        )?;
+
+        assert_eq!(
+            api::lookup_by_credential_id(&env, canister_id, ByteBuf::from("credential 2"))?,
+            types::CredentialLookupResponse::Anchors(vec![user_number])
+        );
+        assert_eq!(
+            api::lookup_by_credential_id(&env, canister_id, ByteBuf::from("unknown"))?,
+            types::CredentialLookupResponse::Anchors(vec![])
+        );
+        Ok(())
+    }
+
+    /// Verifies that the credential ID of a removed device no longer finds the anchor.
+    #[test]
+    fn should_not_lookup_removed_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let device = device_with_credential_id("credential 2");
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.clone(),
+        )?;
+        api::remove(&env, canister_id, principal_1(), user_number, device.pubkey)?;
+
+        assert_eq!(
+            api::lookup_by_credential_id(&env, canister_id, ByteBuf::from("credential 2"))?,
+            types::CredentialLookupResponse::Anchors(vec![])
+        );
+        Ok(())
+    }
+
+    /// Verifies that the index is restored by an upgrade and can answer lookups right away.
+    #[test]
+    fn should_lookup_anchor_by_credential_id_after_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_with_credential_id("credential 2"),
+        )?;
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_eq!(
+            api::lookup_by_credential_id(&env, canister_id, ByteBuf::from("credential 2"))?,
+            types::CredentialLookupResponse::Anchors(vec![user_number])
+        );
+        Ok(())
+    }
+
+    /// Verifies that a credential ID used by several anchors finds all of them, so that copying
+    /// the credential ID of a device into another anchor cannot hide the original anchor.
+    #[test]
+    fn should_lookup_all_anchors_with_credential_id() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let other_user_number = flows::register_anchor_with(
+            &env,
+            canister_id,
+            principal_2(),
+            &device_with_credential_id("credential 2"),
+        );
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_with_credential_id("credential 2"),
+        )?;
+
+        assert_eq!(
+            api::lookup_by_credential_id(&env, canister_id, ByteBuf::from("credential 2"))?,
+            types::CredentialLookupResponse::Anchors(vec![user_number, other_user_number])
+        );
+        Ok(())
+    }
+
+    /// Verifies that two devices of an anchor cannot share a credential ID.
+    #[test]
+    fn should_not_add_device_with_duplicate_credential_id() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_with_credential_id("credential 2"),
+        )?;
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            types::DeviceData {
+                pubkey: ByteBuf::from(framework::ed25519_der_key(0x03).to_vec()),
+                ..device_with_credential_id("credential 2")
+            },
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("There is already a device with the same credential ID\\.").unwrap(),
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the device aliases hidden by `lookup` and the certified `lookup_certified`.
+#[cfg(test)]
+mod certified_lookup_tests {
+    use crate::certificate_validation::validate_certified_lookup;
+    use crate::framework::{device_data_1, device_data_2, principal_1, CallError};
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::StateMachine;
+
+    /// Verifies that neither `lookup` nor `lookup_certified` reveal the aliases of the devices.
+    #[test]
+    fn should_not_expose_device_aliases() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![framework::without_alias(device_data_1())]);
+        let response = api::lookup_certified(&env, canister_id, user_number)?;
+        assert_eq!(response.devices, devices);
+
+        // the aliases are still available to the owner of the anchor
+        assert_eq!(
+            flows::get_devices(&env, canister_id, principal_1(), user_number)?,
+            vec![device_data_1()]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the devices are certified and that the certified hash follows changes.
+    #[test]
+    fn should_certify_devices() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let hash_before = certified_devices_hash(&env, canister_id, user_number)?;
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let hash_after = certified_devices_hash(&env, canister_id, user_number)?;
+        assert_ne!(hash_before, hash_after);
+
+        // changing only the alias does not change the certified hash
+        let mut device = device_data_2();
+        device.alias = "new alias".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device,
+        )?;
+        assert_eq!(
+            certified_devices_hash(&env, canister_id, user_number)?,
+            hash_after
+        );
+        Ok(())
+    }
+
+    /// Verifies that the devices are certified again after an upgrade.
+    #[test]
+    fn should_certify_devices_after_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let hash_before = certified_devices_hash(&env, canister_id, user_number)?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_eq!(
+            certified_devices_hash(&env, canister_id, user_number)?,
+            hash_before
+        );
+        Ok(())
+    }
+
+    fn certified_devices_hash(
+        env: &StateMachine,
+        canister_id: ic_state_machine_tests::CanisterId,
+        user_number: u64,
+    ) -> Result<Vec<u8>, CallError> {
+        let response = api::lookup_certified(env, canister_id, user_number)?;
+        let hash = validate_certified_lookup(
+            &response.certificate.expect("certificate missing"),
+            &response.tree,
+            canister_id,
+            user_number,
+            env.root_key(),
+        )
+        .expect("certified lookup validation failed");
+        Ok(hash)
+    }
+}
+
+/// Tests for the certified query `get_anchor_info_certified`.
+#[cfg(test)]
+mod certified_anchor_info_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_certification::verify_certificate;
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface::certification::{verify_anchor_info, CertificationError};
+    use internet_identity_interface::DeviceData;
+    use regex::Regex;
+
+    /// Verifies that the devices of the anchor info are certified.
+    #[test]
+    fn should_return_certified_anchor_info() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        let response =
+            api::get_anchor_info_certified(&env, canister_id, principal_1(), user_number)?;
+        let devices: Vec<DeviceData> = response
+            .info
+            .devices
+            .iter()
+            .cloned()
+            .map(DeviceData::from)
+            .collect();
+        assert_eq!(devices, vec![device_data_1(), device_data_2()]);
+
+        let root_hash =
+            verify_anchor_info(user_number, &response).expect("anchor info verification failed");
+        verify_certificate(
+            &response.certificate.expect("certificate missing"),
+            &canister_id,
+            &env.root_key(),
+            &root_hash,
+        )
+        .expect("certificate validation failed");
+        Ok(())
+    }
+
+    /// Verifies that devices that do not match the certified hash are detected.
+    #[test]
+    fn should_detect_modified_devices() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let mut response =
+            api::get_anchor_info_certified(&env, canister_id, principal_1(), user_number)?;
+        response.info.devices[0].alias = "modified alias".to_string();
+
+        assert_eq!(
+            verify_anchor_info(user_number, &response),
+            Err(CertificationError::DevicesHashMismatch)
+        );
+        assert_eq!(
+            verify_anchor_info(user_number + 1, &response),
+            Err(CertificationError::DevicesNotCertified)
+        );
+        Ok(())
+    }
+
+    /// Verifies that the certified hash follows the changes of the devices, including their aliases.
+    #[test]
+    fn should_certify_updated_devices() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let mut device = device_data_1();
+        device.alias = "new alias".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+
+        let response =
+            api::get_anchor_info_certified(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(response.info.devices[0].alias, "new alias");
+        verify_anchor_info(user_number, &response).expect("anchor info verification failed");
+        Ok(())
+    }
+
+    /// Verifies that the devices are certified again after an upgrade.
+    #[test]
+    fn should_certify_anchor_info_after_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let response =
+            api::get_anchor_info_certified(&env, canister_id, principal_1(), user_number)?;
+        let root_hash =
+            verify_anchor_info(user_number, &response).expect("anchor info verification failed");
+        verify_certificate(
+            &response.certificate.expect("certificate missing"),
+            &canister_id,
+            &env.root_key(),
+            &root_hash,
+        )
+        .expect("certificate validation failed");
+        Ok(())
+    }
+
+    /// Verifies that only the devices of the anchor can get the certified anchor info.
+    #[test]
+    fn should_not_return_certified_anchor_info_to_other_principal() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        expect_user_error_with_message(
+            api::get_anchor_info_certified(&env, canister_id, principal_2(), user_number),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+}
+
+/// Tests for the created_at and last_usage timestamps of the devices.
+#[cfg(test)]
+mod device_usage_tests {
+    use crate::framework::{device_data_1, device_data_2, principal_1, principal_2, CallError};
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface as types;
+    use serde_bytes::ByteBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn now(env: &StateMachine) -> u64 {
+        env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+    }
+
+    fn device_with_pubkey(
+        info: &types::IdentityAnchorInfo,
+        pubkey: &types::DeviceKey,
+    ) -> types::DeviceWithUsage {
+        info.devices
+            .iter()
+            .find(|device| &device.pubkey == pubkey)
+            .expect("device not found")
+            .clone()
+    }
+
+    /// Verifies that the device used to register the anchor has its creation and usage recorded.
+    #[test]
+    fn should_record_usage_on_registration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let registration_time = now(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        env.advance_time(Duration::from_secs(10));
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+
+        let device = device_with_pubkey(&info, &device_data_1().pubkey);
+        assert_eq!(device.created_at, Some(registration_time));
+        // get_anchor_info is an authenticated call itself
+        assert_eq!(device.last_usage, Some(now(&env)));
+        Ok(())
+    }
+
+    /// Verifies that only the device making the call has its usage updated.
+    #[test]
+    fn should_record_usage_of_calling_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        env.advance_time(Duration::from_secs(10));
+        let add_time = now(&env);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        env.advance_time(Duration::from_secs(10));
+        let delegation_time = now(&env);
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )?;
+
+        env.advance_time(Duration::from_secs(10));
+        let info = api::get_anchor_info(&env, canister_id, principal_2(), user_number)?;
+
+        let device_1 = device_with_pubkey(&info, &device_data_1().pubkey);
+        assert_eq!(device_1.last_usage, Some(delegation_time));
+        let device_2 = device_with_pubkey(&info, &device_data_2().pubkey);
+        assert_eq!(device_2.created_at, Some(add_time));
+        assert_eq!(device_2.last_usage, Some(now(&env)));
+        Ok(())
+    }
+
+    /// Verifies that the usage is not recorded if the call fails.
+    #[test]
+    fn should_not_record_usage_of_failed_call() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        env.advance_time(Duration::from_secs(10));
+        assert_eq!(
+            api::remove_v2(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                ByteBuf::from("unknown device")
+            )?,
+            types::RemoveDeviceResponse::DeviceNotFound
+        );
+
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        let device_2 = device_with_pubkey(&info, &device_data_2().pubkey);
+        assert_eq!(device_2.last_usage, None);
+        Ok(())
+    }
+
+    /// Verifies that updating a device does not reset its creation timestamp.
+    #[test]
+    fn should_keep_created_at_on_device_update() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let registration_time = now(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        env.advance_time(Duration::from_secs(10));
+        let mut device = device_data_1();
+        device.alias = "new alias".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+
+        env.advance_time(Duration::from_secs(10));
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        let updated = device_with_pubkey(&info, &device.pubkey);
+        assert_eq!(updated.alias, "new alias");
+        assert_eq!(updated.created_at, Some(registration_time));
+        assert_eq!(updated.last_usage, Some(now(&env)));
+        Ok(())
+    }
+}
+
+/// Tests for the event log of the anchors.
+#[cfg(test)]
+mod anchor_event_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+    use internet_identity_interface as types;
+    use internet_identity_interface::AnchorEventType;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn install_with_layout_v2(env: &StateMachine) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: Some(2),
+                admin: None,
+                config: None,
+            }),
+        )
+    }
+
+    fn prepare_delegation(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: types::UserNumber,
+    ) -> Result<(), CallError> {
+        api::prepare_delegation(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that device changes and delegations are logged with the acting and the affected
+    /// device and that the log is kept across upgrades.
+    #[test]
+    fn should_record_anchor_events() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_layout_v2(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let mut device = device_data_2();
+        device.alias = "new alias".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+        )?;
+        prepare_delegation(&env, canister_id, user_number)?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let events = api::get_anchor_events(&env, canister_id, principal_1(), user_number, None)?;
+        assert_eq!(events.next_cursor, None);
+        let events: Vec<(u64, AnchorEventType, Option<types::DeviceKey>)> = events
+            .events
+            .into_iter()
+            .map(|event| {
+                assert_eq!(event.acting_device, device_data_1().pubkey);
+                (
+                    event.sequence_number,
+                    event.event_type,
+                    event.affected_device,
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (0, AnchorEventType::DeviceAdded, Some(device.pubkey.clone())),
+                (
+                    1,
+                    AnchorEventType::DeviceUpdated,
+                    Some(device.pubkey.clone())
+                ),
+                (
+                    2,
+                    AnchorEventType::DeviceRemoved,
+                    Some(device.pubkey.clone())
+                ),
+                (3, AnchorEventType::DelegationPrepared, None),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the verification of a tentative device is logged.
+    #[test]
+    fn should_record_tentative_device_verification() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_layout_v2(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let add_response = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        let verification_code = match add_response {
+            types::AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        };
+        api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            verification_code,
+            None,
+        )?;
+
+        let events = api::get_anchor_events(&env, canister_id, principal_2(), user_number, None)?;
+        let event_types: Vec<AnchorEventType> = events
+            .events
+            .iter()
+            .map(|event| event.event_type.clone())
+            .collect();
+        assert_eq!(
+            event_types,
+            vec![
+                AnchorEventType::TentativeDeviceVerified,
+                AnchorEventType::DeviceAdded
+            ]
+        );
+        assert_eq!(
+            events.events[0].affected_device,
+            Some(device_data_2().pubkey)
+        );
+        Ok(())
+    }
+
+    /// Verifies that the events are returned in pages.
+    #[test]
+    fn should_paginate_anchor_events() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_layout_v2(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+        for _ in 0..20 {
+            prepare_delegation(&env, canister_id, user_number)?;
+        }
+
+        let first_page =
+            api::get_anchor_events(&env, canister_id, principal_1(), user_number, None)?;
+        assert_eq!(first_page.events.len(), 16);
+        assert_eq!(first_page.next_cursor, Some(16));
+
+        let second_page = api::get_anchor_events(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            first_page.next_cursor,
+        )?;
+        assert_eq!(second_page.events.len(), 4);
+        assert_eq!(second_page.events[0].sequence_number, 16);
+        assert_eq!(second_page.next_cursor, None);
+        Ok(())
+    }
+
+    /// Verifies that the events can only be read by the devices of the anchor.
+    #[test]
+    fn should_not_return_events_to_unauthenticated_caller() {
+        let env = StateMachine::new();
+        let canister_id = install_with_layout_v2(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        expect_user_error_with_message(
+            api::get_anchor_events(&env, canister_id, principal_2(), user_number, None),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that no events are logged with storage layout version 1.
+    #[test]
+    fn should_not_record_events_with_layout_v1() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        prepare_delegation(&env, canister_id, user_number)?;
+
+        let events = api::get_anchor_events(&env, canister_id, principal_1(), user_number, None)?;
+        assert!(events.events.is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for locking an anchor with lock_anchor.
+#[cfg(test)]
+mod anchor_lock_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        principal_recovery_1, recovery_device_data_1, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use std::time::UNIX_EPOCH;
+
+    /// Registers an anchor with device_data_1 and a recovery phrase.
+    fn register_anchor_with_recovery(
+        env: &StateMachine,
+        canister_id: CanisterId,
+    ) -> Result<types::UserNumber, CallError> {
+        let user_number = flows::register_anchor(env, canister_id);
+        api::add(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        Ok(user_number)
+    }
+
+    fn prepare_delegation(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        sender: PrincipalId,
+        user_number: types::UserNumber,
+    ) -> Result<types::PrepareDelegationResponse, CallError> {
+        api::prepare_delegation_v2(
+            env,
+            canister_id,
+            sender,
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )
+    }
+
+    /// Verifies that a locked anchor rejects changes and delegations from authentication devices
+    /// and that the lock is shown in the anchor info.
+    #[test]
+    fn should_freeze_locked_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+
+        let locked_at = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(info.locked_since, Some(locked_at));
+
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ is locked, only recovery devices can make changes").unwrap(),
+        );
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_2()
+            )?,
+            types::AddDeviceResponse::AnchorLocked
+        );
+        assert_eq!(
+            api::remove_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                recovery_device_data_1().pubkey
+            )?,
+            types::RemoveDeviceResponse::AnchorLocked
+        );
+        assert_eq!(
+            prepare_delegation(&env, canister_id, principal_1(), user_number)?,
+            types::PrepareDelegationResponse::AnchorLocked
+        );
+        let result =
+            api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ is locked").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that locking an anchor revokes its sessions and that the delegations prepared
+    /// before the lock can no longer be fetched.
+    #[test]
+    fn should_revoke_sessions_on_lock() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        let (user_key, expiration) =
+            match prepare_delegation(&env, canister_id, principal_1(), user_number)? {
+                types::PrepareDelegationResponse::Prepared {
+                    user_key,
+                    expiration,
+                } => (user_key, expiration),
+                response => panic!("expected a prepared delegation, got {:?}", response),
+            };
+
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let result = api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            expiration,
+            None,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ is locked, only recovery devices can make changes").unwrap(),
+        );
+        assert!(api::list_sessions(&env, canister_id, principal_1(), user_number)?.is_empty());
+        let status = api::get_session_revocation_status(
+            &env,
+            canister_id,
+            user_key,
+            ByteBuf::from("session key"),
+        )?;
+        assert!(status.revoked);
+        Ok(())
+    }
+
+    /// Verifies that recovery devices can still change the devices of a locked anchor and
+    /// prepare delegations for it.
+    #[test]
+    fn should_allow_recovery_device_on_locked_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_1().pubkey,
+        )?;
+        api::add(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        assert!(matches!(
+            prepare_delegation(&env, canister_id, principal_recovery_1(), user_number)?,
+            types::PrepareDelegationResponse::Prepared { .. }
+        ));
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(
+            devices,
+            vec![
+                framework::without_alias(recovery_device_data_1()),
+                framework::without_alias(device_data_2())
+            ]
+        );
+        Ok(())
+    }
+
+    /// Verifies that an anchor can only be unlocked with a recovery device.
+    #[test]
+    fn should_unlock_anchor_with_recovery_device_only() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let result = api::unlock_anchor(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("must be authenticated with a recovery device").unwrap(),
+        );
+
+        api::unlock_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(info.locked_since, None);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that an anchor without a recovery device cannot be locked, as it could not be
+    /// unlocked again.
+    #[test]
+    fn should_not_lock_anchor_without_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::lock_anchor(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ would be locked without a recovery device to unlock it")
+                .unwrap(),
+        );
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(info.locked_since, None);
+        Ok(())
+    }
+
+    /// Verifies that the last recovery device of a locked anchor cannot be removed, and that
+    /// `remove_v2` reports it as a result variant.
+    #[test]
+    fn should_keep_last_recovery_device_of_locked_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            recovery_device_data_1().pubkey,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ would be locked without a recovery device to unlock it")
+                .unwrap(),
+        );
+        assert_eq!(
+            api::remove_v2(
+                &env,
+                canister_id,
+                principal_recovery_1(),
+                user_number,
+                recovery_device_data_1().pubkey
+            )?,
+            types::RemoveDeviceResponse::NoRecoveryDeviceToUnlock
+        );
+
+        api::unlock_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+        api::remove(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            recovery_device_data_1().pubkey,
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that locking aborts an active device registration.
+    #[test]
+    fn should_exit_device_registration_mode_on_lock() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert!(info.device_registration.is_none());
+        let result = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        assert!(matches!(
+            result,
+            types::AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+        Ok(())
+    }
+
+    /// Verifies that the lock is kept across upgrades.
+    #[test]
+    fn should_keep_lock_across_upgrades() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_2()
+            )?,
+            types::AddDeviceResponse::AnchorLocked
+        );
+        Ok(())
+    }
+
+    /// Verifies that the lock is kept across a rollback to the previous release and a
+    /// subsequent upgrade.
+    #[test]
+    fn should_keep_lock_across_rollback() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::lock_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM_PREVIOUS.clone());
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert!(info.locked_since.is_some());
+        Ok(())
+    }
+}
+
+/// Tests for restricting recovery devices to anchor management with set_recovery_device_policy.
+#[cfg(test)]
+mod recovery_device_policy_tests {
+    use crate::framework::{
+        device_data_2, expect_user_error_with_message, principal_1, principal_recovery_1,
+        recovery_device_data_1, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
+    use internet_identity_interface as types;
+    use internet_identity_interface::RecoveryDevicePolicy;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    const FRONTEND: &str = "https://some-dapp.com";
+
+    /// Registers an anchor with device_data_1 and a recovery phrase.
+    fn register_anchor_with_recovery(
+        env: &StateMachine,
+        canister_id: CanisterId,
+    ) -> Result<types::UserNumber, CallError> {
+        let user_number = flows::register_anchor(env, canister_id);
+        api::add(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        Ok(user_number)
+    }
+
+    fn prepare_delegation(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        sender: PrincipalId,
+        user_number: types::UserNumber,
+    ) -> Result<types::PrepareDelegationResponse, CallError> {
+        api::prepare_delegation_v2(
+            env,
+            canister_id,
+            sender,
+            user_number,
+            FRONTEND.to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )
+    }
+
+    /// Verifies that recovery devices can sign in to dapps unless the anchor opts in to the
+    /// restriction.
+    #[test]
+    fn should_allow_recovery_device_delegations_by_default() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+
+        let info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(
+            info.recovery_device_policy,
+            Some(RecoveryDevicePolicy::Unrestricted)
+        );
+        assert!(matches!(
+            prepare_delegation(&env, canister_id, principal_recovery_1(), user_number)?,
+            types::PrepareDelegationResponse::Prepared { .. }
+        ));
+        Ok(())
+    }
+
+    /// Verifies that restricted recovery devices cannot prepare delegations while other devices
+    /// still can.
+    #[test]
+    fn should_reject_prepare_delegation_of_restricted_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::set_recovery_device_policy(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            RecoveryDevicePolicy::AnchorManagementOnly,
+        )?;
+
+        assert_eq!(
+            prepare_delegation(&env, canister_id, principal_recovery_1(), user_number)?,
+            types::PrepareDelegationResponse::RecoveryDeviceNotAllowed
+        );
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            FRONTEND.to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("recovery devices of anchor \\d+ can only be used to manage the anchor")
+                .unwrap(),
+        );
+        assert!(matches!(
+            prepare_delegation(&env, canister_id, principal_1(), user_number)?,
+            types::PrepareDelegationResponse::Prepared { .. }
+        ));
+        Ok(())
+    }
+
+    /// Verifies that delegations prepared before the restriction cannot be fetched by the
+    /// recovery device anymore.
+    #[test]
+    fn should_reject_get_delegation_of_restricted_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        let expiration =
+            match prepare_delegation(&env, canister_id, principal_recovery_1(), user_number)? {
+                types::PrepareDelegationResponse::Prepared { expiration, .. } => expiration,
+                response => panic!("failed to prepare delegation: {:?}", response),
+            };
+        api::set_recovery_device_policy(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            RecoveryDevicePolicy::AnchorManagementOnly,
+        )?;
+
+        let result = api::get_delegation(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            FRONTEND.to_string(),
+            ByteBuf::from("session key"),
+            expiration,
+            None,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("recovery devices of anchor \\d+ can only be used to manage the anchor")
+                .unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that restricted recovery devices can still manage the anchor and lift the
+    /// restriction again, and that the policy is kept across upgrades.
+    #[test]
+    fn should_allow_anchor_management_with_restricted_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = register_anchor_with_recovery(&env, canister_id)?;
+        api::set_recovery_device_policy(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            RecoveryDevicePolicy::AnchorManagementOnly,
+        )?;
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let info = api::get_anchor_info(&env, canister_id, principal_recovery_1(), user_number)?;
+        assert_eq!(
+            info.recovery_device_policy,
+            Some(RecoveryDevicePolicy::AnchorManagementOnly)
+        );
+        api::add(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        api::enter_device_registration_mode(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+        )?;
+
+        api::set_recovery_device_policy(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            RecoveryDevicePolicy::Unrestricted,
+        )?;
+        assert!(matches!(
+            prepare_delegation(&env, canister_id, principal_recovery_1(), user_number)?,
+            types::PrepareDelegationResponse::Prepared { .. }
+        ));
+        Ok(())
+    }
+}
+
+/// Tests for deleting anchors with delete_anchor.
+#[cfg(test)]
+mod anchor_deletion_tests {
+    use crate::framework::{
+        device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        principal_recovery_1, recovery_device_data_1, CallError,
+    };
+    use crate::{api, flows, framework};
+    use candid::Principal;
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+
+    fn install_with_security_key_protection(env: &StateMachine) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    device_protection_policy: Some(
+                        types::DeviceProtectionPolicy::RecoveryPhrasesAndSecurityKeys,
+                    ),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+
+    /// A protected hardware security key used for authentication.
+    fn protected_security_key(pubkey: Vec<u8>) -> types::DeviceData {
+        types::DeviceData {
+            pubkey: ByteBuf::from(pubkey),
+            key_type: types::KeyType::CrossPlatform,
+            protection: types::DeviceProtection::Protected,
+            ..device_data_2()
+        }
+    }
+
+    /// Verifies that a recovery device can delete the anchor and that the anchor number is not
+    /// assigned again.
+    #[test]
+    fn should_delete_anchor_with_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+
+        let response = api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+
+        assert_eq!(response, types::DeleteAnchorResponse::Deleted);
+        assert_eq!(api::lookup(&env, canister_id, user_number)?, vec![]);
+        expect_user_error_with_message(
+            api::get_anchor_info(&env, canister_id, principal_1(), user_number),
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        assert!(matches!(
+            api::get_anchor_info_v2(&env, canister_id, principal_1(), user_number)?,
+            types::GetAnchorInfoResponse::UnknownAnchor
+        ));
+        assert_eq!(flows::register_anchor(&env, canister_id), user_number + 1);
+        Ok(())
+    }
+
+    /// Verifies that the anchor stays deleted across upgrades.
+    #[test]
+    fn should_keep_anchor_deleted_after_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        expect_user_error_with_message(
+            api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number),
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that an unprotected device that is not a recovery device cannot delete the anchor.
+    #[test]
+    fn should_not_delete_anchor_with_unprotected_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::delete_anchor(&env, canister_id, principal_1(), user_number);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("anchor \\d+ can only be deleted with a recovery device").unwrap(),
+        );
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![framework::without_alias(framework::device_data_1())]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the anchor is only deleted once all protected devices confirmed the deletion.
+    #[test]
+    fn should_delete_anchor_when_confirmed_by_all_protected_devices() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_with_security_key_protection(&env);
+        let user_number = flows::register_anchor(&env, canister_id);
+        let key_1 = framework::ed25519_der_key(0x31).to_vec();
+        let key_2 = framework::ed25519_der_key(0x32).to_vec();
+        for key in [&key_1, &key_2] {
+            api::add(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                protected_security_key(key.clone()),
+            )?;
+        }
+
+        let response = api::delete_anchor(
+            &env,
+            canister_id,
+            PrincipalId(Principal::self_authenticating(&key_1)),
+            user_number,
+        )?;
+        match response {
+            types::DeleteAnchorResponse::ConfirmationPending {
+                missing_confirmations,
+                ..
+            } => assert_eq!(missing_confirmations, vec![ByteBuf::from(key_2.clone())]),
+            response => panic!("expected pending confirmation, got {:?}", response),
+        }
+        assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 3);
+
+        let response = api::delete_anchor(
+            &env,
+            canister_id,
+            PrincipalId(Principal::self_authenticating(&key_2)),
+            user_number,
+        )?;
+        assert_eq!(response, types::DeleteAnchorResponse::Deleted);
+        assert_eq!(api::lookup(&env, canister_id, user_number)?, vec![]);
+        Ok(())
+    }
+
+    /// Verifies that the sessions of a deleted anchor are revoked and their signatures removed.
+    #[test]
+    fn should_revoke_sessions_of_deleted_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        let frontend_hostname = "https://some-dapp.com".to_string();
+        let session_key = ByteBuf::from("session key");
+        let (user_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.clone(),
+            session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+
+        let status =
+            api::get_session_revocation_status(&env, canister_id, user_key, session_key.clone())?;
+        assert!(status.revoked);
+        expect_user_error_with_message(
+            api::get_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname,
+                session_key,
+                expiration,
+                None,
+            ),
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that deleting an anchor also revokes the delegations of sessions that are no longer
+    /// recorded because the anchor has more sessions than are kept per anchor, and that the
+    /// revocation survives an upgrade.
+    #[test]
+    fn should_revoke_unrecorded_sessions_of_deleted_anchor() -> Result<(), CallError> {
+        // more sessions than MAX_SESSIONS_PER_ANCHOR
+        const NUM_SESSIONS: usize = 101;
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        let frontend_hostname = "https://some-dapp.com".to_string();
+        let mut user_key = None;
+        for i in 0..NUM_SESSIONS {
+            let (key, _) = api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.clone(),
+                ByteBuf::from(format!("session key {}", i)),
+                None,
+                None,
+                None,
+            )?;
+            user_key = Some(key);
+        }
+        let user_key = user_key.unwrap();
+        assert_eq!(
+            api::list_sessions(&env, canister_id, principal_1(), user_number)?.len(),
+            NUM_SESSIONS - 1
+        );
+
+        api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number)?;
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        for i in 0..NUM_SESSIONS {
+            let status = api::get_session_revocation_status(
+                &env,
+                canister_id,
+                user_key.clone(),
+                ByteBuf::from(format!("session key {}", i)),
+            )?;
+            assert!(status.revoked);
+        }
+        Ok(())
+    }
+
+    /// Verifies that other anchors cannot be deleted.
+    #[test]
+    fn should_not_delete_anchor_of_other_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::delete_anchor(&env, canister_id, principal_2(), user_number);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+}
+
+/// Tests related to prepare_delegation, get_delegation and get_principal II canister calls.
+#[cfg(test)]
+mod delegation_tests {
+    use crate::framework::{
+        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
+        CallError,
+    };
+    use crate::{api, flows, framework};
+    use candid::Principal;
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface::GetDelegationResponse;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use std::ops::Add;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Verifies that valid delegations are issued.
+    #[test]
+    fn should_get_valid_delegation() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that non-default expirations are respected.
+    #[test]
+    fn should_get_valid_delegation_with_custom_expiration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            Some(3_600_000_000_000), // 1 hour,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(60 * 60)) // 1 hour
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that the delegations are valid at most for 30 days.
+    #[test]
+    fn should_shorten_expiration_greater_max_ttl() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            Some(Duration::from_secs(31 * 24 * 60 * 60).as_nanos() as u64), // 31 days,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 24 * 60 * 60)) // 30 days
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that delegations can be requested in parallel.
+    #[test]
+    fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp1.com";
+        let frontend_hostname_2 = "https://dapp2.com";
+        let pub_session_key_1 = ByteBuf::from("session public key 1");
+        let pub_session_key_2 = ByteBuf::from("session public key 2");
+        let delegation_params = vec![
+            (
+                &pub_session_key_1,
+                frontend_hostname_1,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_1,
+                frontend_hostname_2,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_2,
+                frontend_hostname_1,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_1,
+                frontend_hostname_1,
+                Duration::from_secs(30),
+            ),
+        ];
+
+        // prepare multiple delegations in parallel before calling get_delegation
+        let prepare_delegation_results =
+            delegation_params
+                .into_iter()
+                .map(|(session_key, frontend_hostname, time_shift)| {
+                    env.advance_time(time_shift);
+                    let (canister_sig_key, expiration) = api::prepare_delegation(
+                        &env,
+                        canister_id,
+                        principal_1(),
+                        user_number,
+                        frontend_hostname.to_string(),
+                        session_key.clone(),
+                        None,
+                        None,
+                        None,
+                    )
+                    .expect("prepare_delegation failed");
+
+                    assert_eq!(
+                        expiration,
+                        env.time()
+                            .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_nanos() as u64
+                    );
+                    (session_key, frontend_hostname, canister_sig_key, expiration)
+                });
+
+        for (session_key, frontend_hostname, canister_sig_key, expiration) in
+            prepare_delegation_results
+        {
+            let signed_delegation = match api::get_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.to_string(),
+                session_key.clone(),
+                expiration,
+                None,
+            )? {
+                GetDelegationResponse::SignedDelegation(delegation) => delegation,
+                GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+            };
+
+            framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+            assert_eq!(signed_delegation.delegation.pubkey, session_key.clone());
+            assert_eq!(signed_delegation.delegation.expiration, expiration);
+        }
+        Ok(())
+    }
+
+    /// Verifies that an anchor that was registered using II_WASM_PREVIOUS gets valid delegations after upgrading to the current version.
+    #[test]
+    fn should_get_valid_delegation_for_old_anchor_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that different front-ends yield different principals.
+    #[test]
+    fn should_issue_different_principals() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp1.com";
+        let frontend_hostname_2 = "https://dapp2.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key_1, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_1.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        let (canister_sig_key_2, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_2.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        assert_ne!(canister_sig_key_1, canister_sig_key_2);
+        Ok(())
+    }
+
+    /// Verifies that a delegation prepared before an II upgrade can be retrieved after the upgrade.
+    #[test]
+    fn should_get_prepared_delegation_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        // the signatures are persisted across upgrades
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that there is a graceful failure if II gets rolled back between prepare_delegation and get_delegation.
+    #[test]
+    fn should_not_get_prepared_delegation_after_ii_rollback() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        // the previous release does not restore the persisted signatures
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM_PREVIOUS.clone());
+
+        match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+            GetDelegationResponse::NoSuchDelegation => {}
+        };
+        Ok(())
+    }
+
+    fn delegation_targets(n: u8) -> Vec<Principal> {
+        (0..n)
+            .map(|i| Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, i, 1, 1]))
+            .collect()
+    }
+
+    /// Verifies that valid delegations restricted to a set of canisters are issued.
+    #[test]
+    fn should_get_valid_delegation_with_targets() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+        let targets = delegation_targets(3);
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            Some(targets.clone()),
+            None,
+        )?;
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            Some(targets.clone()),
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        assert_eq!(signed_delegation.delegation.targets, Some(targets));
+        Ok(())
+    }
+
+    /// Verifies that a delegation can only be retrieved with the targets it was prepared for.
+    #[test]
+    fn should_not_get_delegation_with_different_targets() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            Some(delegation_targets(2)),
+            None,
+        )?;
+
+        for targets in [
+            None,
+            Some(delegation_targets(1)),
+            Some(delegation_targets(3)),
+        ] {
+            match api::get_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.to_string(),
+                pub_session_key.clone(),
+                expiration,
+                targets,
+            )? {
+                GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+                GetDelegationResponse::NoSuchDelegation => {}
+            };
+        }
+        Ok(())
+    }
+
+    /// Verifies that the number of delegation targets is limited.
+    #[test]
+    fn should_not_prepare_delegation_with_too_many_targets() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let targets: Vec<Principal> = (0..1001u16)
+            .map(|i| Principal::from_slice(&i.to_be_bytes()))
+            .collect();
+
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session public key"),
+            None,
+            Some(targets),
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("number of delegation targets 1001 exceeds the limit of 1000").unwrap(),
+        );
+    }
+
+    /// Verifies that there is a graceful failure if get_delegation is called after the expiration of the delegation.
+    #[test]
+    fn should_not_get_delegation_after_expiration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        env.advance_time(Duration::from_secs(30 * 60 + 1)); // one second more than delegation validity of 30 min
+
+        // we have to call prepare again, because expired signatures can only be pruned in update calls
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+            GetDelegationResponse::NoSuchDelegation => {}
+        };
+        Ok(())
+    }
+
+    /// Verifies that delegations can only be prepared by the matching user.
+    #[test]
+    fn can_not_prepare_delegation_for_different_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number, // belongs to principal_1
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that get_delegation can only be called by the matching user.
+    #[test]
+    fn can_not_get_delegation_for_different_user() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        let result = api::get_delegation(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that get_principal and prepare_delegation return the same principal.
+    #[test]
+    fn get_principal_should_match_prepare_delegation() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        let principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+        )?;
+        assert_eq!(Principal::self_authenticating(canister_sig_key), principal);
+        Ok(())
+    }
+
+    /// Verifies that get_principal returns different principals for different front end host names.
+    #[test]
+    fn should_return_different_principals_for_different_frontends() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp-1.com";
+        let frontend_hostname_2 = "https://dapp-2.com";
+
+        let dapp_principal_1 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_1.to_string(),
+        )?;
+
+        let dapp_principal_2 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_2.to_string(),
+        )?;
+
+        assert_ne!(dapp_principal_1, dapp_principal_2);
+        Ok(())
+    }
+
+    /// Verifies that get_principal returns different principals for different anchors.
+    #[test]
+    fn should_return_different_principals_for_different_users() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number_1 =
+            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let frontend_hostname = "https://dapp-1.com";
+
+        let dapp_principal_1 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_1,
+            frontend_hostname.to_string(),
+        )?;
+
+        let dapp_principal_2 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            frontend_hostname.to_string(),
+        )?;
+
+        assert_ne!(dapp_principal_1, dapp_principal_2);
+        Ok(())
+    }
+
+    /// Verifies that get_principal requires authentication.
+    #[test]
+    fn should_not_allow_get_principal_for_other_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+        let frontend_hostname_1 = "https://dapp-1.com";
+
+        let result = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            frontend_hostname_1.to_string(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated\\.").unwrap(),
+        );
+    }
+
+    /// Verifies that get_principals returns the same principals as get_principal, in order.
+    #[test]
+    fn should_get_principals_for_multiple_frontends() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostnames = vec![
+            "https://dapp-2.com".to_string(),
+            "https://dapp-1.com".to_string(),
+            "https://dapp-3.com".to_string(),
+        ];
+
+        let principals = api::get_principals(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostnames.clone(),
+        )?;
+
+        let mut expected = vec![];
+        for frontend_hostname in frontend_hostnames {
+            let principal = api::get_principal(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.clone(),
+            )?;
+            expected.push((frontend_hostname, principal));
+        }
+        assert_eq!(principals, expected);
+        Ok(())
+    }
+
+    /// Verifies that get_principals rejects batches that are too large.
+    #[test]
+    fn should_not_get_principals_for_too_many_frontends() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostnames = (0..101)
+            .map(|i| format!("https://dapp-{}.com", i))
+            .collect();
+
+        let result = api::get_principals(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostnames,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("number of frontends 101 exceeds the limit of 100").unwrap(),
+        );
+    }
+
+    /// Verifies that get_principals requires authentication.
+    #[test]
+    fn should_not_allow_get_principals_for_other_user() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::get_principals(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            vec!["https://dapp-1.com".to_string()],
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated\\.").unwrap(),
+        );
+    }
+}
+
+/// Tests for the canister-side validation of derivation origins against the alternative origins
+/// served by the test app.
+#[cfg(test)]
+mod alternative_origins_tests {
+    use crate::api::test_app::AlternativeOriginsMode;
+    use crate::framework::{expect_user_error_with_message, principal_1, principal_2, CallError};
+    use crate::{api, flows, framework};
+    use candid::Principal;
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+    use internet_identity_interface as types;
+    use internet_identity_interface::{PrepareDelegationResponse, UserNumber};
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use std::time::Duration;
+
+    const ACTUAL_ORIGIN: &str = "https://some-dapp.com";
+
+    /// Installs II with the given canisters allow-listed to provide alternative origins.
+    fn install_ii_canister(env: &StateMachine, allowed_canisters: Vec<Principal>) -> CanisterId {
+        framework::install_ii_canister_with_arg(
+            env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    alternative_origins_canisters: Some(allowed_canisters),
+                    ..Default::default()
+                }),
+            }),
+        )
+    }
+
+    /// Installs the test app and II (with the test app allow-listed), registers an anchor and lets
+    /// the test app list the given alternative origins. Returns the II canister id, the anchor and
+    /// the derivation origin.
+    fn setup(
+        env: &StateMachine,
+        alternative_origins: &str,
+        mode: AlternativeOriginsMode,
+    ) -> Result<(CanisterId, UserNumber, String), CallError> {
+        let test_app_id = framework::install_test_app_canister(env);
+        let canister_id = install_ii_canister(env, vec![test_app_id.get().0]);
+        api::test_app::update_alternative_origins(env, test_app_id, alternative_origins, mode)?;
+        let user_number = flows::register_anchor(env, canister_id);
+        Ok((
+            canister_id,
+            user_number,
+            format!("https://{}.ic0.app", test_app_id),
+        ))
+    }
 
-            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
+    fn prepare_delegation_v2(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: UserNumber,
+        derivation_origin: &str,
+        actual_origin: &str,
+    ) -> Result<PrepareDelegationResponse, CallError> {
+        api::prepare_delegation_v2(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            derivation_origin.to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            Some(actual_origin.to_string()),
+        )
+    }
 
-            let devices = api::lookup(&env, canister_id, user_number)?;
-            assert_eq!(devices, vec![device.clone()]);
+    fn expect_invalid_derivation_origin(response: PrepareDelegationResponse, message: &str) {
+        match response {
+            PrepareDelegationResponse::InvalidDerivationOrigin(err) => assert!(
+                Regex::new(message).unwrap().is_match(&err),
+                "unexpected error message {}",
+                err
+            ),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
 
-            device.alias.push_str("some suffix");
+    /// Verifies that an alternative origin gets the principal of the derivation origin.
+    #[test]
+    fn should_prepare_delegation_for_alternative_origin() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
 
-            api::update(
+        let (user_key, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            derivation_origin.clone(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            Some(ACTUAL_ORIGIN.to_string()),
+        )?;
+
+        assert_eq!(
+            Principal::self_authenticating(user_key),
+            api::get_principal(
                 &env,
                 canister_id,
-                principal,
+                principal_1(),
                 user_number,
-                device.clone().pubkey,
-                device.clone(),
-            )?;
-
-            let devices = api::lookup(&env, canister_id, user_number)?;
-            assert_eq!(devices, vec![device]);
-
-            Ok(())
-        }
+                derivation_origin
+            )?
+        );
+        Ok(())
+    }
 
-        /// Verifies that a protected device can be updated
-        #[test]
-        fn should_update_protected_device() -> Result<(), CallError> {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            let principal = principal_1();
-            let mut device = device_data_1();
-            device.protection = types::DeviceProtection::Protected;
-            device.key_type = types::KeyType::SeedPhrase;
+    /// Verifies that origins not listed by the canister of the derivation origin are rejected.
+    #[test]
+    fn should_not_prepare_delegation_for_unlisted_origin() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            r#"{"alternativeOrigins":["https://other-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
 
-            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            derivation_origin,
+            ByteBuf::from("session key"),
+            None,
+            None,
+            Some(ACTUAL_ORIGIN.to_string()),
+        );
 
-            let devices = api::lookup(&env, canister_id, user_number)?;
-            assert_eq!(devices, vec![device.clone()]);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new(
+                "https://some-dapp\\.com is not listed in the alternative origins of https://[a-z\\d-]+\\.ic0\\.app",
+            )
+            .unwrap(),
+        );
+        Ok(())
+    }
 
-            device.alias.push_str("some suffix");
+    /// Verifies that canisters that are not allow-listed are not called to fetch alternative origins.
+    #[test]
+    fn should_reject_derivation_origin_of_unlisted_canister() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let test_app_id = framework::install_test_app_canister(&env);
+        api::test_app::update_alternative_origins(
+            &env,
+            test_app_id,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
+        let canister_id = install_ii_canister(&env, vec![]);
+        let user_number = flows::register_anchor(&env, canister_id);
 
-            api::update(
+        expect_invalid_derivation_origin(
+            prepare_delegation_v2(
                 &env,
                 canister_id,
-                principal,
                 user_number,
-                device.clone().pubkey,
-                device.clone(),
-            )?;
+                &format!("https://{}.ic0.app", test_app_id),
+                ACTUAL_ORIGIN,
+            )?,
+            "is not allowed to provide alternative origins$",
+        );
+        Ok(())
+    }
 
-            let devices = api::lookup(&env, canister_id, user_number)?;
-            assert_eq!(devices, vec![device]);
+    /// Verifies that derivation origins other than canister URLs are rejected.
+    #[test]
+    fn should_reject_invalid_derivation_origin() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number, _) = setup(
+            &env,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
 
-            Ok(())
+        for derivation_origin in [
+            "https://other-dapp.com",
+            "http://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app",
+            "https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app.com",
+            "https://not.a.canister.ic0.app",
+        ] {
+            expect_invalid_derivation_origin(
+                prepare_delegation_v2(
+                    &env,
+                    canister_id,
+                    user_number,
+                    derivation_origin,
+                    ACTUAL_ORIGIN,
+                )?,
+                "^derivation origin .* does not match",
+            );
         }
+        Ok(())
+    }
 
-        /// Verifies that the device key (i.e. the device ID) cannot be updated
-        #[test]
-        fn should_not_modify_pubkey() {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            let principal = principal_1();
-            let mut device = device_data_1();
-
-            let user_number = flows::register_anchor_with(&env, canister_id, principal, &device);
-
-            let original_pubkey = device.pubkey;
-            let pubkey_2 = ByteBuf::from(PUBKEY_2);
-            assert_ne!(original_pubkey, pubkey_2);
-            device.pubkey = pubkey_2;
-
-            let result = api::update(
+    /// Verifies that redirects and malformed alternative origins are rejected.
+    #[test]
+    fn should_reject_redirects_and_invalid_alternative_origins() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::Redirect {
+                location: "https://some-dapp.com/.well-known/ii-alternative-origins".to_string(),
+            },
+        )?;
+        expect_invalid_derivation_origin(
+            prepare_delegation_v2(
                 &env,
                 canister_id,
-                principal,
                 user_number,
-                original_pubkey,
-                device.clone(),
-            );
+                &derivation_origin,
+                ACTUAL_ORIGIN,
+            )?,
+            "returned invalid status: 302$",
+        );
 
-            expect_user_error_with_message(
-                result,
-                CanisterCalledTrap,
-                Regex::new("device key may not be updated").unwrap(),
-            );
-        }
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            r#"{"origins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
+        expect_invalid_derivation_origin(
+            prepare_delegation_v2(
+                &env,
+                canister_id,
+                user_number,
+                &derivation_origin,
+                ACTUAL_ORIGIN,
+            )?,
+            "has invalid format",
+        );
+        Ok(())
+    }
 
-        /// Verifies that users can only update their own devices.
-        #[test]
-        fn should_not_update_device_of_different_user() {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-            let user_number_2 =
-                flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+    /// Verifies that at most 10 alternative origins are accepted.
+    #[test]
+    fn should_reject_too_many_alternative_origins() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let origins: Vec<String> = (0..11)
+            .map(|i| format!("\"https://some-dapp-{}.com\"", i))
+            .collect();
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            &format!(r#"{{"alternativeOrigins":[{}]}}"#, origins.join(",")),
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
 
-            let result = api::update(
+        expect_invalid_derivation_origin(
+            prepare_delegation_v2(
                 &env,
                 canister_id,
-                principal_1(),
-                user_number_2,
-                device_data_2().pubkey,
-                device_data_2(),
-            );
+                user_number,
+                &derivation_origin,
+                "https://some-dapp-0.com",
+            )?,
+            "at most 10 alternative origins are allowed$",
+        );
+        Ok(())
+    }
 
-            expect_user_error_with_message(
-                result,
-                CanisterCalledTrap,
-                Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
-            );
-        }
+    /// Verifies that the alternative origins are cached for 10 minutes.
+    #[test]
+    fn should_cache_alternative_origins() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let test_app_id = framework::install_test_app_canister(&env);
+        let canister_id = install_ii_canister(&env, vec![test_app_id.get().0]);
+        let user_number = flows::register_anchor(&env, canister_id);
+        let derivation_origin = format!("https://{}.ic0.app", test_app_id);
 
-        /// Verifies that unprotected devices can only be updated from themselves
-        #[test]
-        fn should_not_update_protected_with_different_device() {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            let mut device1 = device_data_1();
-            device1.protection = types::DeviceProtection::Protected;
-            device1.key_type = types::KeyType::SeedPhrase;
+        api::test_app::update_alternative_origins(
+            &env,
+            test_app_id,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
+        assert!(matches!(
+            prepare_delegation_v2(
+                &env,
+                canister_id,
+                user_number,
+                &derivation_origin,
+                ACTUAL_ORIGIN
+            )?,
+            PrepareDelegationResponse::Prepared { .. }
+        ));
 
-            let user_number =
-                flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+        api::test_app::update_alternative_origins(
+            &env,
+            test_app_id,
+            r#"{"alternativeOrigins":[]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
+        assert!(matches!(
+            prepare_delegation_v2(
+                &env,
+                canister_id,
+                user_number,
+                &derivation_origin,
+                ACTUAL_ORIGIN
+            )?,
+            PrepareDelegationResponse::Prepared { .. }
+        ));
 
-            api::add(
+        env.advance_time(Duration::from_secs(601));
+        expect_invalid_derivation_origin(
+            prepare_delegation_v2(
                 &env,
                 canister_id,
-                principal_1(),
                 user_number,
-                device_data_2(),
-            )
-            .unwrap();
+                &derivation_origin,
+                ACTUAL_ORIGIN,
+            )?,
+            "is not listed in the alternative origins",
+        );
+        Ok(())
+    }
+
+    /// Verifies that the derivation origin is only validated for authenticated callers.
+    #[test]
+    fn should_not_validate_derivation_origin_for_unauthenticated_caller() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number, derivation_origin) = setup(
+            &env,
+            r#"{"alternativeOrigins":["https://some-dapp.com"]}"#,
+            AlternativeOriginsMode::CertifiedContent,
+        )?;
+
+        let response = api::prepare_delegation_v2(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            derivation_origin,
+            ByteBuf::from("session key"),
+            None,
+            None,
+            Some(ACTUAL_ORIGIN.to_string()),
+        )?;
+        assert_eq!(response, PrepareDelegationResponse::NotAuthenticated);
+        Ok(())
+    }
+}
+
+/// Tests for listing and revoking sessions, i.e. the delegations issued by prepare_delegation, and
+/// the certified revocation list.
+#[cfg(test)]
+mod session_tests {
+    use crate::certificate_validation::validate_session_revocation_status;
+    use crate::framework::{
+        device_data_2, expect_user_error_with_message, principal_1, principal_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode::CanisterCalledTrap;
+    use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
+    use internet_identity_interface::{GetDelegationResponse, SessionInfo, UserKey, UserNumber};
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn session_key_hash(session_key: &ByteBuf) -> ByteBuf {
+        ByteBuf::from(Sha256::digest(session_key).to_vec())
+    }
+
+    fn prepare_session(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: UserNumber,
+        frontend_hostname: &str,
+        session_key: &ByteBuf,
+        max_time_to_live: Option<u64>,
+    ) -> Result<(UserKey, SessionInfo), CallError> {
+        prepare_session_as(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname,
+            session_key,
+            max_time_to_live,
+        )
+    }
+
+    /// Prepares a delegation and returns the user key and the session it is listed as.
+    fn prepare_session_as(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        sender: PrincipalId,
+        user_number: UserNumber,
+        frontend_hostname: &str,
+        session_key: &ByteBuf,
+        max_time_to_live: Option<u64>,
+    ) -> Result<(UserKey, SessionInfo), CallError> {
+        let (user_key, expiration) = api::prepare_delegation(
+            env,
+            canister_id,
+            sender,
+            user_number,
+            frontend_hostname.to_string(),
+            session_key.clone(),
+            max_time_to_live,
+            None,
+            None,
+        )?;
+        let session = SessionInfo {
+            frontend: frontend_hostname.to_string(),
+            session_key_hash: session_key_hash(session_key),
+            expiration,
+            issued_at: env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+        };
+        Ok((user_key, session))
+    }
 
-            let result = api::update(
-                &env,
-                canister_id,
-                principal_2(),
-                user_number,
-                device1.pubkey.clone(),
-                device1.clone(), // data here doesnt' actually matter
-            );
+    /// Queries the revocation status of the session key and checks it against the certified data.
+    fn assert_revocation_status(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_key: &UserKey,
+        session_key: &ByteBuf,
+        expected: bool,
+    ) -> Result<(), CallError> {
+        let status = api::get_session_revocation_status(
+            env,
+            canister_id,
+            user_key.clone(),
+            session_key.clone(),
+        )?;
+        assert_eq!(status.revoked, expected);
 
-            expect_user_error_with_message(
-                result,
-                CanisterCalledTrap,
-                Regex::new("Device is protected. Must be authenticated with this device to mutate")
-                    .unwrap(),
-            );
-        }
+        let certified_revoked = validate_session_revocation_status(
+            &status.certificate.expect("certificate missing"),
+            &status.tree,
+            canister_id,
+            user_key,
+            session_key,
+            env.root_key(),
+        )
+        .expect("revocation status validation failed");
+        assert_eq!(certified_revoked, expected);
+        Ok(())
+    }
 
-        /// Verifies that non-recovery devices cannot be updated to be protected.
-        #[test]
-        fn should_not_update_non_recovery_device_to_be_protected() {
-            let env = StateMachine::new();
-            let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-            let user_number = flows::register_anchor(&env, canister_id);
+    /// Verifies that the issued delegations are listed as sessions.
+    #[test]
+    fn should_list_sessions() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-            let mut device1 = device_data_1();
-            device1.protection = types::DeviceProtection::Protected;
-            let result = api::update(
-                &env,
-                canister_id,
-                principal_1(),
-                user_number,
-                device1.pubkey.clone(),
-                device1.clone(), // data here doesnt' actually matter
-            );
+        let (_, session_1) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp1.com",
+            &ByteBuf::from("session key 1"),
+            None,
+        )?;
+        let (_, session_2) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp2.com",
+            &ByteBuf::from("session key 2"),
+            None,
+        )?;
 
-            expect_user_error_with_message(
-                result,
-                CanisterCalledTrap,
-                Regex::new("Only recovery phrases can be protected but key type is Unknown")
-                    .unwrap(),
-            );
-        }
+        let sessions = api::list_sessions(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(sessions, vec![session_1, session_2]);
+        Ok(())
     }
 
-    /// Verifies that a device can be removed.
+    /// Verifies that expired sessions are no longer listed.
     #[test]
-    fn should_remove_device() -> Result<(), CallError> {
+    fn should_not_list_expired_sessions() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
-        api::add(
+        prepare_session(
             &env,
             canister_id,
-            principal_1(),
             user_number,
-            device_data_2(),
+            "https://dapp1.com",
+            &ByteBuf::from("session key 1"),
+            Some(Duration::from_secs(60).as_nanos() as u64),
         )?;
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert!(devices.iter().any(|device| device == &device_data_2()));
-
-        api::remove(
+        let (_, session_2) = prepare_session(
             &env,
             canister_id,
-            principal_1(),
             user_number,
-            device_data_2().pubkey,
+            "https://dapp2.com",
+            &ByteBuf::from("session key 2"),
+            Some(Duration::from_secs(600).as_nanos() as u64),
         )?;
 
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 1);
-        assert!(!devices.iter().any(|device| device == &device_data_2()));
+        env.advance_time(Duration::from_secs(61));
+
+        let sessions = api::list_sessions(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(sessions, vec![session_2]);
         Ok(())
     }
 
-    /// Verifies that a protected device can be removed.
+    /// Verifies that a revoked session is certified as revoked and can no longer be used to get a delegation.
     #[test]
-    fn should_remove_protected_device() -> Result<(), CallError> {
+    fn should_revoke_session() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let session_key = ByteBuf::from("session key");
 
-        let mut device2 = device_data_2();
-        device2.protection = types::DeviceProtection::Protected;
-        device2.key_type = types::KeyType::SeedPhrase;
+        let (user_key, session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            frontend_hostname,
+            &session_key,
+            None,
+        )?;
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, false)?;
 
-        api::add(
+        api::revoke_session(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device_data_2(),
+            session.session_key_hash,
         )?;
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert!(devices.iter().any(|device| device == &device_data_2()));
 
-        api::remove(
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, true)?;
+        assert_eq!(
+            api::list_sessions(&env, canister_id, principal_1(), user_number)?,
+            vec![]
+        );
+        match api::get_delegation(
             &env,
             canister_id,
-            principal_2(),
+            principal_1(),
             user_number,
-            device_data_2().pubkey,
-        )?;
+            frontend_hostname.to_string(),
+            session_key.clone(),
+            session.expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+            GetDelegationResponse::NoSuchDelegation => {}
+        };
 
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 1);
-        assert!(!devices.iter().any(|device| device == &device_data_2()));
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            session_key,
+            None,
+            None,
+            None,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("the session key has been revoked").unwrap(),
+        );
         Ok(())
     }
 
-    /// Verifies that the even last device can be removed.
-    /// This behaviour should be changed because it makes anchors unusable, see L2-745.
+    /// Verifies that all sessions of a frontend can be revoked at once without affecting other frontends.
     #[test]
-    fn should_remove_last_device() -> Result<(), CallError> {
+    fn should_revoke_all_sessions_for_frontend() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
+        let session_keys: Vec<ByteBuf> = (0..3)
+            .map(|i| ByteBuf::from(format!("session key {}", i)))
+            .collect();
 
-        api::remove(
+        let (user_key_1, _) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp1.com",
+            &session_keys[0],
+            None,
+        )?;
+        prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp1.com",
+            &session_keys[1],
+            None,
+        )?;
+        let (user_key_2, other_session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp2.com",
+            &session_keys[2],
+            None,
+        )?;
+
+        api::revoke_all_sessions_for_frontend(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device_data_1().pubkey,
+            "https://dapp1.com".to_string(),
         )?;
 
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert!(devices.is_empty());
+        assert_revocation_status(&env, canister_id, &user_key_1, &session_keys[0], true)?;
+        assert_revocation_status(&env, canister_id, &user_key_1, &session_keys[1], true)?;
+        assert_revocation_status(&env, canister_id, &user_key_2, &session_keys[2], false)?;
+        assert_eq!(
+            api::list_sessions(&env, canister_id, principal_1(), user_number)?,
+            vec![other_session]
+        );
         Ok(())
     }
 
-    /// Verifies that users can only remove their own devices.
+    /// Verifies that revoking a session only affects the delegations of the revoking anchor, even
+    /// if another anchor uses the same session key for the same frontend.
     #[test]
-    fn should_not_remove_device_of_different_user() {
+    fn should_not_revoke_sessions_of_other_anchors() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
+        let user_number = flows::register_anchor(&env, canister_id);
+        let attacker_number =
             flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+        let frontend_hostname = "https://some-dapp.com";
+        let session_key = ByteBuf::from("session key");
 
-        let result = api::remove(
+        let (user_key, session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            frontend_hostname,
+            &session_key,
+            None,
+        )?;
+        let (attacker_key, attacker_session) = prepare_session_as(
+            &env,
+            canister_id,
+            principal_2(),
+            attacker_number,
+            frontend_hostname,
+            &session_key,
+            None,
+        )?;
+        api::revoke_session(
+            &env,
+            canister_id,
+            principal_2(),
+            attacker_number,
+            attacker_session.session_key_hash,
+        )?;
+
+        assert_revocation_status(&env, canister_id, &attacker_key, &session_key, true)?;
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, false)?;
+        match api::get_delegation(
             &env,
             canister_id,
             principal_1(),
-            user_number_2,
-            device_data_2().pubkey,
-        );
+            user_number,
+            frontend_hostname.to_string(),
+            session_key,
+            session.expiration,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => {}
+            GetDelegationResponse::NoSuchDelegation => panic!("delegation missing"),
+        };
+        Ok(())
+    }
+
+    /// Verifies that the revocation status can only be queried for user keys of the canister.
+    #[test]
+    fn should_reject_foreign_user_key() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
 
         expect_user_error_with_message(
-            result,
+            api::get_session_revocation_status(
+                &env,
+                canister_id,
+                ByteBuf::from("not a user key"),
+                ByteBuf::from("session key"),
+            ),
             CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+            Regex::new("the user key was not issued by this canister").unwrap(),
         );
     }
 
-    /// Verifies that unprotected devices can not be removed with another device
+    /// Verifies that revoking a session that does not exist fails.
     #[test]
-    fn should_not_remove_protected_with_different_device() {
+    fn should_not_revoke_unknown_session() {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let mut device1 = device_data_1();
-        device1.protection = types::DeviceProtection::Protected;
-        device1.key_type = types::KeyType::SeedPhrase;
-
-        let user_number = flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        api::add(
+        let result = api::revoke_session(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device_data_2(),
-        )
-        .unwrap();
+            session_key_hash(&ByteBuf::from("unknown session key")),
+        );
 
-        let result = api::remove(
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("no matching session found for user [0-9]+").unwrap(),
+        );
+    }
+
+    /// Verifies that sessions can only be listed and revoked by the anchor owner.
+    #[test]
+    fn should_not_list_or_revoke_sessions_of_different_user() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let session_key = ByteBuf::from("session key");
+        let (user_key, session) = prepare_session(
             &env,
             canister_id,
-            principal_2(),
             user_number,
-            device1.pubkey.clone(),
-        );
+            "https://some-dapp.com",
+            &session_key,
+            None,
+        )?;
 
         expect_user_error_with_message(
-            result,
+            api::list_sessions(&env, canister_id, principal_2(), user_number),
             CanisterCalledTrap,
-            Regex::new("Device is protected. Must be authenticated with this device to mutate")
-                .unwrap(),
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+        expect_user_error_with_message(
+            api::revoke_session(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                session.session_key_hash,
+            ),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+        expect_user_error_with_message(
+            api::revoke_all_sessions_for_frontend(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                "https://some-dapp.com".to_string(),
+            ),
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
         );
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, false)?;
+        Ok(())
     }
 
-    /// Verifies that a device can be removed if it has been added using the previous II release.
+    /// Verifies that revocations are dropped from the revocation list once the delegation has expired.
     #[test]
-    fn should_remove_device_after_ii_upgrade() -> Result<(), CallError> {
+    fn should_drop_revocation_after_expiration() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
+        let session_key = ByteBuf::from("session key");
 
-        api::add(
+        let (user_key, session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://some-dapp.com",
+            &session_key,
+            Some(Duration::from_secs(60).as_nanos() as u64),
+        )?;
+        api::revoke_session(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device_data_2(),
+            session.session_key_hash,
         )?;
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert!(devices.iter().any(|device| device == &device_data_2()));
-
-        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, true)?;
 
-        api::remove(
+        env.advance_time(Duration::from_secs(61));
+        // expired revocations are pruned on update calls
+        prepare_session(
             &env,
             canister_id,
-            principal_1(),
             user_number,
-            device_data_2().pubkey,
+            "https://some-dapp.com",
+            &ByteBuf::from("other session key"),
+            None,
         )?;
 
-        let devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 1);
-        assert!(!devices.iter().any(|device| device == &device_data_2()));
+        assert_revocation_status(&env, canister_id, &user_key, &session_key, false)?;
         Ok(())
     }
 
-    /// Verifies that get_anchor_info requires authentication.
+    /// Verifies that sessions and revocations survive an II upgrade.
     #[test]
-    fn should_not_allow_get_anchor_info_for_different_user() {
+    fn should_keep_sessions_and_revocations_across_upgrade() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
+        let revoked_session_key = ByteBuf::from("session key 1");
 
-        let result = api::get_anchor_info(&env, canister_id, principal_2(), user_number);
+        let (user_key, revoked_session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp1.com",
+            &revoked_session_key,
+            None,
+        )?;
+        let (_, session) = prepare_session(
+            &env,
+            canister_id,
+            user_number,
+            "https://dapp2.com",
+            &ByteBuf::from("session key 2"),
+            None,
+        )?;
+        api::revoke_session(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            revoked_session.session_key_hash,
+        )?;
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_revocation_status(&env, canister_id, &user_key, &revoked_session_key, true)?;
+        assert_eq!(
+            api::list_sessions(&env, canister_id, principal_1(), user_number)?,
+            vec![session]
         );
+        Ok(())
     }
 }
 
-/// Tests related to prepare_delegation, get_delegation and get_principal II canister calls.
+/// Tests for the versioned endpoints (e.g. `add_v2`) that return errors instead of trapping.
 #[cfg(test)]
-mod delegation_tests {
+mod versioned_api_tests {
     use crate::framework::{
-        device_data_1, device_data_2, expect_user_error_with_message, principal_1, principal_2,
-        CallError,
+        device_data_1, device_data_2, principal_1, principal_2, recovery_device_data_1,
+        recovery_device_data_2, CallError,
     };
     use crate::{api, flows, framework};
-    use candid::Principal;
-    use ic_error_types::ErrorCode::CanisterCalledTrap;
     use ic_state_machine_tests::StateMachine;
-    use internet_identity_interface::GetDelegationResponse;
-    use regex::Regex;
+    use internet_identity_interface::{
+        AddDeviceResponse, DeviceData, DeviceProtection, GetAnchorInfoResponse, KeyType,
+        PrepareDelegationResponse, RemoveDeviceResponse, UpdateDeviceResponse,
+    };
     use serde_bytes::ByteBuf;
-    use std::ops::Add;
-    use std::time::{Duration, UNIX_EPOCH};
+    use sha2::{Digest, Sha256};
 
-    /// Verifies that valid delegations are issued.
+    /// Verifies that devices can be added, updated and removed using the versioned endpoints.
     #[test]
-    fn should_get_valid_delegation() -> Result<(), CallError> {
+    fn should_add_update_and_remove_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
 
-        let (canister_sig_key, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
         assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_2()
+            )?,
+            AddDeviceResponse::Added
         );
-
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
-
-        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
-        Ok(())
-    }
-
-    /// Verifies that non-default expirations are respected.
-    #[test]
-    fn should_get_valid_delegation_with_custom_expiration() -> Result<(), CallError> {
-        let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (canister_sig_key, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            Some(3_600_000_000_000), // 1 hour
-        )?;
+        let mut device = device_data_2();
+        device.alias = "new alias".to_string();
         assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(60 * 60)) // 1 hour
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            api::update_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device.pubkey.clone(),
+                device.clone()
+            )?,
+            UpdateDeviceResponse::Updated
         );
-
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        match api::get_anchor_info_v2(&env, canister_id, principal_1(), user_number)? {
+            GetAnchorInfoResponse::AnchorInfo(info) => {
+                let devices: Vec<DeviceData> =
+                    info.devices.into_iter().map(DeviceData::from).collect();
+                assert_eq!(devices, vec![device_data_1(), device.clone()])
+            }
+            response => panic!("unexpected response {:?}", response),
         };
 
-        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        assert_eq!(
+            api::remove_v2(&env, canister_id, principal_1(), user_number, device.pubkey)?,
+            RemoveDeviceResponse::Removed
+        );
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![framework::without_alias(device_data_1())]
+        );
         Ok(())
     }
 
-    /// Verifies that the delegations are valid at most for 30 days.
+    /// Verifies that unassigned anchors and unauthenticated calls are reported as errors.
     #[test]
-    fn should_shorten_expiration_greater_max_ttl() -> Result<(), CallError> {
+    fn should_return_unknown_anchor_and_not_authenticated() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
 
-        let (canister_sig_key, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            Some(Duration::from_secs(31 * 24 * 60 * 60).as_nanos() as u64), // 31 days
-        )?;
+        match api::get_anchor_info_v2(&env, canister_id, principal_1(), user_number + 1)? {
+            GetAnchorInfoResponse::UnknownAnchor => {}
+            response => panic!("unexpected response {:?}", response),
+        };
+        match api::get_anchor_info_v2(&env, canister_id, principal_2(), user_number)? {
+            GetAnchorInfoResponse::NotAuthenticated => {}
+            response => panic!("unexpected response {:?}", response),
+        };
         assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 24 * 60 * 60)) // 30 days
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                device_data_2()
+            )?,
+            AddDeviceResponse::NotAuthenticated
+        );
+        assert_eq!(
+            api::remove_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number + 1,
+                device_data_1().pubkey
+            )?,
+            RemoveDeviceResponse::UnknownAnchor
         );
-
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
-
-        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
         Ok(())
     }
 
-    /// Verifies that delegations can be requested in parallel.
+    /// Verifies that invalid devices are rejected with an error instead of a trap.
     #[test]
-    fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
+    fn should_return_device_errors() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp1.com";
-        let frontend_hostname_2 = "https://dapp2.com";
-        let pub_session_key_1 = ByteBuf::from("session public key 1");
-        let pub_session_key_2 = ByteBuf::from("session public key 2");
-        let delegation_params = vec![
-            (
-                &pub_session_key_1,
-                frontend_hostname_1,
-                Duration::from_secs(0),
-            ),
-            (
-                &pub_session_key_1,
-                frontend_hostname_2,
-                Duration::from_secs(0),
-            ),
-            (
-                &pub_session_key_2,
-                frontend_hostname_1,
-                Duration::from_secs(0),
-            ),
-            (
-                &pub_session_key_1,
-                frontend_hostname_1,
-                Duration::from_secs(30),
-            ),
-        ];
 
-        // prepare multiple delegations in parallel before calling get_delegation
-        let prepare_delegation_results =
-            delegation_params
-                .into_iter()
-                .map(|(session_key, frontend_hostname, time_shift)| {
-                    env.advance_time(time_shift);
-                    let (canister_sig_key, expiration) = api::prepare_delegation(
-                        &env,
-                        canister_id,
-                        principal_1(),
-                        user_number,
-                        frontend_hostname.to_string(),
-                        session_key.clone(),
-                        None,
-                    )
-                    .expect("prepare_delegation failed");
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_1()
+            )?,
+            AddDeviceResponse::DeviceAlreadyAdded
+        );
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                recovery_device_data_1()
+            )?,
+            AddDeviceResponse::Added
+        );
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                recovery_device_data_2()
+            )?,
+            AddDeviceResponse::InvalidDevice(
+                "There is already a recovery phrase and only one is allowed.".to_string()
+            )
+        );
 
-                    assert_eq!(
-                        expiration,
-                        env.time()
-                            .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_nanos() as u64
-                    );
-                    (session_key, frontend_hostname, canister_sig_key, expiration)
-                });
+        let mut protected_device = device_data_2();
+        protected_device.protection = DeviceProtection::Protected;
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                protected_device
+            )?,
+            AddDeviceResponse::InvalidDevice(
+                "Only recovery phrases can be protected but key type is Unknown".to_string()
+            )
+        );
 
-        for (session_key, frontend_hostname, canister_sig_key, expiration) in
-            prepare_delegation_results
-        {
-            let signed_delegation = match api::get_delegation(
+        assert_eq!(
+            api::update_v2(
                 &env,
                 canister_id,
                 principal_1(),
                 user_number,
-                frontend_hostname.to_string(),
-                session_key.clone(),
-                expiration,
-            )? {
-                GetDelegationResponse::SignedDelegation(delegation) => delegation,
-                GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-            };
+                device_data_2().pubkey,
+                device_data_2()
+            )?,
+            UpdateDeviceResponse::DeviceNotFound
+        );
+        Ok(())
+    }
 
-            framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
-            assert_eq!(signed_delegation.delegation.pubkey, session_key.clone());
-            assert_eq!(signed_delegation.delegation.expiration, expiration);
+    /// Verifies that the limit of devices per anchor is reported including the limit.
+    #[test]
+    fn should_return_too_many_devices() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        for i in 0..9u8 {
+            let mut device = device_data_2();
+            device.pubkey = ByteBuf::from(framework::ed25519_der_key(0x20 + i).to_vec());
+            assert_eq!(
+                api::add_v2(&env, canister_id, principal_1(), user_number, device)?,
+                AddDeviceResponse::Added
+            );
         }
+        assert_eq!(
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                device_data_2()
+            )?,
+            AddDeviceResponse::TooManyDevices { limit: 10 }
+        );
         Ok(())
     }
 
-    /// Verifies that an anchor that was registered using II_WASM_PREVIOUS gets valid delegations after upgrading to the current version.
+    /// Verifies that protected devices can only be removed when authenticated with the device itself.
     #[test]
-    fn should_get_valid_delegation_for_old_anchor_after_ii_upgrade() -> Result<(), CallError> {
+    fn should_return_device_protected() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM_PREVIOUS.clone());
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
 
-        let (canister_sig_key, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
+        let mut protected_recovery_phrase = recovery_device_data_1();
+        protected_recovery_phrase.protection = DeviceProtection::Protected;
+        assert_eq!(protected_recovery_phrase.key_type, KeyType::SeedPhrase);
         assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            api::add_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                protected_recovery_phrase.clone()
+            )?,
+            AddDeviceResponse::Added
         );
 
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
-
-        framework::verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        assert_eq!(
+            api::remove_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                protected_recovery_phrase.pubkey
+            )?,
+            RemoveDeviceResponse::DeviceProtected
+        );
         Ok(())
     }
 
-    /// Verifies that different front-ends yield different principals.
+    /// Verifies that delegations can be prepared and that errors are returned instead of traps.
     #[test]
-    fn should_issue_different_principals() -> Result<(), CallError> {
+    fn should_prepare_delegation() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp1.com";
-        let frontend_hostname_2 = "https://dapp2.com";
-        let pub_session_key = ByteBuf::from("session public key");
+        let frontend_hostname = "https://some-dapp.com".to_string();
+        let session_key = ByteBuf::from("session key");
 
-        let (canister_sig_key_1, _) = api::prepare_delegation(
+        let (user_key, expiration) = match api::prepare_delegation_v2(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname_1.to_string(),
-            pub_session_key.clone(),
+            frontend_hostname.clone(),
+            session_key.clone(),
             None,
-        )?;
-        let (canister_sig_key_2, _) = api::prepare_delegation(
+            None,
+            None,
+        )? {
+            PrepareDelegationResponse::Prepared {
+                user_key,
+                expiration,
+            } => (user_key, expiration),
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert_eq!(
+            (user_key, expiration),
+            api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.clone(),
+                session_key.clone(),
+                None,
+                None,
+                None,
+            )?
+        );
+
+        let response = api::prepare_delegation_v2(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname_2.to_string(),
-            pub_session_key.clone(),
+            "a".repeat(300),
+            session_key.clone(),
+            None,
             None,
+            None,
+        )?;
+        assert_eq!(
+            response,
+            PrepareDelegationResponse::InvalidArgument(
+                "frontend hostname 300 exceeds the limit of 255 bytes".to_string()
+            )
+        );
+
+        api::revoke_session(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            ByteBuf::from(Sha256::digest(&session_key).to_vec()),
         )?;
+        assert_eq!(
+            api::prepare_delegation_v2(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname,
+                session_key,
+                None,
+                None,
+                None,
+            )?,
+            PrepareDelegationResponse::SessionRevoked
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the HTTP interactions according to the HTTP gateway spec: https://internetcomputer.org/docs/current/references/ic-interface-spec/#http-gateway
+#[cfg(test)]
+mod http_tests {
+    use crate::certificate_validation::validate_certification;
+    use crate::framework::{
+        assert_metric, device_data_1, device_data_2, principal_1, principal_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_state_machine_tests::StateMachine;
+    use internet_identity_interface::{ChallengeAttempt, HttpRequest, InternetIdentityInit};
+    use serde_bytes::ByteBuf;
+    use std::time::{Duration, SystemTime};
+
+    /// Verifies that expected assets are delivered, certified and have security headers.
+    #[test]
+    fn ii_canister_serves_http_assets() -> Result<(), CallError> {
+        let assets: Vec<(&str, Option<&str>)> = vec![
+            ("/", None),
+            ("/index.html", None),
+            ("/index.js", Some("gzip")),
+            ("/loader.webp", None),
+            ("/favicon.ico", None),
+            ("/ic-badge.svg", None),
+        ];
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        // for each asset, fetch the asset, check the HTTP status code, headers and certificate.
+        for (asset, encoding) in assets {
+            let http_response = api::http_request(
+                &env,
+                canister_id,
+                HttpRequest {
+                    method: "GET".to_string(),
+                    url: asset.to_string(),
+                    headers: vec![],
+                    body: ByteBuf::new(),
+                },
+            )?;
+
+            assert_eq!(http_response.status_code, 200);
+
+            // check the appropriate Content-Encoding header is set
+            if let Some(enc) = encoding {
+                let (_, content_encoding) = http_response
+                    .headers
+                    .iter()
+                    .find(|(name, _)| name.to_lowercase() == "content-encoding")
+                    .expect("Content-Encoding header not found");
+                assert_eq!(
+                    content_encoding, enc,
+                    "unexpected Content-Encoding header value"
+                );
+            }
+
+            // 1. It searches for a response header called Ic-Certificate (case-insensitive).
+            let (_, ic_certificate) = http_response
+                .headers
+                .iter()
+                .find(|(name, _)| name.to_lowercase() == "ic-certificate")
+                .expect("IC-Certificate header not found");
+
+            validate_certification(
+                ic_certificate,
+                canister_id,
+                asset,
+                &http_response.body,
+                None, // should really be `encoding`, but cannot use it because II certifies encoded response bodies, see L2-722 for details
+                env.root_key(),
+                env.time(),
+            )
+            .expect(&format!("validation for asset \"{}\" failed", asset));
+            framework::verify_security_headers(&http_response.headers);
+        }
+        Ok(())
+    }
+
+    /// Verifies that all expected metrics are available via the HTTP endpoint.
+    #[test]
+    fn ii_canister_serves_http_metrics() -> Result<(), CallError> {
+        let metrics = vec![
+            "internet_identity_user_count",
+            "internet_identity_min_user_number",
+            "internet_identity_max_user_number",
+            "internet_identity_signature_count",
+            "internet_identity_stable_memory_pages",
+            "internet_identity_last_upgrade_timestamp",
+            "internet_identity_inflight_challenges",
+            "internet_identity_users_in_registration_mode",
+        ];
+        let env = StateMachine::new();
+        env.advance_time(Duration::from_secs(300)); // advance time to see it reflected on the metrics endpoint
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
 
-        assert_ne!(canister_sig_key_1, canister_sig_key_2);
+        let metrics_body = flows::get_metrics(&env, canister_id);
+        for metric in metrics {
+            let (_, metric_timestamp) = framework::parse_metric(&metrics_body, metric);
+            assert_eq!(
+                metric_timestamp,
+                env.time(),
+                "metric timestamp did not match state machine time"
+            )
+        }
         Ok(())
     }
 
-    /// Verifies that there is a graceful failure if II gets upgraded between prepare_delegation and get_delegation.
+    /// Verifies that the metrics list the expected user range.
     #[test]
-    fn should_not_get_prepared_delegation_after_ii_upgrade() -> Result<(), CallError> {
+    fn metrics_should_list_expected_user_range() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
 
-        let (_, expiration) = api::prepare_delegation(
+        let metrics = flows::get_metrics(&env, canister_id);
+
+        let (min_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_min_user_number");
+        let (max_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_max_user_number");
+        assert_eq!(min_user_number, 10_000);
+        assert_eq!(max_user_number, 3_784_872);
+        Ok(())
+    }
+
+    #[test]
+    fn should_widen_user_range_on_upgrade() {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister_with_arg(
             &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
+            framework::II_WASM.clone(),
+            Some(InternetIdentityInit {
+                assigned_user_number_range: Some((127, 129)),
+                storage_layout_version: None,
+                admin: None,
+                config: None,
+            }),
+        );
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (min_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_min_user_number");
+        let (max_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_max_user_number");
+        assert_eq!(min_user_number, 127);
+        assert_eq!(max_user_number, 128);
 
-        // upgrade, even with the same WASM clears non-stable memory
+        // The storage updates the upper bound on upgrade if it doesn't use the
+        // full capacity. This is a hack that has to go away when we start using
+        // multiple backend canisters.
         framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
 
-        match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
-            GetDelegationResponse::NoSuchDelegation => {}
-        };
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (min_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_min_user_number");
+        let (max_user_number, _) =
+            framework::parse_metric(&metrics, "internet_identity_max_user_number");
+        assert_eq!(min_user_number, 127);
+        assert_eq!(max_user_number, 3_774_999);
+    }
+
+    /// Verifies that the user count metric is updated correctly.
+    #[test]
+    fn metrics_user_count_should_increase_after_register() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+
+        assert_metric(&env, canister_id, "internet_identity_user_count", 0);
+        for count in 0..2 {
+            flows::register_anchor(&env, canister_id);
+            assert_metric(&env, canister_id, "internet_identity_user_count", count + 1);
+        }
         Ok(())
     }
 
-    /// Verifies that there is a graceful failure if get_delegation is called after the expiration of the delegation.
+    /// Verifies that the signature count metric is updated correctly.
     #[test]
-    fn should_not_get_delegation_after_expiration() -> Result<(), CallError> {
+    fn metrics_signature_and_delegation_count() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
         let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        let (_, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
+        assert_metric(&env, canister_id, "internet_identity_signature_count", 0);
+        for count in 0..3 {
+            api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.to_string(),
+                ByteBuf::from(format!("session key {}", count)),
+                None,
+                None,
+                None,
+            )?;
 
-        env.advance_time(Duration::from_secs(30 * 60 + 1)); // one second more than delegation validity of 30 min
+            assert_metric(
+                &env,
+                canister_id,
+                "internet_identity_signature_count",
+                count + 1,
+            );
+            assert_metric(
+                &env,
+                canister_id,
+                "internet_identity_delegation_counter",
+                count + 1,
+            );
+        }
 
-        // we have to call prepare again, because expired signatures can only be pruned in update calls
+        // long after expiry (we don't want this test to break, if we change the default delegation expiration)
+        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
+        // we need to make an update call to prune expired delegations
         api::prepare_delegation(
             &env,
             canister_id,
             principal_1(),
             user_number,
             frontend_hostname.to_string(),
-            pub_session_key.clone(),
+            ByteBuf::from("last session key"),
+            None,
+            None,
             None,
         )?;
 
-        match api::get_delegation(
+        assert_metric(
             &env,
             canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
-            GetDelegationResponse::NoSuchDelegation => {}
-        };
-        Ok(())
-    }
-
-    /// Verifies that delegations can only be prepared by the matching user.
-    #[test]
-    fn can_not_prepare_delegation_for_different_user() {
-        let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-
-        let result = api::prepare_delegation(
+            "internet_identity_signature_count",
+            1, // old ones pruned and a new one created
+        );
+        assert_metric(
             &env,
             canister_id,
-            principal_2(),
-            user_number, // belongs to principal_1
-            "https://some-dapp.com".to_string(),
-            ByteBuf::from("session key"),
-            None,
-        );
-
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+            "internet_identity_delegation_counter",
+            4, // delegation counter is not affected by pruning
         );
+        Ok(())
     }
 
-    /// Verifies that get_delegation can only be called by the matching user.
+    /// Verifies that the stable memory pages count metric is updated correctly.
     #[test]
-    fn can_not_get_delegation_for_different_user() -> Result<(), CallError> {
+    fn metrics_stable_memory_pages_should_increase_with_more_users() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
 
-        let (_, expiration) = api::prepare_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
-        let result = api::get_delegation(
-            &env,
-            canister_id,
-            principal_2(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        );
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (signature_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_stable_memory_pages");
+        // empty II has some metadata in stable memory which requires at least one page
+        assert_eq!(signature_count, 1);
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
-        );
+        // a wasm page is 64kb and a single user takes up 2kb -> 32 users require a complete wasm page
+        for _ in 0..32 {
+            flows::register_anchor(&env, canister_id);
+        }
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (signature_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_stable_memory_pages");
+        assert_eq!(signature_count, 2);
         Ok(())
     }
 
-    /// Verifies that get_principal and prepare_delegation return the same principal.
+    /// Verifies that the last II wasm upgrade timestamp is updated correctly.
     #[test]
-    fn get_principal_should_match_prepare_delegation() -> Result<(), CallError> {
+    fn metrics_last_upgrade_timestamp_should_update_after_upgrade() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
+        // immediately upgrade because installing the canister does not set the metric
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
 
-        let (canister_sig_key, _) = api::prepare_delegation(
+        assert_metric(
             &env,
             canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
+            "internet_identity_last_upgrade_timestamp",
+            env.time()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        );
 
-        let principal = api::get_principal(
+        env.advance_time(Duration::from_secs(300)); // the state machine does not advance time on its own
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+
+        assert_metric(
             &env,
             canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-        )?;
-        assert_eq!(Principal::self_authenticating(canister_sig_key), principal);
+            "internet_identity_last_upgrade_timestamp",
+            env.time()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        );
         Ok(())
     }
 
-    /// Verifies that get_principal returns different principals for different front end host names.
+    /// Verifies that the inflight challenges metric is updated correctly.
     #[test]
-    fn should_return_different_principals_for_different_frontends() -> Result<(), CallError> {
+    fn metrics_inflight_challenges() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp-1.com";
-        let frontend_hostname_2 = "https://dapp-2.com";
 
-        let dapp_principal_1 = api::get_principal(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname_1.to_string(),
-        )?;
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
+        assert_eq!(challenge_count, 0);
 
-        let dapp_principal_2 = api::get_principal(
+        let challenge_1 = api::create_challenge(&env, canister_id)?;
+        api::create_challenge(&env, canister_id)?;
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
+        assert_eq!(challenge_count, 2);
+
+        // solving a challenge removes it from the inflight pool
+        api::register(
             &env,
             canister_id,
             principal_1(),
-            user_number,
-            frontend_hostname_2.to_string(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge_1.challenge_key,
+            },
         )?;
 
-        assert_ne!(dapp_principal_1, dapp_principal_2);
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
+        assert_eq!(challenge_count, 1);
+
+        // long after expiry (we don't want this test to break, if we change the captcha expiration)
+        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
+        // the only call that prunes expired captchas
+        api::create_challenge(&env, canister_id)?;
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
+        assert_eq!(challenge_count, 1); // 1 pruned due to expiry, but also one created
+
         Ok(())
     }
 
-    /// Verifies that get_principal returns different principals for different anchors.
+    /// Verifies that the users in registration mode metric is updated correctly.
     #[test]
-    fn should_return_different_principals_for_different_users() -> Result<(), CallError> {
+    fn metrics_device_registration_mode() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number_1 =
-            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
-            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let frontend_hostname = "https://dapp-1.com";
+        let user_number_1 = flows::register_anchor(&env, canister_id);
+        let user_number_2 = flows::register_anchor(&env, canister_id);
 
-        let dapp_principal_1 = api::get_principal(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number_1,
-            frontend_hostname.to_string(),
-        )?;
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
+        assert_eq!(challenge_count, 0);
 
-        let dapp_principal_2 = api::get_principal(
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_2)?;
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
+        assert_eq!(challenge_count, 2);
+
+        api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
+        assert_eq!(challenge_count, 1);
+
+        // long after expiry (we don't want this test to break, if we change the registration mode expiration)
+        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
+        // make an update call related to tentative devices so that registration mode expiry gets checked
+        api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number_2,
-            frontend_hostname.to_string(),
+            device_data_2(),
         )?;
 
-        assert_ne!(dapp_principal_1, dapp_principal_2);
+        let metrics = flows::get_metrics(&env, canister_id);
+        let (challenge_count, _) =
+            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
+        assert_eq!(challenge_count, 0);
+
         Ok(())
     }
 
-    /// Verifies that get_principal requires authentication.
+    /// Verifies that the anchor operation count metric is updated correctly.
     #[test]
-    fn should_not_allow_get_principal_for_other_user() {
+    fn metrics_anchor_operations() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
-            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
-        let frontend_hostname_1 = "https://dapp-1.com";
 
-        let result = api::get_principal(
+        assert_metric(
             &env,
             canister_id,
-            principal_1(),
-            user_number_2,
-            frontend_hostname_1.to_string(),
+            "internet_identity_anchor_operations_counter",
+            0,
         );
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated\\.").unwrap(),
+        let user_number = flows::register_anchor(&env, canister_id);
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            1,
         );
-    }
-}
-
-/// Tests for the HTTP interactions according to the HTTP gateway spec: https://internetcomputer.org/docs/current/references/ic-interface-spec/#http-gateway
-#[cfg(test)]
-mod http_tests {
-    use crate::certificate_validation::validate_certification;
-    use crate::framework::{
-        assert_metric, device_data_1, device_data_2, principal_1, principal_2, CallError,
-    };
-    use crate::{api, flows, framework};
-    use ic_state_machine_tests::StateMachine;
-    use internet_identity_interface::{ChallengeAttempt, HttpRequest, InternetIdentityInit};
-    use serde_bytes::ByteBuf;
-    use std::time::{Duration, SystemTime};
-
-    /// Verifies that expected assets are delivered, certified and have security headers.
-    #[test]
-    fn ii_canister_serves_http_assets() -> Result<(), CallError> {
-        let assets: Vec<(&str, Option<&str>)> = vec![
-            ("/", None),
-            ("/index.html", None),
-            ("/index.js", Some("gzip")),
-            ("/loader.webp", None),
-            ("/favicon.ico", None),
-            ("/ic-badge.svg", None),
-        ];
-        let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-
-        // for each asset, fetch the asset, check the HTTP status code, headers and certificate.
-        for (asset, encoding) in assets {
-            let http_response = api::http_request(
-                &env,
-                canister_id,
-                HttpRequest {
-                    method: "GET".to_string(),
-                    url: asset.to_string(),
-                    headers: vec![],
-                    body: ByteBuf::new(),
-                },
-            )?;
-
-            assert_eq!(http_response.status_code, 200);
-
-            // check the appropriate Content-Encoding header is set
-            if let Some(enc) = encoding {
-                let (_, content_encoding) = http_response
-                    .headers
-                    .iter()
-                    .find(|(name, _)| name.to_lowercase() == "content-encoding")
-                    .expect("Content-Encoding header not found");
-                assert_eq!(
-                    content_encoding, enc,
-                    "unexpected Content-Encoding header value"
-                );
-            }
 
-            // 1. It searches for a response header called Ic-Certificate (case-insensitive).
-            let (_, ic_certificate) = http_response
-                .headers
-                .iter()
-                .find(|(name, _)| name.to_lowercase() == "ic-certificate")
-                .expect("IC-Certificate header not found");
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            2,
+        );
 
-            validate_certification(
-                ic_certificate,
-                canister_id,
-                asset,
-                &http_response.body,
-                None, // should really be `encoding`, but cannot use it because II certifies encoded response bodies, see L2-722 for details
-                env.root_key(),
-                env.time(),
-            )
-            .expect(&format!("validation for asset \"{}\" failed", asset));
-            framework::verify_security_headers(&http_response.headers);
-        }
-        Ok(())
-    }
+        let mut device = device_data_2();
+        device.alias = "new alias".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device,
+        )?;
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            3,
+        );
 
-    /// Verifies that all expected metrics are available via the HTTP endpoint.
-    #[test]
-    fn ii_canister_serves_http_metrics() -> Result<(), CallError> {
-        let metrics = vec![
-            "internet_identity_user_count",
-            "internet_identity_min_user_number",
-            "internet_identity_max_user_number",
-            "internet_identity_signature_count",
-            "internet_identity_stable_memory_pages",
-            "internet_identity_last_upgrade_timestamp",
-            "internet_identity_inflight_challenges",
-            "internet_identity_users_in_registration_mode",
-        ];
-        let env = StateMachine::new();
-        env.advance_time(Duration::from_secs(300)); // advance time to see it reflected on the metrics endpoint
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+        assert_metric(
+            &env,
+            canister_id,
+            "internet_identity_anchor_operations_counter",
+            4,
+        );
 
-        let metrics_body = flows::get_metrics(&env, canister_id);
-        for metric in metrics {
-            let (_, metric_timestamp) = framework::parse_metric(&metrics_body, metric);
-            assert_eq!(
-                metric_timestamp,
-                env.time(),
-                "metric timestamp did not match state machine time"
-            )
-        }
         Ok(())
     }
+}
 
-    /// Verifies that the metrics list the expected user range.
+/// Tests concerning the device registration flow for remote devices (i.e. authenticators on another computer).
+/// The flow has the following steps:
+/// 1. on device 1: enter registration mode
+/// 2. on device 2: register the new device tentatively -> this returns a verification code
+/// 3. on device 1: enter the verification code
+///
+/// Additionally, there are the following bounds on the registration flow:
+/// 1. registration mode expires after 15 minutes
+/// 2. there is a limit of 3 attempts for step 3 in the above process
+#[cfg(test)]
+mod remote_device_registration_tests {
+    use crate::framework::{
+        device_data_2, expect_user_error_with_message, principal_1, principal_2, CallError,
+    };
+    use crate::{api, flows, framework};
+    use ic_error_types::ErrorCode;
+    use ic_state_machine_tests::{CanisterId, StateMachine};
+    use internet_identity_interface as types;
+    use regex::Regex;
+    use serde_bytes::ByteBuf;
+    use std::ops::Add;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Test entering registration mode including returned expiration time.
     #[test]
-    fn metrics_should_list_expected_user_range() -> Result<(), CallError> {
+    fn can_enter_device_registration_mode() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        let metrics = flows::get_metrics(&env, canister_id);
+        let result =
+            api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
 
-        let (min_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_min_user_number");
-        let (max_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_max_user_number");
-        assert_eq!(min_user_number, 10_000);
-        assert_eq!(max_user_number, 3_784_872);
+        assert_eq!(
+            result,
+            env.time()
+                .add(Duration::from_secs(900)) // 900 seconds -> 15 min
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
         Ok(())
     }
 
+    /// Tests that only an authenticated user can enter device registration mode for themselves.
     #[test]
-    fn should_widen_user_range_on_upgrade() {
+    fn can_not_enter_device_registration_mode_for_other_user() {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister_with_arg(
-            &env,
-            framework::II_WASM.clone(),
-            Some(InternetIdentityInit {
-                assigned_user_number_range: (127, 129),
-            }),
-        );
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (min_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_min_user_number");
-        let (max_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_max_user_number");
-        assert_eq!(min_user_number, 127);
-        assert_eq!(max_user_number, 128);
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        // The storage updates the upper bound on upgrade if it doesn't use the
-        // full capacity. This is a hack that has to go away when we start using
-        // multiple backend canisters.
-        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        let result =
+            api::enter_device_registration_mode(&env, canister_id, principal_2(), user_number);
 
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (min_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_min_user_number");
-        let (max_user_number, _) =
-            framework::parse_metric(&metrics, "internet_identity_max_user_number");
-        assert_eq!(min_user_number, 127);
-        assert_eq!(max_user_number, 3_774_999);
+        expect_user_error_with_message(
+            result,
+            ErrorCode::CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
     }
 
-    /// Verifies that the user count metric is updated correctly.
+    /// Tests that a device with a malformed public key cannot be added tentatively.
     #[test]
-    fn metrics_user_count_should_increase_after_register() -> Result<(), CallError> {
+    fn can_not_add_tentative_device_with_malformed_public_key() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut device = device_data_2();
+        device.pubkey = ByteBuf::from("not a public key");
 
-        assert_metric(&env, canister_id, "internet_identity_user_count", 0);
-        for count in 0..2 {
-            flows::register_anchor(&env, canister_id);
-            assert_metric(&env, canister_id, "internet_identity_user_count", count + 1);
-        }
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let result =
+            api::add_tentative_device(&env, canister_id, principal_2(), user_number, device);
+
+        expect_user_error_with_message(
+            result,
+            ErrorCode::CanisterCalledTrap,
+            Regex::new("malformed public key").unwrap(),
+        );
         Ok(())
     }
 
-    /// Verifies that the signature count metric is updated correctly.
+    /// Tests that the device registration flow can be completed successfully.
     #[test]
-    fn metrics_signature_and_delegation_count() -> Result<(), CallError> {
+    fn can_register_remote_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let frontend_hostname = "https://some-dapp.com";
         let user_number = flows::register_anchor(&env, canister_id);
 
-        assert_metric(&env, canister_id, "internet_identity_signature_count", 0);
-        for count in 0..3 {
-            api::prepare_delegation(
-                &env,
-                canister_id,
-                principal_1(),
-                user_number,
-                frontend_hostname.to_string(),
-                ByteBuf::from(format!("session key {}", count)),
-                None,
-            )?;
-
-            assert_metric(
-                &env,
-                canister_id,
-                "internet_identity_signature_count",
-                count + 1,
-            );
-            assert_metric(
-                &env,
-                canister_id,
-                "internet_identity_delegation_counter",
-                count + 1,
-            );
-        }
-
-        // long after expiry (we don't want this test to break, if we change the default delegation expiration)
-        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
-        // we need to make an update call to prune expired delegations
-        api::prepare_delegation(
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let add_response = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        let verification_code = match add_response {
+            types::AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        };
+        let verification_response = api::verify_tentative_device(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            ByteBuf::from("last session key"),
+            verification_code,
             None,
         )?;
 
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_signature_count",
-            1, // old ones pruned and a new one created
-        );
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_delegation_counter",
-            4, // delegation counter is not affected by pruning
-        );
+        assert!(matches!(
+            verification_response,
+            types::VerifyTentativeDeviceResponse::Verified
+        ));
         Ok(())
     }
 
-    /// Verifies that the stable memory pages count metric is updated correctly.
+    /// Tests that the device registration flow can be completed successfully after submitting an invalid code.
     #[test]
-    fn metrics_stable_memory_pages_should_increase_with_more_users() -> Result<(), CallError> {
+    fn can_verify_remote_device_after_failed_attempt() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (signature_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_stable_memory_pages");
-        // empty II has some metadata in stable memory which requires at least one page
-        assert_eq!(signature_count, 1);
-
-        // a wasm page is 64kb and a single user takes up 2kb -> 32 users require a complete wasm page
-        for _ in 0..32 {
-            flows::register_anchor(&env, canister_id);
-        }
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (signature_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_stable_memory_pages");
-        assert_eq!(signature_count, 2);
-        Ok(())
-    }
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let add_response = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        let verification_code = match add_response {
+            types::AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        };
 
-    /// Verifies that the last II wasm upgrade timestamp is updated correctly.
-    #[test]
-    fn metrics_last_upgrade_timestamp_should_update_after_upgrade() -> Result<(), CallError> {
-        let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        // immediately upgrade because installing the canister does not set the metric
-        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+        assert!(matches!(
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                "invalid code".to_string(),
+                None
+            )?,
+            types::VerifyTentativeDeviceResponse::WrongCode { retries_left: 2 }
+        ));
 
-        assert_metric(
+        let verification_response = api::verify_tentative_device(
             &env,
             canister_id,
-            "internet_identity_last_upgrade_timestamp",
-            env.time()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64,
-        );
-
-        env.advance_time(Duration::from_secs(300)); // the state machine does not advance time on its own
-        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
+            principal_1(),
+            user_number,
+            verification_code,
+            None,
+        )?;
 
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_last_upgrade_timestamp",
-            env.time()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64,
-        );
+        assert!(matches!(
+            verification_response,
+            types::VerifyTentativeDeviceResponse::Verified
+        ));
         Ok(())
     }
 
-    /// Verifies that the inflight challenges metric is updated correctly.
+    /// Tests that the anchor info call returns information about tentative devices.
+    /// This enables the front-end to continue an in progress flow (e.g. after a refresh of the page).
     #[test]
-    fn metrics_inflight_challenges() -> Result<(), CallError> {
+    fn anchor_info_should_return_tentative_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
-        assert_eq!(challenge_count, 0);
-
-        let challenge_1 = api::create_challenge(&env, canister_id)?;
-        api::create_challenge(&env, canister_id)?;
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
-        assert_eq!(challenge_count, 2);
-
-        // solving a challenge removes it from the inflight pool
-        api::register(
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let new_device = device_data_2();
+        api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
-            &device_data_1(),
-            ChallengeAttempt {
-                chars: "a".to_string(),
-                key: challenge_1.challenge_key,
-            },
+            principal_2(),
+            user_number,
+            new_device.clone(),
         )?;
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
 
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
-        assert_eq!(challenge_count, 1);
-
-        // long after expiry (we don't want this test to break, if we change the captcha expiration)
-        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
-        // the only call that prunes expired captchas
-        api::create_challenge(&env, canister_id)?;
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_inflight_challenges");
-        assert_eq!(challenge_count, 1); // 1 pruned due to expiry, but also one created
-
+        assert!(matches!(
+            anchor_info.device_registration,
+            Some(types::DeviceRegistrationInfo {
+                tentative_device: Some(tenative_device),
+                ..
+            }) if tenative_device == new_device
+        ));
         Ok(())
     }
 
-    /// Verifies that the users in registration mode metric is updated correctly.
+    /// Tests that devices cannot be registered tentatively if the registration mode is not enabled.
     #[test]
-    fn metrics_device_registration_mode() -> Result<(), CallError> {
+    fn reject_tentative_device_if_not_in_registration_mode() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number_1 = flows::register_anchor(&env, canister_id);
-        let user_number_2 = flows::register_anchor(&env, canister_id);
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
-        assert_eq!(challenge_count, 0);
-
-        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
-        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number_2)?;
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
-        assert_eq!(challenge_count, 2);
-
-        api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number_1)?;
-
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
-        assert_eq!(challenge_count, 1);
+        let user_number = flows::register_anchor(&env, canister_id);
 
-        // long after expiry (we don't want this test to break, if we change the registration mode expiration)
-        env.advance_time(Duration::from_secs(365 * 24 * 60 * 60));
-        // make an update call related to tentative devices so that registration mode expiry gets checked
-        api::add_tentative_device(
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let result = api::add_tentative_device(
             &env,
             canister_id,
             principal_2(),
-            user_number_2,
+            user_number,
             device_data_2(),
         )?;
 
-        let metrics = flows::get_metrics(&env, canister_id);
-        let (challenge_count, _) =
-            framework::parse_metric(&metrics, "internet_identity_users_in_registration_mode");
-        assert_eq!(challenge_count, 0);
-
+        assert!(matches!(
+            result,
+            types::AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
         Ok(())
     }
 
-    /// Verifies that the anchor operation count metric is updated correctly.
+    /// Tests device registration mode expiration.
     #[test]
-    fn metrics_anchor_operations() -> Result<(), CallError> {
+    fn reject_tentative_device_if_registration_mode_is_expired() -> Result<(), CallError> {
+        const REGISTRATION_MODE_EXPIRATION: Duration = Duration::from_secs(900);
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_anchor_operations_counter",
-            0,
-        );
-
         let user_number = flows::register_anchor(&env, canister_id);
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_anchor_operations_counter",
-            1,
-        );
 
-        api::add(
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        env.advance_time(REGISTRATION_MODE_EXPIRATION + Duration::from_secs(1));
+        let result = api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number,
             device_data_2(),
         )?;
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_anchor_operations_counter",
-            2,
-        );
 
-        let mut device = device_data_2();
-        device.alias = "new alias".to_string();
-        api::update(
+        assert!(matches!(
+            result,
+            types::AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+        Ok(())
+    }
+
+    /// Tests that an appropriate result is returned when a verification code is submitted without a
+    /// corresponding tentative device.
+    #[test]
+    fn reject_verification_without_tentative_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let verification_response = api::verify_tentative_device(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            device.pubkey.clone(),
-            device,
+            "some code".to_string(),
+            None,
         )?;
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_anchor_operations_counter",
-            3,
-        );
 
-        api::remove(
+        assert!(matches!(
+            verification_response,
+            types::VerifyTentativeDeviceResponse::NoDeviceToVerify
+        ));
+        Ok(())
+    }
+
+    /// Tests that the flow is aborted after the expected number of failed verification attempts.
+    #[test]
+    fn reject_verification_with_wrong_code() -> Result<(), CallError> {
+        const MAX_RETRIES: u8 = 3;
+        let env = StateMachine::new();
+        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number,
-            device_data_2().pubkey,
+            device_data_2(),
         )?;
-        assert_metric(
-            &env,
-            canister_id,
-            "internet_identity_anchor_operations_counter",
-            4,
-        );
+        for expected_retries in (0..MAX_RETRIES).rev() {
+            assert!(matches!(
+                api::verify_tentative_device(
+                    &env,
+                    canister_id,
+                    principal_1(),
+                    user_number,
+                    "invalid code".to_string(),
+                    None
+                )?,
+                types::VerifyTentativeDeviceResponse::WrongCode {
+                    retries_left
+                } if retries_left == expected_retries
+            ));
+        }
 
+        assert!(matches!(
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                "invalid code".to_string(),
+                None
+            )?,
+            types::VerifyTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
         Ok(())
     }
-}
 
-/// Tests concerning the device registration flow for remote devices (i.e. authenticators on another computer).
-/// The flow has the following steps:
-/// 1. on device 1: enter registration mode
-/// 2. on device 2: register the new device tentatively -> this returns a verification code
-/// 3. on device 1: enter the verification code
-///
-/// Additionally, there are the following bounds on the registration flow:
-/// 1. registration mode expires after 15 minutes
-/// 2. there is a limit of 3 attempts for step 3 in the above process
-#[cfg(test)]
-mod remote_device_registration_tests {
-    use crate::framework::{
-        device_data_2, expect_user_error_with_message, principal_1, principal_2, CallError,
-    };
-    use crate::{api, flows, framework};
-    use ic_error_types::ErrorCode;
-    use ic_state_machine_tests::StateMachine;
-    use internet_identity_interface as types;
-    use regex::Regex;
-    use std::ops::Add;
-    use std::time::{Duration, UNIX_EPOCH};
+    fn tentative_device(i: u8) -> types::DeviceData {
+        types::DeviceData {
+            pubkey: ByteBuf::from(framework::ed25519_der_key(0x40 + i).to_vec()),
+            alias: format!("Tentative device {}", i),
+            ..device_data_2()
+        }
+    }
+
+    fn verification_code(response: types::AddTentativeDeviceResponse) -> String {
+        match response {
+            types::AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        }
+    }
 
-    /// Test entering registration mode including returned expiration time.
+    /// Tests that multiple devices can be added tentatively and verified with their own codes.
     #[test]
-    fn can_enter_device_registration_mode() -> Result<(), CallError> {
+    fn can_register_multiple_remote_devices() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
-        let result =
-            api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let code_1 = verification_code(api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            tentative_device(1),
+        )?);
+        let code_2 = verification_code(api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            tentative_device(2),
+        )?);
 
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        let registration = anchor_info
+            .device_registration
+            .expect("device registration mode is not active");
+        assert_eq!(registration.tentative_device, Some(tentative_device(1)));
         assert_eq!(
-            result,
-            env.time()
-                .add(Duration::from_secs(900)) // 900 seconds -> 15 min
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            registration.tentative_devices,
+            Some(vec![tentative_device(1), tentative_device(2)])
         );
+
+        assert!(matches!(
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                code_2,
+                Some(tentative_device(2).pubkey)
+            )?,
+            types::VerifyTentativeDeviceResponse::Verified
+        ));
+        // the remaining tentative device can still be verified
+        assert!(matches!(
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                code_1,
+                Some(tentative_device(1).pubkey)
+            )?,
+            types::VerifyTentativeDeviceResponse::Verified
+        ));
+
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(anchor_info.devices.len(), 3);
+        assert!(anchor_info.device_registration.is_none());
         Ok(())
     }
 
-    /// Tests that only an authenticated user can enter device registration mode for themselves.
+    /// Tests that the number of tentative devices per anchor is limited.
     #[test]
-    fn can_not_enter_device_registration_mode_for_other_user() {
+    fn reject_tentative_device_if_too_many_devices_are_added() -> Result<(), CallError> {
+        const MAX_TENTATIVE_DEVICES: u8 = 4;
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
-        let result =
-            api::enter_device_registration_mode(&env, canister_id, principal_2(), user_number);
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        for i in 0..MAX_TENTATIVE_DEVICES {
+            verification_code(api::add_tentative_device(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                tentative_device(i),
+            )?);
+        }
+        let result = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            tentative_device(MAX_TENTATIVE_DEVICES),
+        )?;
 
-        expect_user_error_with_message(
+        assert!(matches!(
             result,
-            ErrorCode::CanisterCalledTrap,
-            Regex::new("[a-z0-9-]+ could not be authenticated.").unwrap(),
-        );
+            types::AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+        ));
+        Ok(())
     }
 
-    /// Tests that the device registration flow can be completed successfully.
+    /// Tests that the same device cannot be added tentatively twice.
     #[test]
-    fn can_register_remote_device() -> Result<(), CallError> {
+    fn can_not_add_same_tentative_device_twice() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
         api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        let add_response = api::add_tentative_device(
+        api::add_tentative_device(
             &env,
             canister_id,
             principal_2(),
             user_number,
             device_data_2(),
         )?;
-        let verification_code = match add_response {
-            types::AddTentativeDeviceResponse::AddedTentatively {
-                verification_code, ..
-            } => verification_code,
-            err => panic!("failed to add tentative device: {:?}", err),
-        };
-        let verification_response = api::verify_tentative_device(
+        let result = api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number,
-            verification_code,
+            device_data_2(),
         )?;
 
         assert!(matches!(
-            verification_response,
-            types::VerifyTentativeDeviceResponse::Verified
+            result,
+            types::AddTentativeDeviceResponse::AlreadyAddedTentatively
         ));
         Ok(())
     }
 
-    /// Tests that the device registration flow can be completed successfully after submitting an invalid code.
+    /// Tests that a wrong code counts as failed attempt only for the given tentative device.
     #[test]
-    fn can_verify_remote_device_after_failed_attempt() -> Result<(), CallError> {
+    fn wrong_code_counts_only_for_given_tentative_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
         api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        let add_response = api::add_tentative_device(
-            &env,
-            canister_id,
-            principal_2(),
-            user_number,
-            device_data_2(),
-        )?;
-        let verification_code = match add_response {
-            types::AddTentativeDeviceResponse::AddedTentatively {
-                verification_code, ..
-            } => verification_code,
-            err => panic!("failed to add tentative device: {:?}", err),
-        };
+        for i in [1, 2] {
+            verification_code(api::add_tentative_device(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                tentative_device(i),
+            )?);
+        }
+
+        // the second device is discarded after its third failed attempt
+        for expected_retries in [2, 1, 0] {
+            assert!(matches!(
+                api::verify_tentative_device(
+                    &env,
+                    canister_id,
+                    principal_1(),
+                    user_number,
+                    "invalid code".to_string(),
+                    Some(tentative_device(2).pubkey)
+                )?,
+                types::VerifyTentativeDeviceResponse::WrongCode {
+                    retries_left
+                } if retries_left == expected_retries
+            ));
+        }
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(
+            anchor_info.device_registration.unwrap().tentative_devices,
+            Some(vec![tentative_device(1)])
+        );
 
+        // the first device has all its retries left
         assert!(matches!(
             api::verify_tentative_device(
                 &env,
                 canister_id,
                 principal_1(),
                 user_number,
-                "invalid code".to_string()
+                "invalid code".to_string(),
+                Some(tentative_device(1).pubkey)
             )?,
             types::VerifyTentativeDeviceResponse::WrongCode { retries_left: 2 }
         ));
-
-        let verification_response = api::verify_tentative_device(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            verification_code,
-        )?;
-
         assert!(matches!(
-            verification_response,
-            types::VerifyTentativeDeviceResponse::Verified
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                "invalid code".to_string(),
+                Some(tentative_device(2).pubkey)
+            )?,
+            types::VerifyTentativeDeviceResponse::NoDeviceToVerify
         ));
         Ok(())
     }
 
-    /// Tests that the anchor info call returns information about tentative devices.
-    /// This enables the front-end to continue an in progress flow (e.g. after a refresh of the page).
+    /// Tests that alphanumeric verification codes are generated if configured.
     #[test]
-    fn anchor_info_should_return_tentative_device() -> Result<(), CallError> {
+    fn should_use_configured_verification_code_format() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
+        let canister_id = framework::install_ii_canister_with_arg(
+            &env,
+            framework::II_WASM.clone(),
+            Some(types::InternetIdentityInit {
+                assigned_user_number_range: None,
+                storage_layout_version: None,
+                admin: None,
+                config: Some(types::InternetIdentityConfigOverrides {
+                    verification_code_format: Some(types::VerificationCodeFormat::Alphanumeric {
+                        length: 8,
+                    }),
+                    ..Default::default()
+                }),
+            }),
+        );
         let user_number = flows::register_anchor(&env, canister_id);
 
         api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        let new_device = device_data_2();
-        api::add_tentative_device(
+        let code = verification_code(api::add_tentative_device(
             &env,
             canister_id,
             principal_2(),
             user_number,
-            new_device.clone(),
-        )?;
-        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+            device_data_2(),
+        )?);
+        assert!(Regex::new("^[A-HJ-NP-Z2-9]{8}$").unwrap().is_match(&code));
 
+        // alphanumeric codes are not case sensitive
         assert!(matches!(
-            anchor_info.device_registration,
-            Some(types::DeviceRegistrationInfo {
-                tentative_device: Some(tenative_device),
-                ..
-            }) if tenative_device == new_device
+            api::verify_tentative_device(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                code.to_lowercase(),
+                None
+            )?,
+            types::VerifyTentativeDeviceResponse::Verified
         ));
         Ok(())
     }
 
-    /// Tests that devices cannot be registered tentatively if the registration mode is not enabled.
-    #[test]
-    fn reject_tentative_device_if_not_in_registration_mode() -> Result<(), CallError> {
-        let env = StateMachine::new();
-        let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
+    fn fail_verification(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: types::UserNumber,
+    ) -> Result<(), CallError> {
+        api::verify_tentative_device(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "invalid code".to_string(),
+            None,
+        )?;
+        Ok(())
+    }
 
-        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        api::exit_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        let result = api::add_tentative_device(
-            &env,
+    fn fail_registration_round(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: types::UserNumber,
+    ) -> Result<(), CallError> {
+        api::enter_device_registration_mode(env, canister_id, principal_1(), user_number)?;
+        verification_code(api::add_tentative_device(
+            env,
             canister_id,
             principal_2(),
             user_number,
             device_data_2(),
-        )?;
-
-        assert!(matches!(
-            result,
-            types::AddTentativeDeviceResponse::DeviceRegistrationModeOff
-        ));
+        )?);
+        for _ in 0..3 {
+            fail_verification(env, canister_id, user_number)?;
+        }
         Ok(())
     }
 
-    /// Tests device registration mode expiration.
+    /// Tests that repeated failed registration rounds block the device registration mode for a
+    /// while, also across upgrades.
     #[test]
-    fn reject_tentative_device_if_registration_mode_is_expired() -> Result<(), CallError> {
-        const REGISTRATION_MODE_EXPIRATION: Duration = Duration::from_secs(900);
+    fn should_block_registration_after_repeated_failed_rounds() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
-        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        env.advance_time(REGISTRATION_MODE_EXPIRATION + Duration::from_secs(1));
-        let result = api::add_tentative_device(
-            &env,
-            canister_id,
-            principal_2(),
-            user_number,
-            device_data_2(),
-        )?;
+        // the first failed round has no consequences
+        fail_registration_round(&env, canister_id, user_number)?;
+        fail_registration_round(&env, canister_id, user_number)?;
+        framework::upgrade_ii_canister(&env, canister_id, framework::II_WASM.clone());
 
-        assert!(matches!(
+        let result =
+            api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
             result,
-            types::AddTentativeDeviceResponse::DeviceRegistrationModeOff
-        ));
+            ErrorCode::CanisterCalledTrap,
+            Regex::new(
+                "cannot register new devices until \\d+ because of repeated failed verifications",
+            )
+            .unwrap(),
+        );
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        let failures = anchor_info
+            .device_registration_failures
+            .expect("no registration failures");
+        assert_eq!(failures.failed_rounds, 2);
+        assert!(failures.cooldown_until.is_some());
+
+        env.advance_time(Duration::from_secs(5 * 60));
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
         Ok(())
     }
 
-    /// Tests that an appropriate result is returned when a verification code is submitted without a
-    /// corresponding tentative device.
+    /// Tests that no tentative devices can be added while the cooldown lasts, even if the device
+    /// registration mode is still active.
     #[test]
-    fn reject_verification_without_tentative_device() -> Result<(), CallError> {
+    fn should_report_cooldown_when_adding_tentative_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
         api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        let verification_response = api::verify_tentative_device(
+        for i in 0..3 {
+            verification_code(api::add_tentative_device(
+                &env,
+                canister_id,
+                principal_2(),
+                user_number,
+                tentative_device(i),
+            )?);
+        }
+        // the wrong codes count for the first remaining device, the first two devices are
+        // discarded after 3 failed attempts each
+        for _ in 0..6 {
+            fail_verification(&env, canister_id, user_number)?;
+        }
+        let result = api::add_tentative_device(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number,
-            "some code".to_string(),
+            tentative_device(3),
         )?;
 
         assert!(matches!(
-            verification_response,
-            types::VerifyTentativeDeviceResponse::NoDeviceToVerify
+            result,
+            types::AddTentativeDeviceResponse::DeviceRegistrationCooldown { .. }
         ));
         Ok(())
     }
 
-    /// Tests that the flow is aborted after the expected number of failed verification attempts.
+    /// Tests that the failure history is forgotten once a device is verified.
     #[test]
-    fn reject_verification_with_wrong_code() -> Result<(), CallError> {
-        const MAX_RETRIES: u8 = 3;
+    fn should_forget_failed_rounds_after_verification() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = framework::install_ii_canister(&env, framework::II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
 
+        fail_registration_round(&env, canister_id, user_number)?;
         api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
-        api::add_tentative_device(
+        let code = verification_code(api::add_tentative_device(
             &env,
             canister_id,
             principal_2(),
             user_number,
             device_data_2(),
-        )?;
-        for expected_retries in (0..MAX_RETRIES).rev() {
-            assert!(matches!(
-                api::verify_tentative_device(
-                    &env,
-                    canister_id,
-                    principal_1(),
-                    user_number,
-                    "invalid code".to_string()
-                )?,
-                types::VerifyTentativeDeviceResponse::WrongCode {
-                    retries_left
-                } if retries_left == expected_retries
-            ));
-        }
+        )?);
+        api::verify_tentative_device(&env, canister_id, principal_1(), user_number, code, None)?;
 
-        assert!(matches!(
-            api::verify_tentative_device(
-                &env,
-                canister_id,
-                principal_1(),
-                user_number,
-                "invalid code".to_string()
-            )?,
-            types::VerifyTentativeDeviceResponse::DeviceRegistrationModeOff
-        ));
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert!(anchor_info.device_registration_failures.is_none());
         Ok(())
     }
 }
@@ -1,6 +1,39 @@
 export const idlFactory = ({ IDL }) => {
+  const VerificationCodeFormat = IDL.Variant({
+    'alphanumeric' : IDL.Record({ 'length' : IDL.Nat8 }),
+    'decimal' : IDL.Record({ 'digits' : IDL.Nat8 }),
+  });
+  const ChallengeConfig = IDL.Variant({
+    'proof_of_work' : IDL.Record({ 'difficulty' : IDL.Nat8 }),
+    'captcha' : IDL.Null,
+  });
+  const RateLimitConfig = IDL.Record({
+    'max_tokens' : IDL.Nat64,
+    'time_per_token_ns' : IDL.Nat64,
+  });
+  const DeviceProtectionPolicy = IDL.Variant({
+    'recovery_phrases_only' : IDL.Null,
+    'recovery_phrases_and_security_keys' : IDL.Null,
+  });
+  const InternetIdentityConfigOverrides = IDL.Record({
+    'captcha_challenge_lifetime_ns' : IDL.Opt(IDL.Nat64),
+    'max_entries_per_user' : IDL.Opt(IDL.Nat64),
+    'verification_code_format' : IDL.Opt(VerificationCodeFormat),
+    'max_device_registration_attempts' : IDL.Opt(IDL.Nat8),
+    'challenge' : IDL.Opt(ChallengeConfig),
+    'max_inflight_challenges' : IDL.Opt(IDL.Nat64),
+    'alternative_origins_canisters' : IDL.Opt(IDL.Vec(IDL.Principal)),
+    'registration_rate_limit' : IDL.Opt(RateLimitConfig),
+    'default_expiration_period_ns' : IDL.Opt(IDL.Nat64),
+    'device_protection_policy' : IDL.Opt(DeviceProtectionPolicy),
+    'max_expiration_period_ns' : IDL.Opt(IDL.Nat64),
+    'registration_mode_duration_ns' : IDL.Opt(IDL.Nat64),
+  });
   const InternetIdentityInit = IDL.Record({
-    'assigned_user_number_range' : IDL.Tuple(IDL.Nat64, IDL.Nat64),
+    'storage_layout_version' : IDL.Opt(IDL.Nat8),
+    'admin' : IDL.Opt(IDL.Principal),
+    'assigned_user_number_range' : IDL.Opt(IDL.Tuple(IDL.Nat64, IDL.Nat64)),
+    'config' : IDL.Opt(InternetIdentityConfigOverrides),
   });
   const UserNumber = IDL.Nat64;
   const DeviceProtection = IDL.Variant({
@@ -32,24 +65,142 @@ export const idlFactory = ({ IDL }) => {
   const AddTentativeDeviceResponse = IDL.Variant({
     'device_registration_mode_off' : IDL.Null,
     'another_device_tentatively_added' : IDL.Null,
+    'device_registration_cooldown' : IDL.Record({
+      'cooldown_until' : Timestamp,
+    }),
     'added_tentatively' : IDL.Record({
       'verification_code' : IDL.Text,
       'device_registration_timeout' : Timestamp,
     }),
+    'already_added_tentatively' : IDL.Null,
+  });
+  const AddDeviceResponse = IDL.Variant({
+    'added' : IDL.Null,
+    'not_authenticated' : IDL.Null,
+    'anchor_locked' : IDL.Null,
+    'too_many_devices' : IDL.Record({ 'limit' : IDL.Nat64 }),
+    'storage_error' : IDL.Text,
+    'unknown_anchor' : IDL.Null,
+    'device_already_added' : IDL.Null,
+    'invalid_device' : IDL.Text,
+  });
+  const DeviceOperation = IDL.Variant({
+    'add' : DeviceData,
+    'remove' : DeviceKey,
+    'update' : DeviceData,
+  });
+  const InternetIdentityConfig = IDL.Record({
+    'captcha_challenge_lifetime_ns' : IDL.Nat64,
+    'max_entries_per_user' : IDL.Nat64,
+    'verification_code_format' : VerificationCodeFormat,
+    'max_device_registration_attempts' : IDL.Nat8,
+    'challenge' : ChallengeConfig,
+    'max_inflight_challenges' : IDL.Nat64,
+    'alternative_origins_canisters' : IDL.Vec(IDL.Principal),
+    'registration_rate_limit' : RateLimitConfig,
+    'default_expiration_period_ns' : IDL.Nat64,
+    'device_protection_policy' : DeviceProtectionPolicy,
+    'max_expiration_period_ns' : IDL.Nat64,
+    'registration_mode_duration_ns' : IDL.Nat64,
   });
   const ChallengeKey = IDL.Text;
   const Challenge = IDL.Record({
     'png_base64' : IDL.Text,
     'challenge_key' : ChallengeKey,
   });
+  const ProofOfWorkChallenge = IDL.Record({
+    'difficulty' : IDL.Nat8,
+    'challenge_key' : ChallengeKey,
+  });
+  const CreateChallengeResponse = IDL.Variant({
+    'proof_of_work' : ProofOfWorkChallenge,
+    'captcha' : Challenge,
+  });
+  const DeleteAnchorResponse = IDL.Variant({
+    'deleted' : IDL.Null,
+    'confirmation_pending' : IDL.Record({
+      'missing_confirmations' : IDL.Vec(DeviceKey),
+      'deadline' : Timestamp,
+    }),
+  });
+  const ExportedAnchor = IDL.Record({
+    'user_number' : UserNumber,
+    'data' : IDL.Vec(IDL.Nat8),
+    'events' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+  });
+  const AnchorExportHeader = IDL.Record({
+    'storage_layout_version' : IDL.Nat8,
+    'assigned_user_number_range' : IDL.Tuple(IDL.Nat64, IDL.Nat64),
+    'salt' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'num_anchors' : IDL.Nat64,
+  });
+  const AnchorExport = IDL.Record({
+    'anchors' : IDL.Vec(ExportedAnchor),
+    'next_user_number' : IDL.Opt(UserNumber),
+    'header' : AnchorExportHeader,
+  });
+  const AnchorEventType = IDL.Variant({
+    'device_updated' : IDL.Null,
+    'device_added' : IDL.Null,
+    'anchor_locked' : IDL.Null,
+    'device_removed' : IDL.Null,
+    'tentative_device_verified' : IDL.Null,
+    'delegation_prepared' : IDL.Null,
+    'anchor_unlocked' : IDL.Null,
+  });
+  const AnchorEvent = IDL.Record({
+    'affected_device' : IDL.Opt(DeviceKey),
+    'sequence_number' : IDL.Nat64,
+    'acting_device' : DeviceKey,
+    'timestamp' : Timestamp,
+    'event_type' : AnchorEventType,
+  });
+  const AnchorEvents = IDL.Record({
+    'events' : IDL.Vec(AnchorEvent),
+    'next_cursor' : IDL.Opt(IDL.Nat64),
+  });
+  const DeviceRegistrationFailures = IDL.Record({
+    'cooldown_until' : IDL.Opt(Timestamp),
+    'failed_rounds' : IDL.Nat32,
+    'last_failure' : Timestamp,
+  });
+  const RecoveryDevicePolicy = IDL.Variant({
+    'unrestricted' : IDL.Null,
+    'anchor_management_only' : IDL.Null,
+  });
+  const DeviceWithUsage = IDL.Record({
+    'alias' : IDL.Text,
+    'last_usage' : IDL.Opt(Timestamp),
+    'protection' : DeviceProtection,
+    'pubkey' : DeviceKey,
+    'created_at' : IDL.Opt(Timestamp),
+    'key_type' : KeyType,
+    'purpose' : Purpose,
+    'credential_id' : IDL.Opt(CredentialId),
+  });
   const DeviceRegistrationInfo = IDL.Record({
     'tentative_device' : IDL.Opt(DeviceData),
+    'tentative_devices' : IDL.Opt(IDL.Vec(DeviceData)),
     'expiration' : Timestamp,
   });
   const IdentityAnchorInfo = IDL.Record({
-    'devices' : IDL.Vec(DeviceData),
+    'device_registration_failures' : IDL.Opt(DeviceRegistrationFailures),
+    'recovery_device_policy' : IDL.Opt(RecoveryDevicePolicy),
+    'locked_since' : IDL.Opt(Timestamp),
+    'devices' : IDL.Vec(DeviceWithUsage),
     'device_registration' : IDL.Opt(DeviceRegistrationInfo),
   });
+  const CertifiedAnchorInfoResponse = IDL.Record({
+    'certificate' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'info' : IdentityAnchorInfo,
+    'tree' : IDL.Vec(IDL.Nat8),
+  });
+  const GetAnchorInfoResponse = IDL.Variant({
+    'not_authenticated' : IDL.Null,
+    'storage_error' : IDL.Text,
+    'unknown_anchor' : IDL.Null,
+    'anchor_info' : IdentityAnchorInfo,
+  });
   const FrontendHostname = IDL.Text;
   const SessionKey = PublicKey;
   const Delegation = IDL.Record({
@@ -65,6 +216,12 @@ export const idlFactory = ({ IDL }) => {
     'no_such_delegation' : IDL.Null,
     'signed_delegation' : SignedDelegation,
   });
+  const UserKey = PublicKey;
+  const SessionRevocationStatus = IDL.Record({
+    'certificate' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'revoked' : IDL.Bool,
+    'tree' : IDL.Vec(IDL.Nat8),
+  });
   const HeaderField = IDL.Tuple(IDL.Text, IDL.Text);
   const HttpRequest = IDL.Record({
     'url' : IDL.Text,
@@ -93,19 +250,68 @@ export const idlFactory = ({ IDL }) => {
     'streaming_strategy' : IDL.Opt(StreamingStrategy),
     'status_code' : IDL.Nat16,
   });
-  const UserKey = PublicKey;
+  const SessionKeyHash = IDL.Vec(IDL.Nat8);
+  const SessionInfo = IDL.Record({
+    'issued_at' : Timestamp,
+    'frontend' : FrontendHostname,
+    'session_key_hash' : SessionKeyHash,
+    'expiration' : Timestamp,
+  });
+  const CredentialLookupResponse = IDL.Variant({
+    'anchors' : IDL.Vec(UserNumber),
+    'not_indexed_yet' : IDL.Null,
+  });
+  const CertifiedLookupResponse = IDL.Record({
+    'certificate' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'tree' : IDL.Vec(IDL.Nat8),
+    'devices' : IDL.Vec(DeviceData),
+  });
+  const PrepareDelegationResponse = IDL.Variant({
+    'not_authenticated' : IDL.Null,
+    'anchor_locked' : IDL.Null,
+    'storage_error' : IDL.Text,
+    'invalid_derivation_origin' : IDL.Text,
+    'unknown_anchor' : IDL.Null,
+    'prepared' : IDL.Record({ 'user_key' : UserKey, 'expiration' : Timestamp }),
+    'recovery_device_not_allowed' : IDL.Null,
+    'session_revoked' : IDL.Null,
+    'invalid_argument' : IDL.Text,
+  });
   const ChallengeResult = IDL.Record({
     'key' : ChallengeKey,
     'chars' : IDL.Text,
   });
   const RegisterResponse = IDL.Variant({
+    'rate_limited' : IDL.Null,
     'bad_challenge' : IDL.Null,
     'canister_full' : IDL.Null,
     'registered' : IDL.Record({ 'user_number' : UserNumber }),
   });
+  const RemoveDeviceResponse = IDL.Variant({
+    'device_protected' : IDL.Null,
+    'not_authenticated' : IDL.Null,
+    'anchor_locked' : IDL.Null,
+    'storage_error' : IDL.Text,
+    'unknown_anchor' : IDL.Null,
+    'no_recovery_device_to_unlock' : IDL.Null,
+    'device_not_found' : IDL.Null,
+    'removed' : IDL.Null,
+  });
   const InternetIdentityStats = IDL.Record({
     'users_registered' : IDL.Nat64,
     'assigned_user_number_range' : IDL.Tuple(IDL.Nat64, IDL.Nat64),
+    'users_deleted' : IDL.Nat64,
+  });
+  const UpdateDeviceResponse = IDL.Variant({
+    'device_protected' : IDL.Null,
+    'not_authenticated' : IDL.Null,
+    'anchor_locked' : IDL.Null,
+    'storage_error' : IDL.Text,
+    'unknown_anchor' : IDL.Null,
+    'updated' : IDL.Null,
+    'invalid_device' : IDL.Text,
+    'no_recovery_device_to_unlock' : IDL.Null,
+    'device_not_found' : IDL.Null,
   });
   const VerifyTentativeDeviceResponse = IDL.Variant({
     'device_registration_mode_off' : IDL.Null,
@@ -120,12 +326,43 @@ export const idlFactory = ({ IDL }) => {
         [AddTentativeDeviceResponse],
         [],
       ),
+    'add_v2' : IDL.Func([UserNumber, DeviceData], [AddDeviceResponse], []),
+    'apply_device_operations' : IDL.Func(
+        [UserNumber, IDL.Vec(DeviceOperation)],
+        [],
+        [],
+      ),
+    'config' : IDL.Func([], [InternetIdentityConfig], ['query']),
     'create_challenge' : IDL.Func([], [Challenge], []),
+    'create_challenge_v2' : IDL.Func([], [CreateChallengeResponse], []),
+    'delete_anchor' : IDL.Func([UserNumber], [DeleteAnchorResponse], []),
     'enter_device_registration_mode' : IDL.Func([UserNumber], [Timestamp], []),
     'exit_device_registration_mode' : IDL.Func([UserNumber], [], []),
+    'export_anchors' : IDL.Func(
+        [UserNumber, IDL.Nat32],
+        [AnchorExport],
+        ['query'],
+      ),
+    'get_anchor_events' : IDL.Func(
+        [UserNumber, IDL.Opt(IDL.Nat64)],
+        [AnchorEvents],
+        ['query'],
+      ),
     'get_anchor_info' : IDL.Func([UserNumber], [IdentityAnchorInfo], []),
+    'get_anchor_info_certified' : IDL.Func(
+        [UserNumber],
+        [CertifiedAnchorInfoResponse],
+        ['query'],
+      ),
+    'get_anchor_info_v2' : IDL.Func([UserNumber], [GetAnchorInfoResponse], []),
     'get_delegation' : IDL.Func(
-        [UserNumber, FrontendHostname, SessionKey, Timestamp],
+        [
+          UserNumber,
+          FrontendHostname,
+          SessionKey,
+          Timestamp,
+          IDL.Opt(IDL.Vec(IDL.Principal)),
+        ],
         [GetDelegationResponse],
         ['query'],
       ),
@@ -134,32 +371,129 @@ export const idlFactory = ({ IDL }) => {
         [IDL.Principal],
         ['query'],
       ),
+    'get_principals' : IDL.Func(
+        [UserNumber, IDL.Vec(FrontendHostname)],
+        [IDL.Vec(IDL.Tuple(FrontendHostname, IDL.Principal))],
+        ['query'],
+      ),
+    'get_session_revocation_status' : IDL.Func(
+        [UserKey, SessionKey],
+        [SessionRevocationStatus],
+        ['query'],
+      ),
     'http_request' : IDL.Func([HttpRequest], [HttpResponse], ['query']),
+    'import_anchors' : IDL.Func(
+        [AnchorExportHeader, IDL.Vec(ExportedAnchor)],
+        [],
+        [],
+      ),
     'init_salt' : IDL.Func([], [], []),
+    'list_sessions' : IDL.Func([UserNumber], [IDL.Vec(SessionInfo)], []),
+    'lock_anchor' : IDL.Func([UserNumber], [], []),
     'lookup' : IDL.Func([UserNumber], [IDL.Vec(DeviceData)], ['query']),
+    'lookup_by_credential_id' : IDL.Func(
+        [CredentialId],
+        [CredentialLookupResponse],
+        ['query'],
+      ),
+    'lookup_certified' : IDL.Func(
+        [UserNumber],
+        [CertifiedLookupResponse],
+        ['query'],
+      ),
     'prepare_delegation' : IDL.Func(
-        [UserNumber, FrontendHostname, SessionKey, IDL.Opt(IDL.Nat64)],
+        [
+          UserNumber,
+          FrontendHostname,
+          SessionKey,
+          IDL.Opt(IDL.Nat64),
+          IDL.Opt(IDL.Vec(IDL.Principal)),
+          IDL.Opt(FrontendHostname),
+        ],
         [UserKey, Timestamp],
         [],
       ),
+    'prepare_delegation_v2' : IDL.Func(
+        [
+          UserNumber,
+          FrontendHostname,
+          SessionKey,
+          IDL.Opt(IDL.Nat64),
+          IDL.Opt(IDL.Vec(IDL.Principal)),
+          IDL.Opt(FrontendHostname),
+        ],
+        [PrepareDelegationResponse],
+        [],
+      ),
     'register' : IDL.Func(
         [DeviceData, ChallengeResult],
         [RegisterResponse],
         [],
       ),
     'remove' : IDL.Func([UserNumber, DeviceKey], [], []),
+    'remove_v2' : IDL.Func([UserNumber, DeviceKey], [RemoveDeviceResponse], []),
+    'revoke_all_sessions_for_frontend' : IDL.Func(
+        [UserNumber, FrontendHostname],
+        [],
+        [],
+      ),
+    'revoke_session' : IDL.Func([UserNumber, SessionKeyHash], [], []),
+    'set_recovery_device_policy' : IDL.Func(
+        [UserNumber, RecoveryDevicePolicy],
+        [],
+        [],
+      ),
     'stats' : IDL.Func([], [InternetIdentityStats], ['query']),
+    'unlock_anchor' : IDL.Func([UserNumber], [], []),
     'update' : IDL.Func([UserNumber, DeviceKey, DeviceData], [], []),
+    'update_v2' : IDL.Func(
+        [UserNumber, DeviceKey, DeviceData],
+        [UpdateDeviceResponse],
+        [],
+      ),
     'verify_tentative_device' : IDL.Func(
-        [UserNumber, IDL.Text],
+        [UserNumber, IDL.Text, IDL.Opt(DeviceKey)],
         [VerifyTentativeDeviceResponse],
         [],
       ),
   });
 };
 export const init = ({ IDL }) => {
+  const VerificationCodeFormat = IDL.Variant({
+    'alphanumeric' : IDL.Record({ 'length' : IDL.Nat8 }),
+    'decimal' : IDL.Record({ 'digits' : IDL.Nat8 }),
+  });
+  const ChallengeConfig = IDL.Variant({
+    'proof_of_work' : IDL.Record({ 'difficulty' : IDL.Nat8 }),
+    'captcha' : IDL.Null,
+  });
+  const RateLimitConfig = IDL.Record({
+    'max_tokens' : IDL.Nat64,
+    'time_per_token_ns' : IDL.Nat64,
+  });
+  const DeviceProtectionPolicy = IDL.Variant({
+    'recovery_phrases_only' : IDL.Null,
+    'recovery_phrases_and_security_keys' : IDL.Null,
+  });
+  const InternetIdentityConfigOverrides = IDL.Record({
+    'captcha_challenge_lifetime_ns' : IDL.Opt(IDL.Nat64),
+    'max_entries_per_user' : IDL.Opt(IDL.Nat64),
+    'verification_code_format' : IDL.Opt(VerificationCodeFormat),
+    'max_device_registration_attempts' : IDL.Opt(IDL.Nat8),
+    'challenge' : IDL.Opt(ChallengeConfig),
+    'max_inflight_challenges' : IDL.Opt(IDL.Nat64),
+    'alternative_origins_canisters' : IDL.Opt(IDL.Vec(IDL.Principal)),
+    'registration_rate_limit' : IDL.Opt(RateLimitConfig),
+    'default_expiration_period_ns' : IDL.Opt(IDL.Nat64),
+    'device_protection_policy' : IDL.Opt(DeviceProtectionPolicy),
+    'max_expiration_period_ns' : IDL.Opt(IDL.Nat64),
+    'registration_mode_duration_ns' : IDL.Opt(IDL.Nat64),
+  });
   const InternetIdentityInit = IDL.Record({
-    'assigned_user_number_range' : IDL.Tuple(IDL.Nat64, IDL.Nat64),
+    'storage_layout_version' : IDL.Opt(IDL.Nat8),
+    'admin' : IDL.Opt(IDL.Principal),
+    'assigned_user_number_range' : IDL.Opt(IDL.Tuple(IDL.Nat64, IDL.Nat64)),
+    'config' : IDL.Opt(InternetIdentityConfigOverrides),
   });
   return [IDL.Opt(InternetIdentityInit)];
 };
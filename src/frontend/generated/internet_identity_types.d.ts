@@ -1,26 +1,90 @@
 import type { Principal } from '@dfinity/principal';
+export type AddDeviceResponse = { 'added' : null } |
+  { 'not_authenticated' : null } |
+  { 'anchor_locked' : null } |
+  { 'too_many_devices' : { 'limit' : bigint } } |
+  { 'storage_error' : string } |
+  { 'unknown_anchor' : null } |
+  { 'device_already_added' : null } |
+  { 'invalid_device' : string };
 export type AddTentativeDeviceResponse = {
     'device_registration_mode_off' : null
   } |
   { 'another_device_tentatively_added' : null } |
+  { 'device_registration_cooldown' : { 'cooldown_until' : Timestamp } } |
   {
     'added_tentatively' : {
       'verification_code' : string,
       'device_registration_timeout' : Timestamp,
     }
-  };
+  } |
+  { 'already_added_tentatively' : null };
+export interface AnchorEvent {
+  'affected_device' : [] | [DeviceKey],
+  'sequence_number' : bigint,
+  'acting_device' : DeviceKey,
+  'timestamp' : Timestamp,
+  'event_type' : AnchorEventType,
+}
+export type AnchorEventType = { 'device_updated' : null } |
+  { 'device_added' : null } |
+  { 'anchor_locked' : null } |
+  { 'device_removed' : null } |
+  { 'tentative_device_verified' : null } |
+  { 'delegation_prepared' : null } |
+  { 'anchor_unlocked' : null };
+export interface AnchorEvents {
+  'events' : Array<AnchorEvent>,
+  'next_cursor' : [] | [bigint],
+}
+export interface AnchorExport {
+  'anchors' : Array<ExportedAnchor>,
+  'next_user_number' : [] | [UserNumber],
+  'header' : AnchorExportHeader,
+}
+export interface AnchorExportHeader {
+  'storage_layout_version' : number,
+  'assigned_user_number_range' : [bigint, bigint],
+  'salt' : [] | [Array<number>],
+  'num_anchors' : bigint,
+}
+export interface CertifiedAnchorInfoResponse {
+  'certificate' : [] | [Array<number>],
+  'info' : IdentityAnchorInfo,
+  'tree' : Array<number>,
+}
+export interface CertifiedLookupResponse {
+  'certificate' : [] | [Array<number>],
+  'tree' : Array<number>,
+  'devices' : Array<DeviceData>,
+}
 export interface Challenge {
   'png_base64' : string,
   'challenge_key' : ChallengeKey,
 }
+export type ChallengeConfig = { 'proof_of_work' : { 'difficulty' : number } } |
+  { 'captcha' : null };
 export type ChallengeKey = string;
 export interface ChallengeResult { 'key' : ChallengeKey, 'chars' : string }
+export type CreateChallengeResponse = {
+    'proof_of_work' : ProofOfWorkChallenge
+  } |
+  { 'captcha' : Challenge };
 export type CredentialId = Array<number>;
+export type CredentialLookupResponse = { 'anchors' : Array<UserNumber> } |
+  { 'not_indexed_yet' : null };
 export interface Delegation {
   'pubkey' : PublicKey,
   'targets' : [] | [Array<Principal>],
   'expiration' : Timestamp,
 }
+export type DeleteAnchorResponse = { 'deleted' : null } |
+  {
+    'confirmation_pending' : {
+      'missing_confirmations' : Array<DeviceKey>,
+      'deadline' : Timestamp,
+    }
+  };
 export interface DeviceData {
   'alias' : string,
   'protection' : DeviceProtection,
@@ -30,13 +94,43 @@ export interface DeviceData {
   'credential_id' : [] | [CredentialId],
 }
 export type DeviceKey = PublicKey;
+export type DeviceOperation = { 'add' : DeviceData } |
+  { 'remove' : DeviceKey } |
+  { 'update' : DeviceData };
 export type DeviceProtection = { 'unprotected' : null } |
   { 'protected' : null };
+export type DeviceProtectionPolicy = { 'recovery_phrases_only' : null } |
+  { 'recovery_phrases_and_security_keys' : null };
+export interface DeviceRegistrationFailures {
+  'cooldown_until' : [] | [Timestamp],
+  'failed_rounds' : number,
+  'last_failure' : Timestamp,
+}
 export interface DeviceRegistrationInfo {
   'tentative_device' : [] | [DeviceData],
+  'tentative_devices' : [] | [Array<DeviceData>],
   'expiration' : Timestamp,
 }
+export interface DeviceWithUsage {
+  'alias' : string,
+  'last_usage' : [] | [Timestamp],
+  'protection' : DeviceProtection,
+  'pubkey' : DeviceKey,
+  'created_at' : [] | [Timestamp],
+  'key_type' : KeyType,
+  'purpose' : Purpose,
+  'credential_id' : [] | [CredentialId],
+}
+export interface ExportedAnchor {
+  'user_number' : UserNumber,
+  'data' : Array<number>,
+  'events' : [] | [Array<number>],
+}
 export type FrontendHostname = string;
+export type GetAnchorInfoResponse = { 'not_authenticated' : null } |
+  { 'storage_error' : string } |
+  { 'unknown_anchor' : null } |
+  { 'anchor_info' : IdentityAnchorInfo };
 export type GetDelegationResponse = { 'no_such_delegation' : null } |
   { 'signed_delegation' : SignedDelegation };
 export type HeaderField = [string, string];
@@ -53,27 +147,102 @@ export interface HttpResponse {
   'status_code' : number,
 }
 export interface IdentityAnchorInfo {
-  'devices' : Array<DeviceData>,
+  'device_registration_failures' : [] | [DeviceRegistrationFailures],
+  'recovery_device_policy' : [] | [RecoveryDevicePolicy],
+  'locked_since' : [] | [Timestamp],
+  'devices' : Array<DeviceWithUsage>,
   'device_registration' : [] | [DeviceRegistrationInfo],
 }
+export interface InternetIdentityConfig {
+  'captcha_challenge_lifetime_ns' : bigint,
+  'max_entries_per_user' : bigint,
+  'verification_code_format' : VerificationCodeFormat,
+  'max_device_registration_attempts' : number,
+  'challenge' : ChallengeConfig,
+  'max_inflight_challenges' : bigint,
+  'alternative_origins_canisters' : Array<Principal>,
+  'registration_rate_limit' : RateLimitConfig,
+  'default_expiration_period_ns' : bigint,
+  'device_protection_policy' : DeviceProtectionPolicy,
+  'max_expiration_period_ns' : bigint,
+  'registration_mode_duration_ns' : bigint,
+}
+export interface InternetIdentityConfigOverrides {
+  'captcha_challenge_lifetime_ns' : [] | [bigint],
+  'max_entries_per_user' : [] | [bigint],
+  'verification_code_format' : [] | [VerificationCodeFormat],
+  'max_device_registration_attempts' : [] | [number],
+  'challenge' : [] | [ChallengeConfig],
+  'max_inflight_challenges' : [] | [bigint],
+  'alternative_origins_canisters' : [] | [Array<Principal>],
+  'registration_rate_limit' : [] | [RateLimitConfig],
+  'default_expiration_period_ns' : [] | [bigint],
+  'device_protection_policy' : [] | [DeviceProtectionPolicy],
+  'max_expiration_period_ns' : [] | [bigint],
+  'registration_mode_duration_ns' : [] | [bigint],
+}
 export interface InternetIdentityInit {
-  'assigned_user_number_range' : [bigint, bigint],
+  'storage_layout_version' : [] | [number],
+  'admin' : [] | [Principal],
+  'assigned_user_number_range' : [] | [[bigint, bigint]],
+  'config' : [] | [InternetIdentityConfigOverrides],
 }
 export interface InternetIdentityStats {
   'users_registered' : bigint,
   'assigned_user_number_range' : [bigint, bigint],
+  'users_deleted' : bigint,
 }
 export type KeyType = { 'platform' : null } |
   { 'seed_phrase' : null } |
   { 'cross_platform' : null } |
   { 'unknown' : null };
+export type PrepareDelegationResponse = { 'not_authenticated' : null } |
+  { 'anchor_locked' : null } |
+  { 'storage_error' : string } |
+  { 'invalid_derivation_origin' : string } |
+  { 'unknown_anchor' : null } |
+  { 'prepared' : { 'user_key' : UserKey, 'expiration' : Timestamp } } |
+  { 'recovery_device_not_allowed' : null } |
+  { 'session_revoked' : null } |
+  { 'invalid_argument' : string };
+export interface ProofOfWorkChallenge {
+  'difficulty' : number,
+  'challenge_key' : ChallengeKey,
+}
 export type PublicKey = Array<number>;
 export type Purpose = { 'authentication' : null } |
   { 'recovery' : null };
-export type RegisterResponse = { 'bad_challenge' : null } |
+export interface RateLimitConfig {
+  'max_tokens' : bigint,
+  'time_per_token_ns' : bigint,
+}
+export type RecoveryDevicePolicy = { 'unrestricted' : null } |
+  { 'anchor_management_only' : null };
+export type RegisterResponse = { 'rate_limited' : null } |
+  { 'bad_challenge' : null } |
   { 'canister_full' : null } |
   { 'registered' : { 'user_number' : UserNumber } };
+export type RemoveDeviceResponse = { 'device_protected' : null } |
+  { 'not_authenticated' : null } |
+  { 'anchor_locked' : null } |
+  { 'storage_error' : string } |
+  { 'unknown_anchor' : null } |
+  { 'no_recovery_device_to_unlock' : null } |
+  { 'device_not_found' : null } |
+  { 'removed' : null };
+export interface SessionInfo {
+  'issued_at' : Timestamp,
+  'frontend' : FrontendHostname,
+  'session_key_hash' : SessionKeyHash,
+  'expiration' : Timestamp,
+}
 export type SessionKey = PublicKey;
+export type SessionKeyHash = Array<number>;
+export interface SessionRevocationStatus {
+  'certificate' : [] | [Array<number>],
+  'revoked' : boolean,
+  'tree' : Array<number>,
+}
 export interface SignedDelegation {
   'signature' : Array<number>,
   'delegation' : Delegation,
@@ -87,8 +256,21 @@ export type StreamingStrategy = {
   };
 export type Timestamp = bigint;
 export type Token = {};
+export type UpdateDeviceResponse = { 'device_protected' : null } |
+  { 'not_authenticated' : null } |
+  { 'anchor_locked' : null } |
+  { 'storage_error' : string } |
+  { 'unknown_anchor' : null } |
+  { 'updated' : null } |
+  { 'invalid_device' : string } |
+  { 'no_recovery_device_to_unlock' : null } |
+  { 'device_not_found' : null };
 export type UserKey = PublicKey;
 export type UserNumber = bigint;
+export type VerificationCodeFormat = {
+    'alphanumeric' : { 'length' : number }
+  } |
+  { 'decimal' : { 'digits' : number } };
 export type VerifyTentativeDeviceResponse = {
     'device_registration_mode_off' : null
   } |
@@ -100,39 +282,110 @@ export interface _SERVICE {
   'add_tentative_device' : (arg_0: UserNumber, arg_1: DeviceData) => Promise<
       AddTentativeDeviceResponse
     >,
+  'add_v2' : (arg_0: UserNumber, arg_1: DeviceData) => Promise<
+      AddDeviceResponse
+    >,
+  'apply_device_operations' : (
+      arg_0: UserNumber,
+      arg_1: Array<DeviceOperation>,
+    ) => Promise<undefined>,
+  'config' : () => Promise<InternetIdentityConfig>,
   'create_challenge' : () => Promise<Challenge>,
+  'create_challenge_v2' : () => Promise<CreateChallengeResponse>,
+  'delete_anchor' : (arg_0: UserNumber) => Promise<DeleteAnchorResponse>,
   'enter_device_registration_mode' : (arg_0: UserNumber) => Promise<Timestamp>,
   'exit_device_registration_mode' : (arg_0: UserNumber) => Promise<undefined>,
+  'export_anchors' : (arg_0: UserNumber, arg_1: number) => Promise<
+      AnchorExport
+    >,
+  'get_anchor_events' : (arg_0: UserNumber, arg_1: [] | [bigint]) => Promise<
+      AnchorEvents
+    >,
   'get_anchor_info' : (arg_0: UserNumber) => Promise<IdentityAnchorInfo>,
+  'get_anchor_info_certified' : (arg_0: UserNumber) => Promise<
+      CertifiedAnchorInfoResponse
+    >,
+  'get_anchor_info_v2' : (arg_0: UserNumber) => Promise<GetAnchorInfoResponse>,
   'get_delegation' : (
       arg_0: UserNumber,
       arg_1: FrontendHostname,
       arg_2: SessionKey,
       arg_3: Timestamp,
+      arg_4: [] | [Array<Principal>],
     ) => Promise<GetDelegationResponse>,
   'get_principal' : (arg_0: UserNumber, arg_1: FrontendHostname) => Promise<
       Principal
     >,
+  'get_principals' : (
+      arg_0: UserNumber,
+      arg_1: Array<FrontendHostname>,
+    ) => Promise<Array<[FrontendHostname, Principal]>>,
+  'get_session_revocation_status' : (
+      arg_0: UserKey,
+      arg_1: SessionKey,
+    ) => Promise<SessionRevocationStatus>,
   'http_request' : (arg_0: HttpRequest) => Promise<HttpResponse>,
+  'import_anchors' : (
+      arg_0: AnchorExportHeader,
+      arg_1: Array<ExportedAnchor>,
+    ) => Promise<undefined>,
   'init_salt' : () => Promise<undefined>,
+  'list_sessions' : (arg_0: UserNumber) => Promise<Array<SessionInfo>>,
+  'lock_anchor' : (arg_0: UserNumber) => Promise<undefined>,
   'lookup' : (arg_0: UserNumber) => Promise<Array<DeviceData>>,
+  'lookup_by_credential_id' : (arg_0: CredentialId) => Promise<
+      CredentialLookupResponse
+    >,
+  'lookup_certified' : (arg_0: UserNumber) => Promise<CertifiedLookupResponse>,
   'prepare_delegation' : (
       arg_0: UserNumber,
       arg_1: FrontendHostname,
       arg_2: SessionKey,
       arg_3: [] | [bigint],
+      arg_4: [] | [Array<Principal>],
+      arg_5: [] | [FrontendHostname],
     ) => Promise<[UserKey, Timestamp]>,
+  'prepare_delegation_v2' : (
+      arg_0: UserNumber,
+      arg_1: FrontendHostname,
+      arg_2: SessionKey,
+      arg_3: [] | [bigint],
+      arg_4: [] | [Array<Principal>],
+      arg_5: [] | [FrontendHostname],
+    ) => Promise<PrepareDelegationResponse>,
   'register' : (arg_0: DeviceData, arg_1: ChallengeResult) => Promise<
       RegisterResponse
     >,
   'remove' : (arg_0: UserNumber, arg_1: DeviceKey) => Promise<undefined>,
+  'remove_v2' : (arg_0: UserNumber, arg_1: DeviceKey) => Promise<
+      RemoveDeviceResponse
+    >,
+  'revoke_all_sessions_for_frontend' : (
+      arg_0: UserNumber,
+      arg_1: FrontendHostname,
+    ) => Promise<undefined>,
+  'revoke_session' : (arg_0: UserNumber, arg_1: SessionKeyHash) => Promise<
+      undefined
+    >,
+  'set_recovery_device_policy' : (
+      arg_0: UserNumber,
+      arg_1: RecoveryDevicePolicy,
+    ) => Promise<undefined>,
   'stats' : () => Promise<InternetIdentityStats>,
+  'unlock_anchor' : (arg_0: UserNumber) => Promise<undefined>,
   'update' : (
       arg_0: UserNumber,
       arg_1: DeviceKey,
       arg_2: DeviceData,
     ) => Promise<undefined>,
-  'verify_tentative_device' : (arg_0: UserNumber, arg_1: string) => Promise<
-      VerifyTentativeDeviceResponse
-    >,
+  'update_v2' : (
+      arg_0: UserNumber,
+      arg_1: DeviceKey,
+      arg_2: DeviceData,
+    ) => Promise<UpdateDeviceResponse>,
+  'verify_tentative_device' : (
+      arg_0: UserNumber,
+      arg_1: string,
+      arg_2: [] | [DeviceKey],
+    ) => Promise<VerifyTentativeDeviceResponse>,
 }
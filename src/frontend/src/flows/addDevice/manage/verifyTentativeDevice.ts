@@ -69,12 +69,13 @@ export const verifyDevice = async (
 ): Promise<void> => {
   const container = document.getElementById("pageContent") as HTMLElement;
   render(pageContent(tentativeDevice.alias), container);
-  init(userNumber, connection, endTimestamp);
+  init(userNumber, connection, tentativeDevice, endTimestamp);
 };
 
 const init = (
   userNumber: bigint,
   connection: AuthenticatedConnection,
+  tentativeDevice: DeviceData,
   endTimestamp: bigint
 ) => {
   const countdown = setupCountdown(
@@ -122,7 +123,7 @@ const init = (
       return;
     }
     const result = await withLoader(() =>
-      connection.verifyTentativeDevice(pinInput.value)
+      connection.verifyTentativeDevice(pinInput.value, tentativeDevice.pubkey)
     );
 
     if (hasOwnProperty(result, "verified")) {
@@ -96,6 +96,15 @@ export const addTentativeDevice = async (
     });
     // TODO L2-309: do this without reload
     window.location.reload();
+  } else if (hasOwnProperty(result, "already_added_tentatively")) {
+    await displayError({
+      title: "Device Already Added",
+      message:
+        'This device has already been added tentatively. Verify it with the verification code that was shown before, or log in using an existing device and restart the "add device" process.',
+      primaryButton: "Ok",
+    });
+    // TODO L2-309: do this without reload
+    window.location.reload();
   } else {
     throw new Error(
       "unknown tentative device registration result: " + JSON.stringify(result)
@@ -1,6 +1,7 @@
 import { html, render } from "lit-html";
 import { DeviceData } from "../../../generated/internet_identity_types";
 import { securityKeyIcon, seedPhraseIcon } from "../../components/icons";
+import { hasOwnProperty } from "../../utils/utils";
 
 const pageContent = (devices: DeviceData[]) => html`
   <article class="l-container c-card c-card--highlight">
@@ -65,6 +66,14 @@ const init = (): Promise<RecoveryMechanism | null> =>
   });
 
 const hasRecoveryPhrase = (devices: DeviceData[]): boolean =>
-  devices.some((device) => device.alias === "Recovery phrase");
+  devices.some(
+    (device) =>
+      hasOwnProperty(device.purpose, "recovery") &&
+      hasOwnProperty(device.key_type, "seed_phrase")
+  );
 const hasRecoveryKey = (devices: DeviceData[]): boolean =>
-  devices.some((device) => device.alias === "Recovery key");
+  devices.some(
+    (device) =>
+      hasOwnProperty(device.purpose, "recovery") &&
+      !hasOwnProperty(device.key_type, "seed_phrase")
+  );
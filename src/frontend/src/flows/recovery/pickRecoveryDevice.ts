@@ -1,5 +1,6 @@
 import { html, render } from "lit-html";
 import { DeviceData } from "../../../generated/internet_identity_types";
+import { hasOwnProperty } from "../../utils/utils";
 
 const pageContent = () => html`
   <div class="l-container c-card c-card--highlight c-card--highlight">
@@ -31,7 +32,9 @@ export const init = (devices: DeviceData[]): Promise<DeviceData> =>
       identityElement.className = "deviceItem";
       render(
         html`<li class="deviceItemAlias">
-          <button class="c-button c-button--secondary">${device.alias}</button>
+          <button class="c-button c-button--secondary">
+            ${recoveryDeviceLabel(device)}
+          </button>
         </li>`,
         identityElement
       );
@@ -40,3 +43,9 @@ export const init = (devices: DeviceData[]): Promise<DeviceData> =>
     });
     deviceList.appendChild(list);
   });
+
+// `lookup` does not reveal the aliases of the devices, so they are labeled by their type.
+const recoveryDeviceLabel = (device: DeviceData): string =>
+  hasOwnProperty(device.key_type, "seed_phrase")
+    ? "Recovery phrase"
+    : "Recovery key";
@@ -409,10 +409,13 @@ export class AuthenticatedConnection extends Connection {
   };
 
   verifyTentativeDevice = async (
-    pin: string
+    pin: string,
+    tentativeDevice: DeviceKey
   ): Promise<VerifyTentativeDeviceResponse> => {
     const actor = await this.getActor();
-    return await actor.verify_tentative_device(this.userNumber, pin);
+    return await actor.verify_tentative_device(this.userNumber, pin, [
+      tentativeDevice,
+    ]);
   };
 
   add = async (
@@ -473,7 +476,9 @@ export class AuthenticatedConnection extends Connection {
       this.userNumber,
       hostname,
       sessionKey,
-      maxTimeToLive !== undefined ? [maxTimeToLive] : []
+      maxTimeToLive !== undefined ? [maxTimeToLive] : [],
+      [],
+      []
     );
   };
 
@@ -490,7 +495,8 @@ export class AuthenticatedConnection extends Connection {
       this.userNumber,
       hostname,
       sessionKey,
-      timestamp
+      timestamp,
+      []
     );
   };
 }
@@ -0,0 +1,181 @@
+//! Canister-side validation of derivation origins, see "Alternative Frontend Origins" in the spec.
+//!
+//! The alternative origins are fetched with an inter-canister call to the `http_request` method of
+//! the canister the derivation origin belongs to. Responses to inter-canister calls are produced by
+//! replicated execution and can therefore be trusted without checking the asset certification.
+//! Only canisters on the `alternative_origins_canisters` allow-list of the config are called, so
+//! that no untrusted canister can keep a call context of II open and thereby block its upgrades.
+use crate::errors::ApiError;
+use crate::{secs_to_nanos, STATE};
+use candid::Principal;
+use ic_cdk::api::call::call;
+use ic_cdk::api::time;
+use internet_identity_interface::{FrontendHostname, HttpRequest, HttpResponse, Timestamp};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+const ALTERNATIVE_ORIGINS_PATH: &str = "/.well-known/ii-alternative-origins";
+// To prevent misuse of the feature
+const MAX_ALTERNATIVE_ORIGINS: usize = 10;
+// How many canisters may be allow-listed to provide alternative origins
+pub const MAX_ALTERNATIVE_ORIGINS_CANISTERS: usize = 100;
+// 10 mins
+const ALTERNATIVE_ORIGINS_CACHE_DURATION_NS: u64 = secs_to_nanos(600);
+// How many alternative origins lists we keep in memory (at most)
+const MAX_CACHED_ALTERNATIVE_ORIGINS: usize = 1000;
+
+/// The alternative origins of a canister as fetched from its `ALTERNATIVE_ORIGINS_PATH`.
+#[derive(Clone, Debug)]
+pub struct CachedAlternativeOrigins {
+    origins: Vec<FrontendHostname>,
+    expiration: Timestamp,
+}
+
+#[derive(Deserialize)]
+struct AlternativeOrigins {
+    #[serde(rename = "alternativeOrigins")]
+    alternative_origins: Vec<FrontendHostname>,
+}
+
+/// Checks that `actual_origin` may use the principals of `derivation_origin`, i.e. that the
+/// derivation origin is a canonical canister URL of an allow-listed canister and that the canister
+/// lists the actual origin as one of its alternative origins.
+pub async fn validate_derivation_origin(
+    derivation_origin: &FrontendHostname,
+    actual_origin: &FrontendHostname,
+) -> Result<(), ApiError> {
+    if derivation_origin == actual_origin {
+        // this is the default behaviour -> no further validation necessary
+        return Ok(());
+    }
+
+    let canister_id =
+        derivation_origin_canister(derivation_origin).map_err(ApiError::InvalidDerivationOrigin)?;
+    let allowed = STATE.with(|s| {
+        s.config
+            .borrow()
+            .alternative_origins_canisters
+            .contains(&canister_id)
+    });
+    if !allowed {
+        return Err(ApiError::InvalidDerivationOrigin(format!(
+            "canister {} of derivation origin {} is not allowed to provide alternative origins",
+            canister_id, derivation_origin
+        )));
+    }
+    let origins = match cached_alternative_origins(canister_id) {
+        Some(origins) => origins,
+        None => {
+            let origins = fetch_alternative_origins(canister_id)
+                .await
+                .map_err(ApiError::InvalidDerivationOrigin)?;
+            cache_alternative_origins(canister_id, origins.clone());
+            origins
+        }
+    };
+
+    if !origins.contains(actual_origin) {
+        return Err(ApiError::InvalidDerivationOrigin(format!(
+            "{} is not listed in the alternative origins of {}",
+            actual_origin, derivation_origin
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the canister id of a derivation origin matching
+/// `^https://([\w-]+)(?:\.raw)?\.ic0\.app$`.
+fn derivation_origin_canister(derivation_origin: &str) -> Result<Principal, String> {
+    let invalid = || {
+        format!(
+            "derivation origin {} does not match https://<canister id>.ic0.app or https://<canister id>.raw.ic0.app",
+            derivation_origin
+        )
+    };
+
+    let host = derivation_origin
+        .strip_prefix("https://")
+        .and_then(|rest| rest.strip_suffix(".ic0.app"))
+        .ok_or_else(invalid)?;
+    let label = host.strip_suffix(".raw").unwrap_or(host);
+    if label.is_empty()
+        || !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(invalid());
+    }
+    Principal::from_text(label).map_err(|_| invalid())
+}
+
+fn cached_alternative_origins(canister_id: Principal) -> Option<Vec<FrontendHostname>> {
+    STATE.with(|s| {
+        s.alternative_origins
+            .borrow()
+            .get(&canister_id)
+            .filter(|cached| cached.expiration > time())
+            .map(|cached| cached.origins.clone())
+    })
+}
+
+fn cache_alternative_origins(canister_id: Principal, origins: Vec<FrontendHostname>) {
+    let now = time();
+    STATE.with(|s| {
+        let mut cache = s.alternative_origins.borrow_mut();
+        cache.retain(|_, cached| cached.expiration > now);
+        // if the cache is full the origins are simply fetched again next time
+        if cache.len() < MAX_CACHED_ALTERNATIVE_ORIGINS || cache.contains_key(&canister_id) {
+            cache.insert(
+                canister_id,
+                CachedAlternativeOrigins {
+                    origins,
+                    expiration: now + ALTERNATIVE_ORIGINS_CACHE_DURATION_NS,
+                },
+            );
+        }
+    })
+}
+
+async fn fetch_alternative_origins(
+    canister_id: Principal,
+) -> Result<Vec<FrontendHostname>, String> {
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        url: ALTERNATIVE_ORIGINS_PATH.to_string(),
+        headers: vec![("Accept".to_string(), "application/json".to_string())],
+        body: ByteBuf::new(),
+    };
+    let (response,): (HttpResponse,) = call(canister_id, "http_request", (request,))
+        .await
+        .map_err(|(_, err)| {
+            format!(
+                "failed to fetch {} from canister {}: {}",
+                ALTERNATIVE_ORIGINS_PATH, canister_id, err
+            )
+        })?;
+
+    // redirects are not followed
+    if response.status_code != 200 {
+        return Err(format!(
+            "{} of canister {} returned invalid status: {}",
+            ALTERNATIVE_ORIGINS_PATH, canister_id, response.status_code
+        ));
+    }
+
+    let body: &[u8] = &response.body;
+    let alternative_origins: AlternativeOrigins = serde_json::from_slice(body).map_err(|err| {
+        format!(
+            "{} of canister {} has invalid format: {}",
+            ALTERNATIVE_ORIGINS_PATH, canister_id, err
+        )
+    })?;
+
+    let n = alternative_origins.alternative_origins.len();
+    if n > MAX_ALTERNATIVE_ORIGINS {
+        return Err(format!(
+            "{} of canister {} has {} entries: at most {} alternative origins are allowed",
+            ALTERNATIVE_ORIGINS_PATH, canister_id, n, MAX_ALTERNATIVE_ORIGINS
+        ));
+    }
+    Ok(alternative_origins.alternative_origins)
+}
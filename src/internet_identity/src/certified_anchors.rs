@@ -0,0 +1,115 @@
+//! Certified hashes of the Identity Anchors, so that query responses derived from an anchor can be
+//! verified with a witness from the certified data. The hashes are keyed by the big-endian anchor
+//! number.
+//!
+//! The hashes are derived from the anchors in stable memory, kept on the heap and persisted across
+//! upgrades with the transient state. Only if they could not be persisted they are recomputed
+//! incrementally (see [CertifiedAnchors::backfill_next]), until then the anchors that have not
+//! been hashed again are not certified.
+use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use internet_identity_interface::UserNumber;
+use std::borrow::Cow;
+
+#[cfg(test)]
+mod test;
+
+/// The hash of an anchor, certified as leaf.
+struct AnchorHash(Hash);
+
+impl AsHashTree for AnchorHash {
+    fn root_hash(&self) -> Hash {
+        leaf_hash(&self.0[..])
+    }
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Leaf(Cow::from(&self.0[..]))
+    }
+}
+
+#[derive(Default)]
+pub struct CertifiedAnchors {
+    hashes: RbTree<[u8; 8], AnchorHash>,
+    // the next anchor to be hashed while the hashes are recomputed, None once all anchors are hashed
+    backfill_cursor: Option<UserNumber>,
+}
+
+impl CertifiedAnchors {
+    /// Creates an empty set of hashes that still has to hash the anchors starting at `first_anchor`.
+    pub fn rebuild_from(first_anchor: UserNumber) -> Self {
+        Self {
+            hashes: RbTree::new(),
+            backfill_cursor: Some(first_anchor),
+        }
+    }
+
+    /// Restores the hashes persisted with [CertifiedAnchors::get] and
+    /// [CertifiedAnchors::backfill_cursor].
+    pub fn restore(
+        hashes: impl IntoIterator<Item = (UserNumber, Hash)>,
+        backfill_cursor: Option<UserNumber>,
+    ) -> Self {
+        let mut certified_anchors = Self {
+            hashes: RbTree::new(),
+            backfill_cursor,
+        };
+        for (user_number, hash) in hashes {
+            certified_anchors.update(user_number, Some(hash));
+        }
+        certified_anchors
+    }
+
+    /// Sets the hash of the anchor, or removes it if the anchor has been deleted.
+    pub fn update(&mut self, user_number: UserNumber, hash: Option<Hash>) {
+        let key = user_number.to_be_bytes();
+        match hash {
+            Some(hash) => self.hashes.insert(key, AnchorHash(hash)),
+            None => self.hashes.delete(&key[..]),
+        }
+    }
+
+    pub fn get(&self, user_number: UserNumber) -> Option<Hash> {
+        self.hashes
+            .get(&user_number.to_be_bytes()[..])
+            .map(|hash| hash.0)
+    }
+
+    /// Whether the certified hashes reflect the anchor, i.e. a witness for the anchor proves its
+    /// hash or its absence.
+    pub fn is_certified(&self, user_number: UserNumber) -> bool {
+        match self.backfill_cursor {
+            None => true,
+            Some(cursor) => user_number < cursor || self.get(user_number).is_some(),
+        }
+    }
+
+    pub fn witness(&self, user_number: UserNumber) -> HashTree<'_> {
+        self.hashes.witness(&user_number.to_be_bytes()[..])
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.hashes.root_hash()
+    }
+
+    /// Returns the next anchor to be hashed since the hashes were created with
+    /// [CertifiedAnchors::rebuild_from], or None once all anchors are hashed.
+    pub fn backfill_cursor(&self) -> Option<UserNumber> {
+        self.backfill_cursor
+    }
+
+    /// Sets the hash of the anchor at the backfill cursor (None if the anchor cannot be read) and
+    /// moves the cursor to the next anchor. All anchors are hashed once the cursor reaches `end`,
+    /// the first anchor number that has not been allocated. Returns whether the hash has changed.
+    pub fn backfill_next(&mut self, hash: Option<Hash>, end: UserNumber) -> bool {
+        let cursor = match self.backfill_cursor {
+            Some(cursor) => cursor,
+            None => return false,
+        };
+        self.backfill_cursor = Some(cursor + 1).filter(|next| *next < end);
+        match hash {
+            Some(hash) if self.get(cursor) != Some(hash) => {
+                self.update(cursor, Some(hash));
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use super::*;
+
+#[test]
+fn test_update_root_hash() {
+    let mut anchors = CertifiedAnchors::default();
+    let empty_root_hash = anchors.root_hash();
+
+    anchors.update(10, Some([1; 32]));
+    assert_eq!(anchors.get(10), Some([1; 32]));
+    assert_ne!(anchors.root_hash(), empty_root_hash);
+    assert_eq!(anchors.witness(10).reconstruct(), anchors.root_hash());
+
+    anchors.update(10, None);
+    assert_eq!(anchors.get(10), None);
+    assert_eq!(anchors.root_hash(), empty_root_hash);
+}
+
+#[test]
+fn test_backfill_anchor_by_anchor() {
+    let mut anchors = CertifiedAnchors::rebuild_from(10);
+    assert!(!anchors.is_certified(10));
+    // anchors written after the upgrade are certified right away
+    anchors.update(12, Some([2; 32]));
+    assert!(anchors.is_certified(12));
+
+    assert!(anchors.backfill_next(Some([0; 32]), 14));
+    assert_eq!(anchors.backfill_cursor(), Some(11));
+    assert!(anchors.is_certified(10));
+    assert!(!anchors.is_certified(11));
+    assert_eq!(anchors.get(10), Some([0; 32]));
+
+    // anchors that cannot be read are skipped
+    assert!(!anchors.backfill_next(None, 14));
+    assert!(anchors.is_certified(11));
+    assert_eq!(anchors.get(11), None);
+
+    // anchor 12 is already up to date
+    assert!(!anchors.backfill_next(Some([2; 32]), 14));
+    assert!(anchors.backfill_next(Some([3; 32]), 14));
+    assert_eq!(anchors.backfill_cursor(), None);
+    assert!(anchors.is_certified(14));
+    assert!(!anchors.backfill_next(Some([4; 32]), 14));
+}
+
+#[test]
+fn test_restore_persisted_hashes() {
+    let mut anchors = CertifiedAnchors::rebuild_from(10);
+    anchors.backfill_next(Some([0; 32]), 12);
+    anchors.update(11, Some([1; 32]));
+
+    let hashes =
+        (10..12).filter_map(|user_number| anchors.get(user_number).map(|hash| (user_number, hash)));
+    let restored = CertifiedAnchors::restore(hashes, anchors.backfill_cursor());
+    assert_eq!(restored.root_hash(), anchors.root_hash());
+    assert_eq!(restored.backfill_cursor(), Some(11));
+    assert!(restored.is_certified(11));
+}
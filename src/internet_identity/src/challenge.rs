@@ -0,0 +1,136 @@
+//! The challenges that protect the registration against bots.
+//!
+//! The mechanism is selected with the `challenge` field of the config: either a captcha that has
+//! to be solved by a human, or a hashcash-style proof of work that has to be solved by the client.
+use crate::ChallengeInfo;
+use internet_identity::proof_of_work::is_solution;
+use internet_identity_interface::{
+    Base64, Challenge, ChallengeConfig, ChallengeKey, CreateChallengeResponse,
+    ProofOfWorkChallenge, Timestamp,
+};
+use rand_chacha::rand_core::RngCore;
+use rand_chacha::ChaCha20Rng;
+
+#[cfg(not(feature = "dummy_captcha"))]
+use captcha::filters::Wave;
+
+pub trait ChallengeMechanism {
+    /// Creates a new challenge for `challenge_key`, returning what is sent to the user and what
+    /// is kept in the canister to check the attempt against.
+    fn create(
+        &self,
+        challenge_key: ChallengeKey,
+        rng: ChaCha20Rng,
+        now: Timestamp,
+    ) -> (CreateChallengeResponse, ChallengeInfo);
+
+    /// Whether `attempt` solves the stored `challenge`.
+    fn check(&self, challenge_key: &ChallengeKey, challenge: &ChallengeInfo, attempt: &str)
+        -> bool;
+}
+
+/// The mechanism new challenges are created with.
+pub fn configured_mechanism(config: &ChallengeConfig) -> Box<dyn ChallengeMechanism> {
+    match config {
+        ChallengeConfig::Captcha => Box::new(Captcha),
+        ChallengeConfig::ProofOfWork { difficulty } => Box::new(ProofOfWork {
+            difficulty: *difficulty,
+        }),
+    }
+}
+
+/// The mechanism an inflight challenge was created with. This is not necessarily the configured
+/// one, as the config may have changed in an upgrade in the meantime.
+pub fn mechanism_of(challenge: &ChallengeInfo) -> Box<dyn ChallengeMechanism> {
+    match challenge.proof_of_work_difficulty {
+        None => Box::new(Captcha),
+        Some(difficulty) => Box::new(ProofOfWork { difficulty }),
+    }
+}
+
+pub struct Captcha;
+
+impl ChallengeMechanism for Captcha {
+    fn create(
+        &self,
+        challenge_key: ChallengeKey,
+        rng: ChaCha20Rng,
+        now: Timestamp,
+    ) -> (CreateChallengeResponse, ChallengeInfo) {
+        let (Base64(png_base64), chars) = create_captcha(rng);
+        (
+            CreateChallengeResponse::Captcha(Challenge {
+                png_base64,
+                challenge_key,
+            }),
+            ChallengeInfo {
+                created: now,
+                chars,
+                proof_of_work_difficulty: None,
+            },
+        )
+    }
+
+    fn check(&self, _: &ChallengeKey, challenge: &ChallengeInfo, attempt: &str) -> bool {
+        attempt == challenge.chars
+    }
+}
+
+#[cfg(feature = "dummy_captcha")]
+fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
+    let mut captcha = captcha::RngCaptcha::from_rng(rng);
+    let captcha = captcha.set_chars(&vec!['a']).add_chars(1).view(96, 48);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => Base64(png_base64),
+        None => ic_cdk::trap("Could not get base64 of captcha"),
+    };
+
+    return (resp, captcha.chars_as_string());
+}
+
+#[cfg(not(feature = "dummy_captcha"))]
+fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
+    let mut captcha = captcha::RngCaptcha::from_rng(rng);
+    let captcha = captcha
+        .add_chars(5)
+        .apply_filter(Wave::new(2.0, 20.0).horizontal())
+        .apply_filter(Wave::new(2.0, 20.0).vertical())
+        .view(220, 120);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => Base64(png_base64),
+        None => ic_cdk::trap("Could not get base64 of captcha"),
+    };
+
+    return (resp, captcha.chars_as_string());
+}
+
+pub struct ProofOfWork {
+    difficulty: u8,
+}
+
+impl ChallengeMechanism for ProofOfWork {
+    fn create(
+        &self,
+        challenge_key: ChallengeKey,
+        _: ChaCha20Rng,
+        now: Timestamp,
+    ) -> (CreateChallengeResponse, ChallengeInfo) {
+        (
+            CreateChallengeResponse::ProofOfWork(ProofOfWorkChallenge {
+                challenge_key,
+                difficulty: self.difficulty,
+            }),
+            ChallengeInfo {
+                created: now,
+                chars: String::new(),
+                proof_of_work_difficulty: Some(self.difficulty),
+            },
+        )
+    }
+
+    fn check(&self, challenge_key: &ChallengeKey, _: &ChallengeInfo, attempt: &str) -> bool {
+        is_solution(challenge_key, attempt, self.difficulty)
+    }
+}
@@ -0,0 +1,121 @@
+//! Index from the credential IDs of WebAuthn devices to the Identity Anchors they belong to,
+//! so that users can sign in with a discoverable credential without knowing their anchor number.
+//!
+//! Credential IDs are public (see `lookup`), so anyone can add a device with the credential ID of
+//! another anchor to their own anchor. The index therefore maps a credential ID to all anchors
+//! using it and leaves it to the client to pick the anchor the user actually signs in to.
+//!
+//! The index is derived from the anchors in stable memory, kept on the heap and persisted across
+//! upgrades with the transient state (see [CredentialIndex::entries]). Only if it could not be
+//! persisted it is rebuilt incrementally (see [CredentialIndex::backfill_next]), until then it
+//! cannot answer lookups (see [CredentialIndex::lookup]).
+use crate::storage::anchor::DeviceDataInternal;
+use internet_identity_interface::{CredentialId, UserNumber};
+use std::collections::{BTreeSet, HashMap};
+
+#[cfg(test)]
+mod test;
+
+#[derive(Default)]
+pub struct CredentialIndex {
+    anchors: HashMap<CredentialId, BTreeSet<UserNumber>>,
+    // the next anchor to be indexed while the index is rebuilt, None once all anchors are indexed
+    backfill_cursor: Option<UserNumber>,
+}
+
+impl CredentialIndex {
+    /// Creates an empty index that still has to index the anchors starting at `first_anchor`.
+    pub fn rebuild_from(first_anchor: UserNumber) -> Self {
+        Self {
+            anchors: HashMap::new(),
+            backfill_cursor: Some(first_anchor),
+        }
+    }
+
+    /// Restores an index persisted with [CredentialIndex::entries] and
+    /// [CredentialIndex::backfill_cursor].
+    pub fn restore(
+        entries: impl IntoIterator<Item = (CredentialId, Vec<UserNumber>)>,
+        backfill_cursor: Option<UserNumber>,
+    ) -> Self {
+        Self {
+            anchors: entries
+                .into_iter()
+                .filter(|(_, anchors)| !anchors.is_empty())
+                .map(|(credential_id, anchors)| (credential_id, anchors.into_iter().collect()))
+                .collect(),
+            backfill_cursor,
+        }
+    }
+
+    /// Returns the indexed credential IDs with the anchors using them, e.g. to persist the index.
+    pub fn entries(&self) -> impl Iterator<Item = (&CredentialId, &BTreeSet<UserNumber>)> {
+        self.anchors.iter()
+    }
+
+    /// Returns the anchors with a device with the given credential ID in ascending order, or None
+    /// if not all anchors have been indexed yet (in which case the result might be incomplete).
+    pub fn lookup(&self, credential_id: &CredentialId) -> Option<Vec<UserNumber>> {
+        if !self.is_complete() {
+            return None;
+        }
+        Some(
+            self.anchors
+                .get(credential_id)
+                .map(|anchors| anchors.iter().cloned().collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Whether all anchors have been indexed.
+    pub fn is_complete(&self) -> bool {
+        self.backfill_cursor.is_none()
+    }
+
+    /// Replaces the credentials indexed for the anchor with the credentials of its new devices.
+    pub fn update(
+        &mut self,
+        user_number: UserNumber,
+        old_devices: &[DeviceDataInternal],
+        new_devices: &[DeviceDataInternal],
+    ) {
+        for credential_id in old_devices.iter().filter_map(|d| d.credential_id.as_ref()) {
+            if let Some(anchors) = self.anchors.get_mut(credential_id) {
+                anchors.remove(&user_number);
+                if anchors.is_empty() {
+                    self.anchors.remove(credential_id);
+                }
+            }
+        }
+        self.insert(user_number, new_devices);
+    }
+
+    /// Returns the next anchor to be indexed since the index was created with
+    /// [CredentialIndex::rebuild_from], or None once all anchors are indexed.
+    pub fn backfill_cursor(&self) -> Option<UserNumber> {
+        self.backfill_cursor
+    }
+
+    /// Indexes the devices of the anchor at the backfill cursor (None if the anchor cannot be read)
+    /// and moves the cursor to the next anchor. The index is complete once the cursor reaches
+    /// `end`, the first anchor number that has not been allocated.
+    pub fn backfill_next(&mut self, devices: Option<&[DeviceDataInternal]>, end: UserNumber) {
+        let cursor = match self.backfill_cursor {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        if let Some(devices) = devices {
+            self.insert(cursor, devices);
+        }
+        self.backfill_cursor = Some(cursor + 1).filter(|next| *next < end);
+    }
+
+    fn insert(&mut self, user_number: UserNumber, devices: &[DeviceDataInternal]) {
+        for credential_id in devices.iter().filter_map(|d| d.credential_id.as_ref()) {
+            self.anchors
+                .entry(credential_id.clone())
+                .or_default()
+                .insert(user_number);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use super::*;
+use internet_identity_interface::{DeviceData, DeviceProtection, KeyType, Purpose};
+use serde_bytes::ByteBuf;
+
+fn device(pubkey: &str, credential_id: Option<&str>) -> DeviceDataInternal {
+    DeviceDataInternal::from(DeviceData {
+        pubkey: ByteBuf::from(pubkey),
+        alias: "device".to_string(),
+        credential_id: credential_id.map(ByteBuf::from),
+        purpose: Purpose::Authentication,
+        key_type: KeyType::Unknown,
+        protection: DeviceProtection::Unprotected,
+    })
+}
+
+fn credential(credential_id: &str) -> CredentialId {
+    ByteBuf::from(credential_id)
+}
+
+#[test]
+fn test_lookup_updated_credentials() {
+    let mut index = CredentialIndex::default();
+    let devices = vec![device("a", Some("cred a")), device("b", None)];
+    index.update(10, &[], &devices);
+
+    assert_eq!(index.lookup(&credential("cred a")), Some(vec![10]));
+    assert_eq!(index.lookup(&credential("cred b")), Some(vec![]));
+
+    index.update(10, &devices, &[device("b", Some("cred b"))]);
+    assert_eq!(index.lookup(&credential("cred a")), Some(vec![]));
+    assert_eq!(index.lookup(&credential("cred b")), Some(vec![10]));
+}
+
+#[test]
+fn test_lookup_all_anchors_of_credential() {
+    let mut index = CredentialIndex::default();
+    let devices = vec![device("a", Some("cred a"))];
+    index.update(11, &[], &devices);
+    index.update(10, &[], &devices);
+    assert_eq!(index.lookup(&credential("cred a")), Some(vec![10, 11]));
+
+    // removing the credential from one anchor does not affect the other anchor
+    index.update(11, &devices, &[]);
+    assert_eq!(index.lookup(&credential("cred a")), Some(vec![10]));
+}
+
+#[test]
+fn test_backfill_anchor_by_anchor() {
+    let mut index = CredentialIndex::rebuild_from(10);
+    index.backfill_next(Some(&[device("0", Some("cred 0"))]), 13);
+    // anchors that cannot be read are skipped
+    index.backfill_next(None, 13);
+    assert_eq!(index.backfill_cursor(), Some(12));
+    assert!(!index.is_complete());
+    // the index cannot answer lookups until all anchors are indexed
+    assert_eq!(index.lookup(&credential("cred 0")), None);
+
+    index.backfill_next(Some(&[device("2", Some("cred 2"))]), 13);
+    assert!(index.is_complete());
+    assert_eq!(index.lookup(&credential("cred 0")), Some(vec![10]));
+    assert_eq!(index.lookup(&credential("cred 2")), Some(vec![12]));
+}
+
+#[test]
+fn test_restore_persisted_entries() {
+    let mut index = CredentialIndex::rebuild_from(10);
+    index.backfill_next(Some(&[device("0", Some("cred 0"))]), 12);
+    index.update(11, &[], &[device("1", Some("cred 0"))]);
+
+    let entries = index
+        .entries()
+        .map(|(credential_id, anchors)| (credential_id.clone(), anchors.iter().cloned().collect()))
+        .collect::<Vec<_>>();
+    let mut restored = CredentialIndex::restore(entries, index.backfill_cursor());
+    assert_eq!(restored.backfill_cursor(), Some(11));
+
+    // the restored index resumes the backfill where the persisted index left off
+    restored.backfill_next(None, 12);
+    assert_eq!(restored.lookup(&credential("cred 0")), Some(vec![10, 11]));
+}
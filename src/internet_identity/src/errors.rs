@@ -0,0 +1,265 @@
+//! Errors of the public API.
+//!
+//! The original endpoints trap with the `Display` message of an [ApiError], the versioned
+//! endpoints (e.g. `add_v2`) return it as part of their typed response instead.
+use candid::Principal;
+use ic_cdk::api::trap;
+use internet_identity::storage::StorageError;
+use internet_identity_interface::{
+    AddDeviceResponse, GetAnchorInfoResponse, PrepareDelegationResponse, RemoveDeviceResponse,
+    Timestamp, UpdateDeviceResponse, UserNumber,
+};
+use std::fmt;
+
+pub enum ApiError {
+    ReadFailed {
+        user_number: UserNumber,
+        err: StorageError,
+    },
+    WriteFailed {
+        user_number: UserNumber,
+        err: StorageError,
+    },
+    NotAuthenticated(Principal),
+    InvalidDevice(String),
+    DeviceAlreadyAdded,
+    TooManyDevices(usize),
+    DeviceNotFound,
+    DeviceProtected,
+    InvalidArgument(String),
+    InvalidDerivationOrigin(String),
+    SessionRevoked,
+    AnchorLocked(UserNumber),
+    RecoveryDeviceRequired,
+    NoRecoveryDeviceToUnlock(UserNumber),
+    RecoveryDeviceNotAllowed(UserNumber),
+    AnchorDeletionNotAllowed(UserNumber),
+    DeviceRegistrationCooldown {
+        user_number: UserNumber,
+        until: Timestamp,
+    },
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadFailed { user_number, err } => write!(
+                f,
+                "failed to read device data of user {}: {}",
+                user_number, err
+            ),
+            Self::WriteFailed { user_number, err } => write!(
+                f,
+                "failed to write device data of user {}: {}",
+                user_number, err
+            ),
+            Self::NotAuthenticated(principal) => {
+                write!(f, "{} could not be authenticated.", principal)
+            }
+            Self::InvalidDevice(message)
+            | Self::InvalidArgument(message)
+            | Self::InvalidDerivationOrigin(message) => {
+                write!(f, "{}", message)
+            }
+            Self::DeviceAlreadyAdded => write!(f, "Device already added."),
+            Self::TooManyDevices(limit) => write!(
+                f,
+                "at most {} authentication information entries are allowed per user",
+                limit
+            ),
+            Self::DeviceNotFound => write!(f, "Could not find device to mutate, check device key"),
+            Self::DeviceProtected => write!(
+                f,
+                "Device is protected. Must be authenticated with this device to mutate"
+            ),
+            Self::SessionRevoked => write!(f, "the session key has been revoked"),
+            Self::AnchorLocked(user_number) => write!(
+                f,
+                "anchor {} is locked, only recovery devices can make changes",
+                user_number
+            ),
+            Self::RecoveryDeviceRequired => {
+                write!(f, "must be authenticated with a recovery device")
+            }
+            Self::NoRecoveryDeviceToUnlock(user_number) => write!(
+                f,
+                "anchor {} would be locked without a recovery device to unlock it",
+                user_number
+            ),
+            Self::RecoveryDeviceNotAllowed(user_number) => write!(
+                f,
+                "recovery devices of anchor {} can only be used to manage the anchor",
+                user_number
+            ),
+            Self::AnchorDeletionNotAllowed(user_number) => write!(
+                f,
+                "anchor {} can only be deleted with a recovery device or by all its protected devices",
+                user_number
+            ),
+            Self::DeviceRegistrationCooldown { user_number, until } => write!(
+                f,
+                "anchor {} cannot register new devices until {} because of repeated failed verifications",
+                user_number, until
+            ),
+        }
+    }
+}
+
+impl ApiError {
+    /// Whether the error is caused by an Identity Anchor that has not been assigned (yet) or
+    /// that has been deleted.
+    fn is_unknown_anchor(&self) -> bool {
+        matches!(
+            self,
+            Self::ReadFailed {
+                err: StorageError::UserNumberOutOfRange { .. }
+                    | StorageError::BadUserNumber(_)
+                    | StorageError::AnchorDeleted(_),
+                ..
+            }
+        )
+    }
+}
+
+/// Traps with the message of the error. Used by the trap-based endpoints and for errors that
+/// cannot be returned by a given versioned endpoint.
+pub fn trap_with(err: ApiError) -> ! {
+    trap(&err.to_string())
+}
+
+impl From<ApiError> for AddDeviceResponse {
+    fn from(err: ApiError) -> Self {
+        match err {
+            err if err.is_unknown_anchor() => Self::UnknownAnchor,
+            ApiError::NotAuthenticated(_) => Self::NotAuthenticated,
+            ApiError::InvalidDevice(message) => Self::InvalidDevice(message),
+            ApiError::DeviceAlreadyAdded => Self::DeviceAlreadyAdded,
+            ApiError::TooManyDevices(limit) => Self::TooManyDevices {
+                limit: limit as u64,
+            },
+            ApiError::AnchorLocked(_) => Self::AnchorLocked,
+            err @ ApiError::ReadFailed { .. } | err @ ApiError::WriteFailed { .. } => {
+                Self::StorageError(err.to_string())
+            }
+            // adding a device cannot fail otherwise, e.g. it never removes a recovery device
+            err @ (ApiError::DeviceNotFound
+            | ApiError::DeviceProtected
+            | ApiError::InvalidArgument(_)
+            | ApiError::InvalidDerivationOrigin(_)
+            | ApiError::SessionRevoked
+            | ApiError::RecoveryDeviceRequired
+            | ApiError::NoRecoveryDeviceToUnlock(_)
+            | ApiError::RecoveryDeviceNotAllowed(_)
+            | ApiError::AnchorDeletionNotAllowed(_)
+            | ApiError::DeviceRegistrationCooldown { .. }) => trap_with(err),
+        }
+    }
+}
+
+impl From<ApiError> for UpdateDeviceResponse {
+    fn from(err: ApiError) -> Self {
+        match err {
+            err if err.is_unknown_anchor() => Self::UnknownAnchor,
+            ApiError::NotAuthenticated(_) => Self::NotAuthenticated,
+            ApiError::InvalidDevice(message) => Self::InvalidDevice(message),
+            ApiError::DeviceNotFound => Self::DeviceNotFound,
+            ApiError::DeviceProtected => Self::DeviceProtected,
+            ApiError::AnchorLocked(_) => Self::AnchorLocked,
+            ApiError::NoRecoveryDeviceToUnlock(_) => Self::NoRecoveryDeviceToUnlock,
+            err @ ApiError::ReadFailed { .. } | err @ ApiError::WriteFailed { .. } => {
+                Self::StorageError(err.to_string())
+            }
+            // updating a device cannot fail otherwise
+            err @ (ApiError::DeviceAlreadyAdded
+            | ApiError::TooManyDevices(_)
+            | ApiError::InvalidArgument(_)
+            | ApiError::InvalidDerivationOrigin(_)
+            | ApiError::SessionRevoked
+            | ApiError::RecoveryDeviceRequired
+            | ApiError::RecoveryDeviceNotAllowed(_)
+            | ApiError::AnchorDeletionNotAllowed(_)
+            | ApiError::DeviceRegistrationCooldown { .. }) => trap_with(err),
+        }
+    }
+}
+
+impl From<ApiError> for RemoveDeviceResponse {
+    fn from(err: ApiError) -> Self {
+        match err {
+            err if err.is_unknown_anchor() => Self::UnknownAnchor,
+            ApiError::NotAuthenticated(_) => Self::NotAuthenticated,
+            ApiError::DeviceNotFound => Self::DeviceNotFound,
+            ApiError::DeviceProtected => Self::DeviceProtected,
+            ApiError::AnchorLocked(_) => Self::AnchorLocked,
+            ApiError::NoRecoveryDeviceToUnlock(_) => Self::NoRecoveryDeviceToUnlock,
+            err @ ApiError::ReadFailed { .. } | err @ ApiError::WriteFailed { .. } => {
+                Self::StorageError(err.to_string())
+            }
+            // removing a device cannot fail otherwise
+            err @ (ApiError::InvalidDevice(_)
+            | ApiError::DeviceAlreadyAdded
+            | ApiError::TooManyDevices(_)
+            | ApiError::InvalidArgument(_)
+            | ApiError::InvalidDerivationOrigin(_)
+            | ApiError::SessionRevoked
+            | ApiError::RecoveryDeviceRequired
+            | ApiError::RecoveryDeviceNotAllowed(_)
+            | ApiError::AnchorDeletionNotAllowed(_)
+            | ApiError::DeviceRegistrationCooldown { .. }) => trap_with(err),
+        }
+    }
+}
+
+impl From<ApiError> for GetAnchorInfoResponse {
+    fn from(err: ApiError) -> Self {
+        match err {
+            err if err.is_unknown_anchor() => Self::UnknownAnchor,
+            ApiError::NotAuthenticated(_) => Self::NotAuthenticated,
+            err @ ApiError::ReadFailed { .. } | err @ ApiError::WriteFailed { .. } => {
+                Self::StorageError(err.to_string())
+            }
+            // reading the anchor info cannot fail otherwise
+            err @ (ApiError::InvalidDevice(_)
+            | ApiError::DeviceAlreadyAdded
+            | ApiError::TooManyDevices(_)
+            | ApiError::DeviceNotFound
+            | ApiError::DeviceProtected
+            | ApiError::InvalidArgument(_)
+            | ApiError::InvalidDerivationOrigin(_)
+            | ApiError::SessionRevoked
+            | ApiError::AnchorLocked(_)
+            | ApiError::RecoveryDeviceRequired
+            | ApiError::NoRecoveryDeviceToUnlock(_)
+            | ApiError::RecoveryDeviceNotAllowed(_)
+            | ApiError::AnchorDeletionNotAllowed(_)
+            | ApiError::DeviceRegistrationCooldown { .. }) => trap_with(err),
+        }
+    }
+}
+
+impl From<ApiError> for PrepareDelegationResponse {
+    fn from(err: ApiError) -> Self {
+        match err {
+            err if err.is_unknown_anchor() => Self::UnknownAnchor,
+            ApiError::NotAuthenticated(_) => Self::NotAuthenticated,
+            ApiError::InvalidArgument(message) => Self::InvalidArgument(message),
+            ApiError::InvalidDerivationOrigin(message) => Self::InvalidDerivationOrigin(message),
+            ApiError::SessionRevoked => Self::SessionRevoked,
+            ApiError::AnchorLocked(_) => Self::AnchorLocked,
+            ApiError::RecoveryDeviceNotAllowed(_) => Self::RecoveryDeviceNotAllowed,
+            err @ ApiError::ReadFailed { .. } | err @ ApiError::WriteFailed { .. } => {
+                Self::StorageError(err.to_string())
+            }
+            // preparing a delegation cannot fail otherwise
+            err @ (ApiError::InvalidDevice(_)
+            | ApiError::DeviceAlreadyAdded
+            | ApiError::TooManyDevices(_)
+            | ApiError::DeviceNotFound
+            | ApiError::DeviceProtected
+            | ApiError::RecoveryDeviceRequired
+            | ApiError::NoRecoveryDeviceToUnlock(_)
+            | ApiError::AnchorDeletionNotAllowed(_)
+            | ApiError::DeviceRegistrationCooldown { .. }) => trap_with(err),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! The event log of an anchor: a bounded list of the changes made to the anchor (and the
+//! delegations prepared for it), kept in stable memory next to the devices of the anchor.
+//!
+//! The changes to the anchor and the delegations are bounded separately, so that frequent sign
+//! ins cannot push the changes out of the log.
+use internet_identity_interface::{
+    AnchorEvent, AnchorEventType, AnchorEvents, DeviceKey, Timestamp,
+};
+
+/// How many events changing the anchor are kept per anchor, the oldest are dropped first.
+pub const MAX_EVENTS_PER_ANCHOR: usize = 64;
+/// How many `DelegationPrepared` events are kept per anchor, the oldest are dropped first.
+pub const MAX_DELEGATION_EVENTS_PER_ANCHOR: usize = 16;
+/// How many events are returned per page.
+pub const EVENTS_PAGE_SIZE: usize = 16;
+
+/// Appends a new event to the log, dropping the oldest events of the same kind (delegation or
+/// change to the anchor) if there are too many of them.
+pub fn append(
+    events: &mut Vec<AnchorEvent>,
+    event_type: AnchorEventType,
+    timestamp: Timestamp,
+    acting_device: DeviceKey,
+    affected_device: Option<DeviceKey>,
+) {
+    let sequence_number = match events.last() {
+        Some(event) => event.sequence_number + 1,
+        None => 0,
+    };
+    events.push(AnchorEvent {
+        sequence_number,
+        timestamp,
+        event_type,
+        acting_device,
+        affected_device,
+    });
+
+    let delegation = is_delegation(&events[events.len() - 1]);
+    let limit = if delegation {
+        MAX_DELEGATION_EVENTS_PER_ANCHOR
+    } else {
+        MAX_EVENTS_PER_ANCHOR
+    };
+    let count = events
+        .iter()
+        .filter(|event| is_delegation(event) == delegation)
+        .count();
+    let mut excess = count.saturating_sub(limit);
+    events.retain(|event| {
+        if excess > 0 && is_delegation(event) == delegation {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+fn is_delegation(event: &AnchorEvent) -> bool {
+    event.event_type == AnchorEventType::DelegationPrepared
+}
+
+/// Returns the page of events starting at the event with sequence number `cursor`, oldest
+/// first. Without a cursor the page starts with the oldest event that is still kept.
+pub fn page(events: &[AnchorEvent], cursor: Option<u64>) -> AnchorEvents {
+    let start = match cursor {
+        Some(cursor) => events
+            .iter()
+            .position(|event| event.sequence_number >= cursor)
+            .unwrap_or(events.len()),
+        None => 0,
+    };
+    let end = usize::min(start + EVENTS_PAGE_SIZE, events.len());
+    AnchorEvents {
+        events: events[start..end].to_vec(),
+        next_cursor: events.get(end).map(|event| event.sequence_number),
+    }
+}
+
+#[cfg(test)]
+mod test;
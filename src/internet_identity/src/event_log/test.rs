@@ -0,0 +1,83 @@
+use super::*;
+use serde_bytes::ByteBuf;
+
+fn log_with_events(n: usize) -> Vec<AnchorEvent> {
+    let mut events = vec![];
+    append_events(&mut events, AnchorEventType::DeviceUpdated, n);
+    events
+}
+
+fn append_events(events: &mut Vec<AnchorEvent>, event_type: AnchorEventType, n: usize) {
+    for i in 0..n {
+        append(
+            events,
+            event_type.clone(),
+            i as Timestamp,
+            ByteBuf::from("device"),
+            None,
+        );
+    }
+}
+
+#[test]
+fn test_number_events_sequentially() {
+    let events = log_with_events(3);
+
+    let sequence_numbers: Vec<u64> = events.iter().map(|e| e.sequence_number).collect();
+    assert_eq!(sequence_numbers, vec![0, 1, 2]);
+}
+
+#[test]
+fn test_drop_oldest_events() {
+    let events = log_with_events(MAX_EVENTS_PER_ANCHOR + 5);
+
+    assert_eq!(events.len(), MAX_EVENTS_PER_ANCHOR);
+    assert_eq!(events.first().unwrap().sequence_number, 5);
+    assert_eq!(
+        events.last().unwrap().sequence_number,
+        MAX_EVENTS_PER_ANCHOR as u64 + 4
+    );
+}
+
+#[test]
+fn test_paginate_events() {
+    let events = log_with_events(EVENTS_PAGE_SIZE + 3);
+
+    let first_page = page(&events, None);
+    assert_eq!(first_page.events, events[..EVENTS_PAGE_SIZE].to_vec());
+    assert_eq!(first_page.next_cursor, Some(EVENTS_PAGE_SIZE as u64));
+
+    let second_page = page(&events, first_page.next_cursor);
+    assert_eq!(second_page.events, events[EVENTS_PAGE_SIZE..].to_vec());
+    assert_eq!(second_page.next_cursor, None);
+}
+
+#[test]
+fn test_start_page_at_oldest_kept_event() {
+    let events = log_with_events(MAX_EVENTS_PER_ANCHOR + 5);
+
+    // the events before sequence number 5 were dropped
+    assert_eq!(page(&events, Some(2)), page(&events, None));
+    assert!(page(&events, Some(1_000)).events.is_empty());
+}
+
+#[test]
+fn test_bound_delegation_events_separately() {
+    let mut events = log_with_events(3);
+    append_events(
+        &mut events,
+        AnchorEventType::DelegationPrepared,
+        MAX_EVENTS_PER_ANCHOR + 5,
+    );
+
+    // the delegations only push out older delegations
+    let delegations = events.iter().filter(|e| is_delegation(e)).count();
+    assert_eq!(delegations, MAX_DELEGATION_EVENTS_PER_ANCHOR);
+    assert_eq!(events.len(), MAX_DELEGATION_EVENTS_PER_ANCHOR + 3);
+    let sequence_numbers: Vec<u64> = events[..3].iter().map(|e| e.sequence_number).collect();
+    assert_eq!(sequence_numbers, vec![0, 1, 2]);
+    assert_eq!(
+        events.last().unwrap().sequence_number,
+        MAX_EVENTS_PER_ANCHOR as u64 + 7
+    );
+}
@@ -1,9 +1,14 @@
-use crate::{assets, AssetHashes, ContentType, ASSETS, LABEL_ASSETS, LABEL_SIG, STATE};
+use crate::{
+    assets, certified_anchors_hash, AssetHashes, ContentType, ASSETS, LABEL_ASSETS, LABEL_REVOKED,
+    LABEL_SIG, STATE,
+};
 use ic_cdk::api::stable::stable64_size;
 use ic_cdk::api::{data_certificate, time};
 use ic_cdk::trap;
 use ic_certified_map::HashTree;
+use internet_identity::certified_anchors::CertifiedAnchors;
 use internet_identity::metrics_encoder::MetricsEncoder;
+use internet_identity::revocation_list::RevocationList;
 use internet_identity::signature_map::SignatureMap;
 use internet_identity_interface::{HeaderField, HttpRequest, HttpResponse};
 use serde::Serialize;
@@ -57,7 +62,10 @@ pub fn http_request(req: HttpRequest) -> HttpResponse {
         probably_an_asset => {
             let certificate_header = STATE.with(|s| {
                 make_asset_certificate_header(
+                    &s.certified_credentials.borrow(),
+                    &s.certified_devices.borrow(),
                     &s.asset_hashes.borrow(),
+                    &s.revoked_sessions.borrow(),
                     &s.sigs.borrow(),
                     probably_an_asset,
                 )
@@ -97,6 +105,11 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             s.storage.borrow().user_count() as f64,
             "Number of users registered in this canister.",
         )?;
+        w.encode_gauge(
+            "internet_identity_deleted_user_count",
+            s.storage.borrow().deleted_count() as f64,
+            "Number of users deleted in this canister (included in the user count).",
+        )?;
         let (lo, hi) = s.storage.borrow().assigned_user_number_range();
         w.encode_gauge(
             "internet_identity_min_user_number",
@@ -136,12 +149,25 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         w.encode_gauge(
             "internet_identity_delegation_counter",
             s.usage_metrics.borrow().delegation_counter as f64,
-            "The number of delegations created since last upgrade",
+            "The number of delegations created",
         )?;
         w.encode_gauge(
             "internet_identity_anchor_operations_counter",
             s.usage_metrics.borrow().anchor_operation_counter as f64,
-            "The number of anchor operations since last upgrade",
+            "The number of anchor operations",
+        )?;
+        let config = s.config.borrow();
+        w.encode_gauge(
+            "internet_identity_registration_rate_limit_available_tokens",
+            s.registration_rate_limit
+                .borrow()
+                .available_tokens(&config.registration_rate_limit, time()) as f64,
+            "The number of registrations that are currently allowed by the rate limit.",
+        )?;
+        w.encode_gauge(
+            "internet_identity_registration_rate_limit_max_tokens",
+            config.registration_rate_limit.max_tokens as f64,
+            "The maximum number of tokens (i.e. the burst size) of the registration rate limit.",
         )?;
         Ok(())
     })
@@ -253,7 +279,10 @@ fn security_headers() -> Vec<HeaderField> {
 }
 
 fn make_asset_certificate_header(
+    certified_credentials: &CertifiedAnchors,
+    certified_devices: &CertifiedAnchors,
     asset_hashes: &AssetHashes,
+    revoked_sessions: &RevocationList,
     sigs: &SignatureMap,
     asset_name: &str,
 ) -> (String, String) {
@@ -262,8 +291,17 @@ fn make_asset_certificate_header(
     });
     let witness = asset_hashes.witness(asset_name.as_bytes());
     let tree = ic_certified_map::fork(
-        ic_certified_map::labeled(LABEL_ASSETS, witness),
-        HashTree::Pruned(ic_certified_map::labeled_hash(LABEL_SIG, &sigs.root_hash())),
+        HashTree::Pruned(certified_anchors_hash(
+            certified_credentials,
+            certified_devices,
+        )),
+        ic_certified_map::fork(
+            ic_certified_map::labeled(LABEL_ASSETS, witness),
+            HashTree::Pruned(ic_certified_map::fork_hash(
+                &ic_certified_map::labeled_hash(LABEL_REVOKED, &revoked_sessions.root_hash()),
+                &ic_certified_map::labeled_hash(LABEL_SIG, &sigs.root_hash()),
+            )),
+        ),
     );
     let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
     serializer.self_describe().unwrap();
@@ -1,4 +1,13 @@
 //! Various APIs for managing internet identities.
 
+pub mod certified_anchors;
+pub mod credential_index;
+pub mod event_log;
 pub mod metrics_encoder;
+pub mod proof_of_work;
+pub mod public_key;
+pub mod rate_limit;
+pub mod registration_failures;
+pub mod revocation_list;
 pub mod signature_map;
+pub mod storage;
@@ -1,35 +1,49 @@
+use crate::alternative_origins::{validate_derivation_origin, CachedAlternativeOrigins};
 use crate::assets::init_assets;
 use crate::AddTentativeDeviceResponse::{AddedTentatively, AnotherDeviceTentativelyAdded};
-use crate::RegistrationState::{DeviceRegistrationModeActive, DeviceTentativelyAdded};
 use crate::VerifyTentativeDeviceResponse::{NoDeviceToVerify, WrongCode};
 use assets::ContentType;
 use candid::{CandidType, Deserialize, Principal};
+use errors::{trap_with, ApiError};
 use ic_cdk::api::call::call;
 use ic_cdk::api::{caller, data_certificate, id, set_certified_data, time, trap};
-use ic_cdk_macros::{init, post_upgrade, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use internet_identity::certified_anchors::CertifiedAnchors;
+use internet_identity::credential_index::CredentialIndex;
+use internet_identity::event_log;
+use internet_identity::proof_of_work;
+use internet_identity::public_key::{parse_public_key, KeyAlgorithm};
+use internet_identity::rate_limit::TokenBucket;
+use internet_identity::registration_failures::RegistrationFailures;
+use internet_identity::revocation_list::RevocationList;
 use internet_identity::signature_map::SignatureMap;
+use internet_identity::storage::anchor::DeviceDataInternal;
+use internet_identity::storage::{self, Salt, StableMemory, Storage, StorageError};
 use rand_chacha::rand_core::{RngCore, SeedableRng};
 use serde::Serialize;
 use serde_bytes::ByteBuf;
-use std::cell::{Cell, RefCell, RefMut};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::ops::Deref;
-use storage::{Salt, Storage};
 
+use internet_identity_interface::hash;
 use internet_identity_interface::*;
 
+mod alternative_origins;
 mod assets;
+mod challenge;
+mod errors;
 mod http;
 
 const fn secs_to_nanos(secs: u64) -> u64 {
     secs * 1_000_000_000
 }
 
-#[cfg(not(feature = "dummy_captcha"))]
-use captcha::filters::Wave;
+// The defaults of the limits below can be overridden with InternetIdentityInit, see `config()`.
 
+// How many devices can be added to an anchor (at most)
+const MAX_ENTRIES_PER_USER: u64 = 10;
 // 30 mins
 const DEFAULT_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(30 * 60);
 // 30 days
@@ -40,92 +54,111 @@ const DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(60);
 // 5 mins
 const CAPTCHA_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
 // How many captcha challenges we keep in memory (at most)
-const MAX_INFLIGHT_CHALLENGES: usize = 500;
+const MAX_INFLIGHT_CHALLENGES: u64 = 500;
 
 // 15 mins
 const REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(900);
+// 1 day
+const MAX_REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(24 * 60 * 60);
 // How many users can be in registration mode simultaneously
 const MAX_USERS_IN_REGISTRATION_MODE: usize = 10_000;
 // How many verification attempts are given for a tentative device
 const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
-
+// How many devices can be added tentatively to an anchor at the same time
+const MAX_TENTATIVE_DEVICES: usize = 4;
+// Bounds of the length of the verification codes of tentative devices
+const MIN_VERIFICATION_CODE_LENGTH: u8 = 6;
+const MAX_VERIFICATION_CODE_LENGTH: u8 = 16;
+// The characters of alphanumeric verification codes, without the easily confused 0, O, 1 and I
+const VERIFICATION_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+// 15 mins, how long the protected devices of an anchor have to confirm its deletion
+const ANCHOR_DELETION_CONFIRMATION_PERIOD_NS: u64 = secs_to_nanos(900);
+
+// Registrations are rate limited: a new registration is allowed every second on average,
+// with bursts of up to 1000 registrations
+const REGISTRATION_RATE_LIMIT_TIME_PER_TOKEN_NS: u64 = secs_to_nanos(1);
+const REGISTRATION_RATE_LIMIT_MAX_TOKENS: u64 = 1000;
+
+// How many sessions are kept per anchor, the oldest ones are dropped first
+const MAX_SESSIONS_PER_ANCHOR: usize = 100;
+
+// How many anchors are indexed by credential ID and certified per update call while the credential
+// index and the certified anchor hashes are rebuilt (i.e. after an upgrade that could not restore
+// them), and how many are processed in post_upgrade itself. Reading, decoding and hashing an anchor
+// takes in the order of a few hundred thousand instructions, so the upgrade batch stays well below
+// the instruction limit of an upgrade even for anchors with many devices.
+const ANCHOR_BACKFILL_BATCH: u64 = 100;
+const ANCHOR_BACKFILL_UPGRADE_BATCH: u64 = 10_000;
+// How many anchors are migrated to storage layout version 2 per update call while a migration is
+// in progress, and how many are migrated by the upgrade that starts it
+const ANCHOR_MIGRATION_BATCH: u32 = 1_000;
+const ANCHOR_MIGRATION_UPGRADE_BATCH: u32 = 20_000;
+
+// How many operations can be passed (at most) to a single call of apply_device_operations
+const MAX_DEVICE_OPERATIONS_PER_CALL: usize = 20;
+
+// How many frontends can be passed (at most) to a single call of get_principals
+const MAX_PRINCIPALS_PER_CALL: usize = 100;
+
+// How many anchors are exported (at most) per call of export_anchors
+const MAX_EXPORTED_ANCHORS: u32 = 1000;
+// Size of the exported anchor data after which export_anchors stops, to stay well below the
+// response size limit
+const MAX_EXPORT_SIZE: usize = 1024 * 1024;
+
+const LABEL_CREDENTIALS: &[u8] = certification::LABEL_CREDENTIALS.as_bytes();
+const LABEL_DEVICES: &[u8] = certification::LABEL_DEVICES.as_bytes();
 const LABEL_ASSETS: &[u8] = b"http_assets";
+const LABEL_REVOKED: &[u8] = b"revoked";
 const LABEL_SIG: &[u8] = b"sig";
 
-/// This is an internal version of `DeviceData` primarily useful to provide a
-/// backwards compatible level between older device data stored in stable memory
-/// (that might not contain purpose or key_type) and new ones added.
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct DeviceDataInternal {
-    pubkey: DeviceKey,
-    alias: String,
-    credential_id: Option<CredentialId>,
-    purpose: Option<Purpose>,
-    key_type: Option<KeyType>,
-    protection: Option<DeviceProtection>,
-}
-
-impl From<DeviceData> for DeviceDataInternal {
-    fn from(device_data: DeviceData) -> Self {
-        Self {
-            pubkey: device_data.pubkey,
-            alias: device_data.alias,
-            credential_id: device_data.credential_id,
-            purpose: Some(device_data.purpose),
-            key_type: Some(device_data.key_type),
-            protection: Some(device_data.protection),
-        }
-    }
-}
-
-impl From<DeviceDataInternal> for DeviceData {
-    fn from(device_data_internal: DeviceDataInternal) -> Self {
-        Self {
-            pubkey: device_data_internal.pubkey,
-            alias: device_data_internal.alias,
-            credential_id: device_data_internal.credential_id,
-            purpose: device_data_internal
-                .purpose
-                .unwrap_or(Purpose::Authentication),
-            key_type: device_data_internal.key_type.unwrap_or(KeyType::Unknown),
-            protection: device_data_internal
-                .protection
-                .unwrap_or(DeviceProtection::Unprotected),
-        }
-    }
-}
-
-mod hash;
-mod storage;
-
 #[derive(Clone, Debug, CandidType, Deserialize)]
 struct InternetIdentityStats {
     assigned_user_number_range: (UserNumber, UserNumber),
     users_registered: u64,
+    users_deleted: u64,
 }
 
 type AssetHashes = RbTree<&'static str, Hash>;
 
+/// Registration state of new devices added using the two step device add flow
+#[derive(Clone, Debug)]
 struct TentativeDeviceRegistration {
     expiration: Timestamp,
-    state: RegistrationState,
+    // at most MAX_TENTATIVE_DEVICES, in the order they were added
+    tentative_devices: Vec<TentativeDevice>,
 }
 
-/// Registration state of new devices added using the two step device add flow
-enum RegistrationState {
-    DeviceRegistrationModeActive,
-    DeviceTentativelyAdded {
-        tentative_device: DeviceData,
-        verification_code: DeviceVerificationCode,
-        failed_attempts: FailedAttemptsCounter,
-    },
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TentativeDevice {
+    device: DeviceData,
+    verification_code: DeviceVerificationCode,
+    failed_attempts: FailedAttemptsCounter,
+}
+
+/// [TentativeDeviceRegistration] as persisted across upgrades. The tentative devices are optional
+/// so that the registrations persisted by releases with a single tentative device can still be
+/// decoded, their tentative device is dropped.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentTentativeDeviceRegistration {
+    expiration: Timestamp,
+    tentative_devices: Option<Vec<TentativeDevice>>,
+}
+
+/// A deletion of an anchor that has been confirmed by some of its protected devices, see
+/// `delete_anchor`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PendingAnchorDeletion {
+    deadline: Timestamp,
+    confirmed_by: Vec<DeviceKey>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
 struct UsageMetrics {
-    // number of prepare_delegation calls since last upgrade
+    // number of prepare_delegation calls
     delegation_counter: u64,
-    // number of anchor operations (register, add, remove, update) since last upgrade
+    // number of anchor operations (register, add, remove, update)
     anchor_operation_counter: u64,
 }
 
@@ -133,55 +166,160 @@ struct State {
     storage: RefCell<Storage<Vec<DeviceDataInternal>>>,
     sigs: RefCell<SignatureMap>,
     asset_hashes: RefCell<AssetHashes>,
+    // certified set of revoked session keys, persisted across upgrades
+    revoked_sessions: RefCell<RevocationList>,
     last_upgrade_timestamp: Cell<Timestamp>,
-    // note: we COULD persist this through upgrades, although this is currently NOT persisted
-    // through upgrades
+    // persisted across upgrades, see PersistentState
     inflight_challenges: RefCell<HashMap<ChallengeKey, ChallengeInfo>>,
-    // tentative device registrations, not persisted across updates
+    // tentative device registrations, persisted across upgrades
     // if a user number is present in this map then registration mode is active until expiration
     tentative_device_registrations: RefCell<HashMap<UserNumber, TentativeDeviceRegistration>>,
-    // additional usage metrics, NOT persisted across updates (but probably should be in the future)
+    // additional usage metrics, persisted across upgrades
     usage_metrics: RefCell<UsageMetrics>,
+    // delegations issued per anchor, persisted across upgrades
+    sessions: RefCell<HashMap<UserNumber, Vec<SessionInfo>>>,
+    // seed hashes of the delegations issued per anchor and when the latest of them expires, not
+    // capped like the sessions so that all delegations can be revoked when the anchor is deleted,
+    // persisted across upgrades
+    delegation_seeds: RefCell<HashMap<UserNumber, HashMap<Hash, Timestamp>>>,
+    // cache of the alternative origins fetched for derivation origin validation, not persisted
+    alternative_origins: RefCell<HashMap<Principal, CachedAlternativeOrigins>>,
+    // principal allowed to export and import anchors, kept in the StableState
+    admin: RefCell<Option<Principal>>,
+    // the limits of the canister, kept in the StableState
+    config: RefCell<InternetIdentityConfig>,
+    // rate limit of the registrations, persisted across upgrades
+    registration_rate_limit: RefCell<TokenBucket>,
+    // anchors locked with lock_anchor and when they were locked, kept in the StableState
+    locked_anchors: RefCell<HashMap<UserNumber, Timestamp>>,
+    // anchors whose recovery devices can only manage the anchor (see set_recovery_device_policy),
+    // kept in the StableState
+    recovery_restricted_anchors: RefCell<HashSet<UserNumber>>,
+    // anchors by the credential IDs of their devices, persisted across upgrades (see
+    // PersistentAnchorIndexes)
+    credential_index: RefCell<CredentialIndex>,
+    // certified hashes of the lookup responses, persisted across upgrades
+    certified_credentials: RefCell<CertifiedAnchors>,
+    // certified hashes of the device lists returned by `get_anchor_info_certified`, persisted
+    // across upgrades
+    certified_devices: RefCell<CertifiedAnchors>,
+    // deletions waiting for the confirmation of protected devices, kept in the StableState
+    pending_anchor_deletions: RefCell<HashMap<UserNumber, PendingAnchorDeletion>>,
+    // failed device registration rounds per anchor, kept in the StableState
+    registration_failures: RefCell<HashMap<UserNumber, RegistrationFailures>>,
+}
+
+/// The part of the state that is not stored in stable memory during normal operation and
+/// gets written to stable memory in pre_upgrade to be restored in post_upgrade.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentState {
+    inflight_challenges: HashMap<ChallengeKey, ChallengeInfo>,
+    tentative_device_registrations: HashMap<UserNumber, PersistentTentativeDeviceRegistration>,
+    usage_metrics: UsageMetrics,
+    signatures: Vec<PersistentSignature>,
+    // optional so that the state persisted by releases without sessions can still be decoded
+    sessions: Option<HashMap<UserNumber, Vec<SessionInfo>>>,
+    revoked_sessions: Option<Vec<PersistentRevocation>>,
+    registration_rate_limit: Option<TokenBucket>,
+    anchor_indexes: Option<PersistentAnchorIndexes>,
+    delegation_seeds: Option<Vec<PersistentDelegationSeed>>,
+}
+
+/// The credential index and the certified anchor hashes, which are derived from the anchors in
+/// stable memory. They are persisted so that an upgrade does not have to rebuild them from all
+/// anchors; if they cannot be restored they are rebuilt (see [process_anchors]).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentAnchorIndexes {
+    // the number of anchors the indexes were derived from, they are not restored if anchors have
+    // been registered since (e.g. by a release that did not persist them)
+    user_count: u64,
+    credential_index: Vec<(CredentialId, Vec<UserNumber>)>,
+    certified_credentials: Vec<(UserNumber, ByteBuf)>,
+    certified_devices: Vec<(UserNumber, ByteBuf)>,
+    // shared by the index and both hashes, see process_anchors
+    backfill_cursor: Option<UserNumber>,
+}
+
+/// The part of the state that must not be lost on upgrades. Like the [PersistentState] it is
+/// written to stable memory in pre_upgrade, but it is kept in its own region that is read by
+/// every upgrade, and an upgrade fails rather than dropping it (see [restore_stable_state]).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StableState {
+    admin: Option<Principal>,
+    // the config is kept as overrides so that limits added by later releases get their
+    // default value
+    config: InternetIdentityConfigOverrides,
+    recovery_restricted_anchors: HashSet<UserNumber>,
+    pending_anchor_deletions: HashMap<UserNumber, PendingAnchorDeletion>,
+    registration_failures: HashMap<UserNumber, RegistrationFailures>,
+    locked_anchors: HashMap<UserNumber, Timestamp>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentSignature {
+    seed_hash: ByteBuf,
+    msg_hash: ByteBuf,
+    expires_at: Timestamp,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentRevocation {
+    // missing for revocations persisted before they were scoped to the seed of the delegation
+    seed_hash: Option<ByteBuf>,
+    // empty if all session keys of the seed are revoked
+    session_key_hash: ByteBuf,
+    expires_at: Timestamp,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PersistentDelegationSeed {
+    user_number: UserNumber,
+    seed_hash: ByteBuf,
+    expires_at: Timestamp,
 }
 
 impl Default for State {
     fn default() -> Self {
         const FIRST_USER_ID: UserNumber = 10_000;
         Self {
-            storage: RefCell::new(Storage::new((
-                FIRST_USER_ID,
-                FIRST_USER_ID.saturating_add(storage::DEFAULT_RANGE_SIZE),
-            ))),
+            storage: RefCell::new(Storage::new(
+                (
+                    FIRST_USER_ID,
+                    FIRST_USER_ID.saturating_add(storage::DEFAULT_RANGE_SIZE),
+                ),
+                StableMemory,
+            )),
             sigs: RefCell::new(SignatureMap::default()),
             asset_hashes: RefCell::new(AssetHashes::default()),
+            revoked_sessions: RefCell::new(RevocationList::default()),
             last_upgrade_timestamp: Cell::new(0),
             inflight_challenges: RefCell::new(HashMap::new()),
             tentative_device_registrations: RefCell::new(HashMap::new()),
             usage_metrics: RefCell::new(UsageMetrics::default()),
+            sessions: RefCell::new(HashMap::new()),
+            delegation_seeds: RefCell::new(HashMap::new()),
+            alternative_origins: RefCell::new(HashMap::new()),
+            admin: RefCell::new(None),
+            config: RefCell::new(default_config()),
+            registration_rate_limit: RefCell::new(TokenBucket::default()),
+            locked_anchors: RefCell::new(HashMap::new()),
+            recovery_restricted_anchors: RefCell::new(HashSet::new()),
+            credential_index: RefCell::new(CredentialIndex::default()),
+            certified_credentials: RefCell::new(CertifiedAnchors::default()),
+            certified_devices: RefCell::new(CertifiedAnchors::default()),
+            pending_anchor_deletions: RefCell::new(HashMap::new()),
+            registration_failures: RefCell::new(HashMap::new()),
         }
     }
 }
 
 // The challenges we store and check against
+#[derive(Clone, Debug, CandidType, Deserialize)]
 struct ChallengeInfo {
     created: Timestamp,
     chars: String,
-}
-
-type ChallengeKey = String;
-
-// The user's attempt
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct ChallengeAttempt {
-    chars: String,
-    key: ChallengeKey,
-}
-
-// What we send the user
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct Challenge {
-    png_base64: String,
-    challenge_key: ChallengeKey,
+    // Set if the challenge is a proof of work challenge rather than a captcha
+    proof_of_work_difficulty: Option<u8>,
 }
 
 thread_local! {
@@ -216,36 +354,35 @@ async fn init_salt() {
 
 /// Enables device registration mode for the given user and returns the expiration timestamp (when it will be disabled again).
 /// If the device registration mode is already active it will just return the expiration timestamp again.
+/// Traps during the cooldown after repeated failed registration rounds, see `registration_failures`.
 #[update]
 fn enter_device_registration_mode(user_number: UserNumber) -> Timestamp {
     STATE.with(|state| {
-        let entries = state
-            .storage
-            .borrow()
-            .read(user_number)
-            .unwrap_or_else(|err| {
-                trap(&format!(
-                    "failed to read device data of user {}: {}",
-                    user_number, err
-                ))
-            });
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+        let (entries, _) =
+            authenticate_unless_locked(state, user_number).unwrap_or_else(|err| trap_with(err));
+
+        if let Some(until) = registration_cooldown_until(state, user_number) {
+            trap_with(ApiError::DeviceRegistrationCooldown { user_number, until });
+        }
 
         prune_expired_tentative_device_registrations(state);
         if state.tentative_device_registrations.borrow().len() >= MAX_USERS_IN_REGISTRATION_MODE {
             trap("too many users in device registration mode");
         }
 
+        record_usage(&mut state.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
         let mut device_registration_state = state.tentative_device_registrations.borrow_mut();
         match device_registration_state.get(&user_number) {
             Some(TentativeDeviceRegistration { expiration, .. }) => *expiration, // already enabled, just return the existing expiration
             None => {
-                let expiration = time() + REGISTRATION_MODE_DURATION;
+                let expiration =
+                    time().saturating_add(state.config.borrow().registration_mode_duration_ns);
                 device_registration_state.insert(
                     user_number,
                     TentativeDeviceRegistration {
                         expiration,
-                        state: DeviceRegistrationModeActive,
+                        tentative_devices: vec![],
                     },
                 );
                 expiration
@@ -257,17 +394,8 @@ fn enter_device_registration_mode(user_number: UserNumber) -> Timestamp {
 #[update]
 fn exit_device_registration_mode(user_number: UserNumber) {
     STATE.with(|state| {
-        let entries = state
-            .storage
-            .borrow()
-            .read(user_number)
-            .unwrap_or_else(|err| {
-                trap(&format!(
-                    "failed to read device data of user {}: {}",
-                    user_number, err
-                ))
-            });
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+        let (entries, _) =
+            authenticate(&state.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
 
         prune_expired_tentative_device_registrations(state);
 
@@ -275,6 +403,8 @@ fn exit_device_registration_mode(user_number: UserNumber) {
             .tentative_device_registrations
             .borrow_mut()
             .remove(&user_number);
+        record_usage(&mut state.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
     })
 }
 
@@ -283,35 +413,42 @@ async fn add_tentative_device(
     user_number: UserNumber,
     device_data: DeviceData,
 ) -> AddTentativeDeviceResponse {
-    let verification_code = new_verification_code().await;
+    check_public_key(&device_data.pubkey).unwrap_or_else(|err| trap_with(err));
+    let mut rng = make_rng().await;
     let now = time();
 
     STATE.with(|state| {
         prune_expired_tentative_device_registrations(state);
 
         let mut tentative_registrations = state.tentative_device_registrations.borrow_mut();
-        let registration = tentative_registrations.get_mut(&user_number);
+        let registration = match tentative_registrations.get_mut(&user_number) {
+            Some(registration) if registration.expiration > now => registration,
+            _ => return AddTentativeDeviceResponse::DeviceRegistrationModeOff,
+        };
+        if let Some(cooldown_until) = registration_cooldown_until(state, user_number) {
+            return AddTentativeDeviceResponse::DeviceRegistrationCooldown { cooldown_until };
+        }
+        if registration
+            .tentative_devices
+            .iter()
+            .any(|tentative| tentative.device.pubkey == device_data.pubkey)
+        {
+            return AddTentativeDeviceResponse::AlreadyAddedTentatively;
+        }
+        if registration.tentative_devices.len() >= MAX_TENTATIVE_DEVICES {
+            return AnotherDeviceTentativelyAdded;
+        }
 
-        match registration {
-            None => AddTentativeDeviceResponse::DeviceRegistrationModeOff,
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration <= now => {
-                AddTentativeDeviceResponse::DeviceRegistrationModeOff
-            }
-            Some(TentativeDeviceRegistration {
-                state: DeviceTentativelyAdded { .. },
-                ..
-            }) => AnotherDeviceTentativelyAdded,
-            Some(mut registration) => {
-                registration.state = DeviceTentativelyAdded {
-                    tentative_device: device_data,
-                    failed_attempts: 0,
-                    verification_code: verification_code.clone(),
-                };
-                AddedTentatively {
-                    device_registration_timeout: registration.expiration,
-                    verification_code,
-                }
-            }
+        let verification_code =
+            new_verification_code(&mut rng, &state.config.borrow().verification_code_format);
+        registration.tentative_devices.push(TentativeDevice {
+            device: device_data,
+            verification_code: verification_code.clone(),
+            failed_attempts: 0,
+        });
+        AddedTentatively {
+            device_registration_timeout: registration.expiration,
+            verification_code,
         }
     })
 }
@@ -320,8 +457,9 @@ async fn add_tentative_device(
 async fn verify_tentative_device(
     user_number: UserNumber,
     user_verification_code: DeviceVerificationCode,
+    tentative_device_key: Option<DeviceKey>,
 ) -> VerifyTentativeDeviceResponse {
-    match get_verified_device(user_number, user_verification_code) {
+    match get_verified_device(user_number, user_verification_code, tentative_device_key) {
         Ok(device) => {
             add(user_number, device).await;
             VerifyTentativeDeviceResponse::Verified
@@ -330,21 +468,22 @@ async fn verify_tentative_device(
     }
 }
 
-/// Checks the device verification code for a tentative device.
-/// If valid, returns the device to be added and exits device registration mode
-/// If invalid, returns the appropriate error to send to the client and increases failed attempts. Exits device registration mode if there are no retries left.
+/// Checks the device verification code against the code of the given tentative device, or of the
+/// first tentative device if none is given (the one shown to clients that expect a single one).
+/// If it matches, returns the device to be added. Device registration mode is exited once no
+/// tentative devices are left.
+/// If invalid, returns the appropriate error to send to the client and increases the failed
+/// attempts of that device only. A tentative device without retries left is discarded, which
+/// counts as a failed registration round of the anchor (see `registration_failures`), and device
+/// registration mode is exited once none are left.
 fn get_verified_device(
     user_number: UserNumber,
     user_verification_code: DeviceVerificationCode,
+    tentative_device_key: Option<DeviceKey>,
 ) -> Result<DeviceData, VerifyTentativeDeviceResponse> {
     STATE.with(|s| {
-        let entries = s.storage.borrow().read(user_number).unwrap_or_else(|err| {
-            trap(&format!(
-                "failed to read device data of user {}: {}",
-                user_number, err
-            ))
-        });
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+        let (_, acting_device) =
+            authenticate_unless_locked(s, user_number).unwrap_or_else(|err| trap_with(err));
 
         prune_expired_tentative_device_registrations(s);
 
@@ -353,50 +492,88 @@ fn get_verified_device(
             .remove(&user_number)
             .ok_or(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)?;
 
-        match tentative_registration.state {
-            DeviceRegistrationModeActive => Err(NoDeviceToVerify),
-            DeviceTentativelyAdded {
-                failed_attempts,
-                verification_code,
-                tentative_device,
-            } => {
-                if user_verification_code == verification_code {
-                    return Ok(tentative_device);
-                }
+        let index = match tentative_device_key {
+            Some(key) => tentative_registration
+                .tentative_devices
+                .iter()
+                .position(|tentative| tentative.device.pubkey == key),
+            None if tentative_registration.tentative_devices.is_empty() => None,
+            None => Some(0),
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                device_registration_state.insert(user_number, tentative_registration);
+                return Err(NoDeviceToVerify);
+            }
+        };
 
-                let failed_attempts = failed_attempts + 1;
-                if failed_attempts < MAX_DEVICE_REGISTRATION_ATTEMPTS {
-                    tentative_registration.state = DeviceTentativelyAdded {
-                        failed_attempts,
-                        tentative_device,
-                        verification_code,
-                    };
-                    // reinsert because retries are allowed
-                    device_registration_state.insert(user_number, tentative_registration);
-                }
-                return Err(WrongCode {
-                    retries_left: (MAX_DEVICE_REGISTRATION_ATTEMPTS - failed_attempts),
-                });
+        if tentative_registration.tentative_devices[index]
+            .verification_code
+            .eq_ignore_ascii_case(&user_verification_code)
+        {
+            s.registration_failures.borrow_mut().remove(&user_number);
+            let tentative = tentative_registration.tentative_devices.remove(index);
+            if !tentative_registration.tentative_devices.is_empty() {
+                device_registration_state.insert(user_number, tentative_registration);
             }
+            record_event(
+                &mut s.storage.borrow_mut(),
+                user_number,
+                AnchorEventType::TentativeDeviceVerified,
+                acting_device,
+                Some(tentative.device.pubkey.clone()),
+            );
+            return Ok(tentative.device);
+        }
+
+        let max_attempts = s.config.borrow().max_device_registration_attempts;
+        let tentative = &mut tentative_registration.tentative_devices[index];
+        tentative.failed_attempts = tentative.failed_attempts.saturating_add(1);
+        let retries_left = max_attempts.saturating_sub(tentative.failed_attempts);
+        if retries_left == 0 {
+            tentative_registration.tentative_devices.remove(index);
+            s.registration_failures
+                .borrow_mut()
+                .entry(user_number)
+                .or_default()
+                .record_failed_round(time());
         }
+        if !tentative_registration.tentative_devices.is_empty() {
+            // reinsert because retries are allowed
+            device_registration_state.insert(user_number, tentative_registration);
+        }
+        Err(WrongCode { retries_left })
     })
 }
 
-/// Return a decimal representation of a random `u32` to be used as verification code
-async fn new_verification_code() -> DeviceVerificationCode {
-    let res: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
-        Ok((res,)) => res,
-        Err((_, err)) => trap(&format!("failed to get randomness: {}", err)),
-    };
-    let rand = u32::from_be_bytes(res[..4].try_into().unwrap_or_else(|_| {
-        trap(&format!(
-            "when creating device verification code from raw_rand output, expected raw randomness to be of length 32, got {}",
-            res.len()
-        ));
-    }));
+/// Returns a random verification code of the given format.
+fn new_verification_code<T: RngCore>(
+    rng: &mut T,
+    format: &VerificationCodeFormat,
+) -> DeviceVerificationCode {
+    match format {
+        VerificationCodeFormat::Decimal { digits } => (0..*digits)
+            .map(|_| char::from(b'0' + (rng.next_u32() % 10) as u8))
+            .collect(),
+        VerificationCodeFormat::Alphanumeric { length } => (0..*length)
+            .map(|_| {
+                let index = rng.next_u32() as usize % VERIFICATION_CODE_ALPHABET.len();
+                char::from(VERIFICATION_CODE_ALPHABET[index])
+            })
+            .collect(),
+    }
+}
 
-    // the format! makes sure that the resulting string has exactly 6 characters.
-    format!("{:06}", (rand % 1_000_000))
+/// Returns the end of the cooldown if the anchor is not allowed to register new devices because
+/// of repeated failed registration rounds. Forgets the expired failure histories of all anchors.
+fn registration_cooldown_until(state: &State, user_number: UserNumber) -> Option<Timestamp> {
+    let now = time();
+    let mut registration_failures = state.registration_failures.borrow_mut();
+    registration_failures.retain(|_, failures| !failures.is_expired(now));
+    registration_failures
+        .get(&user_number)
+        .and_then(|failures| failures.cooldown_until(now))
 }
 
 /// Removes __all__ expired device registrations -> there is no need to check expiration immediately after pruning.
@@ -412,11 +589,28 @@ fn prune_expired_tentative_device_registrations(state: &State) {
 
 #[update]
 async fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) -> RegisterResponse {
+    // the rest of the call runs in a single message, so that the token checked below cannot be
+    // taken by another registration before this one consumes the challenge
+    ensure_salt_set().await;
+
+    // check the rate limit before consuming the challenge, so that a rate limited user can retry
+    // with the same solved challenge
+    let rate_limited = STATE.with(|s| {
+        s.registration_rate_limit
+            .borrow()
+            .available_tokens(&s.config.borrow().registration_rate_limit, time())
+            == 0
+    });
+    if rate_limited {
+        return RegisterResponse::RateLimited;
+    }
+
     if let Err(()) = check_challenge(challenge_result) {
         return RegisterResponse::BadChallenge;
     }
 
-    check_device(&device_data, &vec![]);
+    check_device(&device_data, &vec![]).unwrap_or_else(|err| trap_with(err));
+    let key_algorithm = check_public_key(&device_data.pubkey).unwrap_or_else(|err| trap_with(err));
 
     if caller() != Principal::self_authenticating(device_data.pubkey.clone()) {
         ic_cdk::trap(&format!(
@@ -426,20 +620,38 @@ async fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) -
         ));
     }
 
-    ensure_salt_set().await;
-
     STATE.with(|s| {
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut s.sigs.borrow_mut());
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
+        backfill_anchors(s);
+
+        // cannot fail, the bucket has been checked above
+        s.registration_rate_limit
+            .borrow_mut()
+            .try_take(&s.config.borrow().registration_rate_limit, time());
 
         let mut store = s.storage.borrow_mut();
         match store.allocate_user_number() {
             Some(user_number) => {
+                let now = time();
                 write_anchor_data(
-                    store.deref(),
+                    s,
+                    &mut store,
                     user_number,
-                    vec![DeviceDataInternal::from(device_data)],
-                    s.usage_metrics.borrow_mut(),
-                );
+                    vec![DeviceDataInternal {
+                        created_at: Some(now),
+                        // the device is the caller of register
+                        last_usage: Some(now),
+                        key_algorithm: Some(key_algorithm),
+                        ..DeviceDataInternal::from(device_data)
+                    }],
+                )
+                .unwrap_or_else(|err| trap_with(err));
                 RegisterResponse::Registered { user_number }
             }
             None => RegisterResponse::CanisterFull,
@@ -449,59 +661,111 @@ async fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) -
 
 #[update]
 async fn add(user_number: UserNumber, device_data: DeviceData) {
-    const MAX_ENTRIES_PER_USER: usize = 10;
+    ensure_salt_set().await;
+    add_device(user_number, device_data).unwrap_or_else(|err| trap_with(err))
+}
 
+#[update]
+async fn add_v2(user_number: UserNumber, device_data: DeviceData) -> AddDeviceResponse {
     ensure_salt_set().await;
+    match add_device(user_number, device_data) {
+        Ok(()) => AddDeviceResponse::Added,
+        Err(err) => err.into(),
+    }
+}
 
+fn add_device(user_number: UserNumber, device_data: DeviceData) -> Result<(), ApiError> {
     STATE.with(|s| {
-        let mut entries = s.storage.borrow().read(user_number).unwrap_or_else(|err| {
-            trap(&format!(
-                "failed to read device data of user {}: {}",
-                user_number, err
-            ))
-        });
+        let (mut entries, acting_device) = authenticate_unless_locked(s, user_number)?;
+        let device_key = device_data.pubkey.clone();
+        push_device(&mut entries, device_data)?;
+        write_anchor_data(s, &mut s.storage.borrow_mut(), user_number, entries)?;
+        record_event(
+            &mut s.storage.borrow_mut(),
+            user_number,
+            AnchorEventType::DeviceAdded,
+            acting_device,
+            Some(device_key),
+        );
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
+        backfill_anchors(s);
+        Ok(())
+    })
+}
 
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
-        check_device(&device_data, &entries);
+/// Checks the device and appends it to the devices of an anchor.
+fn push_device(
+    entries: &mut Vec<DeviceDataInternal>,
+    device_data: DeviceData,
+) -> Result<(), ApiError> {
+    let key_algorithm = check_new_device(&device_data, entries)?;
+    entries.push(DeviceDataInternal {
+        created_at: Some(time()),
+        key_algorithm: Some(key_algorithm),
+        ..DeviceDataInternal::from(device_data)
+    });
+    Ok(())
+}
 
-        if entries
-            .iter()
-            .find(|e| e.pubkey == device_data.pubkey)
-            .is_some()
-        {
-            trap("Device already added.");
-        }
+/// Checks a device to be added to the given devices of an anchor and returns the algorithm of its
+/// public key.
+fn check_new_device(
+    device_data: &DeviceData,
+    entries: &[DeviceDataInternal],
+) -> Result<KeyAlgorithm, ApiError> {
+    check_device(device_data, entries)?;
+    let key_algorithm = check_public_key(&device_data.pubkey)?;
+
+    if entries
+        .iter()
+        .find(|e| e.pubkey == device_data.pubkey)
+        .is_some()
+    {
+        return Err(ApiError::DeviceAlreadyAdded);
+    }
 
-        if entries.len() >= MAX_ENTRIES_PER_USER {
-            trap(&format!(
-                "at most {} authentication information entries are allowed per user",
-                MAX_ENTRIES_PER_USER,
-            ));
-        }
+    let max_entries_per_user = STATE.with(|s| s.config.borrow().max_entries_per_user as usize);
+    if entries.len() >= max_entries_per_user {
+        return Err(ApiError::TooManyDevices(max_entries_per_user));
+    }
+    Ok(key_algorithm)
+}
 
-        entries.push(DeviceDataInternal::from(device_data));
-        write_anchor_data(
-            s.storage.borrow().deref(),
-            user_number,
-            entries,
-            s.usage_metrics.borrow_mut(),
-        );
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut s.sigs.borrow_mut());
-    })
+/// Checks the imported devices of an anchor one by one, like `add` checks a new device against
+/// the devices before it. The devices keep their metadata (e.g. their usage), only the key
+/// algorithm is set from the checked public key.
+fn check_imported_devices(
+    devices: Vec<DeviceDataInternal>,
+) -> Result<Vec<DeviceDataInternal>, ApiError> {
+    let mut entries = Vec::with_capacity(devices.len());
+    for device in devices {
+        let key_algorithm = check_new_device(&DeviceData::from(device.clone()), &entries)?;
+        entries.push(DeviceDataInternal {
+            key_algorithm: Some(key_algorithm),
+            ..device
+        });
+    }
+    Ok(entries)
 }
 
 /// Replace or remove an existing device.
 ///
 /// NOTE: all mutable operations should call this function because it handles device protection
-fn mutate_device_or_trap(
+fn mutate_device(
     entries: &mut Vec<DeviceDataInternal>,
     device_key: DeviceKey,
     new_value: Option<DeviceData>,
-) {
-    let index = match entries.iter().position(|e| e.pubkey == device_key) {
-        None => trap("Could not find device to mutate, check device key"),
-        Some(index) => index,
-    };
+) -> Result<(), ApiError> {
+    let index = entries
+        .iter()
+        .position(|e| e.pubkey == device_key)
+        .ok_or(ApiError::DeviceNotFound)?;
 
     let device = entries.get_mut(index).unwrap();
 
@@ -512,14 +776,20 @@ fn mutate_device_or_trap(
         Some(DeviceProtection::Protected) => {
             // If the call is not authenticated with the device to mutate, abort
             if caller() != Principal::self_authenticating(&device.pubkey) {
-                trap("Device is protected. Must be authenticated with this device to mutate");
+                return Err(ApiError::DeviceProtected);
             }
         }
     };
 
     match new_value {
         Some(device_data) => {
-            *device = device_data.into();
+            // the usage and the key algorithm of the device are not part of the update
+            *device = DeviceDataInternal {
+                created_at: device.created_at,
+                last_usage: device.last_usage,
+                key_algorithm: device.key_algorithm,
+                ..device_data.into()
+            };
         }
         None => {
             // NOTE: we void the more efficient remove_swap to ensure device ordering
@@ -527,96 +797,305 @@ fn mutate_device_or_trap(
             entries.remove(index);
         }
     }
+    Ok(())
 }
 
 #[update]
 async fn update(user_number: UserNumber, device_key: DeviceKey, device_data: DeviceData) {
+    update_device(user_number, device_key, device_data).unwrap_or_else(|err| trap_with(err))
+}
+
+#[update]
+async fn update_v2(
+    user_number: UserNumber,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> UpdateDeviceResponse {
+    match update_device(user_number, device_key, device_data) {
+        Ok(()) => UpdateDeviceResponse::Updated,
+        Err(err) => err.into(),
+    }
+}
+
+fn update_device(
+    user_number: UserNumber,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> Result<(), ApiError> {
     if device_key != device_data.pubkey {
-        trap("device key may not be updated");
+        return Err(ApiError::InvalidDevice(
+            "device key may not be updated".to_string(),
+        ));
     }
 
     STATE.with(|s| {
-        let mut entries = s.storage.borrow().read(user_number).unwrap_or_else(|err| {
-            trap(&format!(
-                "failed to read device data of user {}: {}",
-                user_number, err
-            ))
-        });
-
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
-        check_device(&device_data, &entries);
+        let (mut entries, acting_device) = authenticate_unless_locked(s, user_number)?;
+        check_device(&device_data, &entries)?;
 
-        mutate_device_or_trap(&mut entries, device_key, Some(device_data));
+        mutate_device(&mut entries, device_key.clone(), Some(device_data))?;
 
-        write_anchor_data(
-            s.storage.borrow().deref(),
+        write_anchor_data(s, &mut s.storage.borrow_mut(), user_number, entries)?;
+        record_event(
+            &mut s.storage.borrow_mut(),
             user_number,
-            entries,
-            s.usage_metrics.borrow_mut(),
+            AnchorEventType::DeviceUpdated,
+            acting_device,
+            Some(device_key),
         );
 
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut s.sigs.borrow_mut());
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
+        backfill_anchors(s);
+        Ok(())
     })
 }
 
 #[update]
 async fn remove(user_number: UserNumber, device_key: DeviceKey) {
     ensure_salt_set().await;
-    STATE.with(|s| {
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut s.sigs.borrow_mut());
+    remove_device(user_number, device_key).unwrap_or_else(|err| trap_with(err))
+}
 
-        let mut entries = s.storage.borrow().read(user_number).unwrap_or_else(|err| {
-            trap(&format!(
-                "failed to read device data of user {}: {}",
-                user_number, err
-            ))
-        });
+#[update]
+async fn remove_v2(user_number: UserNumber, device_key: DeviceKey) -> RemoveDeviceResponse {
+    ensure_salt_set().await;
+    match remove_device(user_number, device_key) {
+        Ok(()) => RemoveDeviceResponse::Removed,
+        Err(err) => err.into(),
+    }
+}
 
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+fn remove_device(user_number: UserNumber, device_key: DeviceKey) -> Result<(), ApiError> {
+    STATE.with(|s| {
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
+        backfill_anchors(s);
+
+        let (mut entries, acting_device) = authenticate_unless_locked(s, user_number)?;
 
-        mutate_device_or_trap(&mut entries, device_key, None);
-        write_anchor_data(
-            s.storage.borrow().deref(),
+        mutate_device(&mut entries, device_key.clone(), None)?;
+        write_anchor_data(s, &mut s.storage.borrow_mut(), user_number, entries)?;
+        record_event(
+            &mut s.storage.borrow_mut(),
             user_number,
-            entries,
-            s.usage_metrics.borrow_mut(),
+            AnchorEventType::DeviceRemoved,
+            acting_device,
+            Some(device_key),
         );
+        Ok(())
     })
 }
 
-/// Writes the supplied entries to stable memory and updates the anchor operation metric.
-fn write_anchor_data(
+/// Applies the given device operations to the anchor in order, e.g. to replace a lost device by
+/// adding the new device and removing the lost one. Every operation is checked like the
+/// corresponding `add`, `update` or `remove` call, against the devices resulting from the previous
+/// operations. If any operation fails, none are applied; otherwise the devices are written once.
+#[update]
+async fn apply_device_operations(user_number: UserNumber, operations: Vec<DeviceOperation>) {
+    ensure_salt_set().await;
+    apply_operations(user_number, operations).unwrap_or_else(|err| trap_with(err))
+}
+
+fn apply_operations(
+    user_number: UserNumber,
+    operations: Vec<DeviceOperation>,
+) -> Result<(), ApiError> {
+    if operations.len() > MAX_DEVICE_OPERATIONS_PER_CALL {
+        return Err(ApiError::InvalidArgument(format!(
+            "number of device operations {} exceeds the limit of {}",
+            operations.len(),
+            MAX_DEVICE_OPERATIONS_PER_CALL
+        )));
+    }
+
+    STATE.with(|s| {
+        let (mut entries, acting_device) = authenticate_unless_locked(s, user_number)?;
+
+        let mut events = Vec::with_capacity(operations.len());
+        for operation in operations {
+            match operation {
+                DeviceOperation::Add(device_data) => {
+                    let device_key = device_data.pubkey.clone();
+                    push_device(&mut entries, device_data)?;
+                    events.push((AnchorEventType::DeviceAdded, device_key));
+                }
+                DeviceOperation::Update(device_data) => {
+                    check_device(&device_data, &entries)?;
+                    let device_key = device_data.pubkey.clone();
+                    mutate_device(&mut entries, device_key.clone(), Some(device_data))?;
+                    events.push((AnchorEventType::DeviceUpdated, device_key));
+                }
+                DeviceOperation::Remove(device_key) => {
+                    mutate_device(&mut entries, device_key.clone(), None)?;
+                    events.push((AnchorEventType::DeviceRemoved, device_key));
+                }
+            }
+        }
+
+        write_anchor_data(s, &mut s.storage.borrow_mut(), user_number, entries)?;
+        for (event_type, device_key) in events {
+            record_event(
+                &mut s.storage.borrow_mut(),
+                user_number,
+                event_type,
+                acting_device.clone(),
+                Some(device_key),
+            );
+        }
+
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
+        backfill_anchors(s);
+        Ok(())
+    })
+}
+
+/// Reads the devices of the given anchor from stable memory.
+fn read_anchor_data(
     storage: &Storage<Vec<DeviceDataInternal>>,
     user_number: UserNumber,
+) -> Result<Vec<DeviceDataInternal>, ApiError> {
+    storage
+        .read(user_number)
+        .map_err(|err| ApiError::ReadFailed { user_number, err })
+}
+
+/// Writes the supplied entries to stable memory and updates the anchor operation metric and the
+/// credential index.
+fn write_anchor_data(
+    s: &State,
+    storage: &mut Storage<Vec<DeviceDataInternal>>,
+    user_number: UserNumber,
     entries: Vec<DeviceDataInternal>,
-    mut usage_metrics: RefMut<UsageMetrics>,
-) {
-    storage.write(user_number, entries).unwrap_or_else(|err| {
-        trap(&format!(
-            "failed to write device data of user {}: {}",
-            user_number, err
-        ))
-    });
-    usage_metrics.anchor_operation_counter += 1;
+) -> Result<(), ApiError> {
+    // the last recovery device of a locked anchor is needed to unlock it
+    if s.locked_anchors.borrow().contains_key(&user_number) && !has_recovery_device(&entries) {
+        return Err(ApiError::NoRecoveryDeviceToUnlock(user_number));
+    }
+    let old_entries = storage.read(user_number).unwrap_or_default();
+    storage
+        .write(user_number, entries.clone())
+        .map_err(|err| ApiError::WriteFailed { user_number, err })?;
+    s.credential_index
+        .borrow_mut()
+        .update(user_number, &old_entries, &entries);
+    let mut certified_credentials = s.certified_credentials.borrow_mut();
+    certified_credentials.update(user_number, Some(credentials_hash(&entries)));
+    let mut certified_devices = s.certified_devices.borrow_mut();
+    certified_devices.update(user_number, Some(devices_hash(&entries)));
+    update_root_hash(
+        &certified_credentials,
+        &certified_devices,
+        &s.asset_hashes.borrow(),
+        &s.revoked_sessions.borrow(),
+        &s.sigs.borrow(),
+    );
+    s.usage_metrics.borrow_mut().anchor_operation_counter += 1;
+    Ok(())
+}
+
+/// Continues the work on the anchors left over by the last upgrade with the next batch of anchors,
+/// see [process_anchors].
+fn backfill_anchors(s: &State) {
+    process_anchors(s, ANCHOR_MIGRATION_BATCH, ANCHOR_BACKFILL_BATCH);
+}
+
+/// Migrates up to `max_migrated` anchors to storage layout version 2 if a migration is in
+/// progress, then indexes and certifies up to `max_backfilled` anchors while the credential index
+/// and the certified anchor hashes are rebuilt after an upgrade that could not restore them.
+///
+/// The credential index and both certified hashes are rebuilt together, i.e. they share the same
+/// backfill cursor, so every anchor is read from stable memory only once for all of them.
+fn process_anchors(s: &State, max_migrated: u32, max_backfilled: u64) {
+    s.storage.borrow_mut().migrate_records(max_migrated);
+
+    let storage = s.storage.borrow();
+    let mut credential_index = s.credential_index.borrow_mut();
+    let mut certified_credentials = s.certified_credentials.borrow_mut();
+    let mut certified_devices = s.certified_devices.borrow_mut();
+    let (lo, _) = storage.assigned_user_number_range();
+    let end = lo + storage.user_count() as u64;
+    let mut changed = false;
+    for _ in 0..max_backfilled {
+        let user_number = match credential_index.backfill_cursor() {
+            Some(user_number) => user_number,
+            None => break,
+        };
+        let devices = storage.read(user_number).ok();
+        credential_index.backfill_next(devices.as_deref(), end);
+        changed |=
+            certified_credentials.backfill_next(devices.as_deref().map(credentials_hash), end);
+        changed |= certified_devices.backfill_next(devices.as_deref().map(devices_hash), end);
+    }
+    if changed {
+        update_root_hash(
+            &certified_credentials,
+            &certified_devices,
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &s.sigs.borrow(),
+        );
+    }
 }
 
+/// Creates a captcha challenge. Traps if the canister is configured to use a different challenge
+/// mechanism, use `create_challenge_v2` instead.
 #[update]
 async fn create_challenge() -> Challenge {
+    match new_challenge().await {
+        CreateChallengeResponse::Captcha(challenge) => challenge,
+        CreateChallengeResponse::ProofOfWork(_) => {
+            trap("the canister uses proof of work challenges, use create_challenge_v2")
+        }
+    }
+}
+
+/// Creates a challenge using the configured challenge mechanism.
+#[update]
+async fn create_challenge_v2() -> CreateChallengeResponse {
+    new_challenge().await
+}
+
+async fn new_challenge() -> CreateChallengeResponse {
     let mut rng = make_rng().await;
 
     let resp = STATE.with(|s| {
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut s.sigs.borrow_mut());
+        prune_expired_signatures(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &mut s.sigs.borrow_mut(),
+        );
 
         let mut inflight_challenges = s.inflight_challenges.borrow_mut();
 
         let now = time() as u64;
 
+        let config = s.config.borrow();
+
         // Prune old challenges. This drops all challenges that are older than
-        // CAPTCHA_CHALLENGE_LIFETIME
-        inflight_challenges.retain(|_, v| v.created > now - CAPTCHA_CHALLENGE_LIFETIME);
+        // the captcha challenge lifetime
+        inflight_challenges
+            .retain(|_, v| v.created > now.saturating_sub(config.captcha_challenge_lifetime_ns));
 
         // Error out if there are too many inflight challenges
-        if inflight_challenges.len() >= MAX_INFLIGHT_CHALLENGES {
+        if inflight_challenges.len() as u64 >= config.max_inflight_challenges {
             trap("too many inflight captchas");
         }
 
@@ -629,22 +1108,14 @@ async fn create_challenge() -> Challenge {
         for _ in 0..MAX_TRIES {
             let challenge_key = random_string(&mut rng, 10);
             if !inflight_challenges.contains_key(&challenge_key) {
-                // Then we create the CAPTCHA
-                let (Base64(png_base64), chars) = create_captcha(rng);
+                // Then we create the CAPTCHA or proof of work challenge
+                let mechanism = challenge::configured_mechanism(&config.challenge);
+                let (response, info) = mechanism.create(challenge_key.clone(), rng, now);
 
                 // Finally insert
-                inflight_challenges.insert(
-                    challenge_key.clone(),
-                    ChallengeInfo {
-                        created: now,
-                        chars,
-                    },
-                );
+                inflight_challenges.insert(challenge_key, info);
 
-                return Challenge {
-                    png_base64,
-                    challenge_key,
-                };
+                return response;
             }
         }
 
@@ -696,43 +1167,13 @@ async fn make_rng() -> rand_chacha::ChaCha20Rng {
     rand_chacha::ChaCha20Rng::from_seed(seed)
 }
 
-#[cfg(feature = "dummy_captcha")]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::RngCaptcha::from_rng(rng);
-    let captcha = captcha.set_chars(&vec!['a']).add_chars(1).view(96, 48);
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
-    };
-
-    return (resp, captcha.chars_as_string());
-}
-
-#[cfg(not(feature = "dummy_captcha"))]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::RngCaptcha::from_rng(rng);
-    let captcha = captcha
-        .add_chars(5)
-        .apply_filter(Wave::new(2.0, 20.0).horizontal())
-        .apply_filter(Wave::new(2.0, 20.0).vertical())
-        .view(220, 120);
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
-    };
-
-    return (resp, captcha.chars_as_string());
-}
-
-// Check whether the CAPTCHA challenge was solved
+// Check whether the CAPTCHA or proof of work challenge was solved
 fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
     STATE.with(|s| {
         let mut inflight_challenges = s.inflight_challenges.borrow_mut();
         match inflight_challenges.remove(&res.key) {
             Some(challenge) => {
-                if res.chars != challenge.chars {
+                if !challenge::mechanism_of(&challenge).check(&res.key, &challenge, &res.chars) {
                     return Err(());
                 }
                 return Ok(());
@@ -743,22 +1184,488 @@ fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
 }
 
 /// Returns all devices of the user (authentication and recovery) but no information about device registrations.
-/// Note: Will be changed in the future to be more consistent with get_anchor_info.
+/// Only the data needed to sign in is returned, the aliases of the devices are always empty as
+/// anyone can look up any anchor.
 #[query]
 fn lookup(user_number: UserNumber) -> Vec<DeviceData> {
-    STATE.with(|s| {
-        s.storage
-            .borrow()
-            .read(user_number)
-            .unwrap_or_default()
-            .into_iter()
-            .map(DeviceData::from)
-            .collect()
-    })
+    STATE.with(|s| lookup_devices(s.storage.borrow().read(user_number).unwrap_or_default()))
 }
 
-#[update] // this is an update call because queries are not (yet) certified
+/// Like `lookup`, but also returns a witness from the certified data for the hash of the devices
+/// (and the certificate if called as a non-replicated query), so that the response can be verified.
+/// Traps if the anchor has not been certified again since the last upgrade.
+#[query]
+fn lookup_certified(user_number: UserNumber) -> CertifiedLookupResponse {
+    STATE.with(|s| {
+        let certified_credentials = s.certified_credentials.borrow();
+        if !certified_credentials.is_certified(user_number) {
+            trap(&format!(
+                "the devices of anchor {} are not certified yet, try again later",
+                user_number
+            ));
+        }
+        let tree = ic_certified_map::fork(
+            ic_certified_map::fork(
+                ic_certified_map::labeled(
+                    LABEL_CREDENTIALS,
+                    certified_credentials.witness(user_number),
+                ),
+                HashTree::Pruned(ic_certified_map::labeled_hash(
+                    LABEL_DEVICES,
+                    &s.certified_devices.borrow().root_hash(),
+                )),
+            ),
+            HashTree::Pruned(ic_certified_map::fork_hash(
+                &ic_certified_map::labeled_hash(LABEL_ASSETS, &s.asset_hashes.borrow().root_hash()),
+                &ic_certified_map::fork_hash(
+                    &ic_certified_map::labeled_hash(
+                        LABEL_REVOKED,
+                        &s.revoked_sessions.borrow().root_hash(),
+                    ),
+                    &ic_certified_map::labeled_hash(LABEL_SIG, &s.sigs.borrow().root_hash()),
+                ),
+            )),
+        );
+
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer)
+            .unwrap_or_else(|e| trap(&format!("failed to serialize a hash tree: {}", e)));
+
+        CertifiedLookupResponse {
+            devices: lookup_devices(s.storage.borrow().read(user_number).unwrap_or_default()),
+            certificate: data_certificate().map(ByteBuf::from),
+            tree: ByteBuf::from(serializer.into_inner()),
+        }
+    })
+}
+
+/// The devices of an anchor as returned by `lookup`.
+fn lookup_devices(entries: Vec<DeviceDataInternal>) -> Vec<DeviceData> {
+    entries
+        .into_iter()
+        .map(|device| DeviceData {
+            alias: String::new(),
+            ..DeviceData::from(device)
+        })
+        .collect()
+}
+
+/// The certified hash of the devices of an anchor as returned by `lookup`, see
+/// `certification::credentials_hash` of the interface crate.
+fn credentials_hash(entries: &[DeviceDataInternal]) -> Hash {
+    certification::credentials_hash(&lookup_devices(entries.to_vec()))
+}
+
+/// The certified hash of the devices of an anchor as returned by `get_anchor_info`, see
+/// `certification::devices_hash` of the interface crate.
+fn devices_hash(entries: &[DeviceDataInternal]) -> Hash {
+    let devices: Vec<DeviceData> = entries.iter().cloned().map(DeviceData::from).collect();
+    certification::devices_hash(&devices)
+}
+
+/// Returns the anchors with a device with the given credential ID, so that users can sign in with
+/// a discoverable credential without entering their anchor number. Shortly after an upgrade, until
+/// all anchors have been indexed again, returns `NotIndexedYet`.
+#[query]
+fn lookup_by_credential_id(credential_id: CredentialId) -> CredentialLookupResponse {
+    match STATE.with(|s| s.credential_index.borrow().lookup(&credential_id)) {
+        Some(anchors) => CredentialLookupResponse::Anchors(anchors),
+        None => CredentialLookupResponse::NotIndexedYet,
+    }
+}
+
+#[update] // this is an update call because queries are not (yet) certified
 fn get_anchor_info(user_number: UserNumber) -> IdentityAnchorInfo {
+    anchor_info(user_number).unwrap_or_else(|err| trap_with(err))
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_anchor_info_v2(user_number: UserNumber) -> GetAnchorInfoResponse {
+    match anchor_info(user_number) {
+        Ok(info) => GetAnchorInfoResponse::AnchorInfo(info),
+        Err(err) => err.into(),
+    }
+}
+
+fn anchor_info(user_number: UserNumber) -> Result<IdentityAnchorInfo, ApiError> {
+    STATE.with(|state| {
+        let (entries, _) = authenticate(&state.storage.borrow(), user_number)?;
+        let info = anchor_info_of(state, user_number, entries.clone());
+        record_usage(&mut state.storage.borrow_mut(), user_number, entries)?;
+        Ok(info)
+    })
+}
+
+/// Like `get_anchor_info`, but as a query: the response contains a witness for the devices of the
+/// anchor from the certified data (and the certificate if called as a non-replicated query), so
+/// that the devices can be verified with `certification::verify_anchor_info` of the interface
+/// crate. The usage of the calling device is not recorded. Traps if the anchor has not been
+/// certified again since the last upgrade.
+#[query]
+fn get_anchor_info_certified(user_number: UserNumber) -> CertifiedAnchorInfoResponse {
+    STATE.with(|s| {
+        let entries =
+            read_anchor_data(&s.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
+        check_authentication(entries.iter().map(|e| &e.pubkey))
+            .unwrap_or_else(|err| trap_with(err));
+
+        let certified_devices = s.certified_devices.borrow();
+        if !certified_devices.is_certified(user_number) {
+            trap(&format!(
+                "the devices of anchor {} are not certified yet, try again later",
+                user_number
+            ));
+        }
+        let tree = ic_certified_map::fork(
+            ic_certified_map::fork(
+                HashTree::Pruned(ic_certified_map::labeled_hash(
+                    LABEL_CREDENTIALS,
+                    &s.certified_credentials.borrow().root_hash(),
+                )),
+                ic_certified_map::labeled(LABEL_DEVICES, certified_devices.witness(user_number)),
+            ),
+            HashTree::Pruned(ic_certified_map::fork_hash(
+                &ic_certified_map::labeled_hash(LABEL_ASSETS, &s.asset_hashes.borrow().root_hash()),
+                &ic_certified_map::fork_hash(
+                    &ic_certified_map::labeled_hash(
+                        LABEL_REVOKED,
+                        &s.revoked_sessions.borrow().root_hash(),
+                    ),
+                    &ic_certified_map::labeled_hash(LABEL_SIG, &s.sigs.borrow().root_hash()),
+                ),
+            )),
+        );
+
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer)
+            .unwrap_or_else(|e| trap(&format!("failed to serialize a hash tree: {}", e)));
+
+        CertifiedAnchorInfoResponse {
+            info: anchor_info_of(s, user_number, entries),
+            certificate: data_certificate().map(ByteBuf::from),
+            tree: ByteBuf::from(serializer.into_inner()),
+        }
+    })
+}
+
+fn anchor_info_of(
+    state: &State,
+    user_number: UserNumber,
+    entries: Vec<DeviceDataInternal>,
+) -> IdentityAnchorInfo {
+    let devices = entries.into_iter().map(DeviceWithUsage::from).collect();
+    let locked_since = state.locked_anchors.borrow().get(&user_number).cloned();
+    let recovery_device_policy = Some(recovery_device_policy(state, user_number));
+    let now = time();
+    let device_registration = match state
+        .tentative_device_registrations
+        .borrow()
+        .get(&user_number)
+    {
+        Some(registration) if registration.expiration > now => {
+            let tentative_devices: Vec<DeviceData> = registration
+                .tentative_devices
+                .iter()
+                .map(|tentative| tentative.device.clone())
+                .collect();
+            Some(DeviceRegistrationInfo {
+                expiration: registration.expiration,
+                tentative_device: tentative_devices.first().cloned(),
+                tentative_devices: Some(tentative_devices),
+            })
+        }
+        _ => None,
+    };
+    let device_registration_failures = state
+        .registration_failures
+        .borrow()
+        .get(&user_number)
+        .filter(|failures| !failures.is_expired(now))
+        .map(|failures| DeviceRegistrationFailures {
+            failed_rounds: failures.failed_rounds(),
+            last_failure: failures.last_failure(),
+            cooldown_until: failures.cooldown_until(now),
+        });
+    IdentityAnchorInfo {
+        devices,
+        device_registration,
+        locked_since,
+        recovery_device_policy,
+        device_registration_failures,
+    }
+}
+
+/// Locks the anchor: until it is unlocked again only recovery devices can add, update or remove
+/// devices, enter device registration mode or prepare and get delegations. Any device of the
+/// anchor can lock it, e.g. if another device is suspected to be compromised. An active device
+/// registration is aborted and the recorded sessions of the anchor are revoked. Locking a locked
+/// anchor has no effect.
+///
+/// Traps if the anchor has no recovery device, as the anchor could not be unlocked again.
+#[update]
+fn lock_anchor(user_number: UserNumber) {
+    STATE.with(|s| {
+        let (entries, acting_device) =
+            authenticate(&s.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
+        if !s.locked_anchors.borrow().contains_key(&user_number) {
+            if !has_recovery_device(&entries) {
+                trap_with(ApiError::NoRecoveryDeviceToUnlock(user_number));
+            }
+            s.locked_anchors.borrow_mut().insert(user_number, time());
+            s.tentative_device_registrations
+                .borrow_mut()
+                .remove(&user_number);
+            revoke_recorded_sessions(s, user_number);
+            update_root_hash(
+                &s.certified_credentials.borrow(),
+                &s.certified_devices.borrow(),
+                &s.asset_hashes.borrow(),
+                &s.revoked_sessions.borrow(),
+                &s.sigs.borrow(),
+            );
+            record_event(
+                &mut s.storage.borrow_mut(),
+                user_number,
+                AnchorEventType::AnchorLocked,
+                acting_device,
+                None,
+            );
+        }
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
+    })
+}
+
+/// Unlocks an anchor locked with `lock_anchor`. Must be authenticated with a recovery device.
+#[update]
+fn unlock_anchor(user_number: UserNumber) {
+    STATE.with(|s| {
+        let (entries, acting_device) =
+            authenticate(&s.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
+        if !is_recovery_device(&entries, &acting_device) {
+            trap_with(ApiError::RecoveryDeviceRequired);
+        }
+        if s.locked_anchors.borrow_mut().remove(&user_number).is_some() {
+            record_event(
+                &mut s.storage.borrow_mut(),
+                user_number,
+                AnchorEventType::AnchorUnlocked,
+                acting_device,
+                None,
+            );
+        }
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
+    })
+}
+
+/// Sets whether the recovery devices of the anchor can be used to sign in to dapps, or only to
+/// manage the anchor (add, update or remove devices and device registration mode).
+#[update]
+fn set_recovery_device_policy(user_number: UserNumber, policy: RecoveryDevicePolicy) {
+    STATE.with(|s| {
+        let (entries, _) =
+            authenticate_unless_locked(s, user_number).unwrap_or_else(|err| trap_with(err));
+        let mut restricted_anchors = s.recovery_restricted_anchors.borrow_mut();
+        match policy {
+            RecoveryDevicePolicy::Unrestricted => restricted_anchors.remove(&user_number),
+            RecoveryDevicePolicy::AnchorManagementOnly => restricted_anchors.insert(user_number),
+        };
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
+    })
+}
+
+/// Deletes the anchor: its devices and event log are wiped, its sessions are revoked and the
+/// anchor number is never assigned again, so the principals of the anchor cannot be reissued.
+/// A recovery device can delete the anchor right away. Otherwise every protected device of the
+/// anchor has to call `delete_anchor` within ANCHOR_DELETION_CONFIRMATION_PERIOD_NS.
+#[update]
+fn delete_anchor(user_number: UserNumber) -> DeleteAnchorResponse {
+    STATE.with(|s| {
+        let (entries, acting_device) =
+            authenticate_unless_locked(s, user_number).unwrap_or_else(|err| trap_with(err));
+
+        if !is_recovery_device(&entries, &acting_device) {
+            let protected_devices: Vec<DeviceKey> = entries
+                .iter()
+                .filter(|e| e.protection == Some(DeviceProtection::Protected))
+                .map(|e| e.pubkey.clone())
+                .collect();
+            if !protected_devices.contains(&acting_device) {
+                trap_with(ApiError::AnchorDeletionNotAllowed(user_number));
+            }
+
+            let now = time();
+            let mut pending_deletions = s.pending_anchor_deletions.borrow_mut();
+            pending_deletions.retain(|_, pending| pending.deadline > now);
+            let pending =
+                pending_deletions
+                    .entry(user_number)
+                    .or_insert_with(|| PendingAnchorDeletion {
+                        deadline: now + ANCHOR_DELETION_CONFIRMATION_PERIOD_NS,
+                        confirmed_by: vec![],
+                    });
+            if !pending.confirmed_by.contains(&acting_device) {
+                pending.confirmed_by.push(acting_device);
+            }
+            let missing_confirmations: Vec<DeviceKey> = protected_devices
+                .into_iter()
+                .filter(|device_key| !pending.confirmed_by.contains(device_key))
+                .collect();
+            if !missing_confirmations.is_empty() {
+                record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+                    .unwrap_or_else(|err| trap_with(err));
+                return DeleteAnchorResponse::ConfirmationPending {
+                    missing_confirmations,
+                    deadline: pending.deadline,
+                };
+            }
+        }
+
+        delete_anchor_data(s, user_number, &entries);
+        DeleteAnchorResponse::Deleted
+    })
+}
+
+/// Deletes the anchor from stable memory and drops the state kept for it. All delegations issued
+/// for the anchor are revoked and the signatures prepared for them are removed.
+fn delete_anchor_data(s: &State, user_number: UserNumber, entries: &[DeviceDataInternal]) {
+    s.storage
+        .borrow_mut()
+        .delete(user_number)
+        .unwrap_or_else(|err| trap_with(ApiError::WriteFailed { user_number, err }));
+    s.credential_index
+        .borrow_mut()
+        .update(user_number, entries, &[]);
+    s.usage_metrics.borrow_mut().anchor_operation_counter += 1;
+    s.tentative_device_registrations
+        .borrow_mut()
+        .remove(&user_number);
+    s.locked_anchors.borrow_mut().remove(&user_number);
+    s.recovery_restricted_anchors
+        .borrow_mut()
+        .remove(&user_number);
+    s.pending_anchor_deletions.borrow_mut().remove(&user_number);
+    s.registration_failures.borrow_mut().remove(&user_number);
+
+    revoke_all_delegations(s, user_number);
+    let mut certified_credentials = s.certified_credentials.borrow_mut();
+    certified_credentials.update(user_number, None);
+    let mut certified_devices = s.certified_devices.borrow_mut();
+    certified_devices.update(user_number, None);
+    update_root_hash(
+        &certified_credentials,
+        &certified_devices,
+        &s.asset_hashes.borrow(),
+        &s.revoked_sessions.borrow(),
+        &s.sigs.borrow(),
+    );
+}
+
+/// Revokes the sessions recorded for the anchor (see `list_sessions`) and removes the signatures
+/// prepared for them.
+///
+/// Note: the caller is responsible for updating the root hash.
+fn revoke_recorded_sessions(s: &State, user_number: UserNumber) {
+    let sessions = s
+        .sessions
+        .borrow_mut()
+        .remove(&user_number)
+        .unwrap_or_default();
+    let seed_hashes: HashSet<Hash> = sessions
+        .iter()
+        .map(|session| hash::hash_bytes(calculate_seed(user_number, &session.frontend)))
+        .collect();
+    delete_signatures_of_seeds(&mut s.sigs.borrow_mut(), &seed_hashes);
+
+    let mut revoked_sessions = s.revoked_sessions.borrow_mut();
+    let now = time();
+    for session in sessions.iter().filter(|session| session.expiration > now) {
+        revoke(&mut revoked_sessions, user_number, session);
+    }
+}
+
+/// Revokes all delegations issued for the anchor, including those of sessions that are no longer
+/// recorded (see MAX_SESSIONS_PER_ANCHOR), by revoking their seeds as a whole until the latest
+/// delegation of each seed expires. The signatures prepared for them are removed.
+///
+/// Note: the caller is responsible for updating the root hash.
+fn revoke_all_delegations(s: &State, user_number: UserNumber) {
+    let mut seeds = s
+        .delegation_seeds
+        .borrow_mut()
+        .remove(&user_number)
+        .unwrap_or_default();
+    // the seeds of the recorded sessions are usually tracked already, but not if the sessions
+    // were issued by a release that did not track the seeds
+    for session in s
+        .sessions
+        .borrow_mut()
+        .remove(&user_number)
+        .unwrap_or_default()
+    {
+        let seed_hash = hash::hash_bytes(calculate_seed(user_number, &session.frontend));
+        let expires_at = seeds.entry(seed_hash).or_insert(session.expiration);
+        *expires_at = u64::max(*expires_at, session.expiration);
+    }
+    let seed_hashes: HashSet<Hash> = seeds.keys().cloned().collect();
+    delete_signatures_of_seeds(&mut s.sigs.borrow_mut(), &seed_hashes);
+
+    let mut revoked_sessions = s.revoked_sessions.borrow_mut();
+    let now = time();
+    for (seed_hash, expires_at) in seeds {
+        if expires_at > now {
+            revoked_sessions.revoke_seed(seed_hash, expires_at);
+        }
+    }
+}
+
+fn delete_signatures_of_seeds(sigs: &mut SignatureMap, seed_hashes: &HashSet<Hash>) {
+    let deleted_sigs: Vec<(Hash, Hash)> = sigs
+        .entries()
+        .filter(|(seed_hash, _, _)| seed_hashes.contains(seed_hash))
+        .map(|(seed_hash, msg_hash, _)| (seed_hash, msg_hash))
+        .collect();
+    for (seed_hash, msg_hash) in deleted_sigs {
+        sigs.delete(seed_hash, msg_hash);
+    }
+}
+
+fn recovery_device_policy(state: &State, user_number: UserNumber) -> RecoveryDevicePolicy {
+    if state
+        .recovery_restricted_anchors
+        .borrow()
+        .contains(&user_number)
+    {
+        RecoveryDevicePolicy::AnchorManagementOnly
+    } else {
+        RecoveryDevicePolicy::Unrestricted
+    }
+}
+
+/// Fails if delegations cannot be issued to the given device of the anchor because it is a
+/// recovery device and the anchor restricts its recovery devices to anchor management.
+fn check_delegation_allowed(
+    state: &State,
+    user_number: UserNumber,
+    entries: &[DeviceDataInternal],
+    device_key: &DeviceKey,
+) -> Result<(), ApiError> {
+    if recovery_device_policy(state, user_number) == RecoveryDevicePolicy::AnchorManagementOnly
+        && is_recovery_device(entries, device_key)
+    {
+        return Err(ApiError::RecoveryDeviceNotAllowed(user_number));
+    }
+    Ok(())
+}
+
+#[query]
+fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Principal {
+    check_frontend_length(&frontend).unwrap_or_else(|err| trap_with(err));
+
     STATE.with(|state| {
         let entries = state
             .storage
@@ -770,48 +1677,32 @@ fn get_anchor_info(user_number: UserNumber) -> IdentityAnchorInfo {
                     user_number, err
                 ))
             });
+
         trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
 
-        let devices = entries.into_iter().map(DeviceData::from).collect();
-        let now = time();
-        match state
-            .tentative_device_registrations
-            .borrow()
-            .get(&user_number)
-        {
-            Some(TentativeDeviceRegistration {
-                expiration,
-                state:
-                    DeviceTentativelyAdded {
-                        tentative_device, ..
-                    },
-            }) if *expiration > now => IdentityAnchorInfo {
-                devices,
-                device_registration: Some(DeviceRegistrationInfo {
-                    expiration: *expiration,
-                    tentative_device: Some(tentative_device.clone()),
-                }),
-            },
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration > now => {
-                IdentityAnchorInfo {
-                    devices,
-                    device_registration: Some(DeviceRegistrationInfo {
-                        expiration: *expiration,
-                        tentative_device: None,
-                    }),
-                }
-            }
-            None | Some(_) => IdentityAnchorInfo {
-                devices,
-                device_registration: None,
-            },
-        }
+        let seed = calculate_seed(user_number, &frontend);
+        let public_key = der_encode_canister_sig_key(seed.to_vec());
+        Principal::self_authenticating(&public_key)
     })
 }
 
+/// Like `get_principal`, but for up to [MAX_PRINCIPALS_PER_CALL] frontends at once. The anchor is
+/// read (and the caller authenticated) only once for all of them.
 #[query]
-fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Principal {
-    check_frontend_length(&frontend);
+fn get_principals(
+    user_number: UserNumber,
+    frontends: Vec<FrontendHostname>,
+) -> Vec<(FrontendHostname, Principal)> {
+    if frontends.len() > MAX_PRINCIPALS_PER_CALL {
+        trap_with(ApiError::InvalidArgument(format!(
+            "number of frontends {} exceeds the limit of {}",
+            frontends.len(),
+            MAX_PRINCIPALS_PER_CALL
+        )));
+    }
+    for frontend in &frontends {
+        check_frontend_length(frontend).unwrap_or_else(|err| trap_with(err));
+    }
 
     STATE.with(|state| {
         let entries = state
@@ -827,9 +1718,15 @@ fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Princip
 
         trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
 
-        let seed = calculate_seed(user_number, &frontend);
-        let public_key = der_encode_canister_sig_key(seed.to_vec());
-        Principal::self_authenticating(&public_key)
+        let salt = salt();
+        frontends
+            .into_iter()
+            .map(|frontend| {
+                let seed = calculate_seed_with_salt(&salt, user_number, &frontend);
+                let public_key = der_encode_canister_sig_key(seed.to_vec());
+                (frontend, Principal::self_authenticating(&public_key))
+            })
+            .collect()
     })
 }
 
@@ -847,39 +1744,155 @@ async fn prepare_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    actual_origin: Option<FrontendHostname>,
 ) -> (UserKey, Timestamp) {
     ensure_salt_set().await;
+    prepare_delegation_internal(
+        user_number,
+        frontend,
+        session_key,
+        max_time_to_live,
+        targets,
+        actual_origin,
+    )
+    .await
+    .unwrap_or_else(|err| trap_with(err))
+}
+
+#[update]
+async fn prepare_delegation_v2(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    actual_origin: Option<FrontendHostname>,
+) -> PrepareDelegationResponse {
+    ensure_salt_set().await;
+    match prepare_delegation_internal(
+        user_number,
+        frontend,
+        session_key,
+        max_time_to_live,
+        targets,
+        actual_origin,
+    )
+    .await
+    {
+        Ok((user_key, expiration)) => PrepareDelegationResponse::Prepared {
+            user_key,
+            expiration,
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/// Prepares a delegation for `frontend`. If `actual_origin` is given, `frontend` is treated as
+/// the derivation origin and validated against the alternative origins of its canister.
+async fn prepare_delegation_internal(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    session_key: SessionKey,
+    max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    actual_origin: Option<FrontendHostname>,
+) -> Result<(UserKey, Timestamp), ApiError> {
+    if let Some(actual_origin) = actual_origin {
+        // only authenticated callers may make II call the canister of the derivation origin
+        STATE.with(|s| {
+            let entries = read_anchor_data(&s.storage.borrow(), user_number)?;
+            check_authentication(entries.iter().map(|e| &e.pubkey))
+        })?;
+        check_frontend_length(&frontend)?;
+        check_frontend_length(&actual_origin)?;
+        validate_derivation_origin(&frontend, &actual_origin).await?;
+    }
 
     STATE.with(|s| {
-        let entries = s.storage.borrow().read(user_number).unwrap_or_else(|err| {
-            trap(&format!(
-                "failed to read device data of user {}: {}",
-                user_number, err
-            ))
-        });
+        let (entries, acting_device) = authenticate_unless_locked(s, user_number)?;
+        check_delegation_allowed(s, user_number, &entries, &acting_device)?;
 
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+        check_frontend_length(&frontend)?;
+        check_targets_length(&targets)?;
 
-        check_frontend_length(&frontend);
+        let seed = calculate_seed(user_number, &frontend);
+        if s.revoked_sessions
+            .borrow()
+            .is_revoked(hash::hash_bytes(seed), hash::hash_bytes(&session_key))
+        {
+            return Err(ApiError::SessionRevoked);
+        }
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)?;
 
+        let config = s.config.borrow();
         let delta = u64::min(
-            max_time_to_live.unwrap_or(DEFAULT_EXPIRATION_PERIOD_NS),
-            MAX_EXPIRATION_PERIOD_NS,
+            max_time_to_live.unwrap_or(config.default_expiration_period_ns),
+            config.max_expiration_period_ns,
+        );
+        let now = time() as u64;
+        let expiration = now.saturating_add(delta);
+
+        add_session(
+            &mut s.sessions.borrow_mut(),
+            user_number,
+            SessionInfo {
+                frontend: frontend.clone(),
+                session_key_hash: ByteBuf::from(hash::hash_bytes(&session_key).to_vec()),
+                expiration,
+                issued_at: now,
+            },
+        );
+        add_delegation_seed(
+            &mut s.delegation_seeds.borrow_mut(),
+            user_number,
+            hash::hash_bytes(seed),
+            expiration,
         );
-        let expiration = (time() as u64).saturating_add(delta);
 
-        let seed = calculate_seed(user_number, &frontend);
+        prune_expired_revocations(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &mut s.revoked_sessions.borrow_mut(),
+            &s.sigs.borrow(),
+        );
+
+        backfill_anchors(s);
+
+        let certified_credentials = s.certified_credentials.borrow();
+        let certified_devices = s.certified_devices.borrow();
+        let revoked_sessions = s.revoked_sessions.borrow();
         let mut sigs = s.sigs.borrow_mut();
-        add_signature(&mut sigs, session_key, seed, expiration);
-        update_root_hash(&s.asset_hashes.borrow(), &sigs);
-        prune_expired_signatures(&s.asset_hashes.borrow(), &mut sigs);
+        add_signature(&mut sigs, session_key, seed, expiration, targets);
+        update_root_hash(
+            &certified_credentials,
+            &certified_devices,
+            &s.asset_hashes.borrow(),
+            &revoked_sessions,
+            &sigs,
+        );
+        prune_expired_signatures(
+            &certified_credentials,
+            &certified_devices,
+            &s.asset_hashes.borrow(),
+            &revoked_sessions,
+            &mut sigs,
+        );
 
         s.usage_metrics.borrow_mut().delegation_counter += 1;
+        record_event(
+            &mut s.storage.borrow_mut(),
+            user_number,
+            AnchorEventType::DelegationPrepared,
+            acting_device,
+            None,
+        );
 
-        (
+        Ok((
             ByteBuf::from(der_encode_canister_sig_key(seed.to_vec())),
             expiration,
-        )
+        ))
     })
 }
 
@@ -889,43 +1902,434 @@ fn get_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> GetDelegationResponse {
-    check_frontend_length(&frontend);
+    check_frontend_length(&frontend).unwrap_or_else(|err| trap_with(err));
+    check_targets_length(&targets).unwrap_or_else(|err| trap_with(err));
+
+    STATE.with(|state| {
+        let entries = state
+            .storage
+            .borrow()
+            .read(user_number)
+            .unwrap_or_else(|err| {
+                trap(&format!(
+                    "failed to read device data of user {}: {}",
+                    user_number, err
+                ))
+            });
+
+        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+        let caller = caller();
+        if let Some(device) = entries
+            .iter()
+            .find(|e| caller == Principal::self_authenticating(&e.pubkey))
+        {
+            check_delegation_allowed(state, user_number, &entries, &device.pubkey)
+                .unwrap_or_else(|err| trap_with(err));
+            // the delegations prepared before the anchor was locked are not handed out either
+            if state.locked_anchors.borrow().contains_key(&user_number)
+                && !is_recovery_device(&entries, &device.pubkey)
+            {
+                trap_with(ApiError::AnchorLocked(user_number));
+            }
+        }
+
+        // delegations for revoked session keys are no longer handed out
+        let seed = calculate_seed(user_number, &frontend);
+        if state
+            .revoked_sessions
+            .borrow()
+            .is_revoked(hash::hash_bytes(seed), hash::hash_bytes(&session_key))
+        {
+            return GetDelegationResponse::NoSuchDelegation;
+        }
+
+        match get_signature(
+            &state.certified_credentials.borrow(),
+            &state.certified_devices.borrow(),
+            &state.asset_hashes.borrow(),
+            &state.revoked_sessions.borrow(),
+            &state.sigs.borrow(),
+            session_key.clone(),
+            seed,
+            expiration,
+            targets.clone(),
+        ) {
+            Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
+                delegation: Delegation {
+                    pubkey: session_key,
+                    expiration,
+                    targets,
+                },
+                signature: ByteBuf::from(signature),
+            }),
+            None => GetDelegationResponse::NoSuchDelegation,
+        }
+    })
+}
+
+/// Returns a page of the event log of the given anchor, starting at the event with sequence
+/// number `cursor` (or the oldest event that is still kept).
+#[query]
+fn get_anchor_events(user_number: UserNumber, cursor: Option<u64>) -> AnchorEvents {
+    STATE.with(|s| {
+        let storage = s.storage.borrow();
+        let entries = read_anchor_data(&storage, user_number).unwrap_or_else(|err| trap_with(err));
+        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
+
+        let events = storage
+            .read_events(user_number)
+            .unwrap_or_else(|err| trap_with(ApiError::ReadFailed { user_number, err }));
+        event_log::page(&events, cursor)
+    })
+}
+
+/// Returns the sessions (i.e. the delegations issued by prepare_delegation) of the given
+/// anchor that have not expired yet.
+#[update] // this is an update call because queries are not (yet) certified
+fn list_sessions(user_number: UserNumber) -> Vec<SessionInfo> {
+    STATE.with(|s| {
+        let (entries, _) =
+            authenticate(&s.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
+
+        let mut sessions = s.sessions.borrow_mut();
+        prune_expired_sessions(&mut sessions, user_number);
+        sessions.get(&user_number).cloned().unwrap_or_default()
+    })
+}
+
+/// Revokes the session with the given session key hash (as returned by list_sessions).
+#[update]
+fn revoke_session(user_number: UserNumber, session_key_hash: SessionKeyHash) {
+    revoke_sessions(user_number, |session| {
+        session.session_key_hash == session_key_hash
+    });
+}
+
+/// Revokes all sessions of the given anchor for the given frontend.
+#[update]
+fn revoke_all_sessions_for_frontend(user_number: UserNumber, frontend: FrontendHostname) {
+    check_frontend_length(&frontend).unwrap_or_else(|err| trap_with(err));
+    revoke_sessions(user_number, |session| session.frontend == frontend);
+}
+
+fn revoke_sessions(user_number: UserNumber, is_revoked: impl Fn(&SessionInfo) -> bool) {
+    STATE.with(|s| {
+        let (entries, _) =
+            authenticate(&s.storage.borrow(), user_number).unwrap_or_else(|err| trap_with(err));
+
+        let mut sessions = s.sessions.borrow_mut();
+        prune_expired_sessions(&mut sessions, user_number);
+        let anchor_sessions = sessions.entry(user_number).or_default();
+        let (revoked, remaining): (Vec<_>, Vec<_>) = anchor_sessions
+            .drain(..)
+            .partition(|session| is_revoked(session));
+        *anchor_sessions = remaining;
+        if anchor_sessions.is_empty() {
+            sessions.remove(&user_number);
+        }
+        if revoked.is_empty() {
+            trap(&format!(
+                "no matching session found for user {}",
+                user_number
+            ));
+        }
+
+        record_usage(&mut s.storage.borrow_mut(), user_number, entries)
+            .unwrap_or_else(|err| trap_with(err));
+        let mut revoked_sessions = s.revoked_sessions.borrow_mut();
+        for session in revoked {
+            revoke(&mut revoked_sessions, user_number, &session);
+        }
+        update_root_hash(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &revoked_sessions,
+            &s.sigs.borrow(),
+        );
+        prune_expired_revocations(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &mut revoked_sessions,
+            &s.sigs.borrow(),
+        );
+    })
+}
+
+/// Adds the session key of the session to the revocation list, scoped to the seed of the session.
+fn revoke(revoked_sessions: &mut RevocationList, user_number: UserNumber, session: &SessionInfo) {
+    let session_key_hash: Hash = session
+        .session_key_hash
+        .as_slice()
+        .try_into()
+        .unwrap_or_else(|_| trap("internal error: invalid session key hash"));
+    let seed_hash = hash::hash_bytes(calculate_seed(user_number, &session.frontend));
+    revoked_sessions.revoke(seed_hash, session_key_hash, session.expiration);
+}
+
+/// Returns whether the given session key has been revoked for the given user key (as returned by
+/// `prepare_delegation`), together with a witness from the certified revocation list (and the
+/// certificate if called as a non-replicated query) so that the result can be verified.
+#[query]
+fn get_session_revocation_status(
+    user_key: UserKey,
+    session_key: SessionKey,
+) -> SessionRevocationStatus {
+    let seed = seed_of_user_key(&user_key)
+        .unwrap_or_else(|| trap("the user key was not issued by this canister"));
+    STATE.with(|s| {
+        let seed_hash = hash::hash_bytes(seed);
+        let session_key_hash = hash::hash_bytes(&session_key);
+        let revoked_sessions = s.revoked_sessions.borrow();
+        let tree = ic_certified_map::fork(
+            HashTree::Pruned(certified_anchors_hash(
+                &s.certified_credentials.borrow(),
+                &s.certified_devices.borrow(),
+            )),
+            ic_certified_map::fork(
+                HashTree::Pruned(ic_certified_map::labeled_hash(
+                    LABEL_ASSETS,
+                    &s.asset_hashes.borrow().root_hash(),
+                )),
+                ic_certified_map::fork(
+                    ic_certified_map::labeled(
+                        LABEL_REVOKED,
+                        revoked_sessions.witness(seed_hash, session_key_hash),
+                    ),
+                    HashTree::Pruned(ic_certified_map::labeled_hash(
+                        LABEL_SIG,
+                        &s.sigs.borrow().root_hash(),
+                    )),
+                ),
+            ),
+        );
+
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        tree.serialize(&mut serializer)
+            .unwrap_or_else(|e| trap(&format!("failed to serialize a hash tree: {}", e)));
+
+        SessionRevocationStatus {
+            revoked: revoked_sessions.is_revoked(seed_hash, session_key_hash),
+            certificate: data_certificate().map(ByteBuf::from),
+            tree: ByteBuf::from(serializer.into_inner()),
+        }
+    })
+}
+
+/// Records a session for the given anchor. Re-preparing a delegation for the same session key
+/// and frontend updates the existing session.
+fn add_session(
+    sessions: &mut HashMap<UserNumber, Vec<SessionInfo>>,
+    user_number: UserNumber,
+    session: SessionInfo,
+) {
+    prune_expired_sessions(sessions, user_number);
+    let anchor_sessions = sessions.entry(user_number).or_default();
+    anchor_sessions.retain(|existing| {
+        existing.session_key_hash != session.session_key_hash
+            || existing.frontend != session.frontend
+    });
+    anchor_sessions.push(session);
+    if anchor_sessions.len() > MAX_SESSIONS_PER_ANCHOR {
+        // sessions are ordered by issue time, drop the oldest ones
+        let excess = anchor_sessions.len() - MAX_SESSIONS_PER_ANCHOR;
+        anchor_sessions.drain(..excess);
+    }
+}
+
+/// Records the seed of a delegation issued for the given anchor until the delegation expires, see
+/// [revoke_all_delegations].
+fn add_delegation_seed(
+    delegation_seeds: &mut HashMap<UserNumber, HashMap<Hash, Timestamp>>,
+    user_number: UserNumber,
+    seed_hash: Hash,
+    expiration: Timestamp,
+) {
+    let now = time();
+    let anchor_seeds = delegation_seeds.entry(user_number).or_default();
+    anchor_seeds.retain(|_, expires_at| *expires_at > now);
+    let expires_at = anchor_seeds.entry(seed_hash).or_insert(expiration);
+    *expires_at = u64::max(*expires_at, expiration);
+}
+
+fn prune_expired_sessions(
+    sessions: &mut HashMap<UserNumber, Vec<SessionInfo>>,
+    user_number: UserNumber,
+) {
+    let now = time() as u64;
+    if let Some(anchor_sessions) = sessions.get_mut(&user_number) {
+        anchor_sessions.retain(|session| session.expiration > now);
+        if anchor_sessions.is_empty() {
+            sessions.remove(&user_number);
+        }
+    }
+}
+
+/// Returns up to `count` anchors starting at `start`, together with the metadata needed to
+/// restore them into a fresh canister using `import_anchors`. The result is limited to
+/// MAX_EXPORTED_ANCHORS anchors and roughly MAX_EXPORT_SIZE bytes, `next_user_number` is set
+/// if there are more anchors to export.
+#[query]
+fn export_anchors(start: UserNumber, count: u32) -> AnchorExport {
+    trap_if_not_admin();
+
+    STATE.with(|s| {
+        let storage = s.storage.borrow();
+        let header = anchor_export_header(&storage);
+        let (lo, _) = header.assigned_user_number_range;
+        let end = lo + header.num_anchors;
+        let limit = u32::min(count, MAX_EXPORTED_ANCHORS) as usize;
+
+        let mut anchors = vec![];
+        let mut size = 0;
+        let mut user_number = u64::max(start, lo);
+        while user_number < end && anchors.len() < limit && size < MAX_EXPORT_SIZE {
+            let (data, events) = match read_anchor_data(&storage, user_number) {
+                Ok(entries) => {
+                    let events: Vec<AnchorEvent> = storage
+                        .read_events(user_number)
+                        .unwrap_or_else(|err| trap_with(ApiError::ReadFailed { user_number, err }));
+                    let encode_err = |err: candid::Error| {
+                        trap(&format!(
+                            "failed to encode the anchor data of user {}: {}",
+                            user_number, err
+                        ))
+                    };
+                    (
+                        candid::encode_one(entries).unwrap_or_else(encode_err),
+                        candid::encode_one(events).unwrap_or_else(encode_err),
+                    )
+                }
+                Err(ApiError::ReadFailed {
+                    err: StorageError::AnchorDeleted(_),
+                    ..
+                }) => (vec![], vec![]),
+                Err(err) => trap_with(err),
+            };
+            size += data.len() + events.len();
+            anchors.push(ExportedAnchor {
+                user_number,
+                data: ByteBuf::from(data),
+                events: Some(ByteBuf::from(events)),
+            });
+            user_number += 1;
+        }
+
+        AnchorExport {
+            header,
+            anchors,
+            next_user_number: if user_number < end {
+                Some(user_number)
+            } else {
+                None
+            },
+        }
+    })
+}
+
+/// Restores anchors exported with `export_anchors`, e.g. into a canister with a different
+/// storage layout version. The anchors have to be imported in order, starting with the first
+/// anchor of the range. The first import adopts the anchor range and the salt of the exported
+/// canister, which is why importing is only possible into a canister without anchors of its own.
+#[update]
+fn import_anchors(header: AnchorExportHeader, anchors: Vec<ExportedAnchor>) {
+    trap_if_not_admin();
+
+    let salt: Option<Salt> = header.salt.as_ref().map(|salt| {
+        salt.as_slice()
+            .try_into()
+            .unwrap_or_else(|_| trap(&format!("invalid salt length {}", salt.len())))
+    });
+
+    STATE.with(|s| {
+        let mut storage = s.storage.borrow_mut();
+        if storage.user_count() == 0 {
+            storage.set_user_number_range(header.assigned_user_number_range);
+            // the salt may already have been initialized, e.g. by `create_challenge`, but no
+            // principal has been derived from it yet
+            if let Some(salt) = salt {
+                storage.replace_salt(salt);
+            }
+        } else if storage.assigned_user_number_range() != header.assigned_user_number_range
+            || (salt.is_some() && storage.salt().cloned() != salt)
+        {
+            trap(
+                "the anchors were exported from a different canister than the ones imported before",
+            );
+        }
 
-    STATE.with(|state| {
-        let entries = state
-            .storage
-            .borrow()
-            .read(user_number)
-            .unwrap_or_else(|err| {
+        for anchor in anchors {
+            let (lo, _) = storage.assigned_user_number_range();
+            let expected = lo + storage.user_count() as u64;
+            if anchor.user_number != expected {
                 trap(&format!(
-                    "failed to read device data of user {}: {}",
-                    user_number, err
+                    "expected Identity Anchor {} but got {}",
+                    expected, anchor.user_number
+                ));
+            }
+            if anchor.data.is_empty() {
+                let user_number = storage
+                    .allocate_user_number()
+                    .unwrap_or_else(|| trap("the assigned Identity Anchor range is exhausted"));
+                storage
+                    .delete(user_number)
+                    .unwrap_or_else(|err| trap_with(ApiError::WriteFailed { user_number, err }));
+                continue;
+            }
+            let entries: Vec<DeviceDataInternal> =
+                candid::decode_one(&anchor.data).unwrap_or_else(|err| {
+                    trap(&format!(
+                        "failed to decode device data of user {}: {}",
+                        anchor.user_number, err
+                    ))
+                });
+            let entries = check_imported_devices(entries).unwrap_or_else(|err| {
+                trap(&format!(
+                    "invalid devices of user {}: {}",
+                    anchor.user_number, err
                 ))
             });
-
-        trap_if_not_authenticated(entries.iter().map(|e| &e.pubkey));
-
-        match get_signature(
-            &state.asset_hashes.borrow(),
-            &state.sigs.borrow(),
-            session_key.clone(),
-            calculate_seed(user_number, &frontend),
-            expiration,
-        ) {
-            Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
-                delegation: Delegation {
-                    pubkey: session_key,
-                    expiration,
-                    targets: None,
-                },
-                signature: ByteBuf::from(signature),
-            }),
-            None => GetDelegationResponse::NoSuchDelegation,
+            let events: Vec<AnchorEvent> = match anchor.events {
+                Some(events) if !events.is_empty() => {
+                    candid::decode_one(&events).unwrap_or_else(|err| {
+                        trap(&format!(
+                            "failed to decode the event log of user {}: {}",
+                            anchor.user_number, err
+                        ))
+                    })
+                }
+                _ => vec![],
+            };
+            let user_number = storage
+                .allocate_user_number()
+                .unwrap_or_else(|| trap("the assigned Identity Anchor range is exhausted"));
+            write_anchor_data(s, &mut storage, user_number, entries)
+                .unwrap_or_else(|err| trap_with(err));
+            // layout version 1 keeps no event logs, see the spec
+            if !events.is_empty() && storage.version() >= 2 {
+                storage
+                    .write_events(user_number, &events)
+                    .unwrap_or_else(|err| trap_with(ApiError::WriteFailed { user_number, err }));
+            }
         }
     })
 }
 
+fn anchor_export_header(storage: &Storage<Vec<DeviceDataInternal>>) -> AnchorExportHeader {
+    AnchorExportHeader {
+        assigned_user_number_range: storage.assigned_user_number_range(),
+        num_anchors: storage.user_count() as u64,
+        salt: storage.salt().map(|salt| ByteBuf::from(salt.to_vec())),
+        storage_layout_version: storage.version(),
+    }
+}
+
 #[query]
 fn http_request(req: HttpRequest) -> HttpResponse {
     http::http_request(req)
@@ -938,30 +2342,53 @@ fn stats() -> InternetIdentityStats {
         InternetIdentityStats {
             assigned_user_number_range: storage.assigned_user_number_range(),
             users_registered: storage.user_count() as u64,
+            users_deleted: storage.deleted_count() as u64,
         }
     })
 }
 
+/// Returns the limits currently used by the canister. They can be overridden with the install
+/// or upgrade argument.
+#[query]
+fn config() -> InternetIdentityConfig {
+    STATE.with(|s| s.config.borrow().clone())
+}
+
 #[init]
 fn init(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
     STATE.with(|state| {
         if let Some(arg) = maybe_arg {
-            state
-                .storage
-                .replace(Storage::new(arg.assigned_user_number_range));
+            if let Some(range) = arg.assigned_user_number_range {
+                state.storage.replace(Storage::new(range, StableMemory));
+            }
+            if let Some(version) = arg.storage_layout_version {
+                migrate_storage_layout(&mut state.storage.borrow_mut(), version);
+            }
+            if let Some(admin) = arg.admin {
+                state.admin.replace(Some(admin));
+            }
+            if let Some(overrides) = arg.config {
+                apply_config_overrides(&mut state.config.borrow_mut(), overrides);
+            }
         }
         state.storage.borrow().flush();
-        update_root_hash(&state.asset_hashes.borrow(), &state.sigs.borrow());
+        update_root_hash(
+            &state.certified_credentials.borrow(),
+            &state.certified_devices.borrow(),
+            &state.asset_hashes.borrow(),
+            &state.revoked_sessions.borrow(),
+            &state.sigs.borrow(),
+        );
     });
 }
 
 #[post_upgrade]
-fn retrieve_data() {
+fn retrieve_data(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
     STATE.with(|s| {
         s.last_upgrade_timestamp.set(time() as u64);
-        match Storage::from_stable_memory() {
+        match Storage::from_memory(StableMemory) {
             Some(mut storage) => {
                 let (lo, hi) = storage.assigned_user_number_range();
                 let max_entries = storage.max_entries() as u64;
@@ -986,20 +2413,519 @@ fn retrieve_data() {
             }
         }
 
-        // We drop all the signatures on upgrade, users will
-        // re-request them if needed.
-        update_root_hash(&s.asset_hashes.borrow(), &s.sigs.borrow());
+        // Rebuilt unless restored with the persistent state below.
+        let (lo, _) = s.storage.borrow().assigned_user_number_range();
+        s.credential_index
+            .replace(CredentialIndex::rebuild_from(lo));
+        s.certified_credentials
+            .replace(CertifiedAnchors::rebuild_from(lo));
+        s.certified_devices
+            .replace(CertifiedAnchors::rebuild_from(lo));
+
+        // The state is restored before the overrides of the upgrade argument are applied to
+        // the restored config.
+        restore_stable_state(s);
+        restore_persistent_state(s);
+
+        if let Some(arg) = maybe_arg {
+            // The anchors are located relative to the lower bound and the upper bound was set to
+            // the maximum above, see "Initialization" in the spec.
+            if arg.assigned_user_number_range.is_some() {
+                trap("the assigned Identity Anchor range cannot be changed on upgrade");
+            }
+            if let Some(version) = arg.storage_layout_version {
+                migrate_storage_layout(&mut s.storage.borrow_mut(), version);
+            }
+            if let Some(admin) = arg.admin {
+                s.admin.replace(Some(admin));
+            }
+            if let Some(overrides) = arg.config {
+                apply_config_overrides(&mut s.config.borrow_mut(), overrides);
+            }
+        }
+
+        // Only if the credential index and the certified anchor hashes could not be restored they
+        // are rebuilt here. Anchors that are not migrated or processed here are processed by
+        // subsequent update calls.
+        process_anchors(
+            s,
+            ANCHOR_MIGRATION_UPGRADE_BATCH,
+            ANCHOR_BACKFILL_UPGRADE_BATCH,
+        );
+
+        update_root_hash(
+            &s.certified_credentials.borrow(),
+            &s.certified_devices.borrow(),
+            &s.asset_hashes.borrow(),
+            &s.revoked_sessions.borrow(),
+            &s.sigs.borrow(),
+        );
+    });
+}
+
+#[pre_upgrade]
+fn persist_data() {
+    STATE.with(|s| {
+        // Trapping makes the upgrade fail, which keeps the current release running with this
+        // state intact.
+        let stable_state = StableState {
+            admin: s.admin.borrow().clone(),
+            config: config_overrides(s.config.borrow().clone()),
+            recovery_restricted_anchors: s.recovery_restricted_anchors.borrow().clone(),
+            pending_anchor_deletions: s.pending_anchor_deletions.borrow().clone(),
+            registration_failures: s.registration_failures.borrow().clone(),
+            locked_anchors: s.locked_anchors.borrow().clone(),
+        };
+        s.storage
+            .borrow()
+            .write_stable_state(&stable_state)
+            .unwrap_or_else(|err| trap(&format!("failed to write the stable state: {}", err)));
+
+        let state = PersistentState {
+            inflight_challenges: s.inflight_challenges.borrow().clone(),
+            tentative_device_registrations: s
+                .tentative_device_registrations
+                .borrow()
+                .iter()
+                .map(|(user_number, registration)| {
+                    (
+                        *user_number,
+                        PersistentTentativeDeviceRegistration {
+                            expiration: registration.expiration,
+                            tentative_devices: Some(registration.tentative_devices.clone()),
+                        },
+                    )
+                })
+                .collect(),
+            usage_metrics: s.usage_metrics.borrow().clone(),
+            signatures: s
+                .sigs
+                .borrow()
+                .entries()
+                .map(|(seed_hash, msg_hash, expires_at)| PersistentSignature {
+                    seed_hash: ByteBuf::from(seed_hash.to_vec()),
+                    msg_hash: ByteBuf::from(msg_hash.to_vec()),
+                    expires_at,
+                })
+                .collect(),
+            sessions: Some(active_sessions(&s.sessions.borrow())),
+            revoked_sessions: Some(
+                s.revoked_sessions
+                    .borrow()
+                    .entries()
+                    .map(
+                        |(seed_hash, session_key_hash, expires_at)| PersistentRevocation {
+                            seed_hash: Some(ByteBuf::from(seed_hash.to_vec())),
+                            session_key_hash: ByteBuf::from(
+                                session_key_hash
+                                    .map(|hash| hash.to_vec())
+                                    .unwrap_or_default(),
+                            ),
+                            expires_at,
+                        },
+                    )
+                    .collect(),
+            ),
+            registration_rate_limit: Some(s.registration_rate_limit.borrow().clone()),
+            anchor_indexes: Some(persistent_anchor_indexes(s)),
+            delegation_seeds: Some(active_delegation_seeds(&s.delegation_seeds.borrow())),
+        };
+
+        // Trapping here would make the canister impossible to upgrade, so the state is
+        // dropped instead if it cannot be written.
+        let storage = s.storage.borrow();
+        match candid::encode_one(state) {
+            Ok(bytes) => {
+                if storage.write_persistent_state(&bytes).is_err() {
+                    storage.clear_persistent_state();
+                }
+            }
+            Err(_) => storage.clear_persistent_state(),
+        }
     });
 }
 
+fn persistent_anchor_indexes(s: &State) -> PersistentAnchorIndexes {
+    let storage = s.storage.borrow();
+    let (lo, _) = storage.assigned_user_number_range();
+    let user_count = storage.user_count() as u64;
+    let hashes = |certified_anchors: &CertifiedAnchors| {
+        (lo..lo + user_count)
+            .filter_map(|user_number| {
+                certified_anchors
+                    .get(user_number)
+                    .map(|hash| (user_number, ByteBuf::from(hash.to_vec())))
+            })
+            .collect()
+    };
+    let credential_index = s.credential_index.borrow();
+    PersistentAnchorIndexes {
+        user_count,
+        credential_index: credential_index
+            .entries()
+            .map(|(credential_id, anchors)| {
+                (credential_id.clone(), anchors.iter().cloned().collect())
+            })
+            .collect(),
+        certified_credentials: hashes(&s.certified_credentials.borrow()),
+        certified_devices: hashes(&s.certified_devices.borrow()),
+        backfill_cursor: credential_index.backfill_cursor(),
+    }
+}
+
+/// Restores the credential index and the certified anchor hashes persisted by
+/// [persistent_anchor_indexes], unless they were derived from a different set of anchors.
+fn restore_anchor_indexes(s: &State, indexes: PersistentAnchorIndexes) {
+    if indexes.user_count != s.storage.borrow().user_count() as u64 {
+        return;
+    }
+    let hashes = |hashes: Vec<(UserNumber, ByteBuf)>| {
+        hashes
+            .into_iter()
+            .filter_map(|(user_number, hash)| {
+                let hash: Hash = hash.as_slice().try_into().ok()?;
+                Some((user_number, hash))
+            })
+            .collect::<Vec<_>>()
+    };
+    s.credential_index.replace(CredentialIndex::restore(
+        indexes.credential_index,
+        indexes.backfill_cursor,
+    ));
+    s.certified_credentials.replace(CertifiedAnchors::restore(
+        hashes(indexes.certified_credentials),
+        indexes.backfill_cursor,
+    ));
+    s.certified_devices.replace(CertifiedAnchors::restore(
+        hashes(indexes.certified_devices),
+        indexes.backfill_cursor,
+    ));
+}
+
+/// Returns the delegation seeds that have not expired yet, see [active_sessions].
+fn active_delegation_seeds(
+    delegation_seeds: &HashMap<UserNumber, HashMap<Hash, Timestamp>>,
+) -> Vec<PersistentDelegationSeed> {
+    let now = time();
+    delegation_seeds
+        .iter()
+        .flat_map(|(user_number, anchor_seeds)| {
+            anchor_seeds
+                .iter()
+                .filter(move |(_, expires_at)| **expires_at > now)
+                .map(move |(seed_hash, expires_at)| PersistentDelegationSeed {
+                    user_number: *user_number,
+                    seed_hash: ByteBuf::from(seed_hash.to_vec()),
+                    expires_at: *expires_at,
+                })
+        })
+        .collect()
+}
+
+/// Returns the sessions that have not expired yet, so that expired sessions of inactive
+/// anchors do not pile up across upgrades.
+fn active_sessions(
+    sessions: &HashMap<UserNumber, Vec<SessionInfo>>,
+) -> HashMap<UserNumber, Vec<SessionInfo>> {
+    let now = time() as u64;
+    sessions
+        .iter()
+        .filter_map(|(user_number, anchor_sessions)| {
+            let active: Vec<SessionInfo> = anchor_sessions
+                .iter()
+                .filter(|session| session.expiration > now)
+                .cloned()
+                .collect();
+            if active.is_empty() {
+                None
+            } else {
+                Some((*user_number, active))
+            }
+        })
+        .collect()
+}
+
+/// Restores the [StableState] written by the pre_upgrade hook of a previous release, if any.
+///
+/// Traps (i.e. fails the upgrade) if the state cannot be read, as continuing without it would
+/// e.g. reset the config and unlock the locked anchors.
+fn restore_stable_state(s: &State) {
+    let state: StableState = match s.storage.borrow().read_stable_state() {
+        Ok(Some(state)) => state,
+        Ok(None) => return,
+        Err(err) => trap(&format!("failed to read the stable state: {}", err)),
+    };
+    s.admin.replace(state.admin);
+    // The config was validated when it was set. It is not validated again, so that stricter
+    // validation rules of a new release cannot make the upgrade fail.
+    let mut config = default_config();
+    set_config_overrides(&mut config, state.config);
+    s.config.replace(config);
+    s.recovery_restricted_anchors
+        .replace(state.recovery_restricted_anchors);
+    s.pending_anchor_deletions
+        .replace(state.pending_anchor_deletions);
+    s.registration_failures.replace(state.registration_failures);
+    s.locked_anchors.replace(state.locked_anchors);
+}
+
+/// Restores the state written by the pre_upgrade hook of the previous release, if any.
+/// If the state cannot be decoded the canister starts with an empty state and users will
+/// have to restart their flows or re-request their delegations.
+fn restore_persistent_state(s: &State) {
+    let storage = s.storage.borrow();
+    let bytes = match storage.read_persistent_state() {
+        Some(bytes) => bytes,
+        None => return,
+    };
+    // Make sure the state is not restored again if the next upgrade comes from a
+    // release that does not persist its state.
+    storage.clear_persistent_state();
+
+    let state: PersistentState = match candid::decode_one(&bytes) {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    s.inflight_challenges.replace(state.inflight_challenges);
+    s.tentative_device_registrations.replace(
+        state
+            .tentative_device_registrations
+            .into_iter()
+            .map(|(user_number, registration)| {
+                (
+                    user_number,
+                    TentativeDeviceRegistration {
+                        expiration: registration.expiration,
+                        tentative_devices: registration.tentative_devices.unwrap_or_default(),
+                    },
+                )
+            })
+            .collect(),
+    );
+    s.usage_metrics.replace(state.usage_metrics);
+
+    s.sessions.replace(state.sessions.unwrap_or_default());
+    let mut delegation_seeds = s.delegation_seeds.borrow_mut();
+    for seed in state.delegation_seeds.unwrap_or_default() {
+        if let Ok(seed_hash) = seed.seed_hash.as_slice().try_into() {
+            add_delegation_seed(
+                &mut delegation_seeds,
+                seed.user_number,
+                seed_hash,
+                seed.expires_at,
+            );
+        }
+    }
+    if let Some(registration_rate_limit) = state.registration_rate_limit {
+        s.registration_rate_limit.replace(registration_rate_limit);
+    }
+    if let Some(anchor_indexes) = state.anchor_indexes {
+        restore_anchor_indexes(s, anchor_indexes);
+    }
+
+    let mut revoked_sessions = s.revoked_sessions.borrow_mut();
+    for revocation in state.revoked_sessions.unwrap_or_default() {
+        let seed_hash: Option<Hash> = revocation
+            .seed_hash
+            .and_then(|seed_hash| seed_hash.as_slice().try_into().ok());
+        let session_key_hash: Result<Hash, _> = revocation.session_key_hash.as_slice().try_into();
+        match (seed_hash, session_key_hash) {
+            (Some(seed_hash), _) if revocation.session_key_hash.is_empty() => {
+                revoked_sessions.revoke_seed(seed_hash, revocation.expires_at)
+            }
+            (Some(seed_hash), Ok(session_key_hash)) => {
+                revoked_sessions.revoke(seed_hash, session_key_hash, revocation.expires_at)
+            }
+            _ => {}
+        }
+    }
+
+    let mut sigs = s.sigs.borrow_mut();
+    for signature in state.signatures {
+        let seed_hash: Result<Hash, _> = signature.seed_hash.as_slice().try_into();
+        let msg_hash: Result<Hash, _> = signature.msg_hash.as_slice().try_into();
+        if let (Ok(seed_hash), Ok(msg_hash)) = (seed_hash, msg_hash) {
+            sigs.put(seed_hash, msg_hash, signature.expires_at);
+        }
+    }
+}
+
+fn default_config() -> InternetIdentityConfig {
+    InternetIdentityConfig {
+        max_entries_per_user: MAX_ENTRIES_PER_USER,
+        max_expiration_period_ns: MAX_EXPIRATION_PERIOD_NS,
+        default_expiration_period_ns: DEFAULT_EXPIRATION_PERIOD_NS,
+        captcha_challenge_lifetime_ns: CAPTCHA_CHALLENGE_LIFETIME,
+        max_inflight_challenges: MAX_INFLIGHT_CHALLENGES,
+        registration_mode_duration_ns: REGISTRATION_MODE_DURATION,
+        max_device_registration_attempts: MAX_DEVICE_REGISTRATION_ATTEMPTS,
+        registration_rate_limit: RateLimitConfig {
+            time_per_token_ns: REGISTRATION_RATE_LIMIT_TIME_PER_TOKEN_NS,
+            max_tokens: REGISTRATION_RATE_LIMIT_MAX_TOKENS,
+        },
+        challenge: ChallengeConfig::Captcha,
+        device_protection_policy: DeviceProtectionPolicy::RecoveryPhrasesOnly,
+        verification_code_format: VerificationCodeFormat::Decimal { digits: 6 },
+        alternative_origins_canisters: vec![],
+    }
+}
+
+/// Returns overrides that set every limit to the value of the given config.
+fn config_overrides(config: InternetIdentityConfig) -> InternetIdentityConfigOverrides {
+    InternetIdentityConfigOverrides {
+        max_entries_per_user: Some(config.max_entries_per_user),
+        max_expiration_period_ns: Some(config.max_expiration_period_ns),
+        default_expiration_period_ns: Some(config.default_expiration_period_ns),
+        captcha_challenge_lifetime_ns: Some(config.captcha_challenge_lifetime_ns),
+        max_inflight_challenges: Some(config.max_inflight_challenges),
+        registration_mode_duration_ns: Some(config.registration_mode_duration_ns),
+        max_device_registration_attempts: Some(config.max_device_registration_attempts),
+        registration_rate_limit: Some(config.registration_rate_limit),
+        challenge: Some(config.challenge),
+        device_protection_policy: Some(config.device_protection_policy),
+        verification_code_format: Some(config.verification_code_format),
+        alternative_origins_canisters: Some(config.alternative_origins_canisters),
+    }
+}
+
+/// Applies the overrides of the install or upgrade argument to the config.
+///
+/// Traps (i.e. fails the install or upgrade) if the resulting config is inconsistent.
+fn apply_config_overrides(
+    config: &mut InternetIdentityConfig,
+    overrides: InternetIdentityConfigOverrides,
+) {
+    set_config_overrides(config, overrides);
+    validate_config(config);
+}
+
+/// Sets the fields of the config that are overridden, without validating the resulting config.
+fn set_config_overrides(
+    config: &mut InternetIdentityConfig,
+    overrides: InternetIdentityConfigOverrides,
+) {
+    if let Some(max_entries_per_user) = overrides.max_entries_per_user {
+        config.max_entries_per_user = max_entries_per_user;
+    }
+    if let Some(max_expiration_period_ns) = overrides.max_expiration_period_ns {
+        config.max_expiration_period_ns = max_expiration_period_ns;
+    }
+    if let Some(default_expiration_period_ns) = overrides.default_expiration_period_ns {
+        config.default_expiration_period_ns = default_expiration_period_ns;
+    }
+    if let Some(captcha_challenge_lifetime_ns) = overrides.captcha_challenge_lifetime_ns {
+        config.captcha_challenge_lifetime_ns = captcha_challenge_lifetime_ns;
+    }
+    if let Some(max_inflight_challenges) = overrides.max_inflight_challenges {
+        config.max_inflight_challenges = max_inflight_challenges;
+    }
+    if let Some(registration_mode_duration_ns) = overrides.registration_mode_duration_ns {
+        config.registration_mode_duration_ns = registration_mode_duration_ns;
+    }
+    if let Some(max_device_registration_attempts) = overrides.max_device_registration_attempts {
+        config.max_device_registration_attempts = max_device_registration_attempts;
+    }
+    if let Some(registration_rate_limit) = overrides.registration_rate_limit {
+        config.registration_rate_limit = registration_rate_limit;
+    }
+    if let Some(challenge) = overrides.challenge {
+        config.challenge = challenge;
+    }
+    if let Some(device_protection_policy) = overrides.device_protection_policy {
+        config.device_protection_policy = device_protection_policy;
+    }
+    if let Some(verification_code_format) = overrides.verification_code_format {
+        config.verification_code_format = verification_code_format;
+    }
+    if let Some(alternative_origins_canisters) = overrides.alternative_origins_canisters {
+        config.alternative_origins_canisters = alternative_origins_canisters;
+    }
+}
+
+/// Traps if the config is inconsistent.
+fn validate_config(config: &InternetIdentityConfig) {
+    if config.max_entries_per_user == 0 {
+        trap("invalid config: max_entries_per_user must be at least 1");
+    }
+    if config.max_device_registration_attempts == 0 {
+        trap("invalid config: max_device_registration_attempts must be at least 1");
+    }
+    if config.default_expiration_period_ns > config.max_expiration_period_ns {
+        trap("invalid config: default_expiration_period_ns exceeds max_expiration_period_ns");
+    }
+    if config.registration_mode_duration_ns > MAX_REGISTRATION_MODE_DURATION {
+        trap(&format!(
+            "invalid config: registration_mode_duration_ns must be at most {}",
+            MAX_REGISTRATION_MODE_DURATION
+        ));
+    }
+    let verification_code_length = match config.verification_code_format {
+        VerificationCodeFormat::Decimal { digits } => digits,
+        VerificationCodeFormat::Alphanumeric { length } => length,
+    };
+    if verification_code_length < MIN_VERIFICATION_CODE_LENGTH
+        || verification_code_length > MAX_VERIFICATION_CODE_LENGTH
+    {
+        trap(&format!(
+            "invalid config: verification codes must have between {} and {} characters",
+            MIN_VERIFICATION_CODE_LENGTH, MAX_VERIFICATION_CODE_LENGTH
+        ));
+    }
+    // a bucket without tokens would reject all registrations
+    if config.registration_rate_limit.max_tokens == 0 {
+        trap("invalid config: registration_rate_limit.max_tokens must be at least 1");
+    }
+    if let ChallengeConfig::ProofOfWork { difficulty } = config.challenge {
+        if difficulty < proof_of_work::MIN_DIFFICULTY || difficulty > proof_of_work::MAX_DIFFICULTY
+        {
+            trap(&format!(
+                "invalid config: proof of work difficulty must be between {} and {}",
+                proof_of_work::MIN_DIFFICULTY,
+                proof_of_work::MAX_DIFFICULTY
+            ));
+        }
+    }
+    if config.alternative_origins_canisters.len()
+        > alternative_origins::MAX_ALTERNATIVE_ORIGINS_CANISTERS
+    {
+        trap(&format!(
+            "invalid config: at most {} canisters can provide alternative origins",
+            alternative_origins::MAX_ALTERNATIVE_ORIGINS_CANISTERS
+        ));
+    }
+}
+
+/// Brings the storage to the requested layout version.
+/// Only the migration from layout version 1 to 2 is supported, there is no way back.
+fn migrate_storage_layout(storage: &mut Storage<Vec<DeviceDataInternal>>, version: u8) {
+    match (storage.version(), version) {
+        (current, requested) if current == requested => (),
+        (1, 2) => storage.migrate_to_v2(),
+        (current, requested) => trap(&format!(
+            "cannot migrate storage from layout version {} to {}",
+            current, requested
+        )),
+    }
+}
+
 fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash {
-    let salt = STATE
+    calculate_seed_with_salt(&salt(), user_number, frontend)
+}
+
+fn salt() -> Salt {
+    STATE
         .with(|s| s.storage.borrow().salt().cloned())
-        .unwrap_or_else(|| trap("Salt is not set. Try calling init_salt() to set it"));
+        .unwrap_or_else(|| trap("Salt is not set. Try calling init_salt() to set it"))
+}
 
+fn calculate_seed_with_salt(
+    salt: &Salt,
+    user_number: UserNumber,
+    frontend: &FrontendHostname,
+) -> Hash {
     let mut blob: Vec<u8> = vec![];
     blob.push(salt.len() as u8);
-    blob.extend_from_slice(&salt);
+    blob.extend_from_slice(salt);
 
     let user_number_str = user_number.to_string();
     let user_number_blob = user_number_str.bytes();
@@ -1037,6 +2963,20 @@ fn der_encode_canister_sig_key(seed: Vec<u8>) -> Vec<u8> {
     der
 }
 
+/// Returns the seed of a user key returned by `prepare_delegation`, or None if the key is not a
+/// canister signature public key of this canister.
+fn seed_of_user_key(user_key: &[u8]) -> Option<Hash> {
+    let seed: Hash = user_key
+        .len()
+        .checked_sub(32)
+        .and_then(|start| user_key[start..].try_into().ok())?;
+    if der_encode_canister_sig_key(seed.to_vec()) == user_key {
+        Some(seed)
+    } else {
+        None
+    }
+}
+
 fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
     use hash::Value;
 
@@ -1054,23 +2994,50 @@ fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
     hash::hash_with_domain(b"ic-request-auth-delegation", &map_hash)
 }
 
-fn update_root_hash(a: &AssetHashes, m: &SignatureMap) {
+fn update_root_hash(
+    c: &CertifiedAnchors,
+    d: &CertifiedAnchors,
+    a: &AssetHashes,
+    r: &RevocationList,
+    m: &SignatureMap,
+) {
     use ic_certified_map::{fork_hash, labeled_hash};
 
     let prefixed_root_hash = fork_hash(
         // NB: Labels added in lexicographic order
-        &labeled_hash(LABEL_ASSETS, &a.root_hash()),
-        &labeled_hash(LABEL_SIG, &m.root_hash()),
+        &certified_anchors_hash(c, d),
+        &fork_hash(
+            &labeled_hash(LABEL_ASSETS, &a.root_hash()),
+            &fork_hash(
+                &labeled_hash(LABEL_REVOKED, &r.root_hash()),
+                &labeled_hash(LABEL_SIG, &m.root_hash()),
+            ),
+        ),
     );
     set_certified_data(&prefixed_root_hash[..]);
 }
 
+/// The hash of the subtree of the certified anchor hashes (labels "credentials" and "devices"),
+/// which witnesses for the other subtrees contain in pruned form.
+fn certified_anchors_hash(c: &CertifiedAnchors, d: &CertifiedAnchors) -> Hash {
+    use ic_certified_map::{fork_hash, labeled_hash};
+
+    fork_hash(
+        &labeled_hash(LABEL_CREDENTIALS, &c.root_hash()),
+        &labeled_hash(LABEL_DEVICES, &d.root_hash()),
+    )
+}
+
 fn get_signature(
+    certified_credentials: &CertifiedAnchors,
+    certified_devices: &CertifiedAnchors,
     asset_hashes: &AssetHashes,
+    revoked_sessions: &RevocationList,
     sigs: &SignatureMap,
     pk: PublicKey,
     seed: Hash,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
 ) -> Option<Vec<u8>> {
     let certificate = data_certificate().unwrap_or_else(|| {
         trap("data certificate is only available in query calls");
@@ -1078,7 +3045,7 @@ fn get_signature(
     let msg_hash = delegation_signature_msg_hash(&Delegation {
         pubkey: pk,
         expiration,
-        targets: None,
+        targets,
     });
     let witness = sigs.witness(hash::hash_bytes(seed), msg_hash)?;
 
@@ -1093,11 +3060,23 @@ fn get_signature(
     }
 
     let tree = ic_certified_map::fork(
-        HashTree::Pruned(ic_certified_map::labeled_hash(
-            LABEL_ASSETS,
-            &asset_hashes.root_hash(),
+        HashTree::Pruned(certified_anchors_hash(
+            certified_credentials,
+            certified_devices,
         )),
-        ic_certified_map::labeled(&LABEL_SIG[..], witness),
+        ic_certified_map::fork(
+            HashTree::Pruned(ic_certified_map::labeled_hash(
+                LABEL_ASSETS,
+                &asset_hashes.root_hash(),
+            )),
+            ic_certified_map::fork(
+                HashTree::Pruned(ic_certified_map::labeled_hash(
+                    LABEL_REVOKED,
+                    &revoked_sessions.root_hash(),
+                )),
+                ic_certified_map::labeled(&LABEL_SIG[..], witness),
+            ),
+        ),
     );
 
     #[derive(Serialize)]
@@ -1117,11 +3096,17 @@ fn get_signature(
     Some(cbor.into_inner())
 }
 
-fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration: Timestamp) {
+fn add_signature(
+    sigs: &mut SignatureMap,
+    pk: PublicKey,
+    seed: Hash,
+    expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+) {
     let msg_hash = delegation_signature_msg_hash(&Delegation {
         pubkey: pk,
         expiration,
-        targets: None,
+        targets,
     });
     let expires_at = (time() as u64).saturating_add(DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS);
     sigs.put(hash::hash_bytes(seed), msg_hash, expires_at);
@@ -1132,45 +3117,205 @@ fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration:
 /// This function is supposed to piggy back on update calls to
 /// amortize the cost of tree pruning.  Each operation on the signature map
 /// will prune at most MAX_SIGS_TO_PRUNE other signatures.
-fn prune_expired_signatures(asset_hashes: &AssetHashes, sigs: &mut SignatureMap) {
+fn prune_expired_signatures(
+    certified_credentials: &CertifiedAnchors,
+    certified_devices: &CertifiedAnchors,
+    asset_hashes: &AssetHashes,
+    revoked_sessions: &RevocationList,
+    sigs: &mut SignatureMap,
+) {
     const MAX_SIGS_TO_PRUNE: usize = 10;
     let num_pruned = sigs.prune_expired(time() as u64, MAX_SIGS_TO_PRUNE);
 
     if num_pruned > 0 {
-        update_root_hash(asset_hashes, sigs);
+        update_root_hash(
+            certified_credentials,
+            certified_devices,
+            asset_hashes,
+            revoked_sessions,
+            sigs,
+        );
+    }
+}
+
+/// Removes a batch of revocations whose delegations have expired from the revocation list.
+///
+/// Like [prune_expired_signatures] this piggy backs on update calls.
+fn prune_expired_revocations(
+    certified_credentials: &CertifiedAnchors,
+    certified_devices: &CertifiedAnchors,
+    asset_hashes: &AssetHashes,
+    revoked_sessions: &mut RevocationList,
+    sigs: &SignatureMap,
+) {
+    const MAX_REVOCATIONS_TO_PRUNE: usize = 10;
+    let num_pruned = revoked_sessions.prune_expired(time() as u64, MAX_REVOCATIONS_TO_PRUNE);
+
+    if num_pruned > 0 {
+        update_root_hash(
+            certified_credentials,
+            certified_devices,
+            asset_hashes,
+            revoked_sessions,
+            sigs,
+        );
+    }
+}
+
+/// Traps if the caller is not the `admin` set with the install or upgrade argument.
+fn trap_if_not_admin() {
+    let is_admin = STATE.with(|s| s.admin.borrow().as_ref() == Some(&caller()));
+    if !is_admin {
+        trap(&format!(
+            "{} is not authorized to call this method",
+            caller()
+        ));
+    }
+}
+
+/// Authenticates the caller against the devices of the anchor. Returns the devices of the anchor,
+/// with the usage of the device the caller authenticated with set to now, and the key of that
+/// device.
+///
+/// The usage is not written here, so that it is only persisted if the call succeeds: calls that
+/// change the devices write it with the change (see [write_anchor_data]), other calls write it
+/// with [record_usage]. Queries use [check_authentication] as they cannot persist the usage.
+fn authenticate(
+    storage: &Storage<Vec<DeviceDataInternal>>,
+    user_number: UserNumber,
+) -> Result<(Vec<DeviceDataInternal>, DeviceKey), ApiError> {
+    let mut entries = read_anchor_data(storage, user_number)?;
+    let caller = caller();
+    let device = entries
+        .iter_mut()
+        .find(|e| caller == Principal::self_authenticating(&e.pubkey))
+        .ok_or(ApiError::NotAuthenticated(caller))?;
+    device.last_usage = Some(time());
+    let device_key = device.pubkey.clone();
+    Ok((entries, device_key))
+}
+
+/// Writes the usage recorded by [authenticate] for a call that succeeded without changing the
+/// devices of the anchor. The usage is not certified, so the certified hashes stay valid.
+fn record_usage(
+    storage: &mut Storage<Vec<DeviceDataInternal>>,
+    user_number: UserNumber,
+    entries: Vec<DeviceDataInternal>,
+) -> Result<(), ApiError> {
+    storage
+        .write(user_number, entries)
+        .map_err(|err| ApiError::WriteFailed { user_number, err })
+}
+
+/// Like [authenticate], but fails if the anchor is locked (see `lock_anchor`)
+/// and the caller is not authenticated with a recovery device.
+///
+/// NOTE: all calls that change the devices of an anchor or issue delegations for it should use
+/// this function because it enforces the lock.
+fn authenticate_unless_locked(
+    state: &State,
+    user_number: UserNumber,
+) -> Result<(Vec<DeviceDataInternal>, DeviceKey), ApiError> {
+    let (entries, acting_device) = authenticate(&state.storage.borrow(), user_number)?;
+    if state.locked_anchors.borrow().contains_key(&user_number)
+        && !is_recovery_device(&entries, &acting_device)
+    {
+        return Err(ApiError::AnchorLocked(user_number));
+    }
+    Ok((entries, acting_device))
+}
+
+fn is_recovery_device(entries: &[DeviceDataInternal], device_key: &DeviceKey) -> bool {
+    entries
+        .iter()
+        .any(|e| &e.pubkey == device_key && e.purpose == Some(Purpose::Recovery))
+}
+
+fn has_recovery_device(entries: &[DeviceDataInternal]) -> bool {
+    entries.iter().any(|e| e.purpose == Some(Purpose::Recovery))
+}
+
+/// Appends an event to the event log of the anchor. Event logs are only kept with storage layout
+/// version 2.
+///
+/// Traps if the event log cannot be written so that the change it records is rolled back as well.
+fn record_event(
+    storage: &mut Storage<Vec<DeviceDataInternal>>,
+    user_number: UserNumber,
+    event_type: AnchorEventType,
+    acting_device: DeviceKey,
+    affected_device: Option<DeviceKey>,
+) {
+    if storage.version() < 2 {
+        return;
     }
+    let mut events = storage
+        .read_events(user_number)
+        .unwrap_or_else(|err| trap_with(ApiError::ReadFailed { user_number, err }));
+    event_log::append(
+        &mut events,
+        event_type,
+        time(),
+        acting_device,
+        affected_device,
+    );
+    storage
+        .write_events(user_number, &events)
+        .unwrap_or_else(|err| trap_with(ApiError::WriteFailed { user_number, err }));
 }
 
 // Checks if the caller is authenticated against any of the public keys provided
 // and traps if not.
 fn trap_if_not_authenticated<'a>(public_keys: impl Iterator<Item = &'a PublicKey>) {
+    check_authentication(public_keys).unwrap_or_else(|err| trap_with(err))
+}
+
+fn check_authentication<'a>(
+    public_keys: impl Iterator<Item = &'a PublicKey>,
+) -> Result<(), ApiError> {
     for pk in public_keys {
         if caller() == Principal::self_authenticating(pk) {
-            return;
+            return Ok(());
         }
     }
-    ic_cdk::trap(&format!("{} could not be authenticated.", caller()))
+    Err(ApiError::NotAuthenticated(caller()))
 }
 
 /// This checks some device invariants, in particular:
 ///   * Sizes of various fields do not exceed limits
-///   * Only recovery phrases can be protected
+///   * Only the key types allowed by the configured [DeviceProtectionPolicy] can be protected
 ///   * There can only be one recovery phrase
+///   * The credential ID is not used by another device of the anchor
 ///
-///  Otherwise, trap.
+///  Otherwise, returns an error.
 ///
-///  NOTE: while in the future we may lift this restriction, for now we do ensure that
-///  protected devices are limited to recovery phrases, which the webapp expects.
-fn check_device(device_data: &DeviceData, existing_devices: &[DeviceDataInternal]) {
-    check_entry_limits(device_data);
-
-    if device_data.protection == DeviceProtection::Protected
-        && device_data.key_type != KeyType::SeedPhrase
-    {
-        trap(&format!(
-            "Only recovery phrases can be protected but key type is {:?}",
-            device_data.key_type
-        ));
+///  NOTE: the policy defaults to protecting recovery phrases only, which the webapp expects.
+///  Protection is independent of the purpose: a protected security key can be used for
+///  authentication or for recovery, either way it can only be updated or removed by itself.
+fn check_device(
+    device_data: &DeviceData,
+    existing_devices: &[DeviceDataInternal],
+) -> Result<(), ApiError> {
+    check_entry_limits(device_data)?;
+
+    if device_data.protection == DeviceProtection::Protected {
+        let policy = STATE.with(|s| s.config.borrow().device_protection_policy.clone());
+        match (policy, &device_data.key_type) {
+            (_, KeyType::SeedPhrase) => (),
+            (DeviceProtectionPolicy::RecoveryPhrasesAndSecurityKeys, KeyType::CrossPlatform) => (),
+            (DeviceProtectionPolicy::RecoveryPhrasesOnly, key_type) => {
+                return Err(ApiError::InvalidDevice(format!(
+                    "Only recovery phrases can be protected but key type is {:?}",
+                    key_type
+                )))
+            }
+            (DeviceProtectionPolicy::RecoveryPhrasesAndSecurityKeys, key_type) => {
+                return Err(ApiError::InvalidDevice(format!(
+                    "Only recovery phrases and security keys can be protected but key type is {:?}",
+                    key_type
+                )))
+            }
+        }
     }
 
     // if the device is a recovery phrase, check if a different recovery phrase already exists
@@ -1180,29 +3325,50 @@ fn check_device(device_data: &DeviceData, existing_devices: &[DeviceDataInternal
                 && existing_device.key_type == Some(KeyType::SeedPhrase)
         })
     {
-        trap("There is already a recovery phrase and only one is allowed.");
+        return Err(ApiError::InvalidDevice(
+            "There is already a recovery phrase and only one is allowed.".to_string(),
+        ));
+    }
+
+    // the credential ID has to identify the device, see lookup_by_credential_id
+    if let Some(credential_id) = &device_data.credential_id {
+        if existing_devices.iter().any(|existing_device| {
+            existing_device.pubkey != device_data.pubkey
+                && existing_device.credential_id.as_ref() == Some(credential_id)
+        }) {
+            return Err(ApiError::InvalidDevice(
+                "There is already a device with the same credential ID.".to_string(),
+            ));
+        }
     }
+    Ok(())
 }
 
-fn check_entry_limits(device_data: &DeviceData) {
+/// Parses the public key of a new device, rejecting malformed keys and keys of unsupported
+/// algorithms.
+fn check_public_key(pubkey: &DeviceKey) -> Result<KeyAlgorithm, ApiError> {
+    parse_public_key(pubkey).map_err(|err| ApiError::InvalidDevice(err.to_string()))
+}
+
+fn check_entry_limits(device_data: &DeviceData) -> Result<(), ApiError> {
     const ALIAS_LEN_LIMIT: usize = 64;
     const PK_LEN_LIMIT: usize = 300;
     const CREDENTIAL_ID_LEN_LIMIT: usize = 200;
 
     let n = device_data.alias.len();
     if n > ALIAS_LEN_LIMIT {
-        trap(&format!(
+        return Err(ApiError::InvalidDevice(format!(
             "alias length {} exceeds the limit of {} bytes",
             n, ALIAS_LEN_LIMIT,
-        ));
+        )));
     }
 
     let n = device_data.pubkey.len();
     if n > PK_LEN_LIMIT {
-        trap(&format!(
+        return Err(ApiError::InvalidDevice(format!(
             "public key length {} exceeds the limit of {} bytes",
             n, PK_LEN_LIMIT,
-        ));
+        )));
     }
 
     let n = device_data
@@ -1211,23 +3377,41 @@ fn check_entry_limits(device_data: &DeviceData) {
         .map(|bytes| bytes.len())
         .unwrap_or_default();
     if n > CREDENTIAL_ID_LEN_LIMIT {
-        trap(&format!(
+        return Err(ApiError::InvalidDevice(format!(
             "credential id length {} exceeds the limit of {} bytes",
             n, CREDENTIAL_ID_LEN_LIMIT,
-        ));
+        )));
     }
+    Ok(())
 }
 
-fn check_frontend_length(frontend: &FrontendHostname) {
+fn check_frontend_length(frontend: &FrontendHostname) -> Result<(), ApiError> {
     const FRONTEND_HOSTNAME_LIMIT: usize = 255;
 
     let n = frontend.len();
     if frontend.len() > FRONTEND_HOSTNAME_LIMIT {
-        trap(&format!(
+        return Err(ApiError::InvalidArgument(format!(
             "frontend hostname {} exceeds the limit of {} bytes",
             n, FRONTEND_HOSTNAME_LIMIT,
-        ));
+        )));
+    }
+    Ok(())
+}
+
+fn check_targets_length(targets: &Option<Vec<Principal>>) -> Result<(), ApiError> {
+    const DELEGATION_TARGETS_LIMIT: usize = 1000;
+
+    let n = targets
+        .as_ref()
+        .map(|targets| targets.len())
+        .unwrap_or_default();
+    if n > DELEGATION_TARGETS_LIMIT {
+        return Err(ApiError::InvalidArgument(format!(
+            "number of delegation targets {} exceeds the limit of {}",
+            n, DELEGATION_TARGETS_LIMIT,
+        )));
     }
+    Ok(())
 }
 
 // Checks if salt is empty and calls `init_salt` to set it.
@@ -0,0 +1,43 @@
+//! Hashcash-style proof of work, an alternative to the captcha challenge.
+//!
+//! A solution to the challenge with key `k` and difficulty `d` is a nonce `n` such that the
+//! SHA-256 hash of the UTF-8 string `k:n` starts with (at least) `d` zero bits. On average,
+//! `2^d` hashes have to be computed to find a solution, checking it takes a single hash.
+use sha2::{Digest, Sha256};
+
+/// Longer nonces are rejected. Solutions are found long before running out of shorter nonces.
+pub const MAX_NONCE_LENGTH: usize = 64;
+/// Lower difficulties (down to 0, which accepts any nonce) take no noticeable work to solve.
+pub const MIN_DIFFICULTY: u8 = 8;
+/// Higher difficulties cannot be solved by clients in a reasonable time.
+pub const MAX_DIFFICULTY: u8 = 32;
+
+/// Checks whether `nonce` solves the challenge with the given key and difficulty.
+pub fn is_solution(challenge_key: &str, nonce: &str, difficulty: u8) -> bool {
+    if nonce.len() > MAX_NONCE_LENGTH {
+        return false;
+    }
+    leading_zero_bits(&hash(challenge_key, nonce)) >= difficulty as u32
+}
+
+fn hash(challenge_key: &str, nonce: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(challenge_key.as_bytes());
+    hasher.update(b":");
+    hasher.update(nonce.as_bytes());
+    hasher.finalize().into()
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,42 @@
+use super::*;
+
+fn solve(challenge_key: &str, difficulty: u8) -> String {
+    (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| leading_zero_bits(&hash(challenge_key, nonce)) >= difficulty as u32)
+        .unwrap()
+}
+
+#[test]
+fn test_count_leading_zero_bits() {
+    assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+    assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+    assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+    assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+}
+
+#[test]
+fn test_accept_solution() {
+    let nonce = solve("abcdefghij", 12);
+
+    assert!(is_solution("abcdefghij", &nonce, 12));
+    assert!(is_solution("abcdefghij", &nonce, 0));
+}
+
+#[test]
+fn test_reject_insufficient_difficulty() {
+    let nonce = (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| leading_zero_bits(&hash("abcdefghij", nonce)) == 12)
+        .unwrap();
+
+    assert!(is_solution("abcdefghij", &nonce, 12));
+    assert!(!is_solution("abcdefghij", &nonce, 13));
+}
+
+#[test]
+fn test_reject_long_nonce() {
+    let nonce = "0".repeat(MAX_NONCE_LENGTH + 1);
+
+    assert!(!is_solution("abcdefghij", &nonce, 0));
+}
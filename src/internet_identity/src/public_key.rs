@@ -0,0 +1,308 @@
+//! Parsing of the public keys of devices.
+//!
+//! Device keys are DER encoded `SubjectPublicKeyInfo`s (RFC 5280): an algorithm identifier
+//! followed by the key itself. Recovery phrases use Ed25519 keys, WebAuthn authenticators use
+//! COSE keys (RFC 8152) wrapped in a `SubjectPublicKeyInfo` with the algorithm OID
+//! `1.3.6.1.4.1.56387.1.1`, plain ECDSA P-256 and RSA keys are accepted as well.
+//!
+//! Only the structure of a key is checked, not whether it is e.g. a valid curve point.
+use candid::{CandidType, Deserialize};
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+
+/// 1.2.840.10045.2.1
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.3.101.112
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+/// 1.2.840.113549.1.1.1
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// 1.3.6.1.4.1.56387.1.1
+const OID_COSE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x01];
+
+// COSE key parameters and values, see https://www.iana.org/assignments/cose/cose.xhtml
+const COSE_KTY: i128 = 1;
+const COSE_ALG: i128 = 3;
+const COSE_KTY_OKP: i128 = 1;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_KTY_RSA: i128 = 3;
+const COSE_ALG_ES256: i128 = -7;
+const COSE_ALG_EDDSA: i128 = -8;
+const COSE_ALG_RS256: i128 = -257;
+const COSE_CRV_P256: i128 = 1;
+const COSE_CRV_ED25519: i128 = 6;
+
+/// The algorithm of a device key, as detected by [parse_public_key].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum KeyAlgorithm {
+    #[serde(rename = "ecdsa_p256")]
+    EcdsaP256,
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    #[serde(rename = "rsa")]
+    Rsa,
+    #[serde(rename = "cose_ecdsa_p256")]
+    CoseEcdsaP256,
+    #[serde(rename = "cose_ed25519")]
+    CoseEd25519,
+    #[serde(rename = "cose_rsa")]
+    CoseRsa,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PublicKeyError {
+    Malformed(&'static str),
+    UnsupportedAlgorithm(String),
+}
+
+impl fmt::Display for PublicKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublicKeyError::Malformed(reason) => write!(f, "malformed public key: {}", reason),
+            PublicKeyError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported public key algorithm: {}", algorithm)
+            }
+        }
+    }
+}
+
+/// Parses a DER encoded public key and returns its algorithm.
+pub fn parse_public_key(der: &[u8]) -> Result<KeyAlgorithm, PublicKeyError> {
+    let mut reader = DerReader::new(der);
+    let mut spki = DerReader::new(reader.read(TAG_SEQUENCE)?);
+    reader.finish()?;
+
+    let mut algorithm = DerReader::new(spki.read(TAG_SEQUENCE)?);
+    let key = spki.read(TAG_BIT_STRING)?;
+    spki.finish()?;
+    let key = match key.split_first() {
+        Some((0, key)) => key,
+        _ => return Err(PublicKeyError::Malformed("bit string has unused bits")),
+    };
+
+    let oid = algorithm.read(TAG_OID)?;
+    let key_algorithm = match oid {
+        OID_EC_PUBLIC_KEY => {
+            if algorithm.read(TAG_OID)? != OID_P256 {
+                return Err(PublicKeyError::UnsupportedAlgorithm(
+                    "ECDSA with a curve other than P-256".to_string(),
+                ));
+            }
+            check_ec_point(key)?;
+            KeyAlgorithm::EcdsaP256
+        }
+        OID_ED25519 => {
+            if key.len() != 32 {
+                return Err(PublicKeyError::Malformed("Ed25519 key must be 32 bytes"));
+            }
+            KeyAlgorithm::Ed25519
+        }
+        OID_RSA_ENCRYPTION => {
+            if !algorithm.read(TAG_NULL)?.is_empty() {
+                return Err(PublicKeyError::Malformed("RSA parameters must be NULL"));
+            }
+            let mut key_reader = DerReader::new(key);
+            let mut rsa_key = DerReader::new(key_reader.read(TAG_SEQUENCE)?);
+            key_reader.finish()?;
+            rsa_key.read_positive_integer()?; // modulus
+            rsa_key.read_positive_integer()?; // public exponent
+            rsa_key.finish()?;
+            KeyAlgorithm::Rsa
+        }
+        OID_COSE => parse_cose_key(key)?,
+        oid => return Err(PublicKeyError::UnsupportedAlgorithm(oid_to_string(oid)?)),
+    };
+    algorithm.finish()?;
+    Ok(key_algorithm)
+}
+
+/// Checks that `key` is an uncompressed or compressed P-256 point.
+fn check_ec_point(key: &[u8]) -> Result<(), PublicKeyError> {
+    match key.first() {
+        Some(0x04) if key.len() == 65 => Ok(()),
+        Some(0x02) | Some(0x03) if key.len() == 33 => Ok(()),
+        _ => Err(PublicKeyError::Malformed("invalid P-256 point encoding")),
+    }
+}
+
+fn parse_cose_key(key: &[u8]) -> Result<KeyAlgorithm, PublicKeyError> {
+    let map = match serde_cbor::from_slice(key) {
+        Ok(Value::Map(map)) => map,
+        _ => return Err(PublicKeyError::Malformed("COSE key is not a CBOR map")),
+    };
+    let (key_algorithm, expected_alg) = match cose_int(&map, COSE_KTY)? {
+        Some(COSE_KTY_EC2) => {
+            if cose_int(&map, -1)? != Some(COSE_CRV_P256) {
+                return Err(PublicKeyError::UnsupportedAlgorithm(
+                    "COSE EC2 key with a curve other than P-256".to_string(),
+                ));
+            }
+            if cose_bytes(&map, -2)?.len() != 32 || cose_bytes(&map, -3)?.len() != 32 {
+                return Err(PublicKeyError::Malformed(
+                    "COSE P-256 coordinates must be 32 bytes",
+                ));
+            }
+            (KeyAlgorithm::CoseEcdsaP256, COSE_ALG_ES256)
+        }
+        Some(COSE_KTY_OKP) => {
+            if cose_int(&map, -1)? != Some(COSE_CRV_ED25519) {
+                return Err(PublicKeyError::UnsupportedAlgorithm(
+                    "COSE OKP key with a curve other than Ed25519".to_string(),
+                ));
+            }
+            if cose_bytes(&map, -2)?.len() != 32 {
+                return Err(PublicKeyError::Malformed(
+                    "COSE Ed25519 key must be 32 bytes",
+                ));
+            }
+            (KeyAlgorithm::CoseEd25519, COSE_ALG_EDDSA)
+        }
+        Some(COSE_KTY_RSA) => {
+            if cose_bytes(&map, -1)?.is_empty() || cose_bytes(&map, -2)?.is_empty() {
+                return Err(PublicKeyError::Malformed(
+                    "COSE RSA key parameters are empty",
+                ));
+            }
+            (KeyAlgorithm::CoseRsa, COSE_ALG_RS256)
+        }
+        Some(kty) => {
+            return Err(PublicKeyError::UnsupportedAlgorithm(format!(
+                "COSE key type {}",
+                kty
+            )))
+        }
+        None => return Err(PublicKeyError::Malformed("COSE key has no key type")),
+    };
+    match cose_int(&map, COSE_ALG)? {
+        Some(alg) if alg != expected_alg => Err(PublicKeyError::UnsupportedAlgorithm(format!(
+            "COSE algorithm {}",
+            alg
+        ))),
+        _ => Ok(key_algorithm),
+    }
+}
+
+fn cose_int(map: &BTreeMap<Value, Value>, label: i128) -> Result<Option<i128>, PublicKeyError> {
+    match map.get(&Value::Integer(label)) {
+        None => Ok(None),
+        Some(Value::Integer(value)) => Ok(Some(*value)),
+        Some(_) => Err(PublicKeyError::Malformed(
+            "COSE key parameter is not an integer",
+        )),
+    }
+}
+
+fn cose_bytes(map: &BTreeMap<Value, Value>, label: i128) -> Result<&[u8], PublicKeyError> {
+    match map.get(&Value::Integer(label)) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        _ => Err(PublicKeyError::Malformed("COSE key parameter is missing")),
+    }
+}
+
+/// Renders an encoded OID in the dotted notation, e.g. `1.3.101.112`.
+fn oid_to_string(oid: &[u8]) -> Result<String, PublicKeyError> {
+    const MALFORMED: PublicKeyError = PublicKeyError::Malformed("invalid OID");
+    let mut arcs: Vec<u64> = vec![];
+    let mut arc: u64 = 0;
+    let mut arc_len = 0;
+    for byte in oid {
+        // arcs must be minimally encoded and fit into a u64
+        if (arc_len == 0 && *byte == 0x80) || arc_len == 9 {
+            return Err(MALFORMED);
+        }
+        arc = arc << 7 | (byte & 0x7f) as u64;
+        arc_len += 1;
+        if byte & 0x80 == 0 {
+            if arcs.is_empty() {
+                // the first encoded arc combines the first two arcs of the OID
+                let first = u64::min(arc / 40, 2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+            arc_len = 0;
+        }
+    }
+    if arcs.is_empty() || arc_len != 0 {
+        return Err(MALFORMED);
+    }
+    Ok(arcs
+        .iter()
+        .map(|arc| arc.to_string())
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+/// Reads DER encoded values (only the subset used by public keys).
+struct DerReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Reads a value with the given tag and returns its contents.
+    fn read(&mut self, tag: u8) -> Result<&'a [u8], PublicKeyError> {
+        match self.data.first() {
+            Some(actual) if *actual == tag => (),
+            Some(_) => return Err(PublicKeyError::Malformed("unexpected DER tag")),
+            None => return Err(PublicKeyError::Malformed("unexpected end of DER value")),
+        }
+        let (len, header_len) = match self.data.get(1) {
+            Some(len) if *len < 0x80 => (*len as usize, 2),
+            Some(0x81) => match self.data.get(2) {
+                Some(len) if *len >= 0x80 => (*len as usize, 3),
+                _ => return Err(PublicKeyError::Malformed("invalid DER length")),
+            },
+            Some(0x82) => match (self.data.get(2), self.data.get(3)) {
+                (Some(high), Some(low)) if *high != 0 => ((*high as usize) << 8 | *low as usize, 4),
+                _ => return Err(PublicKeyError::Malformed("invalid DER length")),
+            },
+            _ => return Err(PublicKeyError::Malformed("invalid DER length")),
+        };
+        if self.data.len() < header_len + len {
+            return Err(PublicKeyError::Malformed("unexpected end of DER value"));
+        }
+        let contents = &self.data[header_len..header_len + len];
+        self.data = &self.data[header_len + len..];
+        Ok(contents)
+    }
+
+    /// Reads a minimally encoded, positive integer.
+    fn read_positive_integer(&mut self) -> Result<&'a [u8], PublicKeyError> {
+        let integer = self.read(TAG_INTEGER)?;
+        match integer {
+            [] => Err(PublicKeyError::Malformed("empty integer")),
+            [first, ..] if first & 0x80 != 0 => Err(PublicKeyError::Malformed("negative integer")),
+            [0] => Err(PublicKeyError::Malformed("integer is zero")),
+            [0, second, ..] if second & 0x80 == 0 => Err(PublicKeyError::Malformed(
+                "integer is not minimally encoded",
+            )),
+            _ => Ok(integer),
+        }
+    }
+
+    /// Fails if there is data left.
+    fn finish(&self) -> Result<(), PublicKeyError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(PublicKeyError::Malformed("trailing data"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
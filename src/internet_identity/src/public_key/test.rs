@@ -0,0 +1,166 @@
+use super::*;
+use hex_literal::hex;
+
+/// Encodes a DER value with the given tag and contents.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut value = vec![tag];
+    match contents.len() {
+        len if len < 0x80 => value.push(len as u8),
+        len if len < 0x100 => value.extend([0x81, len as u8]),
+        len => value.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    value.extend(contents);
+    value
+}
+
+/// Encodes a `SubjectPublicKeyInfo` with the given algorithm identifier contents.
+fn spki(algorithm: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut bit_string = vec![0];
+    bit_string.extend(key);
+    let mut contents = der(TAG_SEQUENCE, algorithm);
+    contents.extend(der(TAG_BIT_STRING, &bit_string));
+    der(TAG_SEQUENCE, &contents)
+}
+
+fn rsa_key(modulus: &[u8]) -> Vec<u8> {
+    let mut algorithm = der(TAG_OID, OID_RSA_ENCRYPTION);
+    algorithm.extend(der(TAG_NULL, &[]));
+    let mut key = der(TAG_INTEGER, modulus);
+    key.extend(der(TAG_INTEGER, &[0x01, 0x00, 0x01]));
+    spki(&algorithm, &der(TAG_SEQUENCE, &key))
+}
+
+fn cose_key(cbor: &[u8]) -> Vec<u8> {
+    spki(&der(TAG_OID, OID_COSE), cbor)
+}
+
+/// COSE key of a WebAuthn authenticator, taken from the stable memory backup used in the
+/// canister tests.
+const WEBAUTHN_KEY: [u8; 96] = hex!("305e300c060a2b0601040183b8430101034e00a5010203262001215820ee6f212d1b94fcc014f050b087f06ad34157ff53c19981e3976842b1644b0a1c2258200d6bc5ee077bd2300b3c86df87aa5fdf90d256d0131efbe44424330de8b00471");
+/// Ed25519 key of a recovery phrase, taken from the same backup.
+const RECOVERY_PHRASE_KEY: [u8; 44] = hex!(
+    "302a300506032b6570032100f1ba3b80ce24f382fa32fd07233ceb8e305d57dafe6ad3d1c00e401315692631"
+);
+
+#[test]
+fn should_parse_webauthn_key() {
+    assert_eq!(
+        parse_public_key(&WEBAUTHN_KEY),
+        Ok(KeyAlgorithm::CoseEcdsaP256)
+    );
+}
+
+#[test]
+fn should_parse_recovery_phrase_key() {
+    assert_eq!(
+        parse_public_key(&RECOVERY_PHRASE_KEY),
+        Ok(KeyAlgorithm::Ed25519)
+    );
+}
+
+#[test]
+fn should_parse_ecdsa_p256_key() {
+    let mut algorithm = der(TAG_OID, OID_EC_PUBLIC_KEY);
+    algorithm.extend(der(TAG_OID, OID_P256));
+    let mut point = vec![0x04];
+    point.extend([0xab; 64]);
+
+    assert_eq!(
+        parse_public_key(&spki(&algorithm, &point)),
+        Ok(KeyAlgorithm::EcdsaP256)
+    );
+    assert_eq!(
+        parse_public_key(&spki(&algorithm, &point[..33])),
+        Err(PublicKeyError::Malformed("invalid P-256 point encoding"))
+    );
+}
+
+#[test]
+fn should_parse_rsa_key() {
+    let mut modulus = vec![0x00, 0xc1];
+    modulus.extend([0xab; 255]);
+
+    assert_eq!(parse_public_key(&rsa_key(&modulus)), Ok(KeyAlgorithm::Rsa));
+    assert_eq!(
+        parse_public_key(&rsa_key(&[0xc1, 0xab])),
+        Err(PublicKeyError::Malformed("negative integer"))
+    );
+    assert_eq!(
+        parse_public_key(&rsa_key(&[0x00, 0x01])),
+        Err(PublicKeyError::Malformed(
+            "integer is not minimally encoded"
+        ))
+    );
+}
+
+#[test]
+fn should_parse_cose_keys() {
+    // {1: 1 (OKP), 3: -8 (EdDSA), -1: 6 (Ed25519), -2: x}
+    let mut ed25519 = hex!("a4010103272006215820").to_vec();
+    ed25519.extend([0xab; 32]);
+    // {1: 3 (RSA), 3: -257 (RS256), -1: n, -2: e}
+    let mut rsa = hex!("a401030339010020590100").to_vec();
+    rsa.extend([0xab; 256]);
+    rsa.extend(hex!("2143010001"));
+
+    assert_eq!(
+        parse_public_key(&cose_key(&ed25519)),
+        Ok(KeyAlgorithm::CoseEd25519)
+    );
+    assert_eq!(parse_public_key(&cose_key(&rsa)), Ok(KeyAlgorithm::CoseRsa));
+}
+
+#[test]
+fn should_reject_cose_key_with_mismatching_algorithm() {
+    // {1: 1 (OKP), 3: -7 (ES256), -1: 6 (Ed25519), -2: x}
+    let mut key = hex!("a4010103262006215820").to_vec();
+    key.extend([0xab; 32]);
+
+    assert_eq!(
+        parse_public_key(&cose_key(&key)),
+        Err(PublicKeyError::UnsupportedAlgorithm(
+            "COSE algorithm -7".to_string()
+        ))
+    );
+    assert_eq!(
+        parse_public_key(&cose_key(&key[..20])),
+        Err(PublicKeyError::Malformed("COSE key is not a CBOR map"))
+    );
+}
+
+#[test]
+fn should_reject_unsupported_algorithm() {
+    // DSA, 1.2.840.10040.4.1
+    let algorithm = der(TAG_OID, &hex!("2a8648ce380401"));
+
+    assert_eq!(
+        parse_public_key(&spki(&algorithm, &[0xab; 32])),
+        Err(PublicKeyError::UnsupportedAlgorithm(
+            "1.2.840.10040.4.1".to_string()
+        ))
+    );
+}
+
+#[test]
+fn should_reject_malformed_keys() {
+    assert!(parse_public_key(b"").is_err());
+    assert!(parse_public_key(b"test").is_err());
+    assert_eq!(
+        parse_public_key(&RECOVERY_PHRASE_KEY[..43]),
+        Err(PublicKeyError::Malformed("unexpected end of DER value"))
+    );
+
+    let mut trailing_data = RECOVERY_PHRASE_KEY.to_vec();
+    trailing_data.push(0);
+    assert_eq!(
+        parse_public_key(&trailing_data),
+        Err(PublicKeyError::Malformed("trailing data"))
+    );
+
+    let mut unused_bits = RECOVERY_PHRASE_KEY;
+    unused_bits[11] = 1;
+    assert_eq!(
+        parse_public_key(&unused_bits),
+        Err(PublicKeyError::Malformed("bit string has unused bits"))
+    );
+}
@@ -0,0 +1,58 @@
+//! Token bucket used to limit the rate at which new Identity Anchors can be registered.
+//!
+//! A token is added every `time_per_token_ns` and at most `max_tokens` tokens are kept, i.e.
+//! `max_tokens` is the size of the largest burst the bucket allows.
+use candid::{CandidType, Deserialize};
+use internet_identity_interface::{RateLimitConfig, Timestamp};
+
+/// The state of a token bucket: `tokens` were available at time `timestamp`.
+///
+/// The default bucket is full as soon as it is refilled for the first time.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq)]
+pub struct TokenBucket {
+    tokens: u64,
+    timestamp: Timestamp,
+}
+
+impl TokenBucket {
+    /// Takes a token from the bucket after refilling it. Returns false if there is no token left.
+    pub fn try_take(&mut self, config: &RateLimitConfig, now: Timestamp) -> bool {
+        self.refill(config, now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// Returns the number of tokens available at time `now`.
+    pub fn available_tokens(&self, config: &RateLimitConfig, now: Timestamp) -> u64 {
+        let mut bucket = self.clone();
+        bucket.refill(config, now);
+        bucket.tokens
+    }
+
+    /// Adds the tokens accumulated since the last refill.
+    fn refill(&mut self, config: &RateLimitConfig, now: Timestamp) {
+        let elapsed = now.saturating_sub(self.timestamp);
+        let new_tokens = match elapsed.checked_div(config.time_per_token_ns) {
+            Some(new_tokens) => new_tokens,
+            // no rate limit
+            None => u64::MAX,
+        };
+
+        let tokens = self.tokens.saturating_add(new_tokens);
+        if tokens >= config.max_tokens {
+            // a full bucket does not accumulate time
+            self.tokens = config.max_tokens;
+            self.timestamp = now;
+        } else {
+            self.tokens = tokens;
+            // keep the time elapsed since the last token was added
+            self.timestamp += new_tokens * config.time_per_token_ns;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
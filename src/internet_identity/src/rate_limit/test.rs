@@ -0,0 +1,56 @@
+use super::*;
+
+const CONFIG: RateLimitConfig = RateLimitConfig {
+    time_per_token_ns: 10,
+    max_tokens: 3,
+};
+
+#[test]
+fn test_take_tokens_up_to_burst() {
+    let mut bucket = TokenBucket::default();
+
+    assert!(bucket.try_take(&CONFIG, 1_000));
+    assert!(bucket.try_take(&CONFIG, 1_000));
+    assert!(bucket.try_take(&CONFIG, 1_000));
+    assert!(!bucket.try_take(&CONFIG, 1_000));
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_000), 0);
+}
+
+#[test]
+fn test_refill_over_time() {
+    let mut bucket = TokenBucket::default();
+    for _ in 0..3 {
+        assert!(bucket.try_take(&CONFIG, 1_000));
+    }
+
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_009), 0);
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_010), 1);
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_025), 2);
+    // never more than max_tokens
+    assert_eq!(bucket.available_tokens(&CONFIG, 2_000), 3);
+}
+
+#[test]
+fn test_keep_partial_refill_period() {
+    let mut bucket = TokenBucket::default();
+    for _ in 0..3 {
+        assert!(bucket.try_take(&CONFIG, 1_000));
+    }
+
+    assert!(bucket.try_take(&CONFIG, 1_015));
+    // the 5ns elapsed after the last token was added count towards the next one
+    assert!(!bucket.try_take(&CONFIG, 1_019));
+    assert!(bucket.try_take(&CONFIG, 1_020));
+}
+
+#[test]
+fn test_no_rate_limit_without_time_per_token() {
+    let config = RateLimitConfig {
+        time_per_token_ns: 0,
+        max_tokens: 1,
+    };
+    let mut bucket = TokenBucket::default();
+
+    assert!(bucket.try_take(&config, 1_000));
+    assert!(bucket.try_take(&config, 1_000));
+}
@@ -0,0 +1,69 @@
+//! History of the failed device registration rounds of an Identity Anchor, used to impose
+//! escalating cooldowns on the device registration flow.
+//!
+//! A round fails when tentative devices are discarded because wrong verification codes were
+//! entered too often. The first failed round has no consequences, after that every failed round
+//! blocks the registration of new devices twice as long as the previous one, starting with
+//! [BASE_COOLDOWN_NS] and up to [MAX_COOLDOWN_NS]. The history is forgotten [RETENTION_NS] after
+//! the last failed round.
+use candid::{CandidType, Deserialize};
+use internet_identity_interface::Timestamp;
+
+// 5 mins
+pub const BASE_COOLDOWN_NS: u64 = 5 * 60 * 1_000_000_000;
+// 1 day
+pub const MAX_COOLDOWN_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+// 7 days
+pub const RETENTION_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, PartialEq, Eq)]
+pub struct RegistrationFailures {
+    failed_rounds: u32,
+    last_failure: Timestamp,
+}
+
+impl RegistrationFailures {
+    /// Records a failed round, an expired history is forgotten first.
+    pub fn record_failed_round(&mut self, now: Timestamp) {
+        if self.is_expired(now) {
+            self.failed_rounds = 0;
+        }
+        self.failed_rounds = self.failed_rounds.saturating_add(1);
+        self.last_failure = now;
+    }
+
+    pub fn failed_rounds(&self) -> u32 {
+        self.failed_rounds
+    }
+
+    pub fn last_failure(&self) -> Timestamp {
+        self.last_failure
+    }
+
+    /// Returns the end of the cooldown if the registration of new devices is blocked at time
+    /// `now`.
+    pub fn cooldown_until(&self, now: Timestamp) -> Option<Timestamp> {
+        if self.failed_rounds < 2 {
+            return None;
+        }
+        let doublings = u32::min(self.failed_rounds - 2, 32);
+        let cooldown = u64::min(
+            BASE_COOLDOWN_NS.saturating_mul(1 << doublings),
+            MAX_COOLDOWN_NS,
+        );
+        let until = self.last_failure.saturating_add(cooldown);
+        if now < until {
+            Some(until)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the history can be forgotten at time `now`.
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        now >= self.last_failure.saturating_add(RETENTION_NS)
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,54 @@
+use super::*;
+
+const MINUTE_NS: u64 = 60 * 1_000_000_000;
+
+#[test]
+fn test_no_cooldown_after_first_failed_round() {
+    let mut failures = RegistrationFailures::default();
+    failures.record_failed_round(1_000);
+
+    assert_eq!(failures.failed_rounds(), 1);
+    assert_eq!(failures.cooldown_until(1_000), None);
+}
+
+#[test]
+fn test_escalate_cooldown() {
+    let mut failures = RegistrationFailures::default();
+    failures.record_failed_round(1_000);
+    failures.record_failed_round(2_000);
+    assert_eq!(
+        failures.cooldown_until(2_000),
+        Some(2_000 + BASE_COOLDOWN_NS)
+    );
+    assert_eq!(failures.cooldown_until(2_000 + BASE_COOLDOWN_NS), None);
+
+    failures.record_failed_round(3_000);
+    assert_eq!(failures.cooldown_until(3_000), Some(3_000 + 10 * MINUTE_NS));
+    failures.record_failed_round(4_000);
+    assert_eq!(failures.cooldown_until(4_000), Some(4_000 + 20 * MINUTE_NS));
+}
+
+#[test]
+fn test_limit_cooldown() {
+    let mut failures = RegistrationFailures::default();
+    for _ in 0..100 {
+        failures.record_failed_round(1_000);
+    }
+
+    assert_eq!(
+        failures.cooldown_until(1_000),
+        Some(1_000 + MAX_COOLDOWN_NS)
+    );
+}
+
+#[test]
+fn test_expire_history() {
+    let mut failures = RegistrationFailures::default();
+    failures.record_failed_round(1_000);
+
+    assert!(!failures.is_expired(1_000 + RETENTION_NS - 1));
+    assert!(failures.is_expired(1_000 + RETENTION_NS));
+
+    failures.record_failed_round(1_000 + RETENTION_NS);
+    assert_eq!(failures.failed_rounds(), 1);
+}
@@ -0,0 +1,261 @@
+//! Maintains the certified set of revoked session keys and their expirations.
+use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use std::borrow::Cow;
+use std::collections::BinaryHeap;
+
+/// Expiration of a revoked session key, certified as big-endian nanoseconds since the epoch.
+struct Expiration([u8; 8]);
+
+impl Expiration {
+    fn new(expires_at: u64) -> Self {
+        Self(expires_at.to_be_bytes())
+    }
+
+    fn get(&self) -> u64 {
+        u64::from_be_bytes(self.0)
+    }
+}
+
+impl AsHashTree for Expiration {
+    fn root_hash(&self) -> Hash {
+        leaf_hash(&self.0[..])
+    }
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Leaf(Cow::from(&self.0[..]))
+    }
+}
+
+/// The revocations of a seed: either individual session keys, or all session keys of the seed
+/// at once, which is certified as a single expiration leaf in place of the session key subtree.
+enum SeedRevocations {
+    SessionKeys(RbTree<Hash, Expiration>),
+    All(Expiration),
+}
+
+impl AsHashTree for SeedRevocations {
+    fn root_hash(&self) -> Hash {
+        match self {
+            SeedRevocations::SessionKeys(submap) => submap.root_hash(),
+            SeedRevocations::All(expiration) => expiration.root_hash(),
+        }
+    }
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            SeedRevocations::SessionKeys(submap) => submap.as_hash_tree(),
+            SeedRevocations::All(expiration) => expiration.as_hash_tree(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+struct RevocationExpiration {
+    expires_at: u64,
+    seed_hash: Hash,
+    /// `None` if all session keys of the seed are revoked.
+    session_key_hash: Option<Hash>,
+}
+
+impl Ord for RevocationExpiration {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // BinaryHeap is a max heap, but we want expired entries
+        // first, hence the inversed order.
+        other.expires_at.cmp(&self.expires_at)
+    }
+}
+
+impl PartialOrd for RevocationExpiration {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(&other))
+    }
+}
+
+/// Revoked session keys (identified by their hash) are kept until the delegation issued to
+/// them has expired, after which the revocation no longer matters.
+///
+/// Like the signatures, revocations are scoped to the seed (i.e. the anchor and frontend) of the
+/// delegation, so that revoking a session of one anchor does not affect the delegations of other
+/// anchors for the same session key.
+///
+/// A seed can also be revoked as a whole (e.g. when its anchor is deleted), which revokes all
+/// session keys of the seed, including those whose sessions are not known to the canister.
+#[derive(Default)]
+pub struct RevocationList {
+    certified_map: RbTree<Hash, SeedRevocations>,
+    expiration_queue: BinaryHeap<RevocationExpiration>,
+}
+
+impl RevocationList {
+    /// Revokes the given session key of the given seed until `expires_at`. Revoking a session key
+    /// again extends the revocation if the new expiration is later.
+    ///
+    /// If all session keys of the seed are revoked already, the revocation of the seed is
+    /// extended instead.
+    pub fn revoke(&mut self, seed_hash: Hash, session_key_hash: Hash, expires_at: u64) {
+        match self.certified_map.get(&seed_hash[..]) {
+            Some(SeedRevocations::All(_)) => return self.revoke_seed(seed_hash, expires_at),
+            Some(SeedRevocations::SessionKeys(submap)) => match submap.get(&session_key_hash[..]) {
+                Some(stored) if stored.get() >= expires_at => return,
+                Some(_) => self.certified_map.modify(&seed_hash[..], |revocations| {
+                    if let SeedRevocations::SessionKeys(submap) = revocations {
+                        submap.modify(&session_key_hash[..], |expiration| {
+                            *expiration = Expiration::new(expires_at);
+                        })
+                    }
+                }),
+                None => self.certified_map.modify(&seed_hash[..], |revocations| {
+                    if let SeedRevocations::SessionKeys(submap) = revocations {
+                        submap.insert(session_key_hash, Expiration::new(expires_at));
+                    }
+                }),
+            },
+            None => {
+                let mut submap = RbTree::new();
+                submap.insert(session_key_hash, Expiration::new(expires_at));
+                self.certified_map
+                    .insert(seed_hash, SeedRevocations::SessionKeys(submap));
+            }
+        }
+        self.expiration_queue.push(RevocationExpiration {
+            expires_at,
+            seed_hash,
+            session_key_hash: Some(session_key_hash),
+        });
+    }
+
+    /// Revokes all session keys of the given seed until `expires_at`, or until the latest
+    /// expiration of the session keys of the seed revoked so far if that is later. Revoking a seed
+    /// again extends the revocation if the new expiration is later.
+    pub fn revoke_seed(&mut self, seed_hash: Hash, expires_at: u64) {
+        // the revocation of the seed replaces those of its session keys,
+        // so it must not expire before any of them
+        let expires_at = self
+            .entries()
+            .filter(|(seed, _, _)| *seed == seed_hash)
+            .map(|(_, _, expires_at)| expires_at)
+            .fold(expires_at, u64::max);
+        if self.get(seed_hash, None) == Some(expires_at) {
+            return;
+        }
+        self.certified_map.delete(&seed_hash[..]);
+        self.certified_map
+            .insert(seed_hash, SeedRevocations::All(Expiration::new(expires_at)));
+        self.expiration_queue.push(RevocationExpiration {
+            expires_at,
+            seed_hash,
+            session_key_hash: None,
+        });
+    }
+
+    /// Returns whether the session key is revoked, either individually or as part of the seed.
+    pub fn is_revoked(&self, seed_hash: Hash, session_key_hash: Hash) -> bool {
+        self.get(seed_hash, Some(session_key_hash)).is_some() || self.get(seed_hash, None).is_some()
+    }
+
+    pub fn prune_expired(&mut self, now: u64, max_to_prune: usize) -> usize {
+        let mut num_pruned = 0;
+
+        for _step in 0..max_to_prune {
+            if let Some(expiration) = self.expiration_queue.peek() {
+                if expiration.expires_at > now {
+                    return num_pruned;
+                }
+            }
+            if let Some(expiration) = self.expiration_queue.pop() {
+                // the revocation might have been extended in the meantime
+                let expired = self
+                    .get(expiration.seed_hash, expiration.session_key_hash)
+                    .map(|stored| stored <= now)
+                    .unwrap_or(false);
+                if expired {
+                    self.delete(expiration.seed_hash, expiration.session_key_hash);
+                }
+            }
+            num_pruned += 1;
+        }
+
+        num_pruned
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certified_map.is_empty()
+    }
+
+    /// Returns all revocations as (seed hash, session key hash, expiration) triples, in no
+    /// particular order. The session key hash is `None` if all session keys of the seed are
+    /// revoked.
+    pub fn entries(&self) -> impl Iterator<Item = (Hash, Option<Hash>, u64)> + '_ {
+        // The queue also contains the outdated expirations of extended revocations,
+        // only the entries matching the certified map are current.
+        self.expiration_queue
+            .iter()
+            .filter(move |expiration| {
+                self.get(expiration.seed_hash, expiration.session_key_hash)
+                    == Some(expiration.expires_at)
+            })
+            .map(|expiration| {
+                (
+                    expiration.seed_hash,
+                    expiration.session_key_hash,
+                    expiration.expires_at,
+                )
+            })
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.certified_map.root_hash()
+    }
+
+    /// Returns a witness proving either the presence or the absence of the session key hash
+    /// under the seed hash, or the revocation of all session keys of the seed (a leaf at the seed
+    /// hash).
+    pub fn witness(&self, seed_hash: Hash, session_key_hash: Hash) -> HashTree<'_> {
+        match self.certified_map.get(&seed_hash[..]) {
+            Some(SeedRevocations::SessionKeys(_)) => {
+                self.certified_map
+                    .nested_witness(&seed_hash[..], |revocations| match revocations {
+                        SeedRevocations::SessionKeys(submap) => {
+                            submap.witness(&session_key_hash[..])
+                        }
+                        SeedRevocations::All(expiration) => expiration.as_hash_tree(),
+                    })
+            }
+            // witnesses the expiration leaf of a revoked seed, or the absence of the seed
+            Some(SeedRevocations::All(_)) | None => self.certified_map.witness(&seed_hash[..]),
+        }
+    }
+
+    /// Returns the expiration of the revocation of the given session key of the seed, or of the
+    /// seed as a whole if `session_key_hash` is `None`.
+    fn get(&self, seed_hash: Hash, session_key_hash: Option<Hash>) -> Option<u64> {
+        match (self.certified_map.get(&seed_hash[..])?, session_key_hash) {
+            (SeedRevocations::SessionKeys(submap), Some(session_key_hash)) => {
+                submap.get(&session_key_hash[..]).map(Expiration::get)
+            }
+            (SeedRevocations::All(expiration), None) => Some(expiration.get()),
+            _ => None,
+        }
+    }
+
+    fn delete(&mut self, seed_hash: Hash, session_key_hash: Option<Hash>) {
+        let mut is_empty = false;
+        match session_key_hash {
+            Some(session_key_hash) => self.certified_map.modify(&seed_hash[..], |revocations| {
+                if let SeedRevocations::SessionKeys(submap) = revocations {
+                    submap.delete(&session_key_hash[..]);
+                    is_empty = submap.is_empty();
+                }
+            }),
+            None => is_empty = true,
+        }
+        if is_empty {
+            self.certified_map.delete(&seed_hash[..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
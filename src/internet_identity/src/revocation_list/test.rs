@@ -0,0 +1,143 @@
+use super::*;
+use ic_certified_map::Hash;
+use sha2::{Digest, Sha256};
+
+fn session_key_hash(x: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(x.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn seed_hash(x: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(b"seed");
+    hasher.update(x.to_be_bytes());
+    hasher.finalize().into()
+}
+
+#[test]
+fn test_revocation_lookup() {
+    let mut list = RevocationList::default();
+    list.revoke(seed_hash(1), session_key_hash(1), 10);
+
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert!(!list.is_revoked(seed_hash(1), session_key_hash(2)));
+    assert_eq!(
+        list.witness(seed_hash(1), session_key_hash(1)).reconstruct(),
+        list.root_hash()
+    );
+    // absence proofs are valid as well
+    assert_eq!(
+        list.witness(seed_hash(1), session_key_hash(2)).reconstruct(),
+        list.root_hash()
+    );
+    assert_eq!(
+        list.witness(seed_hash(2), session_key_hash(1)).reconstruct(),
+        list.root_hash()
+    );
+}
+
+#[test]
+fn test_revocation_is_scoped_to_seed() {
+    let mut list = RevocationList::default();
+    list.revoke(seed_hash(1), session_key_hash(1), 10);
+
+    assert!(!list.is_revoked(seed_hash(2), session_key_hash(1)));
+
+    list.revoke(seed_hash(2), session_key_hash(1), 20);
+    list.prune_expired(/*time now*/ 10, /*max_to_prune*/ 10);
+    assert!(!list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert!(list.is_revoked(seed_hash(2), session_key_hash(1)));
+}
+
+#[test]
+fn test_revocation_expiration() {
+    let mut list = RevocationList::default();
+
+    list.revoke(seed_hash(1), session_key_hash(1), 10);
+    list.revoke(seed_hash(1), session_key_hash(2), 20);
+    list.revoke(seed_hash(2), session_key_hash(3), 15);
+
+    assert_eq!(2, list.prune_expired(/*time now*/ 15, /*max_to_prune*/ 10));
+    assert!(!list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(2)));
+    assert!(!list.is_revoked(seed_hash(2), session_key_hash(3)));
+    assert_eq!(list.len(), 1);
+}
+
+#[test]
+fn test_extended_revocation_is_not_pruned_early() {
+    let mut list = RevocationList::default();
+
+    list.revoke(seed_hash(1), session_key_hash(1), 10);
+    list.revoke(seed_hash(1), session_key_hash(1), 30);
+    // revoking with an earlier expiration does not shorten the revocation
+    list.revoke(seed_hash(1), session_key_hash(1), 5);
+    assert_eq!(list.len(), 1);
+
+    list.prune_expired(/*time now*/ 20, /*max_to_prune*/ 10);
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert_eq!(
+        list.entries().collect::<Vec<_>>(),
+        vec![(seed_hash(1), Some(session_key_hash(1)), 30)]
+    );
+
+    list.prune_expired(/*time now*/ 30, /*max_to_prune*/ 10);
+    assert!(!list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert!(list.is_empty());
+}
+
+#[test]
+fn test_revocation_list_restore_from_entries() {
+    let mut list = RevocationList::default();
+    for i in 0..10 {
+        list.revoke(seed_hash(i % 3), session_key_hash(i), 10 * i);
+    }
+    list.revoke_seed(seed_hash(3), 50);
+
+    let mut restored = RevocationList::default();
+    for (seed_hash, session_key_hash, expires_at) in list.entries() {
+        match session_key_hash {
+            Some(session_key_hash) => restored.revoke(seed_hash, session_key_hash, expires_at),
+            None => restored.revoke_seed(seed_hash, expires_at),
+        }
+    }
+    assert_eq!(restored.len(), list.len());
+    assert_eq!(restored.root_hash(), list.root_hash());
+}
+
+#[test]
+fn test_seed_revocation_revokes_all_session_keys() {
+    let mut list = RevocationList::default();
+    list.revoke(seed_hash(1), session_key_hash(1), 30);
+    list.revoke_seed(seed_hash(1), 20);
+
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(1)));
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(2)));
+    assert!(!list.is_revoked(seed_hash(2), session_key_hash(1)));
+    assert_eq!(
+        list.witness(seed_hash(1), session_key_hash(2)).reconstruct(),
+        list.root_hash()
+    );
+    // the revocation of the seed does not expire before the revoked session keys
+    assert_eq!(
+        list.entries().collect::<Vec<_>>(),
+        vec![(seed_hash(1), None, 30)]
+    );
+}
+
+#[test]
+fn test_seed_revocation_expiration() {
+    let mut list = RevocationList::default();
+    list.revoke_seed(seed_hash(1), 10);
+    // revoking a session key of a revoked seed extends the revocation of the seed
+    list.revoke(seed_hash(1), session_key_hash(1), 20);
+    assert_eq!(list.len(), 1);
+
+    list.prune_expired(/*time now*/ 10, /*max_to_prune*/ 10);
+    assert!(list.is_revoked(seed_hash(1), session_key_hash(2)));
+
+    list.prune_expired(/*time now*/ 20, /*max_to_prune*/ 10);
+    assert!(!list.is_revoked(seed_hash(1), session_key_hash(2)));
+    assert!(list.is_empty());
+}
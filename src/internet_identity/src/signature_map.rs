@@ -97,6 +97,18 @@ impl SignatureMap {
         self.expiration_queue.is_empty()
     }
 
+    /// Returns all signatures as (seed hash, message hash, expiration) triples, in no
+    /// particular order. Putting them into an empty map restores this map.
+    pub fn entries(&self) -> impl Iterator<Item = (Hash, Hash, u64)> + '_ {
+        self.expiration_queue.iter().map(|expiration| {
+            (
+                expiration.seed_hash,
+                expiration.msg_hash,
+                expiration.expires_at,
+            )
+        })
+    }
+
     pub fn root_hash(&self) -> Hash {
         self.certified_map.root_hash()
     }
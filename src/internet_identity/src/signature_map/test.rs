@@ -50,6 +50,29 @@ fn test_signature_expiration() {
     assert!(map.witness(seed(2), message(2)).is_some());
 }
 
+#[test]
+fn test_signature_map_restore_from_entries() {
+    let mut map = SignatureMap::default();
+    for i in 0..10 {
+        map.put(seed(i % 3), message(i), 10 * i);
+    }
+
+    let mut restored = SignatureMap::default();
+    for (seed_hash, msg_hash, expires_at) in map.entries() {
+        restored.put(seed_hash, msg_hash, expires_at);
+    }
+    assert_eq!(restored.len(), map.len());
+    assert_eq!(restored.root_hash(), map.root_hash());
+
+    // expirations are restored as well
+    assert_eq!(
+        5,
+        restored.prune_expired(/*time now*/ 45, /*max_to_prune*/ 10)
+    );
+    assert_eq!(5, map.prune_expired(/*time now*/ 45, /*max_to_prune*/ 10));
+    assert_eq!(restored.root_hash(), map.root_hash());
+}
+
 #[test]
 fn test_signature_expiration_limit() {
     let mut map = SignatureMap::default();
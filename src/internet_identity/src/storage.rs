@@ -4,18 +4,48 @@ use ic_cdk::api::{
     trap,
 };
 use internet_identity_interface::UserNumber;
+use std::cell::RefCell;
 use std::convert::TryInto;
 use std::fmt;
 use std::marker::PhantomData;
+use std::rc::Rc;
+
+pub mod anchor;
 
 const HEADER_SIZE: u64 = 512;
 const DEFAULT_ENTRY_SIZE: u16 = 2048;
+/// Size of an entry in the anchor index of layout version 2, see [IndexEntry].
+const INDEX_ENTRY_SIZE: u64 = 16;
+/// Size of the smallest block handed out by the layout version 2 allocator.
+const MIN_BLOCK_SIZE: u64 = 128;
+/// Number of block sizes (powers of two) the allocator manages: 128 B up to 64 KiB.
+const NUM_SIZE_CLASSES: usize = 10;
+/// The event index is split into chunks of the largest block size, see [Storage::write_events].
+const EVENT_INDEX_CHUNK_SIZE_CLASS: u8 = NUM_SIZE_CLASSES as u8 - 1;
+/// Length of a version 1 record, respectively size of a version 2 index entry, marking a deleted
+/// anchor, see [Storage::delete]. Both exceed the size any record can have.
+const DELETED_RECORD_V1: u16 = u16::MAX - 1;
+const DELETED_RECORD_V2: u32 = u32::MAX;
+/// Marks the state persisted across upgrades, see [Storage::write_persistent_state].
+const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS";
+/// Marks the state kept in stable memory, see [Storage::write_stable_state].
+const STABLE_STATE_MAGIC: [u8; 4] = *b"IIST";
+/// Version of the stable state header, increased when its encoding changes incompatibly.
+const STABLE_STATE_VERSION: u8 = 1;
+/// Size of the stable state header: magic, version and size of the state.
+const STABLE_STATE_HEADER_SIZE: u64 = 13;
 const EMPTY_SALT: [u8; 32] = [0; 32];
 const WASM_PAGE_SIZE: u64 = 65536;
 const GB: u64 = 1 << 30;
 const STABLE_MEMORY_SIZE: u64 = 8 * GB;
 /// We reserve last ~10% of the stable memory for later new features.
 const STABLE_MEMORY_RESERVE: u64 = STABLE_MEMORY_SIZE / 10;
+/// The reserve is never used by the anchors. It starts with the stable state (see
+/// [Storage::write_stable_state]), followed by the state persisted across upgrades (see
+/// [Storage::write_persistent_state]).
+const STABLE_STATE_OFFSET: u64 = STABLE_MEMORY_SIZE - STABLE_MEMORY_RESERVE;
+const STABLE_STATE_MAX_SIZE: u64 = GB / 4;
+const PERSISTENT_STATE_OFFSET: u64 = STABLE_STATE_OFFSET + STABLE_STATE_MAX_SIZE;
 
 /// The maximum number of users this canister can store.
 pub const DEFAULT_RANGE_SIZE: u64 =
@@ -23,9 +53,91 @@ pub const DEFAULT_RANGE_SIZE: u64 =
 
 pub type Salt = [u8; 32];
 
+/// The memory the [Storage] is backed by. The canister uses the stable memory, other
+/// implementations allow using the storage outside of a canister (e.g. to inspect a
+/// dump of the stable memory).
+pub trait Memory {
+    /// Returns the size of the memory in WebAssembly pages.
+    fn size(&self) -> u64;
+    /// Grows the memory by the given number of pages. Returns the previous size or None if
+    /// the memory cannot be grown.
+    fn grow(&self, pages: u64) -> Option<u64>;
+    fn read(&self, offset: u64, dst: &mut [u8]);
+    fn write(&self, offset: u64, src: &[u8]);
+}
+
+/// The stable memory of the canister.
+#[derive(Clone, Copy, Default)]
+pub struct StableMemory;
+
+impl Memory for StableMemory {
+    fn size(&self) -> u64 {
+        stable64_size()
+    }
+
+    fn grow(&self, pages: u64) -> Option<u64> {
+        stable64_grow(pages).ok()
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        stable64_read(offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        stable64_write(offset, src)
+    }
+}
+
+/// A memory kept on the heap, e.g. holding a dump of the stable memory.
+pub type VecMemory = Rc<RefCell<Vec<u8>>>;
+
+impl Memory for VecMemory {
+    fn size(&self) -> u64 {
+        self.borrow().len() as u64 / WASM_PAGE_SIZE
+    }
+
+    fn grow(&self, pages: u64) -> Option<u64> {
+        let size = self.size();
+        let new_size = size.checked_add(pages)?;
+        if new_size * WASM_PAGE_SIZE > STABLE_MEMORY_SIZE {
+            return None;
+        }
+        self.borrow_mut()
+            .resize((new_size * WASM_PAGE_SIZE) as usize, 0);
+        Some(size)
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let offset = offset as usize;
+        dst.copy_from_slice(&self.borrow()[offset..offset + dst.len()]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let offset = offset as usize;
+        self.borrow_mut()[offset..offset + src.len()].copy_from_slice(src);
+    }
+}
+
 /// Data type responsible for managing user data in stable memory.
-pub struct Storage<T> {
+///
+/// Two layouts are supported:
+///  * version 1: every anchor is stored in a fixed size slot of `entry_size` bytes.
+///  * version 2: the header is followed by an index with one [IndexEntry] per anchor
+///    pointing to a variable-sized record on the heap. Records are allocated in power of two
+///    sized blocks and relocated to a bigger block when they outgrow their current one.
+///    Freed blocks are kept in one free list per size class.
+///
+/// For layout version 2 `entry_size` is no longer the size of a record, but the amount of
+/// stable memory budgeted per anchor, which determines the maximum number of anchors.
+///
+/// Layout version 2 additionally stores an event log per anchor on the heap, see
+/// [Storage::write_events].
+///
+/// Deleted anchors keep their slot (respectively index entry), which is marked as deleted so that
+/// the anchor number is never handed out again, see [Storage::delete].
+pub struct Storage<T, M: Memory = StableMemory> {
     header: Header,
+    memory: M,
     _marker: PhantomData<T>,
 }
 
@@ -38,12 +150,33 @@ struct Header {
     id_range_hi: u64,
     entry_size: u16,
     salt: [u8; 32],
+    // The fields below are only used by layout version 2.
+    heap_start: u64,
+    heap_end: u64,
+    free_lists: [u64; NUM_SIZE_CLASSES],
+    event_directory: u64,
+    // Zero in headers written by releases that could not delete anchors.
+    num_deleted: u32,
+    // The records from `migration_next` up to `migration_end` (exclusive) still have layout
+    // version 1, see [Storage::migrate_to_v2]. Both are zero if no migration is in progress.
+    migration_next: u32,
+    migration_end: u32,
 }
 
-impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
+const _: () = assert!(std::mem::size_of::<Header>() <= HEADER_SIZE as usize);
+
+/// Location of an anchor record in layout version 2. An offset of 0 means that there is no record.
+#[derive(Default)]
+struct IndexEntry {
+    offset: u64,
+    size: u32,
+    size_class: u8,
+}
+
+impl<T: candid::CandidType + serde::de::DeserializeOwned, M: Memory> Storage<T, M> {
     /// Creates a new empty storage that manages the data of users in
     /// the specified range.
-    pub fn new((id_range_lo, id_range_hi): (UserNumber, UserNumber)) -> Self {
+    pub fn new((id_range_lo, id_range_hi): (UserNumber, UserNumber), memory: M) -> Self {
         if id_range_hi < id_range_lo {
             trap(&format!(
                 "improper Identity Anchor range: [{}, {})",
@@ -67,7 +200,15 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
                 id_range_hi,
                 entry_size: DEFAULT_ENTRY_SIZE,
                 salt: EMPTY_SALT,
+                heap_start: 0,
+                heap_end: 0,
+                free_lists: [0; NUM_SIZE_CLASSES],
+                event_directory: 0,
+                num_deleted: 0,
+                migration_next: 0,
+                migration_end: 0,
             },
+            memory,
             _marker: PhantomData,
         }
     }
@@ -88,15 +229,32 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
         self.flush();
     }
 
-    /// Initializes storage by reading stable memory.
+    /// Overwrites the salt, e.g. with the salt of the canister anchors are imported from.
+    ///
+    /// Traps if there are anchors because their principals are derived from the current salt.
+    pub fn replace_salt(&mut self, salt: Salt) {
+        if self.header.num_users > 0 {
+            trap("Attempted to replace the salt of a storage with anchors.");
+        }
+        self.header.salt = salt;
+        self.flush();
+    }
+
+    /// Initializes storage by reading the given memory.
     ///
-    /// Returns None if the stable memory is empty.
+    /// Returns None if the memory is empty.
     ///
-    /// Panics if the stable memory is not empty but cannot be
+    /// Panics if the memory is not empty but cannot be
     /// decoded.
-    pub fn from_stable_memory() -> Option<Self> {
-        if stable64_size() < 1 {
-            return None;
+    pub fn from_memory(memory: M) -> Option<Self> {
+        Self::try_from_memory(memory).unwrap_or_else(|err| trap(&err))
+    }
+
+    /// Like [Storage::from_memory], but returns an error instead of trapping if the header
+    /// cannot be decoded.
+    pub fn try_from_memory(memory: M) -> Result<Option<Self>, String> {
+        if memory.size() < 1 {
+            return Ok(None);
         }
 
         let mut header: Header = unsafe { std::mem::zeroed() };
@@ -106,23 +264,30 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
                 &mut header as *mut _ as *mut u8,
                 std::mem::size_of::<Header>(),
             );
-            stable64_read(0, slice);
+            memory.read(0, slice);
         }
 
         if &header.magic != b"IIC" {
-            trap(&format!(
+            return Err(format!(
                 "stable memory header: invalid magic: {:?}",
                 &header.magic,
             ));
         }
-        if header.version != 1 {
-            trap(&format!("unsupported header version: {}", header.version));
+        if header.version != 1 && header.version != 2 {
+            return Err(format!("unsupported header version: {}", header.version));
+        }
+        if header.version == 2 && header.heap_end < header.heap_start {
+            return Err("stable memory header: heap end is before heap start".to_string());
+        }
+        if header.migration_end > header.num_users {
+            return Err("stable memory header: migration ends after the last anchor".to_string());
         }
 
-        Some(Self {
+        Ok(Some(Self {
             header,
+            memory,
             _marker: PhantomData,
-        })
+        }))
     }
 
     /// Allocates a fresh Identity Anchor.
@@ -134,73 +299,423 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
         if user_number >= self.header.id_range_hi {
             return None;
         }
+        if self.header.version >= 2 {
+            // The index entry of the new anchor must not overwrite version 1 records that have
+            // not been migrated yet. The index region might still contain data of the version 1
+            // records that were migrated, make sure the new anchor does not point to a bogus
+            // record.
+            let entry_end = HEADER_SIZE + (self.header.num_users as u64 + 1) * INDEX_ENTRY_SIZE;
+            while self.is_migrating()
+                && self.record_offset_v1(self.header.migration_next) < entry_end
+            {
+                self.migrate_next_record();
+            }
+            self.write_index_entry(self.header.num_users, &IndexEntry::default());
+        }
         self.header.num_users += 1;
         self.flush();
         Some(user_number)
     }
 
     /// Writes the data of the specified user to stable memory.
-    pub fn write(&self, user_number: UserNumber, data: T) -> Result<(), StorageError> {
+    pub fn write(&mut self, user_number: UserNumber, data: T) -> Result<(), StorageError> {
         let record_number = self.user_number_to_record(user_number)?;
-
-        let stable_offset = HEADER_SIZE + record_number as u64 * self.header.entry_size as u64;
+        if self.is_deleted_record(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
         let buf = candid::encode_one(data).map_err(StorageError::SerializationError)?;
 
+        if self.has_v1_layout(record_number) {
+            self.write_v1(record_number, &buf)
+        } else {
+            self.write_v2(record_number, &buf)
+        }
+    }
+
+    fn write_v1(&self, record_number: u32, buf: &[u8]) -> Result<(), StorageError> {
         if buf.len() > self.value_size_limit() {
             return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
         }
 
-        let current_size = stable64_size();
-        let pages =
-            (stable_offset + self.header.entry_size as u64 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
-        if pages > current_size {
-            let pages_to_grow = pages - current_size;
-            let result = stable64_grow(pages - current_size);
-            if result.is_err() {
-                trap(&format!(
-                    "failed to grow stable memory by {} pages",
-                    pages_to_grow
-                ))
-            }
-        }
-        stable64_write(stable_offset, &(buf.len() as u16).to_le_bytes());
-        stable64_write(stable_offset + std::mem::size_of::<u16>() as u64, &buf);
+        let stable_offset = self.record_offset_v1(record_number);
+        self.grow_memory_to(stable_offset + self.header.entry_size as u64);
+        self.memory
+            .write(stable_offset, &(buf.len() as u16).to_le_bytes());
+        self.memory
+            .write(stable_offset + std::mem::size_of::<u16>() as u64, buf);
+        Ok(())
+    }
+
+    fn write_v2(&mut self, record_number: u32, buf: &[u8]) -> Result<(), StorageError> {
+        let entry = self.read_index_entry(record_number);
+        let entry = self.write_block(entry, buf)?;
+        self.write_index_entry(record_number, &entry);
         Ok(())
     }
 
+    /// Writes `buf` to the block the given entry points to, or to a new block if it does not fit.
+    /// Returns the entry pointing to the written block.
+    fn write_block(&mut self, entry: IndexEntry, buf: &[u8]) -> Result<IndexEntry, StorageError> {
+        let size_class =
+            size_class_for(buf.len()).ok_or(StorageError::EntrySizeLimitExceeded(buf.len()))?;
+
+        let (offset, size_class) = if entry.offset != 0 && entry.size_class >= size_class {
+            // the record still fits into its current block
+            (entry.offset, entry.size_class)
+        } else {
+            // the record grew out of its block (or never had one), relocate it
+            let offset = self.allocate_block(size_class)?;
+            if entry.offset != 0 {
+                self.free_block(entry.offset, entry.size_class);
+            }
+            self.flush();
+            (offset, size_class)
+        };
+
+        self.memory.write(offset, buf);
+        Ok(IndexEntry {
+            offset,
+            size: buf.len() as u32,
+            size_class,
+        })
+    }
+
     /// Reads the data of the specified user from stable memory.
     pub fn read(&self, user_number: UserNumber) -> Result<T, StorageError> {
         let record_number = self.user_number_to_record(user_number)?;
+        if self.is_deleted_record(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+
+        let buf = self
+            .read_record(record_number)
+            .unwrap_or_else(|err| trap(&err.to_string()));
+
+        let data: T = candid::decode_one(&buf).map_err(StorageError::DeserializationError)?;
+
+        Ok(data)
+    }
+
+    /// Reads the candid encoded data of the specified user without decoding it.
+    ///
+    /// Unlike [Storage::read] this does not trap on corrupted records, which makes it suitable
+    /// to check a dump of the stable memory.
+    pub fn read_bytes(&self, user_number: UserNumber) -> Result<Vec<u8>, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if self.is_deleted_record(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+        self.read_record(record_number)
+    }
+
+    /// Deletes the data and the event log of the specified user. The anchor stays allocated but
+    /// is marked as deleted: reading or writing it fails with [StorageError::AnchorDeleted].
+    pub fn delete(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if self.is_deleted_record(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+
+        if self.has_v1_layout(record_number) {
+            let stable_offset = self.record_offset_v1(record_number);
+            self.grow_memory_to(stable_offset + self.header.entry_size as u64);
+            let mut buf = vec![0; self.header.entry_size as usize];
+            buf[0..2].copy_from_slice(&DELETED_RECORD_V1.to_le_bytes());
+            self.memory.write(stable_offset, &buf);
+        } else {
+            let entry = self.read_index_entry(record_number);
+            if entry.offset != 0 {
+                self.free_block(entry.offset, entry.size_class);
+            }
+            self.write_index_entry(record_number, &deleted_index_entry());
+            if let Some(entry_offset) = self.event_index_entry(record_number)? {
+                let entry = self.read_index_entry_at(entry_offset);
+                if entry.offset != 0 {
+                    self.free_block(entry.offset, entry.size_class);
+                }
+                self.write_index_entry_at(entry_offset, &IndexEntry::default());
+            }
+        }
+        self.header.num_deleted += 1;
+        self.flush();
+        Ok(())
+    }
 
-        let stable_offset = HEADER_SIZE + record_number as u64 * self.header.entry_size as u64;
-        if stable_offset + self.header.entry_size as u64 > stable64_size() * WASM_PAGE_SIZE {
-            trap("a record for a valid Identity Anchor is out of stable memory bounds");
+    fn is_deleted_record(&self, record_number: u32) -> bool {
+        if self.has_v1_layout(record_number) {
+            let stable_offset = self.record_offset_v1(record_number);
+            if stable_offset + 2 > self.memory_size() {
+                return false;
+            }
+            let mut len = [0; 2];
+            self.memory.read(stable_offset, &mut len);
+            u16::from_le_bytes(len) == DELETED_RECORD_V1
+        } else {
+            let entry = self.read_index_entry(record_number);
+            entry.offset == 0 && entry.size == DELETED_RECORD_V2
+        }
+    }
+
+    fn read_record(&self, record_number: u32) -> Result<Vec<u8>, StorageError> {
+        if self.has_v1_layout(record_number) {
+            self.read_v1(record_number)
+        } else {
+            self.read_v2(record_number)
+        }
+    }
+
+    fn read_v1(&self, record_number: u32) -> Result<Vec<u8>, StorageError> {
+        let stable_offset = self.record_offset_v1(record_number);
+        if stable_offset + self.header.entry_size as u64 > self.memory_size() {
+            return Err(StorageError::CorruptedRecord(
+                "a record for a valid Identity Anchor is out of stable memory bounds".to_string(),
+            ));
         }
 
         let mut buf = vec![0; self.header.entry_size as usize];
-        stable64_read(stable_offset, &mut buf);
+        self.memory.read(stable_offset, &mut buf);
         let len = u16::from_le_bytes(buf[0..2].try_into().unwrap()) as usize;
 
         // This error most likely indicates stable memory corruption.
         if len > self.value_size_limit() {
-            trap(&format!(
+            return Err(StorageError::CorruptedRecord(format!(
                 "persisted value size {} exeeds maximum size {}",
                 len,
                 self.value_size_limit()
-            ))
+            )));
         }
 
-        let data: T =
-            candid::decode_one(&buf[2..2 + len]).map_err(StorageError::DeserializationError)?;
+        buf.drain(0..2);
+        buf.truncate(len);
+        Ok(buf)
+    }
 
-        Ok(data)
+    fn read_v2(&self, record_number: u32) -> Result<Vec<u8>, StorageError> {
+        self.read_block(&self.read_index_entry(record_number))
+    }
+
+    /// Reads the value stored in the block the given entry points to.
+    fn read_block(&self, entry: &IndexEntry) -> Result<Vec<u8>, StorageError> {
+        if entry.offset == 0 {
+            return Ok(vec![]);
+        }
+
+        // This error most likely indicates stable memory corruption.
+        if entry.size as u64 > block_size(entry.size_class) {
+            return Err(StorageError::CorruptedRecord(format!(
+                "persisted value size {} exeeds block size {}",
+                entry.size,
+                block_size(entry.size_class)
+            )));
+        }
+        if entry.offset + entry.size as u64 > self.memory_size() {
+            return Err(StorageError::CorruptedRecord(
+                "a record for a valid Identity Anchor is out of stable memory bounds".to_string(),
+            ));
+        }
+
+        let mut buf = vec![0; entry.size as usize];
+        self.memory.read(entry.offset, &mut buf);
+        Ok(buf)
+    }
+
+    /// Writes the event log of the specified user to stable memory.
+    ///
+    /// Event logs are stored on the heap like the anchor records. They are located with a second
+    /// index, which is only allocated once the first event is written: the `event_directory` of
+    /// the header points to a block holding the offsets of the index chunks, each chunk holds the
+    /// [IndexEntry]s of a range of anchors.
+    ///
+    /// Traps with layout version 1, which does not support event logs.
+    pub fn write_events<E: candid::CandidType>(
+        &mut self,
+        user_number: UserNumber,
+        events: &[E],
+    ) -> Result<(), StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if self.header.version == 1 {
+            trap("event logs are not supported with storage layout version 1");
+        }
+        if self.is_deleted_record(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+        let buf = candid::encode_one(events).map_err(StorageError::SerializationError)?;
+
+        let entry_offset = self.allocate_event_index_entry(record_number)?;
+        let entry = self.read_index_entry_at(entry_offset);
+        let entry = self.write_block(entry, &buf)?;
+        self.write_index_entry_at(entry_offset, &entry);
+        Ok(())
+    }
+
+    /// Reads the event log of the specified user, see [Storage::write_events].
+    ///
+    /// Returns an empty log if no events were written for the user (which is always the case
+    /// with layout version 1).
+    pub fn read_events<E: candid::CandidType + serde::de::DeserializeOwned>(
+        &self,
+        user_number: UserNumber,
+    ) -> Result<Vec<E>, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if self.header.version == 1 {
+            return Ok(vec![]);
+        }
+        let entry_offset = match self.event_index_entry(record_number)? {
+            Some(entry_offset) => entry_offset,
+            None => return Ok(vec![]),
+        };
+
+        let buf = self.read_block(&self.read_index_entry_at(entry_offset))?;
+        if buf.is_empty() {
+            return Ok(vec![]);
+        }
+        candid::decode_one(&buf).map_err(StorageError::DeserializationError)
+    }
+
+    /// Returns the offset of the event index entry of the given record, or None if the index
+    /// chunk of the record has not been allocated yet.
+    fn event_index_entry(&self, record_number: u32) -> Result<Option<u64>, StorageError> {
+        let directory = self.header.event_directory;
+        if directory == 0 {
+            return Ok(None);
+        }
+        let chunk_pointer = directory + event_index_chunk(record_number) * 8;
+        if chunk_pointer + 8 > self.memory_size() {
+            return Err(StorageError::CorruptedRecord(
+                "the event directory is out of stable memory bounds".to_string(),
+            ));
+        }
+        let mut chunk = [0; 8];
+        self.memory.read(chunk_pointer, &mut chunk);
+        let chunk = u64::from_le_bytes(chunk);
+        if chunk == 0 {
+            return Ok(None);
+        }
+        if chunk + block_size(EVENT_INDEX_CHUNK_SIZE_CLASS) > self.memory_size() {
+            return Err(StorageError::CorruptedRecord(
+                "an event index chunk is out of stable memory bounds".to_string(),
+            ));
+        }
+        Ok(Some(
+            chunk + (record_number as u64 % event_index_chunk_entries()) * INDEX_ENTRY_SIZE,
+        ))
+    }
+
+    /// Like [Storage::event_index_entry], but allocates the event directory and the index chunk
+    /// of the record if necessary.
+    fn allocate_event_index_entry(&mut self, record_number: u32) -> Result<u64, StorageError> {
+        if let Some(entry_offset) = self.event_index_entry(record_number)? {
+            return Ok(entry_offset);
+        }
+
+        if self.header.event_directory == 0 {
+            let chunks = event_index_chunk(self.max_entries() as u32 - 1) + 1;
+            let size_class = size_class_for(chunks as usize * 8)
+                .ok_or(StorageError::EntrySizeLimitExceeded(chunks as usize * 8))?;
+            self.header.event_directory = self.allocate_zeroed_block(size_class)?;
+            self.flush();
+        }
+        let chunk = self.allocate_zeroed_block(EVENT_INDEX_CHUNK_SIZE_CLASS)?;
+        self.flush();
+        let chunk_pointer = self.header.event_directory + event_index_chunk(record_number) * 8;
+        self.memory.write(chunk_pointer, &chunk.to_le_bytes());
+
+        Ok(chunk + (record_number as u64 % event_index_chunk_entries()) * INDEX_ENTRY_SIZE)
+    }
+
+    /// Starts the migration of the storage from layout version 1 to layout version 2.
+    ///
+    /// The records are migrated in batches by [Storage::migrate_records], so that the migration
+    /// does not have to fit into a single message. Until a record has been migrated it keeps its
+    /// version 1 slot, which is used to read, write and delete it.
+    ///
+    /// The index of layout version 2 overlaps with the version 1 records. This is safe because
+    /// records are migrated in order and an index entry is much smaller than a version 1 record,
+    /// i.e. the index entry of an anchor is only ever written after all the version 1 records
+    /// it overlaps with have been migrated. The heap starts after the last version 1 record.
+    pub fn migrate_to_v2(&mut self) {
+        if self.header.version != 1 {
+            trap(&format!(
+                "cannot migrate storage with layout version {} to version 2",
+                self.header.version
+            ));
+        }
+
+        let index_end = HEADER_SIZE + self.max_entries() as u64 * INDEX_ENTRY_SIZE;
+        let records_end = self.record_offset_v1(self.header.num_users);
+        self.header.heap_start = u64::max(index_end, records_end);
+        self.header.heap_end = self.header.heap_start;
+        self.header.free_lists = [0; NUM_SIZE_CLASSES];
+        self.header.event_directory = 0;
+        self.header.migration_next = 0;
+        self.header.migration_end = self.header.num_users;
+
+        self.header.version = 2;
+        self.flush();
+    }
+
+    /// Migrates up to `max_records` records that still have layout version 1, see
+    /// [Storage::migrate_to_v2]. Returns whether records are left to be migrated.
+    pub fn migrate_records(&mut self, max_records: u32) -> bool {
+        if !self.is_migrating() {
+            return false;
+        }
+        for _ in 0..max_records {
+            if !self.is_migrating() {
+                break;
+            }
+            self.migrate_next_record();
+        }
+        self.flush();
+        self.is_migrating()
+    }
+
+    /// Whether a migration to layout version 2 is in progress.
+    pub fn is_migrating(&self) -> bool {
+        self.header.migration_next < self.header.migration_end
+    }
+
+    /// Migrates the next version 1 record to layout version 2.
+    ///
+    /// Note: the caller is responsible for flushing the header.
+    fn migrate_next_record(&mut self) {
+        let record_number = self.header.migration_next;
+        if self.is_deleted_record(record_number) {
+            self.header.migration_next += 1;
+            self.write_index_entry(record_number, &deleted_index_entry());
+        } else {
+            let buf = self
+                .read_v1(record_number)
+                .unwrap_or_else(|err| trap(&err.to_string()));
+            self.header.migration_next += 1;
+            // the index entry still contains bytes of an already migrated record
+            self.write_index_entry(record_number, &IndexEntry::default());
+            self.write_v2(record_number, &buf).unwrap_or_else(|err| {
+                trap(&format!(
+                    "failed to migrate record {} to layout version 2: {}",
+                    record_number, err
+                ))
+            });
+        }
+        if !self.is_migrating() {
+            self.header.migration_next = 0;
+            self.header.migration_end = 0;
+        }
+    }
+
+    /// Whether the record has layout version 1, i.e. the storage has layout version 1 or the
+    /// record has not been migrated yet, see [Storage::migrate_to_v2].
+    fn has_v1_layout(&self, record_number: u32) -> bool {
+        self.header.version == 1
+            || (record_number >= self.header.migration_next
+                && record_number < self.header.migration_end)
     }
 
     /// Make sure all the required metadata is recorded to stable memory.
     pub fn flush(&self) {
-        if stable64_size() < 1 {
-            let result = stable64_grow(1);
-            if result.is_err() {
+        if self.memory.size() < 1 {
+            let result = self.memory.grow(1);
+            if result.is_none() {
                 trap("failed to grow stable memory by 1 page");
             }
         }
@@ -209,14 +724,160 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
                 &self.header as *const _ as *const u8,
                 std::mem::size_of::<Header>(),
             );
-            stable64_write(0, &slice);
+            self.memory.write(0, &slice);
+        }
+    }
+
+    /// Writes the given state to the reserved part of the stable memory. Unlike the state
+    /// written by [Storage::write_persistent_state], it is kept until it is overwritten, i.e.
+    /// it is read again by every upgrade, see [Storage::read_stable_state].
+    pub fn write_stable_state<S: candid::CandidType>(&self, state: &S) -> Result<(), StorageError> {
+        let buf = candid::encode_one(state).map_err(StorageError::SerializationError)?;
+        if STABLE_STATE_HEADER_SIZE + buf.len() as u64 > STABLE_STATE_MAX_SIZE {
+            return Err(StorageError::OutOfMemory(buf.len() as u64));
+        }
+        let data_offset = STABLE_STATE_OFFSET + STABLE_STATE_HEADER_SIZE;
+        self.grow_memory_to(data_offset + buf.len() as u64);
+
+        let mut header = [0; STABLE_STATE_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&STABLE_STATE_MAGIC);
+        header[4] = STABLE_STATE_VERSION;
+        header[5..13].copy_from_slice(&(buf.len() as u64).to_le_bytes());
+        self.memory.write(STABLE_STATE_OFFSET, &header);
+        self.memory.write(data_offset, &buf);
+        Ok(())
+    }
+
+    /// Reads the state written by [Storage::write_stable_state].
+    ///
+    /// Returns None if no state has been written yet. Unlike [Storage::read_persistent_state]
+    /// this fails if the state is corrupted or has an unsupported version, so that the caller
+    /// can refuse to continue without it.
+    pub fn read_stable_state<S: candid::CandidType + serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<Option<S>, StorageError> {
+        let data_offset = STABLE_STATE_OFFSET + STABLE_STATE_HEADER_SIZE;
+        let memory_size = self.memory_size();
+        if data_offset > memory_size {
+            return Ok(None);
+        }
+
+        let mut header = [0; STABLE_STATE_HEADER_SIZE as usize];
+        self.memory.read(STABLE_STATE_OFFSET, &mut header);
+        if header.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if header[0..4] != STABLE_STATE_MAGIC {
+            return Err(StorageError::CorruptedRecord(format!(
+                "stable state: invalid magic: {:?}",
+                &header[0..4]
+            )));
+        }
+        if header[4] != STABLE_STATE_VERSION {
+            return Err(StorageError::CorruptedRecord(format!(
+                "unsupported stable state version: {}",
+                header[4]
+            )));
+        }
+        let len = u64::from_le_bytes(header[5..13].try_into().unwrap());
+        if len > STABLE_STATE_MAX_SIZE - STABLE_STATE_HEADER_SIZE || len > memory_size - data_offset
+        {
+            return Err(StorageError::CorruptedRecord(format!(
+                "stable state size {} exceeds the stable state region",
+                len
+            )));
+        }
+
+        let mut buf = vec![0; len as usize];
+        self.memory.read(data_offset, &mut buf);
+        candid::decode_one(&buf)
+            .map(Some)
+            .map_err(StorageError::DeserializationError)
+    }
+
+    /// Writes the given state to the reserved part of the stable memory, so that it can be
+    /// restored using [Storage::read_persistent_state] after an upgrade. The anchors never use
+    /// the reserve, so the state stays valid until it is restored.
+    pub fn write_persistent_state(&self, state: &[u8]) -> Result<(), StorageError> {
+        let offset = PERSISTENT_STATE_OFFSET;
+        let data_offset = offset + PERSISTENT_STATE_MAGIC.len() as u64 + 8;
+        let end = data_offset + state.len() as u64;
+        if end > STABLE_MEMORY_SIZE {
+            return Err(StorageError::OutOfMemory(state.len() as u64));
+        }
+        self.grow_memory_to(end);
+        self.memory.write(offset, &PERSISTENT_STATE_MAGIC);
+        self.memory.write(
+            offset + PERSISTENT_STATE_MAGIC.len() as u64,
+            &(state.len() as u64).to_le_bytes(),
+        );
+        self.memory.write(data_offset, state);
+        Ok(())
+    }
+
+    /// Reads the state written by [Storage::write_persistent_state].
+    ///
+    /// Returns None if there is no persisted state, e.g. because the canister was upgraded
+    /// from a release that did not persist any state.
+    pub fn read_persistent_state(&self) -> Option<Vec<u8>> {
+        let offset = PERSISTENT_STATE_OFFSET;
+        let data_offset = offset + PERSISTENT_STATE_MAGIC.len() as u64 + 8;
+        let memory_size = self.memory_size();
+        if data_offset > memory_size {
+            return None;
+        }
+
+        let mut magic = [0; 4];
+        self.memory.read(offset, &mut magic);
+        if magic != PERSISTENT_STATE_MAGIC {
+            return None;
+        }
+        let mut len = [0; 8];
+        self.memory
+            .read(offset + PERSISTENT_STATE_MAGIC.len() as u64, &mut len);
+        let len = u64::from_le_bytes(len);
+        if len > memory_size - data_offset {
+            return None;
+        }
+
+        let mut state = vec![0; len as usize];
+        self.memory.read(data_offset, &mut state);
+        Some(state)
+    }
+
+    /// Invalidates the persisted state so that it cannot be restored a second time.
+    pub fn clear_persistent_state(&self) {
+        let offset = PERSISTENT_STATE_OFFSET;
+        if offset + PERSISTENT_STATE_MAGIC.len() as u64 <= self.memory_size() {
+            self.memory.write(offset, &[0; 4]);
         }
     }
 
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+
+    /// Returns the number of allocated anchors, including the deleted ones.
     pub fn user_count(&self) -> usize {
         self.header.num_users as usize
     }
 
+    pub fn deleted_count(&self) -> usize {
+        self.header.num_deleted as usize
+    }
+
+    pub fn entry_size(&self) -> u16 {
+        self.header.entry_size
+    }
+
+    /// Returns the start and end offsets of the heap (layout version 2 only).
+    pub fn heap_bounds(&self) -> Option<(u64, u64)> {
+        match self.header.version {
+            1 => None,
+            _ => Some((self.header.heap_start, self.header.heap_end)),
+        }
+    }
+
     /// Returns the maximum number of entries that this storage can fit.
     pub fn max_entries(&self) -> usize {
         ((STABLE_MEMORY_SIZE - HEADER_SIZE as u64 - STABLE_MEMORY_RESERVE)
@@ -251,6 +912,90 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
         self.header.entry_size as usize - std::mem::size_of::<u16>()
     }
 
+    fn record_offset_v1(&self, record_number: u32) -> u64 {
+        HEADER_SIZE + record_number as u64 * self.header.entry_size as u64
+    }
+
+    fn read_index_entry(&self, record_number: u32) -> IndexEntry {
+        self.read_index_entry_at(HEADER_SIZE + record_number as u64 * INDEX_ENTRY_SIZE)
+    }
+
+    fn read_index_entry_at(&self, stable_offset: u64) -> IndexEntry {
+        if stable_offset + INDEX_ENTRY_SIZE > self.memory_size() {
+            return IndexEntry::default();
+        }
+
+        let mut buf = [0; INDEX_ENTRY_SIZE as usize];
+        self.memory.read(stable_offset, &mut buf);
+        IndexEntry {
+            offset: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            size: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            size_class: buf[12],
+        }
+    }
+
+    fn write_index_entry(&self, record_number: u32, entry: &IndexEntry) {
+        self.write_index_entry_at(HEADER_SIZE + record_number as u64 * INDEX_ENTRY_SIZE, entry)
+    }
+
+    fn write_index_entry_at(&self, stable_offset: u64, entry: &IndexEntry) {
+        let mut buf = [0; INDEX_ENTRY_SIZE as usize];
+        buf[0..8].copy_from_slice(&entry.offset.to_le_bytes());
+        buf[8..12].copy_from_slice(&entry.size.to_le_bytes());
+        buf[12] = entry.size_class;
+
+        self.grow_memory_to(stable_offset + INDEX_ENTRY_SIZE);
+        self.memory.write(stable_offset, &buf);
+    }
+
+    /// Returns the offset of a free block of the given size class, either taken from the
+    /// corresponding free list or from the end of the heap.
+    ///
+    /// Note: the caller is responsible for flushing the header.
+    fn allocate_block(&mut self, size_class: u8) -> Result<u64, StorageError> {
+        let mut free_lists = self.header.free_lists;
+        let head = free_lists[size_class as usize];
+        if head != 0 {
+            let mut next = [0; 8];
+            self.memory.read(head, &mut next);
+            free_lists[size_class as usize] = u64::from_le_bytes(next);
+            self.header.free_lists = free_lists;
+            return Ok(head);
+        }
+
+        let offset = self.header.heap_end;
+        let end = offset + block_size(size_class);
+        if end > STABLE_MEMORY_SIZE - STABLE_MEMORY_RESERVE {
+            return Err(StorageError::OutOfMemory(block_size(size_class)));
+        }
+        self.grow_memory_to(end);
+        self.header.heap_end = end;
+        Ok(offset)
+    }
+
+    /// Like [Storage::allocate_block], but also clears the block, for blocks whose entries are
+    /// read as "not allocated" while they are zero (e.g. the event index). Reused blocks still
+    /// contain the freed record and the link of the free list.
+    ///
+    /// Note: the caller is responsible for flushing the header.
+    fn allocate_zeroed_block(&mut self, size_class: u8) -> Result<u64, StorageError> {
+        let offset = self.allocate_block(size_class)?;
+        self.memory
+            .write(offset, &vec![0; block_size(size_class) as usize]);
+        Ok(offset)
+    }
+
+    /// Puts the block at the given offset on the free list of its size class.
+    ///
+    /// Note: the caller is responsible for flushing the header.
+    fn free_block(&mut self, offset: u64, size_class: u8) {
+        let mut free_lists = self.header.free_lists;
+        self.memory
+            .write(offset, &free_lists[size_class as usize].to_le_bytes());
+        free_lists[size_class as usize] = offset;
+        self.header.free_lists = free_lists;
+    }
+
     fn user_number_to_record(&self, user_number: u64) -> Result<u32, StorageError> {
         if user_number < self.header.id_range_lo || user_number >= self.header.id_range_hi {
             return Err(StorageError::UserNumberOutOfRange {
@@ -265,6 +1010,54 @@ impl<T: candid::CandidType + serde::de::DeserializeOwned> Storage<T> {
         }
         Ok(record_number)
     }
+
+    /// Returns the size of the memory in bytes.
+    fn memory_size(&self) -> u64 {
+        self.memory.size() * WASM_PAGE_SIZE
+    }
+
+    /// Grows the memory so that it is at least `end` bytes large.
+    fn grow_memory_to(&self, end: u64) {
+        let current_size = self.memory.size();
+        let pages = (end + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        if pages > current_size {
+            let pages_to_grow = pages - current_size;
+            let result = self.memory.grow(pages_to_grow);
+            if result.is_none() {
+                trap(&format!(
+                    "failed to grow stable memory by {} pages",
+                    pages_to_grow
+                ))
+            }
+        }
+    }
+}
+
+/// Returns the smallest size class whose blocks can hold a value of `len` bytes.
+fn size_class_for(len: usize) -> Option<u8> {
+    (0..NUM_SIZE_CLASSES as u8).find(|size_class| block_size(*size_class) >= len as u64)
+}
+
+fn deleted_index_entry() -> IndexEntry {
+    IndexEntry {
+        offset: 0,
+        size: DELETED_RECORD_V2,
+        size_class: 0,
+    }
+}
+
+fn block_size(size_class: u8) -> u64 {
+    MIN_BLOCK_SIZE << size_class
+}
+
+/// Number of event index entries held by a chunk of the event index.
+fn event_index_chunk_entries() -> u64 {
+    block_size(EVENT_INDEX_CHUNK_SIZE_CLASS) / INDEX_ENTRY_SIZE
+}
+
+/// Returns the number of the event index chunk holding the entry of the given record.
+fn event_index_chunk(record_number: u32) -> u64 {
+    record_number as u64 / event_index_chunk_entries()
 }
 
 pub enum StorageError {
@@ -276,6 +1069,9 @@ pub enum StorageError {
     DeserializationError(candid::error::Error),
     SerializationError(candid::error::Error),
     EntrySizeLimitExceeded(usize),
+    OutOfMemory(u64),
+    CorruptedRecord(String),
+    AnchorDeleted(UserNumber),
 }
 
 impl fmt::Display for StorageError {
@@ -299,6 +1095,16 @@ impl fmt::Display for StorageError {
                  which is larger then the max allowed entry size",
                 n
             ),
+            Self::OutOfMemory(n) => write!(
+                f,
+                "failed to allocate a block of {} bytes: stable memory is exhausted",
+                n
+            ),
+            Self::CorruptedRecord(message) => write!(f, "{}", message),
+            Self::AnchorDeleted(n) => write!(f, "Identity Anchor {} has been deleted", n),
         }
     }
 }
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,75 @@
+//! The data of an Identity Anchor as it is stored in stable memory.
+use crate::public_key::KeyAlgorithm;
+use candid::{CandidType, Deserialize};
+use internet_identity_interface::{
+    CredentialId, DeviceData, DeviceKey, DeviceProtection, DeviceWithUsage, KeyType, Purpose,
+    Timestamp,
+};
+
+/// This is an internal version of `DeviceData` primarily useful to provide a
+/// backwards compatible level between older device data stored in stable memory
+/// (that might not contain purpose or key_type) and new ones added.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeviceDataInternal {
+    pub pubkey: DeviceKey,
+    pub alias: String,
+    pub credential_id: Option<CredentialId>,
+    pub purpose: Option<Purpose>,
+    pub key_type: Option<KeyType>,
+    pub protection: Option<DeviceProtection>,
+    pub created_at: Option<Timestamp>,
+    pub last_usage: Option<Timestamp>,
+    /// Detected when the device is added, not set for devices added before keys were parsed.
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+impl From<DeviceData> for DeviceDataInternal {
+    fn from(device_data: DeviceData) -> Self {
+        Self {
+            pubkey: device_data.pubkey,
+            alias: device_data.alias,
+            credential_id: device_data.credential_id,
+            purpose: Some(device_data.purpose),
+            key_type: Some(device_data.key_type),
+            protection: Some(device_data.protection),
+            created_at: None,
+            last_usage: None,
+            key_algorithm: None,
+        }
+    }
+}
+
+impl From<DeviceDataInternal> for DeviceData {
+    fn from(device_data_internal: DeviceDataInternal) -> Self {
+        Self {
+            pubkey: device_data_internal.pubkey,
+            alias: device_data_internal.alias,
+            credential_id: device_data_internal.credential_id,
+            purpose: device_data_internal
+                .purpose
+                .unwrap_or(Purpose::Authentication),
+            key_type: device_data_internal.key_type.unwrap_or(KeyType::Unknown),
+            protection: device_data_internal
+                .protection
+                .unwrap_or(DeviceProtection::Unprotected),
+        }
+    }
+}
+
+impl From<DeviceDataInternal> for DeviceWithUsage {
+    fn from(device_data_internal: DeviceDataInternal) -> Self {
+        let created_at = device_data_internal.created_at;
+        let last_usage = device_data_internal.last_usage;
+        let device_data = DeviceData::from(device_data_internal);
+        Self {
+            pubkey: device_data.pubkey,
+            alias: device_data.alias,
+            credential_id: device_data.credential_id,
+            purpose: device_data.purpose,
+            key_type: device_data.key_type,
+            protection: device_data.protection,
+            created_at,
+            last_usage,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use super::*;
+
+fn new_storage(memory: &VecMemory) -> Storage<Vec<String>, VecMemory> {
+    Storage::new((10, 20), memory.clone())
+}
+
+#[test]
+fn test_write_and_read_after_reload() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    storage
+        .write(user_number, vec!["device".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.user_count(), 1);
+    assert_eq!(storage.assigned_user_number_range(), (10, 20));
+    assert_eq!(
+        storage.read(user_number).ok(),
+        Some(vec!["device".to_string()])
+    );
+}
+
+#[test]
+fn test_replace_salt_without_anchors() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    storage.update_salt([1; 32]);
+    storage.replace_salt([2; 32]);
+
+    let storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.salt(), Some(&[2; 32]));
+}
+
+#[test]
+fn test_keep_records_when_migrating_to_v2() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    for i in 0..3 {
+        let user_number = storage.allocate_user_number().unwrap();
+        storage
+            .write(user_number, vec![i.to_string(); i + 1])
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    storage.migrate_to_v2();
+    assert!(!storage.migrate_records(u32::MAX));
+    // grow a record so that it gets relocated
+    storage
+        .write(11, vec!["x".repeat(200)])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.version(), 2);
+    assert_eq!(storage.read(10).ok(), Some(vec!["0".to_string()]));
+    assert_eq!(storage.read(11).ok(), Some(vec!["x".repeat(200)]));
+    assert_eq!(storage.read(12).ok(), Some(vec!["2".to_string(); 3]));
+}
+
+#[test]
+fn test_migrate_to_v2_in_batches() {
+    let memory = VecMemory::default();
+    let mut storage: Storage<Vec<String>, VecMemory> = Storage::new((10, 1_000), memory.clone());
+    for i in 0..200 {
+        let user_number = storage.allocate_user_number().unwrap();
+        storage
+            .write(user_number, vec![i.to_string()])
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    storage.delete(15).unwrap_or_else(|err| panic!("{}", err));
+
+    storage.migrate_to_v2();
+    assert!(storage.migrate_records(3));
+    // records that have not been migrated yet can still be written and deleted
+    storage
+        .write(14, vec!["x".repeat(200)])
+        .unwrap_or_else(|err| panic!("{}", err));
+    storage.delete(16).unwrap_or_else(|err| panic!("{}", err));
+    // the index entry of a new anchor overlaps with records that have not been migrated yet
+    let user_number = storage.allocate_user_number().unwrap();
+    storage
+        .write(user_number, vec!["new".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let mut storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+    assert!(storage.is_migrating());
+    assert_eq!(storage.read(13).ok(), Some(vec!["3".to_string()]));
+    assert!(!storage.migrate_records(u32::MAX));
+
+    assert_eq!(storage.version(), 2);
+    assert_eq!(storage.deleted_count(), 2);
+    for i in 0..200 {
+        let user_number = 10 + i;
+        match user_number {
+            14 => assert_eq!(storage.read(14).ok(), Some(vec!["x".repeat(200)])),
+            15 | 16 => assert!(matches!(
+                storage.read(user_number),
+                Err(StorageError::AnchorDeleted(_))
+            )),
+            _ => assert_eq!(storage.read(user_number).ok(), Some(vec![i.to_string()])),
+        }
+    }
+    assert_eq!(storage.read(210).ok(), Some(vec!["new".to_string()]));
+}
+
+#[test]
+fn test_report_corrupted_length_prefix() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    storage
+        .write(user_number, vec!["device".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    memory.write(HEADER_SIZE, &u16::MAX.to_le_bytes());
+
+    assert!(matches!(
+        storage.read_bytes(user_number),
+        Err(StorageError::CorruptedRecord(_))
+    ));
+}
+
+#[test]
+fn test_reject_invalid_header() {
+    let memory = VecMemory::default();
+    new_storage(&memory).flush();
+    memory.write(0, b"XYZ");
+
+    let result = Storage::<Vec<String>, VecMemory>::try_from_memory(memory);
+    assert!(matches!(result, Err(message) if message.contains("invalid magic")));
+}
+
+#[test]
+fn test_empty_memory() {
+    let result = Storage::<Vec<String>, VecMemory>::try_from_memory(VecMemory::default());
+    assert!(matches!(result, Ok(None)));
+}
+
+#[test]
+fn test_write_and_read_events_after_reload() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    for _ in 0..2 {
+        let user_number = storage.allocate_user_number().unwrap();
+        storage
+            .write(user_number, vec!["device".to_string()])
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    storage.migrate_to_v2();
+    storage.migrate_records(u32::MAX);
+    assert_eq!(storage.read_events::<String>(10).ok(), Some(vec![]));
+
+    storage
+        .write_events(10, &["added".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+    // grow the log so that it gets relocated
+    storage
+        .write_events(10, &vec!["x".repeat(200); 2])
+        .unwrap_or_else(|err| panic!("{}", err));
+    storage
+        .write_events(11, &["removed".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.read_events::<String>(10).ok(),
+        Some(vec!["x".repeat(200); 2])
+    );
+    assert_eq!(
+        storage.read_events::<String>(11).ok(),
+        Some(vec!["removed".to_string()])
+    );
+    assert_eq!(storage.read(10).ok(), Some(vec!["device".to_string()]));
+    assert_eq!(storage.read(11).ok(), Some(vec!["device".to_string()]));
+}
+
+#[test]
+fn test_no_events_with_v1() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    let user_number = storage.allocate_user_number().unwrap();
+
+    assert_eq!(
+        storage.read_events::<String>(user_number).ok(),
+        Some(vec![])
+    );
+}
+
+#[test]
+fn test_delete_anchor() {
+    for version in [1, 2] {
+        let memory = VecMemory::default();
+        let mut storage = new_storage(&memory);
+        if version == 2 {
+            storage.migrate_to_v2();
+        }
+        for _ in 0..2 {
+            let user_number = storage.allocate_user_number().unwrap();
+            storage
+                .write(user_number, vec!["device".to_string()])
+                .unwrap_or_else(|err| panic!("{}", err));
+        }
+
+        storage.delete(10).unwrap_or_else(|err| panic!("{}", err));
+
+        let mut storage: Storage<Vec<String>, VecMemory> = Storage::from_memory(memory).unwrap();
+        assert_eq!(storage.user_count(), 2);
+        assert_eq!(storage.deleted_count(), 1);
+        assert!(matches!(
+            storage.read(10),
+            Err(StorageError::AnchorDeleted(10))
+        ));
+        assert!(matches!(
+            storage.write(10, vec!["device".to_string()]),
+            Err(StorageError::AnchorDeleted(10))
+        ));
+        assert!(matches!(
+            storage.delete(10),
+            Err(StorageError::AnchorDeleted(10))
+        ));
+        assert_eq!(storage.read(11).ok(), Some(vec!["device".to_string()]));
+    }
+}
+
+#[test]
+fn test_keep_deleted_anchors_when_migrating_to_v2() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    for _ in 0..3 {
+        let user_number = storage.allocate_user_number().unwrap();
+        storage
+            .write(user_number, vec!["device".to_string()])
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    storage.delete(11).unwrap_or_else(|err| panic!("{}", err));
+
+    storage.migrate_to_v2();
+    storage.migrate_records(u32::MAX);
+
+    assert!(matches!(
+        storage.read(11),
+        Err(StorageError::AnchorDeleted(11))
+    ));
+    assert_eq!(storage.read(12).ok(), Some(vec!["device".to_string()]));
+    assert_eq!(storage.deleted_count(), 1);
+}
+
+#[test]
+fn test_delete_events_of_deleted_anchor() {
+    let memory = VecMemory::default();
+    let mut storage = new_storage(&memory);
+    storage.migrate_to_v2();
+    let user_number = storage.allocate_user_number().unwrap();
+    storage
+        .write(user_number, vec!["device".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+    storage
+        .write_events(user_number, &["event".to_string()])
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    storage
+        .delete(user_number)
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let events: Vec<String> = storage
+        .read_events(user_number)
+        .unwrap_or_else(|err| panic!("{}", err));
+    assert!(events.is_empty());
+}
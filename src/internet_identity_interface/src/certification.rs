@@ -0,0 +1,109 @@
+//! Helpers to verify the certified responses of the Internet Identity canister.
+use crate::hash::{self, Hash, Value};
+use crate::{
+    CertifiedAnchorInfoResponse, DeviceData, DeviceProtection, KeyType, Purpose, UserNumber,
+};
+use candid::types::ic_types::hash_tree::LookupResult;
+use candid::types::ic_types::HashTree;
+use std::collections::HashMap;
+
+/// The label of the subtree of the certified data that contains the hashes of the devices of
+/// every anchor as returned by `lookup`, see [credentials_hash].
+pub const LABEL_CREDENTIALS: &str = "credentials";
+
+/// The label of the subtree of the certified data that contains the hashes of the devices of
+/// every anchor, see [devices_hash].
+pub const LABEL_DEVICES: &str = "devices";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificationError {
+    /// The tree is not a CBOR encoded hash tree.
+    MalformedTree(String),
+    /// The tree does not contain the path ["devices", <user_number>].
+    DevicesNotCertified,
+    /// The certified hash does not match the returned devices.
+    DevicesHashMismatch,
+}
+
+/// The certified hash of the devices of an anchor as returned by `lookup`, i.e. [devices_hash]
+/// without the aliases.
+pub fn credentials_hash(devices: &[DeviceData]) -> Hash {
+    hash_devices(devices, false)
+}
+
+/// The certified hash of the devices of an anchor: the representation independent hash of the
+/// array of devices, each a map of `pubkey`, `alias`, `credential_id` (if present), `purpose`,
+/// `key_type` and `protection`, the latter three as the names of their variants.
+pub fn devices_hash(devices: &[DeviceData]) -> Hash {
+    hash_devices(devices, true)
+}
+
+/// Verifies that the devices of the anchor info returned by `get_anchor_info_certified` are the
+/// ones certified in the returned tree and returns the root hash of the tree.
+///
+/// NOTE: the response is only trustworthy if the returned root hash is the certified data of the
+/// Internet Identity canister in the returned certificate and the certificate is valid (i.e. it
+/// is signed by the IC root key and recent). Only the devices are certified, not their usage
+/// timestamps or the other fields of the anchor info.
+pub fn verify_anchor_info(
+    user_number: UserNumber,
+    response: &CertifiedAnchorInfoResponse,
+) -> Result<Hash, CertificationError> {
+    let tree: HashTree = serde_cbor::from_slice(&response.tree)
+        .map_err(|err| CertificationError::MalformedTree(err.to_string()))?;
+
+    let devices: Vec<DeviceData> = response
+        .info
+        .devices
+        .iter()
+        .cloned()
+        .map(DeviceData::from)
+        .collect();
+    match tree.lookup_path(&[
+        LABEL_DEVICES.into(),
+        (&user_number.to_be_bytes()[..]).into(),
+    ]) {
+        LookupResult::Found(hash) if hash == &devices_hash(&devices)[..] => Ok(tree.digest()),
+        LookupResult::Found(_) => Err(CertificationError::DevicesHashMismatch),
+        _ => Err(CertificationError::DevicesNotCertified),
+    }
+}
+
+fn hash_devices(devices: &[DeviceData], with_alias: bool) -> Hash {
+    let mut device_hashes = Vec::with_capacity(devices.len() * 32);
+    for device in devices {
+        let mut m = HashMap::new();
+        m.insert("pubkey", Value::Bytes(device.pubkey.as_slice()));
+        if with_alias {
+            m.insert("alias", Value::String(&device.alias));
+        }
+        if let Some(credential_id) = device.credential_id.as_ref() {
+            m.insert("credential_id", Value::Bytes(credential_id.as_slice()));
+        }
+        m.insert(
+            "purpose",
+            Value::String(match device.purpose {
+                Purpose::Authentication => "authentication",
+                Purpose::Recovery => "recovery",
+            }),
+        );
+        m.insert(
+            "key_type",
+            Value::String(match device.key_type {
+                KeyType::Unknown => "unknown",
+                KeyType::Platform => "platform",
+                KeyType::CrossPlatform => "cross_platform",
+                KeyType::SeedPhrase => "seed_phrase",
+            }),
+        );
+        m.insert(
+            "protection",
+            Value::String(match device.protection {
+                DeviceProtection::Protected => "protected",
+                DeviceProtection::Unprotected => "unprotected",
+            }),
+        );
+        device_hashes.extend_from_slice(&hash::hash_of_map(m));
+    }
+    hash::hash_bytes(device_hashes)
+}
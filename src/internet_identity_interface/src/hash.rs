@@ -1,14 +1,14 @@
 //! Provides helper functions to calculate the representation independent hash
 //! of structured data.
-use ic_certified_map::Hash;
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::convert::AsRef;
 
-#[derive(Clone, Serialize, Deserialize)]
+pub type Hash = [u8; 32];
+
+#[derive(Clone)]
 pub enum Value<'a> {
-    Bytes(#[serde(with = "serde_bytes")] &'a [u8]),
+    Bytes(&'a [u8]),
     String(&'a str),
     U64(u64),
     Array(Vec<Value<'a>>),
@@ -2,6 +2,9 @@ use candid::{CandidType, Deserialize, Func, Principal};
 use serde_bytes::{ByteBuf, Bytes};
 use std::borrow::Cow;
 
+pub mod certification;
+pub mod hash;
+
 pub type UserNumber = u64;
 pub type CredentialId = ByteBuf;
 pub type PublicKey = ByteBuf;
@@ -13,6 +16,7 @@ pub type Timestamp = u64; // in nanos since epoch
 pub type Signature = ByteBuf;
 pub type DeviceVerificationCode = String;
 pub type FailedAttemptsCounter = u8;
+pub type SessionKeyHash = ByteBuf;
 
 pub struct Base64(pub String);
 
@@ -26,6 +30,34 @@ pub struct DeviceData {
     pub protection: DeviceProtection,
 }
 
+/// A device as returned by `get_anchor_info`, i.e. including when it was added and last used.
+/// The timestamps are not known for devices that were added (or last used) before they were
+/// tracked.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct DeviceWithUsage {
+    pub pubkey: DeviceKey,
+    pub alias: String,
+    pub credential_id: Option<CredentialId>,
+    pub purpose: Purpose,
+    pub key_type: KeyType,
+    pub protection: DeviceProtection,
+    pub created_at: Option<Timestamp>,
+    pub last_usage: Option<Timestamp>,
+}
+
+impl From<DeviceWithUsage> for DeviceData {
+    fn from(device: DeviceWithUsage) -> Self {
+        Self {
+            pubkey: device.pubkey,
+            alias: device.alias,
+            credential_id: device.credential_id,
+            purpose: device.purpose,
+            key_type: device.key_type,
+            protection: device.protection,
+        }
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
 pub enum Purpose {
     #[serde(rename = "recovery")]
@@ -42,6 +74,8 @@ pub enum RegisterResponse {
     CanisterFull,
     #[serde(rename = "bad_challenge")]
     BadChallenge,
+    #[serde(rename = "rate_limited")]
+    RateLimited,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -72,6 +106,53 @@ pub struct Challenge {
 
 pub type ChallengeKey = String;
 
+/// A proof of work challenge: the solution is a nonce such that the SHA-256 hash of
+/// `<challenge_key>:<nonce>` starts with `difficulty` zero bits.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ProofOfWorkChallenge {
+    pub challenge_key: ChallengeKey,
+    pub difficulty: u8,
+}
+
+/// The challenge returned by `create_challenge_v2`, depending on the configured [ChallengeConfig].
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CreateChallengeResponse {
+    #[serde(rename = "captcha")]
+    Captcha(Challenge),
+    #[serde(rename = "proof_of_work")]
+    ProofOfWork(ProofOfWorkChallenge),
+}
+
+/// The mechanism used for the challenges that protect the registration.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum ChallengeConfig {
+    #[serde(rename = "captcha")]
+    Captcha,
+    #[serde(rename = "proof_of_work")]
+    ProofOfWork { difficulty: u8 },
+}
+
+/// The format of the verification codes of tentative devices.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum VerificationCodeFormat {
+    #[serde(rename = "decimal")]
+    Decimal { digits: u8 },
+    /// Upper case letters and digits, without the easily confused 0, O, 1 and I.
+    #[serde(rename = "alphanumeric")]
+    Alphanumeric { length: u8 },
+}
+
+/// Which devices can be marked as [DeviceProtection::Protected]. A protected device can only be
+/// updated or removed by authenticating with the device itself, independently of its purpose.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum DeviceProtectionPolicy {
+    #[serde(rename = "recovery_phrases_only")]
+    RecoveryPhrasesOnly,
+    /// Recovery phrases and hardware security keys ([KeyType::CrossPlatform]).
+    #[serde(rename = "recovery_phrases_and_security_keys")]
+    RecoveryPhrasesAndSecurityKeys,
+}
+
 // The user's attempt
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ChallengeAttempt {
@@ -111,6 +192,13 @@ pub enum AddTentativeDeviceResponse {
     DeviceRegistrationModeOff,
     #[serde(rename = "another_device_tentatively_added")]
     AnotherDeviceTentativelyAdded,
+    /// The device has already been added tentatively and waits for its verification.
+    #[serde(rename = "already_added_tentatively")]
+    AlreadyAddedTentatively,
+    /// No new devices can be registered until `cooldown_until` because of repeated failed
+    /// verifications, see [DeviceRegistrationFailures].
+    #[serde(rename = "device_registration_cooldown")]
+    DeviceRegistrationCooldown { cooldown_until: Timestamp },
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -128,13 +216,268 @@ pub enum VerifyTentativeDeviceResponse {
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct DeviceRegistrationInfo {
     pub expiration: Timestamp,
+    /// The first of the `tentative_devices`, kept for clients that expect a single one.
     pub tentative_device: Option<DeviceData>,
+    pub tentative_devices: Option<Vec<DeviceData>>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct IdentityAnchorInfo {
-    pub devices: Vec<DeviceData>,
+    pub devices: Vec<DeviceWithUsage>,
     pub device_registration: Option<DeviceRegistrationInfo>,
+    /// When the anchor was locked with `lock_anchor`, if it is locked.
+    pub locked_since: Option<Timestamp>,
+    pub recovery_device_policy: Option<RecoveryDevicePolicy>,
+    pub device_registration_failures: Option<DeviceRegistrationFailures>,
+}
+
+/// The device registration rounds of an anchor that failed because wrong verification codes were
+/// entered too often. Repeated failures block the registration of new devices for increasingly
+/// long cooldowns, the history is forgotten a week after the last failure.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub struct DeviceRegistrationFailures {
+    pub failed_rounds: u32,
+    pub last_failure: Timestamp,
+    /// The end of the current cooldown, if there is one.
+    pub cooldown_until: Option<Timestamp>,
+}
+
+/// Whether the recovery devices of an anchor can be used to sign in to dapps, see
+/// `set_recovery_device_policy`.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum RecoveryDevicePolicy {
+    #[serde(rename = "unrestricted")]
+    Unrestricted,
+    /// Recovery devices can only add, update or remove devices and use the device registration
+    /// mode, `prepare_delegation` and `get_delegation` reject them.
+    #[serde(rename = "anchor_management_only")]
+    AnchorManagementOnly,
+}
+
+/// Result of `delete_anchor`.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum DeleteAnchorResponse {
+    #[serde(rename = "deleted")]
+    Deleted,
+    /// The deletion was confirmed by a protected device, but the protected devices listed still
+    /// have to confirm it before the deadline.
+    #[serde(rename = "confirmation_pending")]
+    ConfirmationPending {
+        missing_confirmations: Vec<DeviceKey>,
+        deadline: Timestamp,
+    },
+}
+
+/// An entry of the event log of an anchor, see `get_anchor_events`.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct AnchorEvent {
+    /// Increases by one with every event of the anchor, used as the pagination cursor.
+    pub sequence_number: u64,
+    pub timestamp: Timestamp,
+    pub event_type: AnchorEventType,
+    /// The device the call causing the event was authenticated with.
+    pub acting_device: DeviceKey,
+    /// The device that was added, updated or removed.
+    pub affected_device: Option<DeviceKey>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum AnchorEventType {
+    #[serde(rename = "device_added")]
+    DeviceAdded,
+    #[serde(rename = "device_updated")]
+    DeviceUpdated,
+    #[serde(rename = "device_removed")]
+    DeviceRemoved,
+    #[serde(rename = "tentative_device_verified")]
+    TentativeDeviceVerified,
+    #[serde(rename = "delegation_prepared")]
+    DelegationPrepared,
+    #[serde(rename = "anchor_locked")]
+    AnchorLocked,
+    #[serde(rename = "anchor_unlocked")]
+    AnchorUnlocked,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct AnchorEvents {
+    pub events: Vec<AnchorEvent>,
+    /// The cursor to fetch the next page of events with, if there are more events.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct SessionInfo {
+    pub frontend: FrontendHostname,
+    pub session_key_hash: SessionKeyHash,
+    pub expiration: Timestamp,
+    pub issued_at: Timestamp,
+}
+
+/// The response of `get_anchor_info_certified`, see [certification::verify_anchor_info].
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedAnchorInfoResponse {
+    pub info: IdentityAnchorInfo,
+    pub certificate: Option<ByteBuf>,
+    pub tree: ByteBuf,
+}
+
+/// The response of `lookup_certified`, see `lookup`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedLookupResponse {
+    pub devices: Vec<DeviceData>,
+    pub certificate: Option<ByteBuf>,
+    pub tree: ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SessionRevocationStatus {
+    pub revoked: bool,
+    pub certificate: Option<ByteBuf>,
+    pub tree: ByteBuf,
+}
+
+/// An operation of `apply_device_operations`.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum DeviceOperation {
+    #[serde(rename = "add")]
+    Add(DeviceData),
+    /// Replaces the device with the same public key.
+    #[serde(rename = "update")]
+    Update(DeviceData),
+    #[serde(rename = "remove")]
+    Remove(DeviceKey),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum AddDeviceResponse {
+    #[serde(rename = "added")]
+    Added,
+    #[serde(rename = "unknown_anchor")]
+    UnknownAnchor,
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_device")]
+    InvalidDevice(String),
+    #[serde(rename = "device_already_added")]
+    DeviceAlreadyAdded,
+    #[serde(rename = "too_many_devices")]
+    TooManyDevices { limit: u64 },
+    #[serde(rename = "anchor_locked")]
+    AnchorLocked,
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum UpdateDeviceResponse {
+    #[serde(rename = "updated")]
+    Updated,
+    #[serde(rename = "unknown_anchor")]
+    UnknownAnchor,
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_device")]
+    InvalidDevice(String),
+    #[serde(rename = "device_not_found")]
+    DeviceNotFound,
+    #[serde(rename = "device_protected")]
+    DeviceProtected,
+    #[serde(rename = "anchor_locked")]
+    AnchorLocked,
+    #[serde(rename = "no_recovery_device_to_unlock")]
+    NoRecoveryDeviceToUnlock,
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum RemoveDeviceResponse {
+    #[serde(rename = "removed")]
+    Removed,
+    #[serde(rename = "unknown_anchor")]
+    UnknownAnchor,
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "device_not_found")]
+    DeviceNotFound,
+    #[serde(rename = "device_protected")]
+    DeviceProtected,
+    #[serde(rename = "anchor_locked")]
+    AnchorLocked,
+    #[serde(rename = "no_recovery_device_to_unlock")]
+    NoRecoveryDeviceToUnlock,
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum CredentialLookupResponse {
+    #[serde(rename = "anchors")]
+    Anchors(Vec<UserNumber>),
+    #[serde(rename = "not_indexed_yet")]
+    NotIndexedYet,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum GetAnchorInfoResponse {
+    #[serde(rename = "anchor_info")]
+    AnchorInfo(IdentityAnchorInfo),
+    #[serde(rename = "unknown_anchor")]
+    UnknownAnchor,
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum PrepareDelegationResponse {
+    #[serde(rename = "prepared")]
+    Prepared {
+        user_key: UserKey,
+        expiration: Timestamp,
+    },
+    #[serde(rename = "unknown_anchor")]
+    UnknownAnchor,
+    #[serde(rename = "not_authenticated")]
+    NotAuthenticated,
+    #[serde(rename = "invalid_argument")]
+    InvalidArgument(String),
+    #[serde(rename = "invalid_derivation_origin")]
+    InvalidDerivationOrigin(String),
+    #[serde(rename = "session_revoked")]
+    SessionRevoked,
+    #[serde(rename = "anchor_locked")]
+    AnchorLocked,
+    #[serde(rename = "recovery_device_not_allowed")]
+    RecoveryDeviceNotAllowed,
+    #[serde(rename = "storage_error")]
+    StorageError(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub struct AnchorExportHeader {
+    pub assigned_user_number_range: (UserNumber, UserNumber),
+    pub num_anchors: u64,
+    pub salt: Option<ByteBuf>,
+    pub storage_layout_version: u8,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub struct ExportedAnchor {
+    pub user_number: UserNumber,
+    // candid encoded devices of the anchor, empty if the anchor has been deleted
+    pub data: ByteBuf,
+    // candid encoded event log of the anchor, missing in exports of releases without event logs
+    pub events: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AnchorExport {
+    pub header: AnchorExportHeader,
+    pub anchors: Vec<ExportedAnchor>,
+    pub next_user_number: Option<UserNumber>,
 }
 
 pub type HeaderField = (String, String);
@@ -171,5 +514,51 @@ pub struct HttpResponse {
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct InternetIdentityInit {
-    pub assigned_user_number_range: (UserNumber, UserNumber),
+    pub assigned_user_number_range: Option<(UserNumber, UserNumber)>,
+    pub storage_layout_version: Option<u8>,
+    pub admin: Option<Principal>,
+    pub config: Option<InternetIdentityConfigOverrides>,
+}
+
+/// The limits of the canister, see `config()`.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub struct InternetIdentityConfig {
+    pub max_entries_per_user: u64,
+    pub max_expiration_period_ns: u64,
+    pub default_expiration_period_ns: u64,
+    pub captcha_challenge_lifetime_ns: u64,
+    pub max_inflight_challenges: u64,
+    pub registration_mode_duration_ns: u64,
+    pub max_device_registration_attempts: u8,
+    pub registration_rate_limit: RateLimitConfig,
+    pub challenge: ChallengeConfig,
+    pub device_protection_policy: DeviceProtectionPolicy,
+    pub verification_code_format: VerificationCodeFormat,
+    pub alternative_origins_canisters: Vec<Principal>,
+}
+
+/// Overrides of the [InternetIdentityConfig] passed on install or upgrade. Fields that are
+/// `None` keep their current value.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct InternetIdentityConfigOverrides {
+    pub max_entries_per_user: Option<u64>,
+    pub max_expiration_period_ns: Option<u64>,
+    pub default_expiration_period_ns: Option<u64>,
+    pub captcha_challenge_lifetime_ns: Option<u64>,
+    pub max_inflight_challenges: Option<u64>,
+    pub registration_mode_duration_ns: Option<u64>,
+    pub max_device_registration_attempts: Option<u8>,
+    pub registration_rate_limit: Option<RateLimitConfig>,
+    pub challenge: Option<ChallengeConfig>,
+    pub device_protection_policy: Option<DeviceProtectionPolicy>,
+    pub verification_code_format: Option<VerificationCodeFormat>,
+    pub alternative_origins_canisters: Option<Vec<Principal>>,
+}
+
+/// Token bucket rate limit: a token is added every `time_per_token_ns`, at most `max_tokens`
+/// can be accumulated.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub time_per_token_ns: u64,
+    pub max_tokens: u64,
 }
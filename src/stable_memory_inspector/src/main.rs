@@ -0,0 +1,152 @@
+//! Offline inspection of a dump of the Internet Identity stable memory.
+//!
+//! ```text
+//! stable_memory_inspector <dump> header
+//! stable_memory_inspector <dump> anchor <identity anchor>
+//! stable_memory_inspector <dump> check
+//! ```
+//!
+//! `header` prints the storage header, `anchor` decodes the devices of a single Identity Anchor
+//! and `check` validates the record of every Identity Anchor (length prefix resp. index entry and
+//! Candid encoding) and prints a JSON report of the corrupted records. `check` exits with status 1
+//! if there are corrupted records, all commands exit with status 2 if the dump cannot be read.
+use internet_identity::storage::anchor::DeviceDataInternal;
+use internet_identity::storage::{Storage, StorageError, VecMemory};
+use internet_identity_interface::UserNumber;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::process::exit;
+use std::{env, fs};
+
+const WASM_PAGE_SIZE: usize = 65536;
+
+type AnchorStorage = Storage<Vec<DeviceDataInternal>, VecMemory>;
+
+#[derive(Serialize)]
+struct Report {
+    storage_layout_version: u8,
+    num_anchors: usize,
+    corrupted_records: Vec<CorruptedRecord>,
+}
+
+#[derive(Serialize)]
+struct CorruptedRecord {
+    user_number: UserNumber,
+    error: String,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let storage = match args.get(1) {
+        Some(path) => load_storage(path),
+        None => usage(),
+    };
+
+    match (args.get(2).map(String::as_str), args.get(3)) {
+        (Some("header"), None) => print_header(&storage),
+        (Some("anchor"), Some(user_number)) => {
+            let user_number = user_number.parse().unwrap_or_else(|_| usage());
+            print_anchor(&storage, user_number);
+        }
+        (Some("check"), None) => {
+            let report = check(&storage);
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.corrupted_records.is_empty() {
+                exit(1);
+            }
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: stable_memory_inspector <dump> (header | anchor <identity anchor> | check)");
+    exit(2)
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(2)
+}
+
+fn load_storage(path: &str) -> AnchorStorage {
+    let mut bytes =
+        fs::read(path).unwrap_or_else(|err| fail(&format!("failed to read {}: {}", path, err)));
+    // the stable memory always consists of whole pages
+    let len = (bytes.len() + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE * WASM_PAGE_SIZE;
+    bytes.resize(len, 0);
+
+    match Storage::try_from_memory(VecMemory::new(RefCell::new(bytes))) {
+        Ok(Some(storage)) => storage,
+        Ok(None) => fail(&format!("{} is empty", path)),
+        Err(err) => fail(&err),
+    }
+}
+
+fn print_header(storage: &AnchorStorage) {
+    let (lo, hi) = storage.assigned_user_number_range();
+    println!("version:     {}", storage.version());
+    println!("num_users:   {}", storage.user_count());
+    println!("num_deleted: {}", storage.deleted_count());
+    println!("id_range:    [{}, {})", lo, hi);
+    println!("entry_size:  {}", storage.entry_size());
+    println!(
+        "salt:        {}",
+        storage
+            .salt()
+            .map(hex::encode)
+            .unwrap_or_else(|| "<not set>".to_string())
+    );
+    if let Some((heap_start, heap_end)) = storage.heap_bounds() {
+        println!("heap:        [{}, {})", heap_start, heap_end);
+    }
+}
+
+fn print_anchor(storage: &AnchorStorage, user_number: UserNumber) {
+    let devices = read_anchor(storage, user_number).unwrap_or_else(|err| {
+        fail(&format!(
+            "failed to read device data of user {}: {}",
+            user_number, err
+        ))
+    });
+    for device in devices {
+        println!("alias:         {}", device.alias);
+        println!("pubkey:        {}", hex::encode(&device.pubkey));
+        if let Some(credential_id) = device.credential_id {
+            println!("credential_id: {}", hex::encode(&credential_id));
+        }
+        println!("purpose:       {:?}", device.purpose);
+        println!("key_type:      {:?}", device.key_type);
+        println!("protection:    {:?}", device.protection);
+        println!();
+    }
+}
+
+/// Validates the records of all Identity Anchors that have not been deleted.
+fn check(storage: &AnchorStorage) -> Report {
+    let (lo, _) = storage.assigned_user_number_range();
+    let corrupted_records = (lo..lo + storage.user_count() as u64)
+        .filter_map(|user_number| match read_anchor(storage, user_number) {
+            Ok(_) | Err(StorageError::AnchorDeleted(_)) => None,
+            Err(err) => Some(CorruptedRecord {
+                user_number,
+                error: err.to_string(),
+            }),
+        })
+        .collect();
+
+    Report {
+        storage_layout_version: storage.version(),
+        num_anchors: storage.user_count(),
+        corrupted_records,
+    }
+}
+
+/// Like [Storage::read], but reports corrupted records instead of panicking.
+fn read_anchor(
+    storage: &AnchorStorage,
+    user_number: UserNumber,
+) -> Result<Vec<DeviceDataInternal>, StorageError> {
+    let buf = storage.read_bytes(user_number)?;
+    candid::decode_one(&buf).map_err(StorageError::DeserializationError)
+}